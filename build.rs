@@ -0,0 +1,35 @@
+// creates the scratch disks the disk driver tests attach to QEMU (see test-args in Cargo.toml)
+// bootimage passes the same QEMU arguments to every test binary, so the images have to exist for all of
+// them; they are sparse, zero-filled raw images and only created when missing
+// they live in TEST_DISK_DIR under the package root, not in the target directory: test-args can only
+// name fixed paths, and the target directory moves with CARGO_TARGET_DIR
+
+use std::env;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+// the -drive file= paths in test-args point here (relative to the package root); change both together
+const TEST_DISK_DIR: &str = "test-disks";
+const DISK_SIZE: u64 = 8 * 1024 * 1024;
+const TEST_DISKS: &[&str] = &["ata-test.img", "virtio-test.img", "ahci-test.img", "nvme-test.img"];
+
+fn main()
+{
+    // test-args paths are relative to the package root, which is where the runner starts QEMU
+    let directory = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join(TEST_DISK_DIR);
+    fs::create_dir_all(&directory).expect("[ERR] Cannot create the test disk directory");
+
+    for disk in TEST_DISKS
+    {
+        let path = directory.join(disk);
+        if !path.exists()
+        {
+            File::create(&path).and_then(|file| file.set_len(DISK_SIZE)).expect("[ERR] Cannot create a test disk image");
+        }
+
+        // rerun when an image is deleted
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,292 @@
+// ACPI table discovery: just enough to find the MADT (the list of processors and interrupt controllers)
+// and the MCFG (where PCIe configuration space is memory mapped)
+// tables are read in place, through the physical memory mapping
+
+use crate::memory;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+// the RSDP is in the first KiB of the EBDA or in the BIOS area, on a 16 byte boundary
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+pub const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+pub const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+
+// MCFG: header, 8 reserved bytes, then one entry per ECAM region
+const MCFG_ENTRIES_OFFSET: usize = size_of::<SdtHeader>() + 8;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+// local APIC flags
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError
+{
+    NoRsdp,                     // no ACPI (or not a BIOS machine)
+    BadChecksum([u8; 4]),       // of the table with this signature
+    TableNotFound([u8; 4]),
+    BadTable([u8; 4]),          // truncated, or an entry runs past the end
+}
+
+// header shared by every system description table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader
+{
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+// a processor, from a MADT local APIC entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor
+{
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,      // usable now (otherwise it may be hot-plugged later, if online capable)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic
+{
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,      // first global system interrupt it handles
+}
+
+// Multiple APIC Description Table
+#[derive(Debug, Clone)]
+pub struct Madt
+{
+    pub local_apic_address: PhysAddr,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+}
+
+impl Madt
+{
+    // parse a complete MADT (header included)
+    pub fn parse(table: &[u8]) -> Result<Self, AcpiError>
+    {
+        let signature = *MADT_SIGNATURE;
+        let header_size = size_of::<SdtHeader>();
+
+        if table.len() < header_size + 8 || &table[..4] != MADT_SIGNATURE
+        {
+            return Err(AcpiError::BadTable(signature));
+        }
+        if !checksum_ok(table)
+        {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+
+        let mut madt = Madt
+        {
+            local_apic_address: PhysAddr::new(read_u32(table, header_size) as u64),
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+        };
+
+        // variable length entries: type, length, data
+        let mut offset = header_size + 8;
+        while offset + 2 <= table.len()
+        {
+            let (entry_type, length) = (table[offset], table[offset + 1] as usize);
+            if length < 2 || offset + length > table.len()
+            {
+                return Err(AcpiError::BadTable(signature));
+            }
+            let entry = &table[offset..offset + length];
+
+            match entry_type
+            {
+                MADT_LOCAL_APIC if length >= 8 =>
+                {
+                    let flags = read_u32(entry, 4);
+                    if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0
+                    {
+                        madt.processors.push(Processor
+                        {
+                            acpi_id: entry[2],
+                            apic_id: entry[3],
+                            enabled: flags & LOCAL_APIC_ENABLED != 0,
+                        });
+                    }
+                }
+                MADT_IO_APIC if length >= 12 => madt.io_apics.push(IoApic
+                {
+                    id: entry[2],
+                    address: PhysAddr::new(read_u32(entry, 4) as u64),
+                    gsi_base: read_u32(entry, 8),
+                }),
+                MADT_LOCAL_APIC_OVERRIDE if length >= 12 =>
+                {
+                    madt.local_apic_address = PhysAddr::new(u64::from_le_bytes(entry[4..12].try_into().unwrap()));
+                }
+                _ => {}     // x2APIC, interrupt source overrides, NMIs: not used yet
+            }
+
+            offset += length;
+        }
+
+        Ok(madt)
+    }
+}
+
+// an ECAM region of the MCFG: configuration space of buses start_bus..=end_bus of a PCI segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcamRegion
+{
+    pub base: PhysAddr,         // of bus 0 (even if start_bus is higher)
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+// PCI Express memory mapped configuration table
+#[derive(Debug, Clone)]
+pub struct Mcfg
+{
+    pub regions: Vec<EcamRegion>,
+}
+
+impl Mcfg
+{
+    // parse a complete MCFG (header included)
+    pub fn parse(table: &[u8]) -> Result<Self, AcpiError>
+    {
+        let signature = *MCFG_SIGNATURE;
+
+        if table.len() < MCFG_ENTRIES_OFFSET || &table[..4] != MCFG_SIGNATURE
+        {
+            return Err(AcpiError::BadTable(signature));
+        }
+        if !checksum_ok(table)
+        {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+
+        let regions = table[MCFG_ENTRIES_OFFSET..].chunks_exact(MCFG_ENTRY_SIZE).map(|entry| EcamRegion
+        {
+            base: PhysAddr::new(u64::from_le_bytes(entry[0..8].try_into().unwrap())),
+            segment: read_u16(entry, 8),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        }).collect();
+
+        Ok(Mcfg { regions })
+    }
+}
+
+// find and parse the MADT
+pub fn madt() -> Result<Madt, AcpiError>
+{
+    let address = find_table(MADT_SIGNATURE)?;
+    Madt::parse(table_bytes(address))
+}
+
+// find and parse the MCFG (only PCI Express systems have one)
+pub fn mcfg() -> Result<Mcfg, AcpiError>
+{
+    let address = find_table(MCFG_SIGNATURE)?;
+    Mcfg::parse(table_bytes(address))
+}
+
+// physical address of the table with this signature, from the RSDT (or XSDT)
+pub fn find_table(signature: &[u8; 4]) -> Result<PhysAddr, AcpiError>
+{
+    let rsdp = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+    let rsdp_bytes = physical_bytes(rsdp, 36);
+
+    // revision 2 and up has the 64 bit XSDT
+    let (root, entry_size) = if rsdp_bytes[15] >= 2 && checksum_ok(rsdp_bytes)
+    {
+        (PhysAddr::new(u64::from_le_bytes(rsdp_bytes[24..32].try_into().unwrap())), 8)
+    }
+    else
+    {
+        (PhysAddr::new(read_u32(rsdp_bytes, 16) as u64), 4)
+    };
+
+    let root_table = table_bytes(root);
+    if !checksum_ok(root_table)
+    {
+        return Err(AcpiError::BadChecksum(root_table[..4].try_into().unwrap()));
+    }
+
+    let entries = root_table[size_of::<SdtHeader>()..].chunks_exact(entry_size).map(|entry| match entry_size
+    {
+        8 => u64::from_le_bytes(entry.try_into().unwrap()),
+        _ => read_u32(entry, 0) as u64,
+    });
+
+    for address in entries.map(PhysAddr::new)
+    {
+        if physical_bytes(address, 4) == signature
+        {
+            return Ok(address);
+        }
+    }
+
+    Err(AcpiError::TableNotFound(*signature))
+}
+
+// the Root System Description Pointer, searched where the BIOS leaves it
+pub fn find_rsdp() -> Option<PhysAddr>
+{
+    let ebda = (read_u16(physical_bytes(PhysAddr::new(EBDA_SEGMENT_POINTER), 2), 0) as u64) << 4;
+
+    let mut areas = [(ebda, ebda + 1024), BIOS_AREA];
+    if ebda == 0
+    {
+        areas[0] = (0, 0);
+    }
+
+    areas.into_iter()
+        .flat_map(|(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&address| physical_bytes(address, 8) == RSDP_SIGNATURE && checksum_ok(physical_bytes(address, 20)))
+}
+
+// a whole table, as long as its header says
+fn table_bytes(address: PhysAddr) -> &'static [u8]
+{
+    let length = read_u32(physical_bytes(address, size_of::<SdtHeader>()), 4);
+    physical_bytes(address, length as usize)
+}
+
+fn physical_bytes(address: PhysAddr, len: usize) -> &'static [u8]
+{
+    unsafe { core::slice::from_raw_parts(memory::phys_to_virt(address).as_ptr(), len) }
+}
+
+// every ACPI structure sums up to 0 (mod 256)
+fn checksum_ok(bytes: &[u8]) -> bool
+{
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16
+{
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
@@ -1,14 +1,34 @@
 use x86_64::{structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB}, VirtAddr};
 use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use x86_64::instructions::interrupts::without_interrupts;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;    // arbitrary starting address
-pub const HEAP_SIZE: usize = 100 * 1024;    // 100 KiB heap
+pub const HEAP_SIZE: usize = 1024 * 1024;    // 1 MiB heap (kernel thread stacks live here too)
 
 // absolutely low-effort Dummy allocator
 pub struct Dummy;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: IrqSafeHeap = IrqSafeHeap(LockedHeap::empty());
+
+// the heap lock must never be held by a preempted thread:
+// another thread (or the scheduler) allocating with interrupts off would spin on it forever
+// -> disable interrupts for the duration of every alloc/dealloc
+pub struct IrqSafeHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for IrqSafeHeap
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        without_interrupts(|| unsafe { self.0.alloc(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+        without_interrupts(|| unsafe { self.0.dealloc(ptr, layout) })
+    }
+}
 
 // need to map the heap region before actually using it...
 pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), MapToError<Size4KiB>>
@@ -41,7 +61,7 @@ pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl
     };
 
     // initialise the ALLOCATOR
-    unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE); }
+    unsafe { ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE); }
 
     Ok(())
 }
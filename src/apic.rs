@@ -0,0 +1,151 @@
+// local APIC (xAPIC, memory mapped): the per-CPU interrupt controller, used here to identify CPUs
+// and to send inter-processor interrupts; external interrupts still come through the 8259 PICs
+
+use crate::memory;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+
+// every CPU sees its own local APIC at the same physical address
+pub const LAPIC_SIZE: u64 = 0x1000;
+
+// registers (offsets from the base, 32 bit wide, 16 byte aligned)
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_LINT0: usize = 0x350;
+const REG_LVT_LINT1: usize = 0x360;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+// local vector table: delivery modes, mask
+const LVT_NMI: u32 = 0x400;
+const LVT_EXTINT: u32 = 0x700;
+const LVT_MASKED: u32 = 1 << 16;
+
+// interrupt command register
+const ICR_FIXED: u32 = 0x000;
+const ICR_INIT: u32 = 0x500;
+const ICR_STARTUP: u32 = 0x600;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// IA32_APIC_BASE: bit 8 is set on the bootstrap processor
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_BSP: u64 = 1 << 8;
+
+// vector of spurious interrupts (the low 4 bits must be set on older CPUs)
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// virtual address of the mapped registers, 0 before init
+static LAPIC: AtomicU64 = AtomicU64::new(0);
+
+// map the local APIC registers (needs the global memory, see memory::init_global)
+pub fn init(base: PhysAddr) -> Result<(), MapToError<Size4KiB>>
+{
+    if LAPIC.load(Ordering::Acquire) == 0
+    {
+        let virt = memory::map_mmio(base, LAPIC_SIZE)?;
+        LAPIC.store(virt.as_u64(), Ordering::Release);
+    }
+
+    Ok(())
+}
+
+pub fn is_initialised() -> bool
+{
+    LAPIC.load(Ordering::Acquire) != 0
+}
+
+// software enable the local APIC of the calling CPU, so it can receive IPIs
+// the 8259 PICs stay wired to LINT0 of the BSP (virtual wire mode); the other CPUs mask it
+pub fn enable()
+{
+    let lint0 = if is_bootstrap_processor() { LVT_EXTINT } else { LVT_EXTINT | LVT_MASKED };
+    write(REG_LVT_LINT0, lint0);
+    write(REG_LVT_LINT1, LVT_NMI);
+
+    write(REG_SPURIOUS, read(REG_SPURIOUS) | SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+pub fn is_bootstrap_processor() -> bool
+{
+    unsafe { Msr::new(IA32_APIC_BASE).read() & APIC_BASE_BSP != 0 }
+}
+
+// APIC ID of the calling CPU
+pub fn id() -> u8
+{
+    (read(REG_ID) >> 24) as u8
+}
+
+// signal the end of an interrupt delivered by the local APIC
+pub fn end_of_interrupt()
+{
+    write(REG_EOI, 0);
+}
+
+// INIT IPI: puts the target CPU in wait-for-SIPI state
+pub fn send_init(apic_id: u8)
+{
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+// startup IPI: the target starts in real mode at page * 4096 (CS = page << 8, IP = 0)
+pub fn send_startup(apic_id: u8, page: u8)
+{
+    send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+// interrupt one CPU with this vector (its handler has to call end_of_interrupt)
+pub fn send_vector(apic_id: u8, vector: u8)
+{
+    send_ipi(apic_id, ICR_FIXED | ICR_LEVEL_ASSERT | vector as u32);
+}
+
+// interrupt every other CPU with this vector
+pub fn broadcast_vector(vector: u8)
+{
+    send_ipi(0, ICR_ALL_EXCLUDING_SELF | ICR_FIXED | ICR_LEVEL_ASSERT | vector as u32);
+}
+
+// send an IPI (to one CPU, unless the command has a destination shorthand) and wait until the local
+// APIC has accepted it
+fn send_ipi(apic_id: u8, command: u32)
+{
+    x86_64::instructions::interrupts::without_interrupts(||
+    {
+        write(REG_ICR_HIGH, (apic_id as u32) << 24);
+
+        // writing the low half sends it
+        write(REG_ICR_LOW, command);
+
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0
+        {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+fn register(offset: usize) -> *mut u32
+{
+    let base = LAPIC.load(Ordering::Acquire);
+    assert!(base != 0, "[ERR] Local APIC not initialised");
+
+    (base as usize + offset) as *mut u32
+}
+
+fn read(offset: usize) -> u32
+{
+    unsafe { register(offset).read_volatile() }
+}
+
+fn write(offset: usize, value: u32)
+{
+    unsafe { register(offset).write_volatile(value) }
+}
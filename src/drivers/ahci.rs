@@ -0,0 +1,699 @@
+// AHCI SATA host bus adapters (PCI class 01/06, programming interface 01), with DMA
+// the registers are in the ABAR (BAR 5); every implemented port with a SATA disk on it is registered
+// as a block device sda, sdb, ...; packet devices (ATAPI) are found, but not driven
+// each port has a command list of up to 32 slots, an area the HBA copies received FISes to, and a
+// command table per slot: the command FIS and the PRDT (the data's scatter list)
+// reads and writes are READ/WRITE DMA EXT, or their NCQ forms (FPDMA QUEUED) once a port is set to
+// (where HBA and disk support it); completions come through MSI when the local APIC is up
+// (smp::init), else they are polled
+
+use super::ata::IdentifyInfo;
+use super::block::{self, BlockDevice, BlockError};
+use super::dma::DmaFrames;
+use super::model::{Device, DeviceMatch, Driver, DriverError};
+use super::pci::{self, PciDevice, PciMatch};
+use crate::memory::PAGE_SIZE;
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, ThreadId};
+use crate::{apic, interrupts};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const SUBCLASS_SATA: u8 = 0x06;
+pub const PROG_IF_AHCI: u8 = 0x01;
+const ABAR: usize = 5;
+
+// HBA registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+
+const CAP_SLOTS_SHIFT: u32 = 8;         // command slots - 1, 5 bits
+const CAP_SLOTS_MASK: u32 = 0x1f;
+const CAP_SNCQ: u32 = 1 << 30;
+const CAP_S64A: u32 = 1 << 31;          // 64 bit addresses
+
+const GHC_HR: u32 = 1 << 0;             // reset
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;            // AHCI mode
+
+// port registers, from 0x100 + 0x80 * port
+const PORTS_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;             // process the command list
+const CMD_FRE: u32 = 1 << 4;            // receive FISes
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+// port interrupts: D2H register FIS, PIO setup FIS, set device bits FIS (NCQ), and the errors that
+// stop the port (interface, host bus data, host bus fatal, task file)
+const IS_DHRS: u32 = 1 << 0;
+const IS_PSS: u32 = 1 << 1;
+const IS_SDBS: u32 = 1 << 3;
+const IS_FATAL: u32 = 1 << 27 | 1 << 28 | 1 << 29 | 1 << 30;
+
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+const SSTS_DET_MASK: u32 = 0x0f;
+const SSTS_DET_PRESENT: u32 = 3;        // device there, link up
+
+const SIG_ATA: u32 = 0x0000_0101;
+
+// ATA commands
+const ATA_READ_DMA: u8 = 0xc8;
+const ATA_WRITE_DMA: u8 = 0xca;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_FLUSH_CACHE: u8 = 0xe7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY: u8 = 0xec;
+
+const FIS_TYPE_H2D: u8 = 0x27;
+const FIS_H2D_COMMAND: u8 = 1 << 7;     // the FIS carries a command (not a control update)
+const FIS_H2D_SIZE: usize = 20;
+const DEVICE_LBA: u8 = 1 << 6;
+
+// command header: FIS length in dwords, write, PRDT length
+const HEADER_WRITE: u32 = 1 << 6;
+const HEADER_PRDTL_SHIFT: u32 = 16;
+const HEADER_SIZE: usize = 32;
+
+const PRD_INTERRUPT: u32 = 1 << 31;
+
+// port memory: the command list (1 KiB aligned) and received FISes (256 byte aligned) in the first
+// frame, then the command tables, 16 a frame: the FIS area (0x80 bytes), then MAX_PRDT entries
+const RECEIVED_FIS: u64 = 0x400;
+const TABLE_SIZE: usize = 0x100;
+const TABLE_PRDT: usize = 0x80;
+const PRDT_ENTRY_SIZE: usize = 16;
+const MAX_PRDT: usize = (TABLE_SIZE - TABLE_PRDT) / PRDT_ENTRY_SIZE;
+const TABLES_PER_FRAME: usize = PAGE_SIZE / TABLE_SIZE;
+
+pub const SECTOR_SIZE: usize = 512;
+const MAX_TRANSFER: usize = MAX_PRDT * PAGE_SIZE;
+
+const TIMEOUT_POLLS: usize = 10_000_000;
+const IRQ_TIMEOUT_TICKS: u64 = 5 * interrupts::TIMER_HZ;
+
+// disks found so far (they are named in order), and the HBAs with them (their interrupt handlers
+// point at them)
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+static HBAS: IrqSafeMutex<Vec<Arc<Hba>>> = IrqSafeMutex::new(Vec::new());
+
+// a disk by name (the block device, with the AHCI specific settings)
+pub fn disk(name: &str) -> Option<Arc<AhciDisk>>
+{
+    HBAS.lock().iter().flat_map(|hba| hba.disks.iter()).find(|disk| disk.name == name).cloned()
+}
+
+// a host to device register FIS for command
+// with a tag, an NCQ command: the count goes into the features, the tag into the count
+pub fn command_fis(command: u8, lba: u64, count: u16, tag: Option<u8>) -> [u8; FIS_H2D_SIZE]
+{
+    let lba = lba.to_le_bytes();
+    let (features, count) = match tag
+    {
+        Some(tag) => (count, (tag as u16) << 3),
+        None => (0, count),
+    };
+
+    let mut fis = [0u8; FIS_H2D_SIZE];
+    fis[0] = FIS_TYPE_H2D;
+    fis[1] = FIS_H2D_COMMAND;
+    fis[2] = command;
+    fis[3] = features as u8;
+    fis[4..7].copy_from_slice(&lba[0..3]);
+    fis[7] = match command
+    {
+        // 28 bit commands keep LBA bits 24-27 in the device register
+        ATA_READ_DMA | ATA_WRITE_DMA => DEVICE_LBA | (lba[3] & 0x0f),
+        ATA_IDENTIFY => 0,
+        _ => DEVICE_LBA,
+    };
+    fis[8..11].copy_from_slice(&lba[3..6]);
+    fis[11] = (features >> 8) as u8;
+    fis[12..14].copy_from_slice(&count.to_le_bytes());
+    fis
+}
+
+// the first four dwords of a command header (the rest is reserved)
+pub fn command_header(write: bool, prdt_len: usize, table: PhysAddr) -> [u32; 4]
+{
+    let flags = (FIS_H2D_SIZE / 4) as u32 | if write { HEADER_WRITE } else { 0 } | (prdt_len as u32) << HEADER_PRDTL_SHIFT;
+    [flags, 0, table.as_u64() as u32, (table.as_u64() >> 32) as u32]
+}
+
+// a PRDT entry for len bytes (even, at most 4 MiB) at address
+pub fn prdt_entry(address: PhysAddr, len: usize, interrupt: bool) -> [u32; 4]
+{
+    let count = (len as u32 - 1) | if interrupt { PRD_INTERRUPT } else { 0 };
+    [address.as_u64() as u32, (address.as_u64() >> 32) as u32, 0, count]
+}
+
+// an ATA command for a port to run
+#[derive(Debug, Clone, Copy)]
+struct Command
+{
+    opcode: u8,
+    lba: u64,
+    count: u16,
+    ncq: bool,      // queued (FPDMA): the slot is the tag
+    write: bool,    // data goes to the device
+}
+
+impl Command
+{
+    fn new(opcode: u8) -> Self
+    {
+        Command { opcode, lba: 0, count: 0, ncq: false, write: false }
+    }
+}
+
+// memory mapped registers
+#[derive(Clone, Copy)]
+struct Registers(VirtAddr);
+
+impl Registers
+{
+    fn read(&self, offset: usize) -> u32
+    {
+        unsafe { ptr::read_volatile((self.0 + offset as u64).as_ptr()) }
+    }
+
+    fn write(&self, offset: usize, value: u32)
+    {
+        unsafe { ptr::write_volatile((self.0 + offset as u64).as_mut_ptr(), value) }
+    }
+
+    // poll until done(register) holds
+    fn wait(&self, offset: usize, done: impl Fn(u32) -> bool) -> Result<(), BlockError>
+    {
+        (0..TIMEOUT_POLLS).map(|_| self.read(offset)).any(done).then_some(()).ok_or(BlockError::Timeout)
+    }
+}
+
+// what the slots of a port are doing
+struct PortState
+{
+    used: u32,                              // taken by a thread (until it has the result)
+    issued: u32,                            // with the HBA
+    recover: bool,                          // the port stopped on an error
+    waiters: [Option<ThreadId>; 32],
+    results: [Option<Result<(), u8>>; 32],  // the ATA error register on failure
+}
+
+// a port with a device, running
+struct Port
+{
+    registers: Registers,
+    memory: DmaFrames,
+    slots: u32,                 // mask of the slots the HBA has
+    interrupts: AtomicBool,
+    state: IrqSafeMutex<PortState>,
+}
+
+impl Port
+{
+    // set up the port's memory and start it; None if there is no device (or it does not come up)
+    fn start(registers: Registers, slot_count: usize, addresses_64: bool) -> Option<(Port, u32)>
+    {
+        let port = Port
+        {
+            registers,
+            memory: DmaFrames::new(1 + slot_count.div_ceil(TABLES_PER_FRAME)).ok()?,
+            slots: if slot_count == 32 { u32::MAX } else { (1 << slot_count) - 1 },
+            interrupts: AtomicBool::new(false),
+            state: IrqSafeMutex::new(PortState { used: 0, issued: 0, recover: false, waiters: [None; 32], results: [None; 32] }),
+        };
+
+        if !addresses_64 && (0..port.memory.len()).any(|index| port.memory.phys(index).as_u64() >> 32 != 0)
+        {
+            return None;
+        }
+
+        port.stop().ok()?;
+        let list = port.memory.phys(0);
+        let fis = list + RECEIVED_FIS;
+        registers.write(PX_CLB, list.as_u64() as u32);
+        registers.write(PX_CLBU, (list.as_u64() >> 32) as u32);
+        registers.write(PX_FB, fis.as_u64() as u32);
+        registers.write(PX_FBU, (fis.as_u64() >> 32) as u32);
+        registers.write(PX_SERR, u32::MAX);
+        registers.write(PX_IS, u32::MAX);
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_FRE);
+
+        if registers.read(PX_SSTS) & SSTS_DET_MASK != SSTS_DET_PRESENT
+        {
+            return None;
+        }
+        registers.wait(PX_TFD, |tfd| tfd & (TFD_BSY | TFD_DRQ) == 0).ok()?;
+
+        registers.write(PX_IE, IS_DHRS | IS_PSS | IS_SDBS | IS_FATAL);
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_ST);
+
+        let signature = registers.read(PX_SIG);
+        Some((port, signature))
+    }
+
+    // stop processing commands and receiving FISes
+    fn stop(&self) -> Result<(), BlockError>
+    {
+        let registers = self.registers;
+        registers.write(PX_CMD, registers.read(PX_CMD) & !CMD_ST);
+        registers.wait(PX_CMD, |cmd| cmd & CMD_CR == 0)?;
+        registers.write(PX_CMD, registers.read(PX_CMD) & !CMD_FRE);
+        registers.wait(PX_CMD, |cmd| cmd & CMD_FR == 0)
+    }
+
+    // restart after an error stopped the port (its commands are failed already)
+    fn recover(&self)
+    {
+        let registers = self.registers;
+        registers.write(PX_CMD, registers.read(PX_CMD) & !CMD_ST);
+        let _ = registers.wait(PX_CMD, |cmd| cmd & CMD_CR == 0);
+
+        registers.write(PX_SERR, u32::MAX);
+        registers.write(PX_IS, u32::MAX);
+        let _ = registers.wait(PX_TFD, |tfd| tfd & (TFD_BSY | TFD_DRQ) == 0);
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_ST);
+    }
+
+    // note the slots the HBA is done with, wake their waiters
+    // called by the interrupt handler; must not block or allocate!
+    fn complete(&self)
+    {
+        let mut state = self.state.lock();
+        let status = self.registers.read(PX_IS);
+        self.registers.write(PX_IS, status);
+
+        let finished = if status & IS_FATAL != 0
+        {
+            // the port stopped: nothing outstanding will finish
+            state.recover = true;
+            state.issued
+        }
+        else
+        {
+            state.issued & !(self.registers.read(PX_CI) | self.registers.read(PX_SACT))
+        };
+
+        let error = (status & IS_FATAL != 0).then(|| (self.registers.read(PX_TFD) >> 8) as u8);
+        for slot in (0..32).filter(|slot| finished & 1 << slot != 0)
+        {
+            state.results[slot] = Some(error.map_or(Ok(()), Err));
+            if let Some(waiter) = state.waiters[slot]
+            {
+                thread::unpark(waiter);
+            }
+        }
+        state.issued &= !finished;
+    }
+
+    fn acquire_slot(&self) -> usize
+    {
+        loop
+        {
+            {
+                let mut state = self.state.lock();
+                let free = self.slots & !state.used;
+                if free != 0
+                {
+                    let slot = free.trailing_zeros() as usize;
+                    state.used |= 1 << slot;
+                    state.results[slot] = None;
+                    return slot;
+                }
+            }
+            thread::yield_now();
+        }
+    }
+
+    fn table(&self, slot: usize) -> (*mut u8, PhysAddr)
+    {
+        let frame = 1 + slot / TABLES_PER_FRAME;
+        let offset = slot % TABLES_PER_FRAME * TABLE_SIZE;
+        (self.memory.ptr::<u8>(frame).wrapping_add(offset), self.memory.phys(frame) + offset as u64)
+    }
+
+    // run a command with the first len bytes of data; returns the frames for reading out
+    fn execute(&self, command: Command, data: DmaFrames, len: usize) -> Result<DmaFrames, BlockError>
+    {
+        let slot = self.acquire_slot();
+        let fis = command_fis(command.opcode, command.lba, command.count, command.ncq.then_some(slot as u8));
+
+        // the table: FIS, PRDT
+        let (table, table_phys) = self.table(slot);
+        let segments: Vec<_> = data.segments(len).collect();
+        unsafe
+        {
+            ptr::write_bytes(table, 0, TABLE_SIZE);
+            ptr::copy_nonoverlapping(fis.as_ptr(), table, fis.len());
+            for (index, &(address, len)) in segments.iter().enumerate()
+            {
+                let entry = prdt_entry(address, len, index + 1 == segments.len());
+                ptr::copy_nonoverlapping(entry.as_ptr(), table.add(TABLE_PRDT + index * PRDT_ENTRY_SIZE).cast(), entry.len());
+            }
+
+            let header = command_header(command.write, segments.len(), table_phys);
+            ptr::copy_nonoverlapping(header.as_ptr(), self.memory.ptr::<u8>(0).add(slot * HEADER_SIZE).cast(), header.len());
+        }
+
+        {
+            let mut state = self.state.lock();
+            state.waiters[slot] = thread::current_id();
+            state.issued |= 1 << slot;
+            fence(Ordering::SeqCst);
+            if command.ncq
+            {
+                self.registers.write(PX_SACT, 1 << slot);
+            }
+            self.registers.write(PX_CI, 1 << slot);
+        }
+
+        let done = || self.state.lock().results[slot].is_some();
+        if self.interrupts.load(Ordering::Acquire) && x86_64::instructions::interrupts::are_enabled()
+        {
+            thread::sleep_ticks_interruptible(IRQ_TIMEOUT_TICKS, done);
+        }
+        else
+        {
+            for _ in 0..TIMEOUT_POLLS
+            {
+                self.complete();
+                if done()
+                {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        let mut state = self.state.lock();
+        state.waiters[slot] = None;
+        let Some(result) = state.results[slot].take() else
+        {
+            // the HBA may still use the slot and the data: both stay taken
+            core::mem::forget(data);
+            return Err(BlockError::Timeout);
+        };
+        state.used &= !(1 << slot);
+
+        if core::mem::take(&mut state.recover)
+        {
+            self.recover();
+        }
+
+        result.map(|_| data).map_err(BlockError::DeviceError)
+    }
+}
+
+// a port without a (usable) disk stops again, before its memory goes
+impl Drop for Port
+{
+    fn drop(&mut self)
+    {
+        self.registers.write(PX_IE, 0);
+        let _ = self.stop();
+    }
+}
+
+// a SATA disk on a port
+pub struct AhciDisk
+{
+    name: String,
+    index: usize,               // port number
+    port: Port,
+    info: IdentifyInfo,
+    ncq_capable: bool,          // HBA and disk
+    ncq: AtomicBool,
+}
+
+impl AhciDisk
+{
+    fn identify(port: &Port) -> Result<IdentifyInfo, BlockError>
+    {
+        let data = DmaFrames::new(1).map_err(|_| BlockError::OutOfMemory)?;
+        let data = port.execute(Command::new(ATA_IDENTIFY), data, SECTOR_SIZE)?;
+
+        let mut bytes = [0u8; SECTOR_SIZE];
+        data.read(&mut bytes);
+        let mut words = [0u16; 256];
+        for (word, pair) in words.iter_mut().zip(bytes.chunks_exact(2))
+        {
+            *word = u16::from_le_bytes([pair[0], pair[1]]);
+        }
+
+        Ok(IdentifyInfo::parse(&words))
+    }
+
+    pub fn info(&self) -> &IdentifyInfo
+    {
+        &self.info
+    }
+
+    pub fn port(&self) -> usize
+    {
+        self.index
+    }
+
+    pub fn ncq_capable(&self) -> bool
+    {
+        self.ncq_capable
+    }
+
+    // queue reads and writes with NCQ (where HBA and disk can); returns whether they are
+    pub fn set_ncq(&self, enabled: bool) -> bool
+    {
+        let enabled = enabled && self.ncq_capable;
+        self.ncq.store(enabled, Ordering::Relaxed);
+        enabled
+    }
+
+    fn transfer(&self, lba: u64, data: DmaFrames, len: usize, write: bool) -> Result<DmaFrames, BlockError>
+    {
+        let count = (len / SECTOR_SIZE) as u16;
+        let ncq = self.ncq.load(Ordering::Relaxed);
+
+        let opcode = match (ncq, self.info.lba48, write)
+        {
+            (true, _, false) => ATA_READ_FPDMA_QUEUED,
+            (true, _, true) => ATA_WRITE_FPDMA_QUEUED,
+            (false, true, false) => ATA_READ_DMA_EXT,
+            (false, true, true) => ATA_WRITE_DMA_EXT,
+            (false, false, false) => ATA_READ_DMA,
+            (false, false, true) => ATA_WRITE_DMA,
+        };
+        self.port.execute(Command { opcode, lba, count, ncq, write }, data, len)
+    }
+}
+
+impl BlockDevice for AhciDisk
+{
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize
+    {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64
+    {
+        self.info.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        block::check_request(SECTOR_SIZE, self.info.sectors, lba, buffer.len())?;
+
+        for (index, chunk) in buffer.chunks_mut(MAX_TRANSFER).enumerate()
+        {
+            let data = DmaFrames::for_bytes(chunk.len()).map_err(|_| BlockError::OutOfMemory)?;
+            let sector = lba + (index * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            self.transfer(sector, data, chunk.len(), false)?.read(chunk);
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        block::check_request(SECTOR_SIZE, self.info.sectors, lba, buffer.len())?;
+
+        for (index, chunk) in buffer.chunks(MAX_TRANSFER).enumerate()
+        {
+            let data = DmaFrames::for_bytes(chunk.len()).map_err(|_| BlockError::OutOfMemory)?;
+            data.write(chunk);
+            let sector = lba + (index * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            self.transfer(sector, data, chunk.len(), true)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        let none = DmaFrames::new(0).map_err(|_| BlockError::OutOfMemory)?;
+        let opcode = if self.info.lba48 { ATA_FLUSH_CACHE_EXT } else { ATA_FLUSH_CACHE };
+        self.port.execute(Command::new(opcode), none, 0).map(|_| ())
+    }
+}
+
+// an HBA and its disks (for the interrupt handler)
+struct Hba
+{
+    registers: Registers,
+    disks: Vec<Arc<AhciDisk>>,
+}
+
+// the HBA's (single) MSI: argument is the HBA
+fn hba_interrupt(hba: usize)
+{
+    let hba = unsafe { &*(hba as *const Hba) };
+    let pending = hba.registers.read(HBA_IS);
+
+    for disk in hba.disks.iter().filter(|disk| pending & 1 << disk.index != 0)
+    {
+        disk.port.complete();
+    }
+    hba.registers.write(HBA_IS, pending);
+}
+
+// reset the HBA into AHCI mode and find the disks on its ports
+fn init_hba(device: &'static PciDevice) -> Result<Hba, DriverError>
+{
+    let registers = Registers(device.map_bar(ABAR).map_err(DriverError::failed)?);
+    device.enable_bus_master();
+
+    registers.write(HBA_GHC, GHC_AE);
+    registers.write(HBA_GHC, GHC_AE | GHC_HR);
+    registers.wait(HBA_GHC, |ghc| ghc & GHC_HR == 0).map_err(DriverError::failed)?;
+    registers.write(HBA_GHC, GHC_AE);
+
+    let capabilities = registers.read(HBA_CAP);
+    let slot_count = ((capabilities >> CAP_SLOTS_SHIFT) & CAP_SLOTS_MASK) as usize + 1;
+    let implemented = registers.read(HBA_PI);
+
+    let mut disks = Vec::new();
+    for index in (0..32).filter(|index| implemented & 1 << index != 0)
+    {
+        let port_registers = Registers(registers.0 + PORTS_BASE + PORT_SIZE * index as u64);
+        let Some((port, signature)) = Port::start(port_registers, slot_count, capabilities & CAP_S64A != 0) else { continue };
+
+        // packet devices (signature 0xeb140101) and port multipliers are left alone
+        if signature != SIG_ATA
+        {
+            continue;
+        }
+
+        let Ok(info) = AhciDisk::identify(&port) else { continue };
+        let letter = (b'a' + DISKS.fetch_add(1, Ordering::Relaxed) as u8) as char;
+        disks.push(Arc::new(AhciDisk
+        {
+            name: alloc::format!("sd{}", letter),
+            index,
+            port,
+            info,
+            ncq_capable: capabilities & CAP_SNCQ != 0 && info.ncq,
+            ncq: AtomicBool::new(false),
+        }));
+    }
+
+    Ok(Hba { registers, disks })
+}
+
+pub struct AhciDriver;
+
+impl Driver for AhciDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "ahci"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        const IDS: &[DeviceMatch] = &[DeviceMatch::Pci(PciMatch::class(pci::CLASS_MASS_STORAGE, SUBCLASS_SATA).with_prog_if(PROG_IF_AHCI))];
+        IDS
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        let pci = device.pci().ok_or(DriverError::NoDevice)?;
+        let hba = Arc::new(init_hba(pci)?);
+
+        // MSI goes to a local APIC, which has to be up
+        if apic::is_initialised() && pci.msi.is_some()
+            && let Some(vector) = interrupts::allocate_vector(hba_interrupt, Arc::as_ptr(&hba) as usize)
+        {
+            if pci.enable_msi(vector, apic::id()).is_ok()
+            {
+                hba.disks.iter().for_each(|disk| disk.port.interrupts.store(true, Ordering::Release));
+                hba.registers.write(HBA_GHC, GHC_AE | GHC_IE);
+            }
+            else
+            {
+                interrupts::free_vector(vector);
+            }
+        }
+
+        for disk in &hba.disks
+        {
+            block::register(disk.clone());
+        }
+        HBAS.lock().push(hba);
+        Ok(())
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn builds_command_fises()
+{
+    let fis = command_fis(ATA_READ_DMA_EXT, 0x0605_0403_0201, 8, None);
+    assert_eq!(fis[..14], [FIS_TYPE_H2D, 0x80, 0x25, 0, 0x01, 0x02, 0x03, 0x40, 0x04, 0x05, 0x06, 0, 8, 0]);
+
+    // NCQ: count in the features, tag in bits 3-7 of the count
+    let fis = command_fis(ATA_WRITE_FPDMA_QUEUED, 0x10, 0x0102, Some(5));
+    assert_eq!((fis[3], fis[11], fis[12], fis[13]), (0x02, 0x01, 5 << 3, 0));
+
+    // 28 bit: LBA bits 24-27 in the device register
+    assert_eq!(command_fis(ATA_READ_DMA, 0x0abc_def1, 1, None)[7], 0x4a);
+}
+
+#[test_case]
+fn builds_headers_and_prdt_entries()
+{
+    let header = command_header(true, 3, PhysAddr::new(0x1_2345_6780));
+    assert_eq!(header, [5 | HEADER_WRITE | 3 << 16, 0, 0x2345_6780, 0x1]);
+
+    assert_eq!(prdt_entry(PhysAddr::new(0x8000), 4096, true), [0x8000, 0, 0, 4095 | PRD_INTERRUPT]);
+    assert_eq!(prdt_entry(PhysAddr::new(0x8000), 512, false), [0x8000, 0, 0, 511]);
+}
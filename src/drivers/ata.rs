@@ -0,0 +1,906 @@
+// ATA/ATAPI drives on the IDE controller (PCI class 01/01), in PIO mode
+// each channel (primary, secondary) has a master and a slave drive; IDENTIFY (IDENTIFY PACKET DEVICE
+// for ATAPI) finds them, and they are registered as block devices hda-hdd
+// transfers use 28 bit LBA where it reaches, 48 bit beyond; a channel waits for its interrupt (IRQ 14 /
+// IRQ 15, compatibility mode only) or polls the status register (native mode, interrupts off, or
+// when set to with set_wait_mode)
+
+use super::block::{self, BlockDevice, BlockError};
+use super::model::{Device, DeviceMatch, Driver, DriverError};
+use super::pci::{self, Bar, PciMatch};
+use crate::interrupts::{self, InterruptIndex};
+use crate::sync::{IrqSafeMutex, Mutex};
+use crate::thread::{self, ThreadId};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+
+pub const SUBCLASS_IDE: u8 = 0x01;
+
+// programming interface: channel in native mode (ports from the BARs, PCI interrupt)
+const PROG_IF_NATIVE: [u8; 2] = [1 << 0, 1 << 2];
+
+// compatibility mode: command block base, control register
+const LEGACY_CHANNELS: [(u16, u16); 2] = [(0x1f0, 0x3f6), (0x170, 0x376)];
+const IRQS: [InterruptIndex; 2] = [InterruptIndex::PrimaryAta, InterruptIndex::SecondaryAta];
+
+// native mode: the control register is at offset 2 of the BAR
+const NATIVE_CONTROL_OFFSET: u16 = 2;
+
+// command block registers, from the base
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;           // read
+const REG_FEATURES: u16 = 1;        // write
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;          // read, acknowledges the interrupt
+const REG_COMMAND: u16 = 7;         // write
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;      // data wants to be transferred
+const STATUS_DF: u8 = 1 << 5;       // drive fault
+const STATUS_BSY: u8 = 1 << 7;
+
+// control register (alternate status when read: does not acknowledge the interrupt)
+const CONTROL_NIEN: u8 = 1 << 1;    // no interrupts
+const CONTROL_SRST: u8 = 1 << 2;    // software reset of both drives
+
+const DRIVE_BASE: u8 = 0xa0;        // bits 5 and 7 are set by convention
+const DRIVE_LBA: u8 = 1 << 6;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_PACKET: u8 = 0xa0;
+const CMD_IDENTIFY_PACKET: u8 = 0xa1;
+const CMD_CACHE_FLUSH: u8 = 0xe7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xea;
+const CMD_IDENTIFY: u8 = 0xec;
+
+// LBA mid/high of packet devices after an aborted IDENTIFY (parallel and serial ATAPI)
+const ATAPI_SIGNATURES: [(u8, u8); 2] = [(0x14, 0xeb), (0x69, 0x96)];
+
+// SCSI commands of ATAPI packets
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_12: u8 = 0xa8;
+const ATAPI_PACKET_SIZE: usize = 12;
+const SENSE_KEY_NOT_READY: u8 = 0x02;     // in the upper nibble of the error register
+
+// IDENTIFY words
+const ID_SERIAL: usize = 10;
+const ID_MODEL: usize = 27;
+const ID_LBA28_SECTORS: usize = 60;
+const ID_QUEUE_DEPTH: usize = 75;
+const ID_SATA_CAPABILITIES: usize = 76;
+const ID_COMMAND_SETS: usize = 83;
+const ID_LBA48_SECTORS: usize = 100;
+const COMMAND_SET_LBA48: u16 = 1 << 10;
+const SATA_NCQ: u16 = 1 << 8;
+const QUEUE_DEPTH_MASK: u16 = 0x1f;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const ATAPI_SECTOR_SIZE: usize = 2048;
+
+const LBA28_LIMIT: u64 = 1 << 28;
+const LBA48_LIMIT: u64 = 1 << 48;
+const LBA28_MAX_SECTORS: u64 = 256;
+const LBA48_MAX_SECTORS: u64 = 65536;
+const ATAPI_MAX_SECTORS: u64 = 16;        // per READ(12), keeps the byte count in 16 bits
+
+const TIMEOUT_POLLS: usize = 1_000_000;
+const IRQ_TIMEOUT_TICKS: u64 = 2 * interrupts::TIMER_HZ;
+
+// the compatibility channels' interrupts: fired flag, the thread waiting for it, the status register
+// to read (that acknowledges the interrupt at the drive)
+static IRQ_FIRED: [AtomicBool; 2] = [const { AtomicBool::new(false) }; 2];
+static IRQ_WAITER: [IrqSafeMutex<Option<ThreadId>>; 2] = [const { IrqSafeMutex::new(None) }; 2];
+static IRQ_STATUS_PORT: [AtomicU16; 2] = [const { AtomicU16::new(0) }; 2];
+
+// IDE controllers probed so far (they name their drives in order), and the drives they found
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+static DRIVES: IrqSafeMutex<Vec<Arc<AtaDrive>>> = IrqSafeMutex::new(Vec::new());
+
+// called by the IRQ 14 / IRQ 15 handlers
+// must not block or allocate!
+pub(crate) fn interrupt(channel: usize)
+{
+    let port = IRQ_STATUS_PORT[channel].load(Ordering::Acquire);
+    if port != 0
+    {
+        unsafe { Port::<u8>::new(port).read(); }
+    }
+
+    IRQ_FIRED[channel].store(true, Ordering::Release);
+    if let Some(id) = *IRQ_WAITER[channel].lock()
+    {
+        thread::unpark(id);
+    }
+}
+
+// a detected drive by name (the block device, with the ATA specific settings)
+pub fn drive(name: &str) -> Option<Arc<AtaDrive>>
+{
+    DRIVES.lock().iter().find(|drive| drive.name == name).cloned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LbaMode
+{
+    Lba28,
+    Lba48,
+}
+
+impl LbaMode
+{
+    // the mode for count sectors at lba, None if the drive cannot reach them in one command
+    pub fn for_transfer(lba: u64, count: u64, lba48: bool) -> Option<LbaMode>
+    {
+        let end = lba.checked_add(count)?;
+        if end <= LBA28_LIMIT && count <= LBA28_MAX_SECTORS
+        {
+            Some(LbaMode::Lba28)
+        }
+        else if lba48 && end <= LBA48_LIMIT && count <= LBA48_MAX_SECTORS
+        {
+            Some(LbaMode::Lba48)
+        }
+        else
+        {
+            None
+        }
+    }
+
+    pub fn max_sectors(self) -> u64
+    {
+        match self
+        {
+            LbaMode::Lba28 => LBA28_MAX_SECTORS,
+            LbaMode::Lba48 => LBA48_MAX_SECTORS,
+        }
+    }
+}
+
+// register values of a transfer; count and LBA bytes from low to high (48 bit: the high ones are
+// written first, to the same registers)
+// a count of 0 means the maximum (256 / 65536)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskFile
+{
+    pub drive: u8,
+    pub count: [u8; 2],
+    pub lba: [u8; 6],
+}
+
+impl TaskFile
+{
+    pub fn new(mode: LbaMode, slave: bool, lba: u64, count: u64) -> Self
+    {
+        let drive = DRIVE_BASE | DRIVE_LBA | if slave { DRIVE_SLAVE } else { 0 };
+        let bytes = lba.to_le_bytes();
+
+        match mode
+        {
+            // LBA bits 24-27 go into the drive register
+            LbaMode::Lba28 => TaskFile { drive: drive | (bytes[3] & 0x0f), count: [count as u8, 0], lba: [bytes[0], bytes[1], bytes[2], 0, 0, 0] },
+            LbaMode::Lba48 => TaskFile { drive, count: [count as u8, (count >> 8) as u8], lba: [bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]] },
+        }
+    }
+}
+
+// what IDENTIFY (DEVICE or PACKET DEVICE) tells about a drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdentifyInfo
+{
+    model: [u8; 40],
+    serial: [u8; 20],
+    pub lba48: bool,
+    pub sectors: u64,       // addressable with LBA (0 for packet devices)
+    pub ncq: bool,          // native command queuing (SATA)
+    pub queue_depth: u8,    // commands it queues at most
+}
+
+impl IdentifyInfo
+{
+    pub fn parse(words: &[u16; 256]) -> Self
+    {
+        let lba48 = words[ID_COMMAND_SETS] & COMMAND_SET_LBA48 != 0;
+        let sectors = if lba48
+        {
+            (0..4).fold(0u64, |sectors, index| sectors | (words[ID_LBA48_SECTORS + index] as u64) << (16 * index))
+        }
+        else
+        {
+            words[ID_LBA28_SECTORS] as u64 | (words[ID_LBA28_SECTORS + 1] as u64) << 16
+        };
+
+        // 0 and 0xffff mean the word is not there (parallel ATA)
+        let sata = words[ID_SATA_CAPABILITIES];
+        let ncq = sata != 0xffff && sata & SATA_NCQ != 0;
+        let queue_depth = if ncq { (words[ID_QUEUE_DEPTH] & QUEUE_DEPTH_MASK) as u8 + 1 } else { 1 };
+
+        IdentifyInfo { model: ata_string(&words[ID_MODEL..]), serial: ata_string(&words[ID_SERIAL..]), lba48, sectors, ncq, queue_depth }
+    }
+
+    pub fn model(&self) -> &str
+    {
+        core::str::from_utf8(&self.model).unwrap_or("").trim()
+    }
+
+    pub fn serial(&self) -> &str
+    {
+        core::str::from_utf8(&self.serial).unwrap_or("").trim()
+    }
+}
+
+// IDENTIFY strings: two characters per word, the first in the high byte
+fn ata_string<const N: usize>(words: &[u16]) -> [u8; N]
+{
+    let mut string = [0; N];
+    for (pair, word) in string.chunks_exact_mut(2).zip(words)
+    {
+        pair.copy_from_slice(&word.to_be_bytes());
+    }
+    string
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitMode
+{
+    Irq,
+    Polling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveKind
+{
+    Ata,
+    Atapi,
+}
+
+// one channel: its registers, and the lock that keeps commands of its two drives apart
+struct Channel
+{
+    io_base: u16,
+    control: u16,
+    irq: Option<usize>,         // index into the IRQ_* arrays (compatibility channels)
+    polling: AtomicBool,        // set_wait_mode(Polling)
+    lock: Mutex<()>,
+}
+
+// the interrupt wait of a command, cleaned up when the command ends
+struct IrqWait<'a>
+{
+    channel: &'a Channel,
+    irq: Option<usize>,
+}
+
+impl Drop for IrqWait<'_>
+{
+    fn drop(&mut self)
+    {
+        if let Some(irq) = self.irq
+        {
+            *IRQ_WAITER[irq].lock() = None;
+        }
+        self.channel.write_control(CONTROL_NIEN);
+    }
+}
+
+impl Channel
+{
+    fn new(io_base: u16, control: u16, irq: Option<usize>) -> Self
+    {
+        Channel { io_base, control, irq, polling: AtomicBool::new(false), lock: Mutex::new(()) }
+    }
+
+    fn read(&self, register: u16) -> u8
+    {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8)
+    {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    fn alt_status(&self) -> u8
+    {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    fn write_control(&self, value: u8)
+    {
+        unsafe { Port::new(self.control).write(value) }
+    }
+
+    // the drive needs 400 ns after a select (or command) before its status means anything
+    fn delay_400ns(&self)
+    {
+        for _ in 0..4
+        {
+            self.alt_status();
+        }
+    }
+
+    // poll the alternate status until done(status)
+    fn poll(&self, done: impl Fn(u8) -> bool) -> Result<u8, BlockError>
+    {
+        (0..TIMEOUT_POLLS).map(|_| self.alt_status()).find(|&status| done(status)).ok_or(BlockError::Timeout)
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError>
+    {
+        self.poll(|status| status & STATUS_BSY == 0)
+    }
+
+    // enable (or not) the interrupt for the command about to be issued
+    fn begin(&self) -> IrqWait<'_>
+    {
+        let irq = self.irq.filter(|_| !self.polling.load(Ordering::Relaxed) && x86_64::instructions::interrupts::are_enabled());
+
+        if let Some(irq) = irq
+        {
+            IRQ_FIRED[irq].store(false, Ordering::Release);
+            *IRQ_WAITER[irq].lock() = thread::current_id();
+        }
+        self.write_control(if irq.is_some() { 0 } else { CONTROL_NIEN });
+
+        IrqWait { channel: self, irq }
+    }
+
+    // wait for the drive to finish a step: its interrupt first (if wait has one and interrupt is
+    // set), then not busy; with drq it also has to want data
+    fn wait_ready(&self, wait: &IrqWait, interrupt: bool, drq: bool) -> Result<(), BlockError>
+    {
+        if let Some(irq) = wait.irq.filter(|_| interrupt)
+        {
+            let timed_out = thread::sleep_ticks_interruptible(IRQ_TIMEOUT_TICKS, || IRQ_FIRED[irq].load(Ordering::Acquire));
+            if timed_out && !IRQ_FIRED[irq].load(Ordering::Acquire)
+            {
+                return Err(BlockError::Timeout);
+            }
+            IRQ_FIRED[irq].store(false, Ordering::Release);
+        }
+
+        let status = self.poll(|status| status & STATUS_BSY == 0 && (!drq || status & (STATUS_DRQ | STATUS_ERR | STATUS_DF) != 0))?;
+        if status & (STATUS_ERR | STATUS_DF) != 0
+        {
+            return Err(BlockError::DeviceError(self.read(REG_ERROR)));
+        }
+        if drq && status & STATUS_DRQ == 0
+        {
+            return Err(BlockError::DeviceError(0));
+        }
+
+        Ok(())
+    }
+
+    fn read_data(&self, buffer: &mut [u8])
+    {
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for pair in buffer.chunks_exact_mut(2)
+        {
+            pair.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, buffer: &[u8])
+    {
+        let mut data = Port::<u16>::new(self.io_base + REG_DATA);
+        for pair in buffer.chunks_exact(2)
+        {
+            unsafe { data.write(u16::from_le_bytes([pair[0], pair[1]])); }
+        }
+    }
+
+    // reset both drives; false if the channel does not answer (nothing there)
+    fn reset(&self) -> bool
+    {
+        // a floating bus reads all ones
+        if self.alt_status() == 0xff
+        {
+            return false;
+        }
+
+        self.write_control(CONTROL_SRST | CONTROL_NIEN);
+        for _ in 0..10
+        {
+            self.delay_400ns();
+        }
+        self.write_control(CONTROL_NIEN);
+        self.delay_400ns();
+
+        self.wait_not_busy().is_ok()
+    }
+
+    // IDENTIFY a drive, polling (interrupts are off while detecting)
+    fn identify(&self, slave: bool) -> Option<(DriveKind, [u16; 256])>
+    {
+        self.write(REG_DRIVE, DRIVE_BASE | if slave { DRIVE_SLAVE } else { 0 });
+        self.delay_400ns();
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH]
+        {
+            self.write(register, 0);
+        }
+
+        self.write(REG_COMMAND, CMD_IDENTIFY);
+        self.delay_400ns();
+        if self.read(REG_STATUS) == 0
+        {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+
+        // packet devices abort IDENTIFY and leave their signature
+        let signature = (self.read(REG_LBA_MID), self.read(REG_LBA_HIGH));
+        let kind = if ATAPI_SIGNATURES.contains(&signature)
+        {
+            self.write(REG_COMMAND, CMD_IDENTIFY_PACKET);
+            self.delay_400ns();
+            self.wait_not_busy().ok()?;
+            DriveKind::Atapi
+        }
+        else if signature == (0, 0)
+        {
+            DriveKind::Ata
+        }
+        else
+        {
+            return None;
+        };
+
+        let status = self.poll(|status| status & (STATUS_DRQ | STATUS_ERR) != 0).ok()?;
+        if status & STATUS_ERR != 0
+        {
+            return None;
+        }
+
+        let mut bytes = [0u8; 512];
+        self.read_data(&mut bytes);
+        let mut words = [0u16; 256];
+        for (word, pair) in words.iter_mut().zip(bytes.chunks_exact(2))
+        {
+            *word = u16::from_le_bytes([pair[0], pair[1]]);
+        }
+
+        Some((kind, words))
+    }
+}
+
+// a drive, registered as a block device
+pub struct AtaDrive
+{
+    name: String,
+    channel: Arc<Channel>,
+    slave: bool,
+    kind: DriveKind,
+    info: IdentifyInfo,
+    capacity: AtomicU64,                // in blocks; for ATAPI of the medium, read again on every access
+    forced_mode: IrqSafeMutex<Option<LbaMode>>,
+}
+
+impl AtaDrive
+{
+    pub fn kind(&self) -> DriveKind
+    {
+        self.kind
+    }
+
+    pub fn info(&self) -> &IdentifyInfo
+    {
+        &self.info
+    }
+
+    // wait for interrupts (where the channel has one) or poll; applies to both drives of the channel
+    pub fn set_wait_mode(&self, mode: WaitMode)
+    {
+        self.channel.polling.store(mode == WaitMode::Polling, Ordering::Relaxed);
+    }
+
+    // always use this addressing mode (where it reaches), None to pick per transfer
+    pub fn force_lba_mode(&self, mode: Option<LbaMode>)
+    {
+        *self.forced_mode.lock() = mode;
+    }
+
+    fn lba_mode(&self, lba: u64, count: u64) -> Result<LbaMode, BlockError>
+    {
+        let mode = LbaMode::for_transfer(lba, count, self.info.lba48).ok_or(BlockError::OutOfRange)?;
+        match *self.forced_mode.lock()
+        {
+            Some(LbaMode::Lba48) if self.info.lba48 => Ok(LbaMode::Lba48),
+            _ => Ok(mode),
+        }
+    }
+
+    // select the drive and issue a read/write command for count sectors at lba
+    fn start(&self, mode: LbaMode, lba: u64, count: u64, write: bool) -> Result<(), BlockError>
+    {
+        let channel = &self.channel;
+        let task = TaskFile::new(mode, self.slave, lba, count);
+
+        channel.wait_not_busy()?;
+        channel.write(REG_DRIVE, task.drive);
+        channel.delay_400ns();
+        channel.wait_not_busy()?;
+
+        if mode == LbaMode::Lba48
+        {
+            channel.write(REG_SECTOR_COUNT, task.count[1]);
+            channel.write(REG_LBA_LOW, task.lba[3]);
+            channel.write(REG_LBA_MID, task.lba[4]);
+            channel.write(REG_LBA_HIGH, task.lba[5]);
+        }
+        channel.write(REG_SECTOR_COUNT, task.count[0]);
+        channel.write(REG_LBA_LOW, task.lba[0]);
+        channel.write(REG_LBA_MID, task.lba[1]);
+        channel.write(REG_LBA_HIGH, task.lba[2]);
+
+        let command = match (mode, write)
+        {
+            (LbaMode::Lba28, false) => CMD_READ_SECTORS,
+            (LbaMode::Lba48, false) => CMD_READ_SECTORS_EXT,
+            (LbaMode::Lba28, true) => CMD_WRITE_SECTORS,
+            (LbaMode::Lba48, true) => CMD_WRITE_SECTORS_EXT,
+        };
+        channel.write(REG_COMMAND, command);
+        channel.delay_400ns();
+
+        Ok(())
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        let count = (buffer.len() / SECTOR_SIZE) as u64;
+        let mode = self.lba_mode(lba, count)?;
+        let _guard = self.channel.lock.lock();
+        let wait = self.channel.begin();
+
+        self.start(mode, lba, count, false)?;
+
+        // an interrupt for every sector that is ready
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE)
+        {
+            self.channel.wait_ready(&wait, true, true)?;
+            self.channel.read_data(sector);
+        }
+
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        let count = (buffer.len() / SECTOR_SIZE) as u64;
+        let mode = self.lba_mode(lba, count)?;
+        let _guard = self.channel.lock.lock();
+        let wait = self.channel.begin();
+
+        self.start(mode, lba, count, true)?;
+
+        // the first sector goes without an interrupt, every one after it (and the end) raises one
+        for (index, sector) in buffer.chunks_exact(SECTOR_SIZE).enumerate()
+        {
+            self.channel.wait_ready(&wait, index > 0, true)?;
+            self.channel.write_data(sector);
+        }
+        self.channel.wait_ready(&wait, true, false)
+    }
+
+    fn flush_cache(&self) -> Result<(), BlockError>
+    {
+        let _guard = self.channel.lock.lock();
+        let wait = self.channel.begin();
+
+        self.channel.wait_not_busy()?;
+        self.channel.write(REG_DRIVE, DRIVE_BASE | DRIVE_LBA | if self.slave { DRIVE_SLAVE } else { 0 });
+        self.channel.delay_400ns();
+        self.channel.write(REG_COMMAND, if self.info.lba48 { CMD_CACHE_FLUSH_EXT } else { CMD_CACHE_FLUSH });
+        self.channel.delay_400ns();
+
+        self.channel.wait_ready(&wait, true, false)
+    }
+
+    // send a SCSI command packet, reading what it returns into buffer
+    fn packet(&self, packet: &[u8; ATAPI_PACKET_SIZE], buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        let _guard = self.channel.lock.lock();
+        let wait = self.channel.begin();
+        let channel = &self.channel;
+
+        channel.wait_not_busy()?;
+        channel.write(REG_DRIVE, DRIVE_BASE | if self.slave { DRIVE_SLAVE } else { 0 });
+        channel.delay_400ns();
+        channel.wait_not_busy()?;
+
+        // PIO, and the most bytes per data phase
+        let limit = buffer.len().min(ATAPI_SECTOR_SIZE * ATAPI_MAX_SECTORS as usize) as u16;
+        channel.write(REG_FEATURES, 0);
+        channel.write(REG_LBA_MID, limit as u8);
+        channel.write(REG_LBA_HIGH, (limit >> 8) as u8);
+        channel.write(REG_COMMAND, CMD_PACKET);
+        channel.delay_400ns();
+
+        let result = (||
+        {
+            // the packet goes without an interrupt
+            channel.wait_ready(&wait, false, true)?;
+            channel.write_data(packet);
+
+            let mut done = 0;
+            while done < buffer.len()
+            {
+                channel.wait_ready(&wait, true, true)?;
+                let bytes = (channel.read(REG_LBA_MID) as usize | (channel.read(REG_LBA_HIGH) as usize) << 8).min(buffer.len() - done);
+                channel.read_data(&mut buffer[done..done + bytes]);
+                done += bytes;
+
+                if bytes == 0
+                {
+                    return Err(BlockError::DeviceError(0));
+                }
+            }
+
+            channel.wait_ready(&wait, true, false)
+        })();
+
+        match result
+        {
+            Err(BlockError::DeviceError(error)) if error >> 4 == SENSE_KEY_NOT_READY => Err(BlockError::NoMedium),
+            result => result,
+        }
+    }
+
+    // blocks of the medium (READ CAPACITY)
+    fn read_capacity(&self) -> Result<u64, BlockError>
+    {
+        let mut reply = [0u8; 8];
+        let mut packet = [0u8; ATAPI_PACKET_SIZE];
+        packet[0] = SCSI_READ_CAPACITY;
+        self.packet(&packet, &mut reply)?;
+
+        let last = u32::from_be_bytes(reply[0..4].try_into().unwrap()) as u64;
+        self.capacity.store(last + 1, Ordering::Relaxed);
+        Ok(last + 1)
+    }
+
+    // detect the drive at slave on channel
+    fn detect(channel: &Arc<Channel>, slave: bool, name: String) -> Option<AtaDrive>
+    {
+        let (kind, words) = channel.identify(slave)?;
+        let info = IdentifyInfo::parse(&words);
+
+        let drive = AtaDrive
+        {
+            name,
+            channel: channel.clone(),
+            slave,
+            kind,
+            info,
+            capacity: AtomicU64::new(if kind == DriveKind::Ata { info.sectors } else { 0 }),
+            forced_mode: IrqSafeMutex::new(None),
+        };
+        Some(drive)
+    }
+}
+
+impl BlockDevice for AtaDrive
+{
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize
+    {
+        match self.kind
+        {
+            DriveKind::Ata => SECTOR_SIZE,
+            DriveKind::Atapi => ATAPI_SECTOR_SIZE,
+        }
+    }
+
+    fn block_count(&self) -> u64
+    {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        self.kind == DriveKind::Atapi
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        match self.kind
+        {
+            DriveKind::Ata =>
+            {
+                block::check_request(SECTOR_SIZE, self.block_count(), lba, buffer.len())?;
+                let max = self.lba_mode(lba, 1)?.max_sectors() as usize * SECTOR_SIZE;
+
+                for (index, chunk) in buffer.chunks_mut(max).enumerate()
+                {
+                    self.read_sectors(lba + (index * max / SECTOR_SIZE) as u64, chunk)?;
+                }
+                Ok(())
+            }
+            DriveKind::Atapi =>
+            {
+                // the medium may have changed
+                let capacity = self.read_capacity()?;
+                block::check_request(ATAPI_SECTOR_SIZE, capacity, lba, buffer.len())?;
+                let max = ATAPI_MAX_SECTORS as usize * ATAPI_SECTOR_SIZE;
+
+                for (index, chunk) in buffer.chunks_mut(max).enumerate()
+                {
+                    let start = lba as u32 + (index * max / ATAPI_SECTOR_SIZE) as u32;
+                    let count = (chunk.len() / ATAPI_SECTOR_SIZE) as u32;
+
+                    let mut packet = [0u8; ATAPI_PACKET_SIZE];
+                    packet[0] = SCSI_READ_12;
+                    packet[2..6].copy_from_slice(&start.to_be_bytes());
+                    packet[6..10].copy_from_slice(&count.to_be_bytes());
+                    self.packet(&packet, chunk)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        if self.kind == DriveKind::Atapi
+        {
+            return Err(BlockError::ReadOnly);
+        }
+
+        block::check_request(SECTOR_SIZE, self.block_count(), lba, buffer.len())?;
+        let max = self.lba_mode(lba, 1)?.max_sectors() as usize * SECTOR_SIZE;
+
+        for (index, chunk) in buffer.chunks(max).enumerate()
+        {
+            self.write_sectors(lba + (index * max / SECTOR_SIZE) as u64, chunk)?;
+        }
+
+        // written through: the drive's cache is not trusted to survive
+        self.flush_cache()
+    }
+
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        match self.kind
+        {
+            DriveKind::Ata => self.flush_cache(),
+            DriveKind::Atapi => Ok(()),
+        }
+    }
+}
+
+// the IDE controller: finds the drives of both channels and registers them (hda: primary master,
+// hdb: primary slave, hdc and hdd on the secondary channel; the next controller goes on with hde)
+pub struct AtaDriver;
+
+impl Driver for AtaDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "ata"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        const IDS: &[DeviceMatch] = &[DeviceMatch::Pci(PciMatch::class(pci::CLASS_MASS_STORAGE, SUBCLASS_IDE))];
+        IDS
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        let pci = device.pci().ok_or(DriverError::NoDevice)?;
+        let controller = CONTROLLERS.fetch_add(1, Ordering::Relaxed);
+        pci.enable_io();
+
+        for index in 0..2
+        {
+            let (io_base, control, irq) = if pci.prog_if & PROG_IF_NATIVE[index] != 0
+            {
+                let (Ok(Bar::Io { port: io_base, .. }), Ok(Bar::Io { port: control, .. })) = (pci.bar(2 * index), pci.bar(2 * index + 1)) else { continue };
+                (io_base, control + NATIVE_CONTROL_OFFSET, None)
+            }
+            else if controller == 0
+            {
+                let (io_base, control) = LEGACY_CHANNELS[index];
+                (io_base, control, Some(index))
+            }
+            else
+            {
+                // the legacy ports belong to the first controller
+                continue;
+            };
+
+            let channel = Arc::new(Channel::new(io_base, control, irq));
+            if !channel.reset()
+            {
+                continue;
+            }
+
+            for slave in [false, true]
+            {
+                let letter = (b'a' + (controller * 4 + index * 2 + slave as usize) as u8) as char;
+                if let Some(drive) = AtaDrive::detect(&channel, slave, alloc::format!("hd{}", letter))
+                {
+                    let drive = Arc::new(drive);
+                    DRIVES.lock().push(drive.clone());
+                    block::register(drive);
+                }
+            }
+
+            if let Some(irq) = irq
+            {
+                IRQ_STATUS_PORT[irq].store(io_base + REG_STATUS, Ordering::Release);
+                interrupts::unmask_irq(IRQS[irq]);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn picks_lba_modes()
+{
+    assert_eq!(LbaMode::for_transfer(0, 256, false), Some(LbaMode::Lba28));
+    assert_eq!(LbaMode::for_transfer(0, 257, false), None);
+    assert_eq!(LbaMode::for_transfer(0, 257, true), Some(LbaMode::Lba48));
+    assert_eq!(LbaMode::for_transfer(LBA28_LIMIT - 1, 1, false), Some(LbaMode::Lba28));
+    assert_eq!(LbaMode::for_transfer(LBA28_LIMIT - 1, 2, true), Some(LbaMode::Lba48));
+    assert_eq!(LbaMode::for_transfer(LBA48_LIMIT, 1, true), None);
+}
+
+#[test_case]
+fn encodes_task_files()
+{
+    // LBA bits 24-27 in the drive register, 256 sectors as 0
+    let task = TaskFile::new(LbaMode::Lba28, true, 0x0abc_def1, 256);
+    assert_eq!(task, TaskFile { drive: 0xfa, count: [0, 0], lba: [0xf1, 0xde, 0xbc, 0, 0, 0] });
+
+    let task = TaskFile::new(LbaMode::Lba48, false, 0x1234_5678_9abc, 0x0102);
+    assert_eq!(task, TaskFile { drive: 0xe0, count: [0x02, 0x01], lba: [0xbc, 0x9a, 0x78, 0x56, 0x34, 0x12] });
+}
+
+#[test_case]
+fn parses_identify_data()
+{
+    let mut words = [0u16; 256];
+    words[ID_MODEL] = u16::from_be_bytes(*b"QE");
+    words[ID_MODEL + 1] = u16::from_be_bytes(*b"MU");
+    words[ID_MODEL + 2..ID_MODEL + 20].fill(u16::from_be_bytes(*b"  "));
+    words[ID_SERIAL..ID_SERIAL + 10].fill(u16::from_be_bytes(*b"  "));
+    words[ID_SERIAL] = u16::from_be_bytes(*b"QM");
+
+    words[ID_LBA28_SECTORS] = 0x0800;
+    let info = IdentifyInfo::parse(&words);
+    assert_eq!((info.model(), info.serial(), info.lba48, info.sectors), ("QEMU", "QM", false, 0x800));
+
+    // 48 bit count takes over
+    words[ID_COMMAND_SETS] = COMMAND_SET_LBA48;
+    words[ID_LBA48_SECTORS + 2] = 0x0001;
+    assert_eq!(IdentifyInfo::parse(&words).sectors, 1 << 32);
+
+    // NCQ with a depth of 32
+    assert_eq!((info.ncq, info.queue_depth), (false, 1));
+    words[ID_SATA_CAPABILITIES] = SATA_NCQ;
+    words[ID_QUEUE_DEPTH] = 31;
+    let info = IdentifyInfo::parse(&words);
+    assert_eq!((info.ncq, info.queue_depth), (true, 32));
+}
@@ -0,0 +1,93 @@
+// block devices: storage read and written in whole blocks (disks, CD-ROMs)
+// the drivers that find them register them here, file systems look them up by name
+
+use crate::sync::IrqSafeMutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError
+{
+    OutOfRange,             // past the last block
+    BadBufferSize(usize),   // not a whole number of blocks
+    ReadOnly,
+    NoMedium,
+    Timeout,
+    OutOfMemory,            // for the transfer's buffers
+    DeviceError(u8),        // the device's own error code
+}
+
+pub trait BlockDevice: Send + Sync
+{
+    fn name(&self) -> &str;
+
+    // bytes per block
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool
+    {
+        false
+    }
+
+    // read buffer.len() / block_size() blocks, starting at lba
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    // write buffer.len() / block_size() blocks, starting at lba
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    // make written blocks persistent (write caches)
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        Ok(())
+    }
+}
+
+// check a transfer of len bytes at lba against the size of a device; returns the number of blocks
+pub fn check_request(block_size: usize, block_count: u64, lba: u64, len: usize) -> Result<u64, BlockError>
+{
+    if block_size == 0 || !len.is_multiple_of(block_size)
+    {
+        return Err(BlockError::BadBufferSize(len));
+    }
+
+    let blocks = (len / block_size) as u64;
+    match lba.checked_add(blocks)
+    {
+        Some(end) if end <= block_count => Ok(blocks),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: IrqSafeMutex<Vec<Arc<dyn BlockDevice>>> = IrqSafeMutex::new(Vec::new());
+
+pub fn register(device: Arc<dyn BlockDevice>)
+{
+    DEVICES.lock().push(device);
+}
+
+// every registered device, in registration order
+pub fn devices() -> Vec<Arc<dyn BlockDevice>>
+{
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>>
+{
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn checks_requests()
+{
+    assert_eq!(check_request(512, 100, 0, 1024), Ok(2));
+    assert_eq!(check_request(512, 100, 98, 1024), Ok(2));
+    assert_eq!(check_request(512, 100, 99, 1024), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(512, 100, u64::MAX, 512), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(512, 100, 0, 100), Err(BlockError::BadBufferSize(100)));
+}
@@ -0,0 +1,95 @@
+// memory for devices to read and write themselves (DMA)
+// whole frames from the global frame allocator, zeroed; the kernel reaches them through the physical
+// memory offset (x86 DMA is cache coherent, so the normal mapping will do)
+// frames are not contiguous: devices get one address per frame
+
+use crate::memory::{self, PAGE_SIZE};
+use alloc::vec::Vec;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfMemory;
+
+// frames a device may access; given back on drop (the device must be done with them by then)
+pub struct DmaFrames
+{
+    frames: Vec<PhysFrame>,
+}
+
+impl DmaFrames
+{
+    pub fn new(count: usize) -> Result<Self, OutOfMemory>
+    {
+        let mut frames = DmaFrames { frames: Vec::with_capacity(count) };
+        for _ in 0..count
+        {
+            frames.frames.push(memory::allocate_zeroed_frame().ok_or(OutOfMemory)?);
+        }
+
+        Ok(frames)
+    }
+
+    // enough frames for len bytes
+    pub fn for_bytes(len: usize) -> Result<Self, OutOfMemory>
+    {
+        Self::new(len.div_ceil(PAGE_SIZE))
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.frames.is_empty()
+    }
+
+    // device address of a frame
+    pub fn phys(&self, index: usize) -> PhysAddr
+    {
+        self.frames[index].start_address()
+    }
+
+    // kernel address of a frame
+    pub fn ptr<T>(&self, index: usize) -> *mut T
+    {
+        memory::phys_to_virt(self.phys(index)).as_mut_ptr()
+    }
+
+    // device addresses and lengths of the first len bytes, a piece per frame
+    pub fn segments(&self, len: usize) -> impl Iterator<Item = (PhysAddr, usize)> + '_
+    {
+        (0..len.div_ceil(PAGE_SIZE)).map(move |index| (self.phys(index), (len - index * PAGE_SIZE).min(PAGE_SIZE)))
+    }
+
+    // copy data in from the start, across the frames
+    pub fn write(&self, data: &[u8])
+    {
+        for (index, chunk) in data.chunks(PAGE_SIZE).enumerate()
+        {
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), self.ptr(index), chunk.len()); }
+        }
+    }
+
+    // copy buffer.len() bytes out from the start
+    pub fn read(&self, buffer: &mut [u8])
+    {
+        for (index, chunk) in buffer.chunks_mut(PAGE_SIZE).enumerate()
+        {
+            unsafe { core::ptr::copy_nonoverlapping(self.ptr(index), chunk.as_mut_ptr(), chunk.len()); }
+        }
+    }
+}
+
+impl Drop for DmaFrames
+{
+    fn drop(&mut self)
+    {
+        for frame in self.frames.drain(..)
+        {
+            memory::free_frame(frame);
+        }
+    }
+}
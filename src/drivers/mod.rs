@@ -0,0 +1,125 @@
+// device drivers, and the device tree they bind to (see model)
+
+pub mod model;
+pub mod block;
+pub mod dma;
+pub mod ps2;
+pub mod mouse;
+pub mod pci;
+pub mod ata;
+pub mod ahci;
+pub mod nvme;
+pub mod virtio;
+
+use crate::serial::{ComPort, SerialDriver};
+use crate::interrupts::PitDriver;
+use crate::{acpi, apic};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use model::{DeviceId, DeviceKind, Resource};
+use pci::Bar;
+
+// registers of an I/O APIC: index and data window
+const IO_APIC_SIZE: u64 = 0x20;
+
+// build the device tree from what the buses report (platform devices from the MADT, the legacy ISA
+// devices, everything PCI enumeration found) and register the built-in drivers, which bind to it
+// needs the heap and the global memory; returns the number of devices and of those with a driver
+pub fn init() -> (usize, usize)
+{
+    add_platform_devices();
+    add_isa_devices();
+    add_pci_devices();
+
+    model::register_driver(&PitDriver);
+    model::register_driver(&SerialDriver);
+    model::register_driver(&ps2::Ps2ControllerDriver);
+    model::register_driver(&ps2::Ps2KeyboardDriver);
+    model::register_driver(&mouse::MouseDriver);
+    model::register_driver(&ata::AtaDriver);
+    model::register_driver(&virtio::blk::VirtioBlkDriver);
+    model::register_driver(&ahci::AhciDriver);
+    model::register_driver(&nvme::NvmeDriver);
+
+    model::count()
+}
+
+// interrupt controllers, as the MADT lists them
+fn add_platform_devices()
+{
+    let platform = model::add_device(None, "platform", DeviceKind::Bus, Vec::new());
+    let Ok(madt) = acpi::madt() else { return };
+
+    model::add_device(Some(platform), "local-apic", DeviceKind::Platform("local-apic"),
+        vec![Resource::Mmio { start: madt.local_apic_address, size: apic::LAPIC_SIZE }]);
+
+    for io_apic in madt.io_apics
+    {
+        model::add_device(Some(platform), alloc::format!("io-apic{}", io_apic.id), DeviceKind::Platform("io-apic"),
+            vec![Resource::Mmio { start: io_apic.address, size: IO_APIC_SIZE }]);
+    }
+}
+
+// the fixed legacy devices (a COM port without a UART stays without a driver)
+fn add_isa_devices()
+{
+    let isa = Some(model::add_device(None, "isa", DeviceKind::Bus, Vec::new()));
+
+    model::add_device(isa, "pic", DeviceKind::Isa(model::PNP_PIC),
+        vec![Resource::IoPorts { start: 0x20, len: 2 }, Resource::IoPorts { start: 0xa0, len: 2 }, Resource::Irq(2)]);
+    model::add_device(isa, "pit", DeviceKind::Isa(model::PNP_PIT), vec![Resource::IoPorts { start: 0x40, len: 4 }, Resource::Irq(0)]);
+    model::add_device(isa, "i8042", DeviceKind::Isa(model::PNP_PS2_CONTROLLER),
+        vec![Resource::IoPorts { start: 0x60, len: 1 }, Resource::IoPorts { start: 0x64, len: 1 }]);
+
+    for (index, port) in ComPort::ALL.into_iter().enumerate()
+    {
+        let irq = port.interrupt() as u8 - crate::interrupts::PIC_1_OFFSET;
+        model::add_device(isa, alloc::format!("serial{}", index), DeviceKind::Isa(model::PNP_UART),
+            vec![Resource::IoPorts { start: port.base(), len: 8 }, Resource::Irq(irq)]);
+    }
+}
+
+// a root per PCI segment, every function below the bridge leading to its bus
+fn add_pci_devices()
+{
+    // the registry may be filled already; nothing else can go wrong
+    let _ = pci::init();
+
+    let mut buses: BTreeMap<(u16, u8), DeviceId> = BTreeMap::new();
+    let mut roots: BTreeMap<u16, DeviceId> = BTreeMap::new();
+
+    // bridges sort before the buses behind them (a secondary bus is above its primary one)
+    for device in pci::devices()
+    {
+        let segment = device.address.segment;
+        let parent = match buses.get(&(segment, device.address.bus))
+        {
+            Some(&bridge) => bridge,
+            None => *roots.entry(segment).or_insert_with(|| model::add_device(None, alloc::format!("pci{:04x}", segment), DeviceKind::Bus, Vec::new())),
+        };
+
+        let id = model::add_device(Some(parent), alloc::format!("{}", device.address), DeviceKind::Pci(device), pci_resources(device));
+        if let Some(secondary) = device.secondary_bus()
+        {
+            buses.insert((segment, secondary), id);
+        }
+    }
+}
+
+fn pci_resources(device: &pci::PciDevice) -> Vec<Resource>
+{
+    let mut resources: Vec<Resource> = device.bars.iter().flatten().map(|bar| match *bar
+    {
+        Bar::Memory { address, size, .. } => Resource::Mmio { start: address, size },
+        Bar::Io { port, size } => Resource::IoPorts { start: port, len: size },
+    }).collect();
+
+    // the line is only meaningful with a pin; 0xff (or anything past the PICs) means not routed
+    if device.interrupt_pin != 0 && device.interrupt_line < 16
+    {
+        resources.push(Resource::Irq(device.interrupt_line));
+    }
+
+    resources
+}
@@ -0,0 +1,510 @@
+// driver model: a tree of devices, rooted at the buses (platform, ISA, PCI), and the drivers bound to them
+// a device is added with what its bus knows about it (an ID and its resources); the registered drivers
+// whose ID tables match get to probe it, in registration order, until one takes it
+// the driver hooks run without the tree locked, so a driver may add devices (children of the one it
+// probes) from them
+
+use super::pci::{PciDevice, PciMatch};
+use crate::serial_println;
+use crate::sync::IrqSafeMutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::PhysAddr;
+
+// PNP IDs of the legacy ISA devices
+pub const PNP_PIC: &str = "PNP0000";
+pub const PNP_PIT: &str = "PNP0100";
+pub const PNP_PS2_CONTROLLER: &str = "PNP0303";
+pub const PNP_UART: &str = "PNP0501";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(u32);
+
+// what a device is, as its bus identifies it
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceKind
+{
+    Bus,                        // root of a bus, nothing to drive
+    Platform(&'static str),     // part of the machine (or of another device), by name
+    Isa(&'static str),          // legacy device at fixed ports, by PNP ID
+    Pci(&'static PciDevice),
+}
+
+impl fmt::Display for DeviceKind
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            DeviceKind::Bus => write!(f, "bus"),
+            DeviceKind::Platform(name) => write!(f, "{}", name),
+            DeviceKind::Isa(pnp_id) => write!(f, "{}", pnp_id),
+            DeviceKind::Pci(device) => write!(f, "{:04x}:{:04x} class {:02x}{:02x}{:02x}",
+                device.vendor_id, device.device_id, device.class, device.subclass, device.prog_if),
+        }
+    }
+}
+
+// what a device occupies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource
+{
+    Irq(u8),
+    IoPorts { start: u16, len: u16 },
+    Mmio { start: PhysAddr, size: u64 },
+}
+
+impl fmt::Display for Resource
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            Resource::Irq(irq) => write!(f, "irq {}", irq),
+            Resource::IoPorts { start, len } => write!(f, "io {:#x}-{:#x}", start, start as u32 + len.max(1) as u32 - 1),
+            Resource::Mmio { start, size } => write!(f, "mmio {:#x}-{:#x}", start.as_u64(), start.as_u64() + size.max(1) - 1),
+        }
+    }
+}
+
+// a device in the tree; what drivers get to see
+#[derive(Debug)]
+pub struct Device
+{
+    pub id: DeviceId,
+    pub parent: Option<DeviceId>,
+    pub name: String,
+    pub kind: DeviceKind,
+    pub resources: Vec<Resource>,
+}
+
+impl Device
+{
+    pub fn irqs(&self) -> impl Iterator<Item = u8> + '_
+    {
+        self.resources.iter().filter_map(|resource| match *resource { Resource::Irq(irq) => Some(irq), _ => None })
+    }
+
+    // first port of the first I/O range
+    pub fn io_base(&self) -> Option<u16>
+    {
+        self.resources.iter().find_map(|resource| match *resource { Resource::IoPorts { start, .. } => Some(start), _ => None })
+    }
+
+    pub fn pci(&self) -> Option<&'static PciDevice>
+    {
+        match self.kind
+        {
+            DeviceKind::Pci(device) => Some(device),
+            _ => None,
+        }
+    }
+}
+
+// an entry of a driver's ID table
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceMatch
+{
+    Platform(&'static str),
+    Isa(&'static str),
+    Pci(PciMatch),
+}
+
+impl DeviceMatch
+{
+    pub fn matches(&self, kind: &DeviceKind) -> bool
+    {
+        match (self, kind)
+        {
+            (DeviceMatch::Platform(wanted), DeviceKind::Platform(name)) => wanted == name,
+            (DeviceMatch::Isa(wanted), DeviceKind::Isa(pnp_id)) => wanted == pnp_id,
+            (DeviceMatch::Pci(filter), DeviceKind::Pci(device)) => filter.matches(device),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverError
+{
+    NoDevice,           // nothing answered: the next matching driver may try
+    Failed(String),
+}
+
+impl DriverError
+{
+    // from a driver's own error
+    pub fn failed(error: impl fmt::Debug) -> Self
+    {
+        DriverError::Failed(alloc::format!("{:?}", error))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceState
+{
+    Unbound,
+    Probing,
+    Bound,
+    Suspended,
+    Failed(DriverError),    // the last probe failed (a driver registered later may still take it)
+}
+
+pub trait Driver: Sync
+{
+    fn name(&self) -> &'static str;
+
+    // the devices it can drive
+    fn ids(&self) -> &'static [DeviceMatch];
+
+    // take the device: Ok binds it to the driver
+    fn probe(&self, device: &Device) -> Result<(), DriverError>;
+
+    // let go of the device (its children are gone already)
+    fn remove(&self, _device: &Device) {}
+
+    // stop it (its children are suspended already)
+    fn suspend(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        Ok(())
+    }
+
+    // start it again (before its children)
+    fn resume(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        Ok(())
+    }
+}
+
+struct Node
+{
+    device: Arc<Device>,
+    driver: Option<&'static dyn Driver>,
+    state: DeviceState,
+}
+
+struct DeviceTree
+{
+    nodes: BTreeMap<DeviceId, Node>,        // in the order they were added
+    drivers: Vec<&'static dyn Driver>,
+    next_id: u32,
+}
+
+impl DeviceTree
+{
+    fn children(&self, parent: Option<DeviceId>) -> Vec<DeviceId>
+    {
+        self.nodes.values().filter(|node| node.device.parent == parent).map(|node| node.device.id).collect()
+    }
+
+    // ids below root (all roots for None), parents before their children
+    fn preorder(&self, root: Option<DeviceId>) -> Vec<DeviceId>
+    {
+        let mut order = Vec::new();
+        let mut stack: Vec<DeviceId> = self.children(root).into_iter().rev().collect();
+        while let Some(id) = stack.pop()
+        {
+            order.push(id);
+            stack.extend(self.children(Some(id)).into_iter().rev());
+        }
+        order
+    }
+}
+
+static TREE: IrqSafeMutex<DeviceTree> = IrqSafeMutex::new(DeviceTree { nodes: BTreeMap::new(), drivers: Vec::new(), next_id: 0 });
+
+// add a device below parent (a root for None), and bind a driver to it if one matches
+pub fn add_device(parent: Option<DeviceId>, name: impl Into<String>, kind: DeviceKind, resources: Vec<Resource>) -> DeviceId
+{
+    let id = {
+        let mut tree = TREE.lock();
+        let id = DeviceId(tree.next_id);
+        tree.next_id += 1;
+
+        let device = Arc::new(Device { id, parent, name: name.into(), kind, resources });
+        tree.nodes.insert(id, Node { device, driver: None, state: DeviceState::Unbound });
+        id
+    };
+
+    bind(id);
+    id
+}
+
+// remove a device and everything below it, children first (drivers get remove)
+// false if there is no such device
+pub fn remove_device(id: DeviceId) -> bool
+{
+    let below = {
+        let tree = TREE.lock();
+        if !tree.nodes.contains_key(&id)
+        {
+            return false;
+        }
+        tree.preorder(Some(id))
+    };
+
+    for child in below.into_iter().rev().chain([id])
+    {
+        let Some(node) = TREE.lock().nodes.remove(&child) else { continue };
+        if let Some(driver) = node.driver
+        {
+            driver.remove(&node.device);
+        }
+    }
+
+    true
+}
+
+// make a driver available; it is offered every device no driver has taken yet
+pub fn register_driver(driver: &'static dyn Driver)
+{
+    let unbound = {
+        let mut tree = TREE.lock();
+        if tree.drivers.iter().any(|registered| core::ptr::addr_eq(*registered, driver))
+        {
+            return;
+        }
+        tree.drivers.push(driver);
+
+        tree.nodes.values()
+            .filter(|node| matches!(node.state, DeviceState::Unbound | DeviceState::Failed(_)))
+            .filter(|node| driver.ids().iter().any(|id| id.matches(&node.device.kind)))
+            .map(|node| node.device.id)
+            .collect::<Vec<_>>()
+    };
+
+    for id in unbound
+    {
+        bind(id);
+    }
+}
+
+// offer a device to the matching drivers until one takes it
+fn bind(id: DeviceId)
+{
+    let (device, candidates) = {
+        let mut tree = TREE.lock();
+        let drivers = tree.drivers.clone();
+        let Some(node) = tree.nodes.get_mut(&id) else { return };
+        if !matches!(node.state, DeviceState::Unbound | DeviceState::Failed(_))
+        {
+            return;
+        }
+
+        let candidates: Vec<_> = drivers.into_iter().filter(|driver| driver.ids().iter().any(|id| id.matches(&node.device.kind))).collect();
+        if candidates.is_empty()
+        {
+            return;
+        }
+
+        node.state = DeviceState::Probing;
+        (node.device.clone(), candidates)
+    };
+
+    let mut state = DeviceState::Unbound;
+    for driver in candidates
+    {
+        match driver.probe(&device)
+        {
+            Ok(()) =>
+            {
+                let mut tree = TREE.lock();
+                match tree.nodes.get_mut(&id)
+                {
+                    Some(node) =>
+                    {
+                        node.driver = Some(driver);
+                        node.state = DeviceState::Bound;
+                    }
+
+                    // removed while it was probed
+                    None =>
+                    {
+                        drop(tree);
+                        driver.remove(&device);
+                    }
+                }
+                return;
+            }
+            Err(DriverError::NoDevice) => {}
+            Err(error) => state = DeviceState::Failed(error),
+        }
+    }
+
+    if let Some(node) = TREE.lock().nodes.get_mut(&id)
+    {
+        node.state = state;
+    }
+}
+
+// suspend every bound device, children before their parents
+// on failure, the ones suspended so far are resumed
+pub fn suspend_all() -> Result<(), (DeviceId, DriverError)>
+{
+    let order = TREE.lock().preorder(None);
+    let mut suspended = Vec::new();
+
+    for id in order.into_iter().rev()
+    {
+        let Some((device, driver)) = bound(id, DeviceState::Bound) else { continue };
+        if let Err(error) = driver.suspend(&device)
+        {
+            for id in suspended.into_iter().rev()
+            {
+                let _ = resume(id);
+            }
+            return Err((id, error));
+        }
+
+        set_state(id, DeviceState::Suspended);
+        suspended.push(id);
+    }
+
+    Ok(())
+}
+
+// resume every suspended device, parents before their children
+pub fn resume_all() -> Result<(), (DeviceId, DriverError)>
+{
+    let order = TREE.lock().preorder(None);
+    order.into_iter().try_for_each(|id| resume(id).map_err(|error| (id, error)))
+}
+
+fn resume(id: DeviceId) -> Result<(), DriverError>
+{
+    let Some((device, driver)) = bound(id, DeviceState::Suspended) else { return Ok(()) };
+    driver.resume(&device)?;
+    set_state(id, DeviceState::Bound);
+    Ok(())
+}
+
+// the device and its driver, if it is in state
+fn bound(id: DeviceId, state: DeviceState) -> Option<(Arc<Device>, &'static dyn Driver)>
+{
+    let tree = TREE.lock();
+    let node = tree.nodes.get(&id).filter(|node| node.state == state)?;
+    Some((node.device.clone(), node.driver?))
+}
+
+fn set_state(id: DeviceId, state: DeviceState)
+{
+    if let Some(node) = TREE.lock().nodes.get_mut(&id)
+    {
+        node.state = state;
+    }
+}
+
+pub fn device(id: DeviceId) -> Option<Arc<Device>>
+{
+    TREE.lock().nodes.get(&id).map(|node| node.device.clone())
+}
+
+pub fn state(id: DeviceId) -> Option<DeviceState>
+{
+    TREE.lock().nodes.get(&id).map(|node| node.state.clone())
+}
+
+// name of the driver bound to a device
+pub fn driver_name(id: DeviceId) -> Option<&'static str>
+{
+    TREE.lock().nodes.get(&id).and_then(|node| node.driver).map(|driver| driver.name())
+}
+
+// devices directly below parent (the roots for None), in the order they were added
+pub fn children(parent: Option<DeviceId>) -> Vec<DeviceId>
+{
+    TREE.lock().children(parent)
+}
+
+// first device with this name
+pub fn find(name: &str) -> Option<DeviceId>
+{
+    TREE.lock().nodes.values().find(|node| node.device.name == name).map(|node| node.device.id)
+}
+
+// number of devices, and of those with a driver
+pub fn count() -> (usize, usize)
+{
+    let tree = TREE.lock();
+    let bound = tree.nodes.values().filter(|node| node.driver.is_some()).count();
+    (tree.nodes.len(), bound)
+}
+
+// the tree, one device per line, indented below its parent: name, ID, driver (or state), resources
+pub fn write_tree(out: &mut impl fmt::Write) -> fmt::Result
+{
+    // snapshot first: the tree stays locked (with interrupts off) only while copying
+    let rows: Vec<(usize, Arc<Device>, Option<&'static str>, DeviceState)> = {
+        let tree = TREE.lock();
+        let depth = |mut id: DeviceId|
+        {
+            let mut depth = 0;
+            while let Some(parent) = tree.nodes.get(&id).and_then(|node| node.device.parent)
+            {
+                depth += 1;
+                id = parent;
+            }
+            depth
+        };
+
+        tree.preorder(None).into_iter().filter_map(|id|
+        {
+            let node = tree.nodes.get(&id)?;
+            Some((depth(id), node.device.clone(), node.driver.map(|driver| driver.name()), node.state.clone()))
+        }).collect()
+    };
+
+    for (depth, device, driver, state) in rows
+    {
+        write!(out, "{:indent$}{} [{}]", "", device.name, device.kind, indent = depth * 2)?;
+        match (driver, state)
+        {
+            (Some(driver), DeviceState::Suspended) => write!(out, " -> {} (suspended)", driver)?,
+            (Some(driver), _) => write!(out, " -> {}", driver)?,
+            (None, DeviceState::Failed(DriverError::Failed(reason))) => write!(out, " (probe failed: {})", reason)?,
+            (None, _) if !matches!(device.kind, DeviceKind::Bus) => write!(out, " (no driver)")?,
+            (None, _) => {}
+        }
+
+        for (index, resource) in device.resources.iter().enumerate()
+        {
+            write!(out, "{}{}", if index == 0 { ": " } else { ", " }, resource)?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+// print the tree to serial
+pub fn dump()
+{
+    let (devices, bound) = count();
+    serial_println!("[DEVICES] {} device(s), {} bound", devices, bound);
+
+    let mut tree = String::new();
+    let _ = write_tree(&mut tree);
+    for line in tree.lines()
+    {
+        serial_println!("{}", line);
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn matches_device_ids()
+{
+    let uart = DeviceKind::Isa(PNP_UART);
+
+    assert!(DeviceMatch::Isa(PNP_UART).matches(&uart));
+    assert!(!DeviceMatch::Isa(PNP_PIT).matches(&uart));
+    assert!(!DeviceMatch::Platform(PNP_UART).matches(&uart));
+    assert!(DeviceMatch::Platform("ps2-mouse").matches(&DeviceKind::Platform("ps2-mouse")));
+    assert!(!DeviceMatch::Pci(PciMatch::default()).matches(&DeviceKind::Bus));
+}
@@ -0,0 +1,323 @@
+// PS/2 mouse on the second 8042 port (set up by ps2::init): IRQ 12 delivers the packet bytes, the
+// interrupt handler decodes them and queues MouseEvents for whoever reads the MouseStream
+
+use super::model::{Device, DeviceMatch, Driver, DriverError};
+use super::ps2::{self, DeviceType, PortIndex};
+use crate::sync::IrqSafeMutex;
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+// max number of events buffered between the interrupt handler and the reader
+const EVENT_QUEUE_SIZE: usize = 64;
+
+// first packet byte
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;       // lost bytes show up as packets without it
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+// fourth byte of five button mice: wheel in the low nibble, then the extra buttons
+const PACKET_BUTTON_4: u8 = 1 << 4;
+const PACKET_BUTTON_5: u8 = 1 << 5;
+
+// buttons, as bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons(pub u8);
+
+impl MouseButtons
+{
+    pub const LEFT: u8 = 1 << 0;
+    pub const RIGHT: u8 = 1 << 1;
+    pub const MIDDLE: u8 = 1 << 2;
+    pub const BUTTON_4: u8 = 1 << 3;
+    pub const BUTTON_5: u8 = 1 << 4;
+
+    pub fn is_pressed(&self, button: u8) -> bool
+    {
+        self.0 & button != 0
+    }
+}
+
+// one packet: relative movement since the last one, and the buttons
+// dy is positive upwards (as the mouse reports it), the wheel positive when turned towards the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent
+{
+    pub dx: i16,
+    pub dy: i16,
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+    pub changed: MouseButtons,      // buttons pressed or released with this packet
+}
+
+// collects packet bytes and turns complete packets into events
+pub struct PacketDecoder
+{
+    device: DeviceType,
+    packet: [u8; 4],
+    len: usize,
+    buttons: MouseButtons,
+}
+
+impl PacketDecoder
+{
+    pub const fn new(device: DeviceType) -> Self
+    {
+        PacketDecoder { device, packet: [0; 4], len: 0, buttons: MouseButtons(0) }
+    }
+
+    // feed one byte; Some once it completes a packet
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent>
+    {
+        // resynchronise: a packet starts with a byte that has the always-one bit
+        if self.len == 0 && byte & PACKET_ALWAYS_ONE == 0
+        {
+            return None;
+        }
+
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.device.packet_size()
+        {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y, extra] = self.packet;
+
+        // the movement is garbage if it overflowed
+        let (dx, dy) = if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0
+        {
+            (0, 0)
+        }
+        else
+        {
+            (sign_extend(x, flags & PACKET_X_SIGN != 0), sign_extend(y, flags & PACKET_Y_SIGN != 0))
+        };
+
+        let mut buttons = 0;
+        if flags & PACKET_LEFT != 0 { buttons |= MouseButtons::LEFT; }
+        if flags & PACKET_RIGHT != 0 { buttons |= MouseButtons::RIGHT; }
+        if flags & PACKET_MIDDLE != 0 { buttons |= MouseButtons::MIDDLE; }
+
+        let wheel = match self.device
+        {
+            DeviceType::ScrollWheelMouse => extra as i8,
+            DeviceType::FiveButtonMouse =>
+            {
+                if extra & PACKET_BUTTON_4 != 0 { buttons |= MouseButtons::BUTTON_4; }
+                if extra & PACKET_BUTTON_5 != 0 { buttons |= MouseButtons::BUTTON_5; }
+
+                // 4 bit two's complement
+                ((extra << 4) as i8) >> 4
+            }
+            _ => 0,
+        };
+
+        let changed = MouseButtons(buttons ^ self.buttons.0);
+        self.buttons = MouseButtons(buttons);
+
+        Some(MouseEvent { dx, dy, wheel, buttons: self.buttons, changed })
+    }
+}
+
+// 9 bit two's complement: the sign is in the first packet byte
+fn sign_extend(value: u8, negative: bool) -> i16
+{
+    if negative { value as i16 - 0x100 } else { value as i16 }
+}
+
+static DECODER: IrqSafeMutex<Option<PacketDecoder>> = IrqSafeMutex::new(None);
+
+// lock-free bounded queue, filled by the interrupt handler (allocated by init, not in the handler)
+static EVENT_QUEUE: OnceCell<ArrayQueue<MouseEvent>> = OnceCell::uninit();
+
+// waker of the task currently waiting on the stream
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// number of events lost because the queue was full
+static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// start decoding packets from the mouse ps2::init found (and unmask its interrupt)
+// returns false if there is none
+pub fn init() -> bool
+{
+    let Some(device) = ps2::info().and_then(|info| info.device(PortIndex::Second)).filter(|device| device.is_mouse()) else
+    {
+        return false;
+    };
+
+    EVENT_QUEUE.init_once(|| ArrayQueue::new(EVENT_QUEUE_SIZE));
+    *DECODER.lock() = Some(PacketDecoder::new(device));
+    crate::interrupts::unmask_irq(crate::interrupts::InterruptIndex::Mouse);
+
+    true
+}
+
+// called by the mouse interrupt handler
+// must not block or allocate!
+pub(crate) fn add_byte(byte: u8)
+{
+    let Some(event) = DECODER.lock().as_mut().and_then(|decoder| decoder.add_byte(byte)) else { return };
+    let Ok(queue) = EVENT_QUEUE.try_get() else { return };
+
+    if queue.push(event).is_err()
+    {
+        DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+    else
+    {
+        WAKER.wake();
+    }
+}
+
+// the "ps2-mouse" device the controller driver adds
+pub struct MouseDriver;
+
+impl Driver for MouseDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "ps2-mouse"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        &[DeviceMatch::Platform("ps2-mouse")]
+    }
+
+    fn probe(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        if init() { Ok(()) } else { Err(DriverError::NoDevice) }
+    }
+
+    fn remove(&self, _device: &Device)
+    {
+        crate::interrupts::mask_irq(crate::interrupts::InterruptIndex::Mouse);
+        let _ = ps2::set_port_enabled(PortIndex::Second, false);
+    }
+
+    fn suspend(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        ps2::set_port_enabled(PortIndex::Second, false).map_err(DriverError::failed)
+    }
+
+    fn resume(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        ps2::set_port_enabled(PortIndex::Second, true).map_err(DriverError::failed)
+    }
+}
+
+// total number of events dropped so far
+pub fn dropped_events() -> u64
+{
+    DROPPED_EVENTS.load(Ordering::Relaxed)
+}
+
+// async stream of mouse events
+pub struct MouseStream
+{
+    _private: (),   // prevent construction without take()
+}
+
+impl MouseStream
+{
+    // the stream, once (there is one queue and one waker); None without a mouse, or when it is taken
+    pub fn take() -> Option<Self>
+    {
+        EVENT_QUEUE.try_get().ok()?;
+        if STREAM_TAKEN.swap(true, Ordering::AcqRel)
+        {
+            return None;
+        }
+
+        Some(MouseStream { _private: () })
+    }
+}
+
+impl Stream for MouseStream
+{
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>>
+    {
+        let queue = EVENT_QUEUE.try_get().expect("[ERR] Mouse event queue not initialised");
+
+        // fast path, avoids registering the waker
+        if let Some(event) = queue.pop()
+        {
+            return Poll::Ready(Some(event));
+        }
+
+        WAKER.register(cx.waker());
+
+        // check again: an event may have been pushed before the waker was registered
+        match queue.pop()
+        {
+            Some(event) =>
+            {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn decodes_standard_packets()
+{
+    let mut decoder = PacketDecoder::new(DeviceType::StandardMouse);
+
+    // left button, 5 right, 3 down
+    assert_eq!(decoder.add_byte(PACKET_ALWAYS_ONE | PACKET_LEFT | PACKET_Y_SIGN), None);
+    assert_eq!(decoder.add_byte(5), None);
+    let event = decoder.add_byte(0xfd).expect("no event");
+    assert_eq!((event.dx, event.dy, event.wheel), (5, -3, 0));
+    assert!(event.buttons.is_pressed(MouseButtons::LEFT));
+    assert_eq!(event.changed, MouseButtons(MouseButtons::LEFT));
+
+    // released: changed again, no movement
+    let event = [PACKET_ALWAYS_ONE, 0, 0].into_iter().find_map(|byte| decoder.add_byte(byte)).expect("no event");
+    assert_eq!(event.buttons, MouseButtons(0));
+    assert_eq!(event.changed, MouseButtons(MouseButtons::LEFT));
+}
+
+#[test_case]
+fn resynchronises_and_drops_overflows()
+{
+    let mut decoder = PacketDecoder::new(DeviceType::StandardMouse);
+
+    // a stray byte without the always-one bit is skipped
+    assert_eq!(decoder.add_byte(0x00), None);
+    let event = [PACKET_ALWAYS_ONE | PACKET_X_OVERFLOW, 0xff, 0x10].into_iter().find_map(|byte| decoder.add_byte(byte));
+    assert_eq!(event.map(|event| (event.dx, event.dy)), Some((0, 0)));
+}
+
+#[test_case]
+fn decodes_wheel_packets()
+{
+    let mut decoder = PacketDecoder::new(DeviceType::ScrollWheelMouse);
+    let event = [PACKET_ALWAYS_ONE, 0, 0, 0xff].into_iter().find_map(|byte| decoder.add_byte(byte));
+    assert_eq!(event.map(|event| event.wheel), Some(-1));
+
+    let mut decoder = PacketDecoder::new(DeviceType::FiveButtonMouse);
+    let event = [PACKET_ALWAYS_ONE, 0, 0, PACKET_BUTTON_4 | 0x01].into_iter().find_map(|byte| decoder.add_byte(byte)).expect("no event");
+    assert_eq!(event.wheel, 1);
+    assert!(event.buttons.is_pressed(MouseButtons::BUTTON_4));
+}
@@ -0,0 +1,823 @@
+// NVMe controllers (PCI class 01/08, programming interface 02)
+// the registers are in BAR 0: capabilities, configuration, status, the admin queue's addresses, and
+// the doorbells the driver writes submission queue tails and completion queue heads to
+// the controller is reset and enabled with an admin queue pair, identified, and given one I/O queue
+// pair; every active namespace is registered as a block device nvme0n1, nvme0n2, ...
+// data is described with PRPs: the first page in PRP1, the second in PRP2, or PRP2 points at a list of
+// the rest; completions come through MSI-X entry 0 (both queue pairs) when the local APIC is up
+// (smp::init), else they are polled
+
+use super::block::{self, BlockDevice, BlockError};
+use super::dma::DmaFrames;
+use super::model::{Device, DeviceMatch, Driver, DriverError};
+use super::pci::{self, PciDevice, PciError, PciMatch};
+use crate::memory::PAGE_SIZE;
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, ThreadId};
+use crate::{apic, interrupts};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const SUBCLASS_NVM: u8 = 0x08;
+pub const PROG_IF_NVME: u8 = 0x02;
+const REGISTERS_BAR: usize = 0;
+
+// controller registers
+const REG_CAP: usize = 0x00;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELLS: u64 = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+const CC_IOSQES_SHIFT: u32 = 16;        // log2 of the submission entry size
+const CC_IOCQES_SHIFT: u32 = 20;        // log2 of the completion entry size
+
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;           // controller fatal status
+
+// admin commands
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+// NVM commands
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+// create I/O queue flags: physically contiguous, interrupts enabled
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+
+// a queue's entries fit in a frame (and its command IDs in a u64)
+const QUEUE_SIZE: u16 = (PAGE_SIZE / SUBMISSION_ENTRY_SIZE) as u16;
+const ADMIN_QUEUE: u16 = 0;
+const IO_QUEUE: u16 = 1;
+const MSIX_ENTRY: u16 = 0;
+
+const PRP_LIST_ENTRIES: usize = PAGE_SIZE / 8;
+const MAX_TRANSFER_PAGES: usize = 32;
+
+const TIMEOUT_POLLS: usize = 10_000_000;
+const IRQ_TIMEOUT_TICKS: u64 = 5 * interrupts::TIMER_HZ;
+
+// controllers probed so far (their interrupt handlers point at them)
+static CONTROLLERS: IrqSafeMutex<Vec<Arc<Controller>>> = IrqSafeMutex::new(Vec::new());
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeError
+{
+    Pci(PciError),
+    Timeout,                // the controller did not get ready
+    Fatal,                  // controller fatal status
+    Command(u16),           // status of a failed admin command
+    OutOfMemory,
+}
+
+impl From<PciError> for NvmeError
+{
+    fn from(error: PciError) -> Self
+    {
+        NvmeError::Pci(error)
+    }
+}
+
+// the controller capabilities (CAP)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities
+{
+    pub max_queue_entries: u32,
+    pub doorbell_stride: u64,       // bytes between doorbells
+    pub timeout_ms: u64,            // for the controller to get (not) ready
+    pub min_page_size: usize,
+}
+
+impl Capabilities
+{
+    pub fn decode(cap: u64) -> Self
+    {
+        Capabilities
+        {
+            max_queue_entries: (cap & 0xffff) as u32 + 1,
+            doorbell_stride: 4 << ((cap >> 32) & 0xf),
+            timeout_ms: ((cap >> 24) & 0xff) * 500,
+            min_page_size: 1 << (12 + ((cap >> 48) & 0xf)),
+        }
+    }
+}
+
+// a completion queue entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Completion
+{
+    pub result: u32,            // command specific (dword 0)
+    pub command_id: u16,
+    pub phase: bool,
+    pub status: u16,            // status code and type, 0 for success
+}
+
+impl Completion
+{
+    pub fn decode(dwords: [u32; 4]) -> Self
+    {
+        Completion
+        {
+            result: dwords[0],
+            command_id: dwords[3] as u16,
+            phase: dwords[3] & 1 << 16 != 0,
+            status: ((dwords[3] >> 17) & 0x7fff) as u16,
+        }
+    }
+}
+
+// a submission queue entry, before it gets its command ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Command
+{
+    pub opcode: u8,
+    pub namespace: u32,
+    pub prp: [u64; 2],
+    pub dwords: [u32; 6],       // command dwords 10-15
+}
+
+impl Command
+{
+    pub fn new(opcode: u8, namespace: u32) -> Self
+    {
+        Command { opcode, namespace, prp: [0; 2], dwords: [0; 6] }
+    }
+
+    pub fn encode(&self, command_id: u16) -> [u32; 16]
+    {
+        let mut entry = [0u32; 16];
+        entry[0] = self.opcode as u32 | (command_id as u32) << 16;
+        entry[1] = self.namespace;
+        entry[6] = self.prp[0] as u32;
+        entry[7] = (self.prp[0] >> 32) as u32;
+        entry[8] = self.prp[1] as u32;
+        entry[9] = (self.prp[1] >> 32) as u32;
+        entry[10..16].copy_from_slice(&self.dwords);
+        entry
+    }
+}
+
+// the PRPs for pages (at least one): the second page, or with more the list (the rest of the pages,
+// written to list)
+pub fn prps(pages: &[PhysAddr], list: Option<(*mut u64, PhysAddr)>) -> [u64; 2]
+{
+    match pages
+    {
+        [first] => [first.as_u64(), 0],
+        [first, second] => [first.as_u64(), second.as_u64()],
+        [first, rest @ ..] =>
+        {
+            let (entries, address) = list.expect("[ERR] No PRP list for a transfer of more than two pages");
+            for (index, page) in rest.iter().take(PRP_LIST_ENTRIES).enumerate()
+            {
+                unsafe { entries.add(index).write_volatile(page.as_u64()); }
+            }
+            [first.as_u64(), address.as_u64()]
+        }
+        [] => [0, 0],
+    }
+}
+
+// what Identify Namespace tells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceInfo
+{
+    pub blocks: u64,
+    pub block_size: usize,
+}
+
+impl NamespaceInfo
+{
+    // size (NSZE) at 0, formatted LBA format (FLBAS) at 26, the formats from 128 (4 bytes each,
+    // log2 of the block size in bits 16-23)
+    pub fn parse(data: &[u8]) -> Self
+    {
+        let blocks = u64::from_le_bytes(data[0..8].try_into().unwrap());
+        let format = (data[26] & 0x0f) as usize;
+        let block_shift = data[128 + 4 * format + 2];
+        NamespaceInfo { blocks, block_size: 1 << block_shift }
+    }
+}
+
+// memory mapped registers
+#[derive(Clone, Copy)]
+struct Registers(VirtAddr);
+
+impl Registers
+{
+    fn read<T: Copy>(&self, offset: usize) -> T
+    {
+        unsafe { ptr::read_volatile((self.0 + offset as u64).as_ptr()) }
+    }
+
+    fn write<T: Copy>(&self, offset: usize, value: T)
+    {
+        unsafe { ptr::write_volatile((self.0 + offset as u64).as_mut_ptr(), value) }
+    }
+}
+
+struct QueueState
+{
+    tail: u16,                              // submission queue
+    head: u16,                              // completion queue
+    phase: bool,                            // of the completions not seen yet
+    free: u64,                              // command IDs
+    waiters: [Option<ThreadId>; QUEUE_SIZE as usize],
+    results: [Option<Completion>; QUEUE_SIZE as usize],
+}
+
+// a submission queue and its completion queue, a frame each
+struct QueuePair
+{
+    size: u16,
+    submissions: DmaFrames,
+    completions: DmaFrames,
+    doorbells: VirtAddr,        // submission tail; the completion head follows after the stride
+    stride: u64,
+    interrupts: AtomicBool,
+    state: IrqSafeMutex<QueueState>,
+}
+
+impl QueuePair
+{
+    fn new(id: u16, size: u16, registers: Registers, stride: u64) -> Result<Self, NvmeError>
+    {
+        let frames = || DmaFrames::new(1).map_err(|_| NvmeError::OutOfMemory);
+
+        // one ID less than entries: a full submission queue would look empty
+        let ids = size as u32 - 1;
+        Ok(QueuePair
+        {
+            size,
+            submissions: frames()?,
+            completions: frames()?,
+            doorbells: registers.0 + DOORBELLS + 2 * id as u64 * stride,
+            stride,
+            interrupts: AtomicBool::new(false),
+            state: IrqSafeMutex::new(QueueState
+            {
+                tail: 0,
+                head: 0,
+                phase: true,
+                free: if ids == 64 { u64::MAX } else { (1 << ids) - 1 },
+                waiters: [None; QUEUE_SIZE as usize],
+                results: [None; QUEUE_SIZE as usize],
+            }),
+        })
+    }
+
+    fn acquire_id(&self) -> u16
+    {
+        loop
+        {
+            {
+                let mut state = self.state.lock();
+                if state.free != 0
+                {
+                    let id = state.free.trailing_zeros() as u16;
+                    state.free &= !(1 << id);
+                    state.results[id as usize] = None;
+                    return id;
+                }
+            }
+            thread::yield_now();
+        }
+    }
+
+    fn submit(&self, command: &Command) -> u16
+    {
+        let id = self.acquire_id();
+        let entry = command.encode(id);
+
+        let mut state = self.state.lock();
+        state.waiters[id as usize] = thread::current_id();
+        let slot = self.submissions.ptr::<u8>(0).wrapping_add(state.tail as usize * SUBMISSION_ENTRY_SIZE);
+        unsafe { ptr::copy_nonoverlapping(entry.as_ptr(), slot.cast(), entry.len()); }
+
+        state.tail = (state.tail + 1) % self.size;
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.doorbells.as_mut_ptr::<u32>(), state.tail as u32); }
+        id
+    }
+
+    // take the new completions, wake their waiters
+    // called by the interrupt handler; must not block or allocate!
+    fn complete(&self)
+    {
+        let mut state = self.state.lock();
+        let mut seen = false;
+        loop
+        {
+            let entry = self.completions.ptr::<u32>(0).wrapping_add(state.head as usize * COMPLETION_ENTRY_SIZE / 4);
+            let completion = Completion::decode(core::array::from_fn(|index| unsafe { ptr::read_volatile(entry.wrapping_add(index)) }));
+            if completion.phase != state.phase
+            {
+                break;
+            }
+            fence(Ordering::SeqCst);
+
+            state.head = (state.head + 1) % self.size;
+            if state.head == 0
+            {
+                state.phase = !state.phase;
+            }
+            seen = true;
+
+            let Some(result) = state.results.get_mut(completion.command_id as usize) else { continue };
+            *result = Some(completion);
+            if let Some(waiter) = state.waiters[completion.command_id as usize]
+            {
+                thread::unpark(waiter);
+            }
+        }
+
+        if seen
+        {
+            unsafe { ptr::write_volatile((self.doorbells + self.stride).as_mut_ptr::<u32>(), state.head as u32); }
+        }
+    }
+
+    // submit a command and wait for its completion
+    fn execute(&self, command: &Command) -> Result<Completion, BlockError>
+    {
+        let id = self.submit(command) as usize;
+
+        let done = || self.state.lock().results[id].is_some();
+        if self.interrupts.load(Ordering::Acquire) && x86_64::instructions::interrupts::are_enabled()
+        {
+            // the interrupt may have come before the waiter was set
+            self.complete();
+            thread::sleep_ticks_interruptible(IRQ_TIMEOUT_TICKS, done);
+        }
+        else
+        {
+            for _ in 0..TIMEOUT_POLLS
+            {
+                self.complete();
+                if done()
+                {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        // without a completion the ID stays taken (the controller may still use it)
+        let mut state = self.state.lock();
+        state.waiters[id] = None;
+        let completion = state.results[id].take().ok_or(BlockError::Timeout)?;
+        state.free |= 1 << id;
+
+        match completion.status
+        {
+            0 => Ok(completion),
+            status => Err(BlockError::DeviceError(status as u8)),
+        }
+    }
+}
+
+// what Identify Controller tells
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControllerInfo
+{
+    pub serial: String,
+    pub model: String,
+    pub max_transfer_pages: usize,  // 0: no limit
+    pub namespaces: u32,
+}
+
+impl ControllerInfo
+{
+    // serial number at 4, model at 24 (ASCII, space padded), MDTS at 77 (log2 of minimum pages),
+    // number of namespaces at 516
+    pub fn parse(data: &[u8]) -> Self
+    {
+        let text = |range: core::ops::Range<usize>| String::from(core::str::from_utf8(&data[range]).unwrap_or("").trim());
+        let mdts = data[77];
+        ControllerInfo
+        {
+            serial: text(4..24),
+            model: text(24..64),
+            max_transfer_pages: if mdts == 0 { 0 } else { 1 << mdts },
+            namespaces: u32::from_le_bytes(data[516..520].try_into().unwrap()),
+        }
+    }
+}
+
+pub struct Controller
+{
+    index: usize,
+    registers: Registers,
+    admin: QueuePair,
+    io: OnceCell<QueuePair>,
+    info: OnceCell<ControllerInfo>,
+}
+
+// the controller's MSI-X entry 0: argument is the controller
+fn controller_interrupt(controller: usize)
+{
+    let controller = unsafe { &*(controller as *const Controller) };
+    controller.admin.complete();
+    if let Some(io) = controller.io.get()
+    {
+        io.complete();
+    }
+}
+
+impl Controller
+{
+    // reset the controller and enable it with an admin queue pair
+    fn new(device: &'static PciDevice, index: usize) -> Result<Self, NvmeError>
+    {
+        let registers = Registers(device.map_bar(REGISTERS_BAR)?);
+        device.enable_bus_master();
+
+        let capabilities = Capabilities::decode(registers.read(REG_CAP));
+        let size = QUEUE_SIZE.min(capabilities.max_queue_entries as u16);
+        let admin = QueuePair::new(ADMIN_QUEUE, size, registers, capabilities.doorbell_stride)?;
+        let controller = Controller { index, registers, admin, io: OnceCell::uninit(), info: OnceCell::uninit() };
+
+        registers.write(REG_CC, registers.read::<u32>(REG_CC) & !CC_EN);
+        controller.wait_ready(false)?;
+
+        let queue_sizes = (size as u32 - 1) | (size as u32 - 1) << 16;
+        registers.write(REG_AQA, queue_sizes);
+        registers.write(REG_ASQ, controller.admin.submissions.phys(0).as_u64());
+        registers.write(REG_ACQ, controller.admin.completions.phys(0).as_u64());
+
+        // NVM command set, 4 KiB pages, round robin; entries of 64 and 16 bytes
+        let entry_sizes = (SUBMISSION_ENTRY_SIZE.ilog2()) << CC_IOSQES_SHIFT | (COMPLETION_ENTRY_SIZE.ilog2()) << CC_IOCQES_SHIFT;
+        registers.write(REG_CC, entry_sizes | CC_EN);
+        controller.wait_ready(true)?;
+
+        Ok(controller)
+    }
+
+    fn wait_ready(&self, ready: bool) -> Result<(), NvmeError>
+    {
+        for _ in 0..TIMEOUT_POLLS
+        {
+            let status: u32 = self.registers.read(REG_CSTS);
+            if status & CSTS_CFS != 0 && ready
+            {
+                return Err(NvmeError::Fatal);
+            }
+            if (status & CSTS_RDY != 0) == ready
+            {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+
+        Err(NvmeError::Timeout)
+    }
+
+    fn admin(&self, command: Command) -> Result<Completion, NvmeError>
+    {
+        self.admin.execute(&command).map_err(|error| match error
+        {
+            BlockError::DeviceError(status) => NvmeError::Command(status as u16),
+            _ => NvmeError::Timeout,
+        })
+    }
+
+    // an Identify data structure (a page)
+    fn identify(&self, cns: u32, namespace: u32) -> Result<DmaFrames, NvmeError>
+    {
+        let data = DmaFrames::new(1).map_err(|_| NvmeError::OutOfMemory)?;
+        let mut command = Command::new(ADMIN_IDENTIFY, namespace);
+        command.prp[0] = data.phys(0).as_u64();
+        command.dwords[0] = cns;
+        self.admin(command)?;
+        Ok(data)
+    }
+
+    // identify the controller, create the I/O queue pair, and list the active namespaces
+    fn setup(&self, msix: bool) -> Result<Vec<u32>, NvmeError>
+    {
+        let mut page = [0u8; PAGE_SIZE];
+        self.identify(IDENTIFY_CONTROLLER, 0)?.read(&mut page);
+        self.info.init_once(|| ControllerInfo::parse(&page));
+
+        // one queue pair (0's based counts of submission and completion queues)
+        let mut command = Command::new(ADMIN_SET_FEATURES, 0);
+        command.dwords[0] = FEATURE_NUMBER_OF_QUEUES;
+        self.admin(command)?;
+
+        let capabilities = Capabilities::decode(self.registers.read(REG_CAP));
+        let size = QUEUE_SIZE.min(capabilities.max_queue_entries as u16);
+        let io = QueuePair::new(IO_QUEUE, size, self.registers, capabilities.doorbell_stride)?;
+        let queue_size = ((size as u32 - 1) << 16) | IO_QUEUE as u32;
+
+        let mut command = Command::new(ADMIN_CREATE_IO_CQ, 0);
+        command.prp[0] = io.completions.phys(0).as_u64();
+        command.dwords[0] = queue_size;
+        command.dwords[1] = (MSIX_ENTRY as u32) << 16 | QUEUE_CONTIGUOUS | if msix { QUEUE_INTERRUPTS } else { 0 };
+        self.admin(command)?;
+
+        let mut command = Command::new(ADMIN_CREATE_IO_SQ, 0);
+        command.prp[0] = io.submissions.phys(0).as_u64();
+        command.dwords[0] = queue_size;
+        command.dwords[1] = (IO_QUEUE as u32) << 16 | QUEUE_CONTIGUOUS;
+        self.admin(command)?;
+
+        io.interrupts.store(msix, Ordering::Release);
+        self.io.init_once(|| io);
+
+        // a list of up to 1024 namespace IDs, 0 terminated
+        self.identify(IDENTIFY_ACTIVE_NAMESPACES, 0)?.read(&mut page);
+        Ok(page.chunks_exact(4).map(|id| u32::from_le_bytes(id.try_into().unwrap())).take_while(|&id| id != 0).collect())
+    }
+
+    pub fn info(&self) -> Option<&ControllerInfo>
+    {
+        self.info.get()
+    }
+
+    // pages a transfer may have
+    fn max_transfer_pages(&self) -> usize
+    {
+        match self.info.get().map_or(0, |info| info.max_transfer_pages)
+        {
+            0 => MAX_TRANSFER_PAGES,
+            limit => limit.min(MAX_TRANSFER_PAGES),
+        }
+    }
+}
+
+// an active namespace, registered as a block device
+pub struct Namespace
+{
+    name: String,
+    controller: Arc<Controller>,
+    id: u32,
+    info: NamespaceInfo,
+}
+
+impl Namespace
+{
+    pub fn id(&self) -> u32
+    {
+        self.id
+    }
+
+    pub fn controller(&self) -> &Controller
+    {
+        &self.controller
+    }
+
+    // read or write the blocks of data, len bytes (at most max_transfer_pages pages)
+    fn transfer(&self, opcode: u8, lba: u64, data: &DmaFrames, len: usize) -> Result<(), BlockError>
+    {
+        let pages: Vec<PhysAddr> = data.segments(len).map(|(address, _)| address).collect();
+        let list = if pages.len() > 2 { Some(DmaFrames::new(1).map_err(|_| BlockError::OutOfMemory)?) } else { None };
+
+        let mut command = Command::new(opcode, self.id);
+        command.prp = prps(&pages, list.as_ref().map(|list| (list.ptr::<u64>(0), list.phys(0))));
+        command.dwords[0] = lba as u32;
+        command.dwords[1] = (lba >> 32) as u32;
+        command.dwords[2] = (len / self.info.block_size) as u32 - 1;
+
+        let io = self.controller.io.get().ok_or(BlockError::NoMedium)?;
+        match io.execute(&command)
+        {
+            Err(BlockError::Timeout) =>
+            {
+                // the controller may still read the list
+                core::mem::forget(list);
+                Err(BlockError::Timeout)
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn max_transfer(&self) -> usize
+    {
+        self.controller.max_transfer_pages() * PAGE_SIZE / self.info.block_size * self.info.block_size
+    }
+}
+
+impl BlockDevice for Namespace
+{
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize
+    {
+        self.info.block_size
+    }
+
+    fn block_count(&self) -> u64
+    {
+        self.info.blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        block::check_request(self.info.block_size, self.info.blocks, lba, buffer.len())?;
+        let max = self.max_transfer();
+
+        for (index, chunk) in buffer.chunks_mut(max).enumerate()
+        {
+            let data = DmaFrames::for_bytes(chunk.len()).map_err(|_| BlockError::OutOfMemory)?;
+            let block = lba + (index * max / self.info.block_size) as u64;
+            if let Err(error) = self.transfer(IO_READ, block, &data, chunk.len())
+            {
+                if error == BlockError::Timeout
+                {
+                    core::mem::forget(data);
+                }
+                return Err(error);
+            }
+            data.read(chunk);
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        block::check_request(self.info.block_size, self.info.blocks, lba, buffer.len())?;
+        let max = self.max_transfer();
+
+        for (index, chunk) in buffer.chunks(max).enumerate()
+        {
+            let data = DmaFrames::for_bytes(chunk.len()).map_err(|_| BlockError::OutOfMemory)?;
+            data.write(chunk);
+            let block = lba + (index * max / self.info.block_size) as u64;
+            if let Err(error) = self.transfer(IO_WRITE, block, &data, chunk.len())
+            {
+                if error == BlockError::Timeout
+                {
+                    core::mem::forget(data);
+                }
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        let io = self.controller.io.get().ok_or(BlockError::NoMedium)?;
+        io.execute(&Command::new(IO_FLUSH, self.id)).map(|_| ())
+    }
+}
+
+// a namespace by name (the block device, with its controller)
+pub fn namespace(name: &str) -> Option<Arc<Namespace>>
+{
+    NAMESPACES.lock().iter().find(|namespace| namespace.name == name).cloned()
+}
+
+static NAMESPACES: IrqSafeMutex<Vec<Arc<Namespace>>> = IrqSafeMutex::new(Vec::new());
+
+pub struct NvmeDriver;
+
+impl Driver for NvmeDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "nvme"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        const IDS: &[DeviceMatch] = &[DeviceMatch::Pci(PciMatch::class(pci::CLASS_MASS_STORAGE, SUBCLASS_NVM).with_prog_if(PROG_IF_NVME))];
+        IDS
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        let pci = device.pci().ok_or(DriverError::NoDevice)?;
+        let index = CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed);
+        let controller = Arc::new(Controller::new(pci, index).map_err(DriverError::failed)?);
+
+        // MSI-X goes to a local APIC, which has to be up; both queue pairs share entry 0
+        let msix = apic::is_initialised() && match pci.enable_msix()
+        {
+            Ok(table) => match interrupts::allocate_vector(controller_interrupt, Arc::as_ptr(&controller) as usize)
+            {
+                Some(vector) if table.route(MSIX_ENTRY, vector, apic::id()).is_ok() => true,
+                Some(vector) =>
+                {
+                    interrupts::free_vector(vector);
+                    false
+                }
+                None => false,
+            },
+            Err(_) => false,
+        };
+        controller.admin.interrupts.store(msix, Ordering::Release);
+        CONTROLLERS.lock().push(controller.clone());
+
+        let namespaces = controller.setup(msix).map_err(DriverError::failed)?;
+        for id in namespaces
+        {
+            let mut page = [0u8; PAGE_SIZE];
+            let Ok(data) = controller.identify(IDENTIFY_NAMESPACE, id) else { continue };
+            data.read(&mut page);
+
+            let info = NamespaceInfo::parse(&page);
+            if info.blocks == 0 || info.block_size > PAGE_SIZE
+            {
+                continue;
+            }
+
+            let namespace = Arc::new(Namespace { name: alloc::format!("nvme{}n{}", controller.index, id), controller: controller.clone(), id, info });
+            NAMESPACES.lock().push(namespace.clone());
+            block::register(namespace);
+        }
+
+        Ok(())
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn decodes_capabilities()
+{
+    // QEMU: 2048 entries, stride 4, 15 s timeout, 4 KiB pages
+    let capabilities = Capabilities::decode(0x0000_0020_1e01_07ff);
+    assert_eq!(capabilities, Capabilities { max_queue_entries: 2048, doorbell_stride: 4, timeout_ms: 15_000, min_page_size: 4096 });
+}
+
+#[test_case]
+fn encodes_commands()
+{
+    let mut command = Command::new(IO_READ, 1);
+    command.prp = [0x1000, 0x2_0000_3000];
+    command.dwords[0] = 0x10;
+    command.dwords[2] = 7;
+
+    let entry = command.encode(0x0203);
+    assert_eq!(entry[0], 0x0203_0002);
+    assert_eq!(entry[1], 1);
+    assert_eq!(entry[6..10], [0x1000, 0, 0x3000, 0x2]);
+    assert_eq!(entry[10..13], [0x10, 0, 7]);
+}
+
+#[test_case]
+fn decodes_completions()
+{
+    let completion = Completion::decode([5, 0, 0x0001_0003, 0x0001_0042]);
+    assert_eq!(completion, Completion { result: 5, command_id: 0x42, phase: true, status: 0 });
+
+    // invalid field: status code 2, with the phase bit clear
+    assert_eq!(Completion::decode([0, 0, 0, 2 << 17 | 7]).status, 2);
+}
+
+#[test_case]
+fn builds_prps()
+{
+    let pages = [PhysAddr::new(0x1000), PhysAddr::new(0x5000), PhysAddr::new(0x9000)];
+    assert_eq!(prps(&pages[..1], None), [0x1000, 0]);
+    assert_eq!(prps(&pages[..2], None), [0x1000, 0x5000]);
+
+    // more: PRP2 points at the list of the rest
+    let mut list = [0u64; 4];
+    assert_eq!(prps(&pages, Some((list.as_mut_ptr(), PhysAddr::new(0x7000)))), [0x1000, 0x7000]);
+    assert_eq!(list[..2], [0x5000, 0x9000]);
+}
+
+#[test_case]
+fn parses_namespaces()
+{
+    let mut page = [0u8; PAGE_SIZE];
+    page[0..8].copy_from_slice(&16384u64.to_le_bytes());
+
+    // format 1 in use: 4 KiB blocks
+    page[26] = 1;
+    page[128 + 2] = 9;
+    page[128 + 4 + 2] = 12;
+    assert_eq!(NamespaceInfo::parse(&page), NamespaceInfo { blocks: 16384, block_size: 4096 });
+}
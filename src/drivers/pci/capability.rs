@@ -0,0 +1,287 @@
+// capability list (status bit 4, first pointer at 0x34) and message signalled interrupts
+// MSI: one address/data pair in configuration space; MSI-X: a table of them in the memory of a BAR
+// messages go to the local APIC of one CPU: address 0xfee0_0000 with the APIC ID in bits 12-19, the
+// vector in the data (fixed delivery, edge triggered)
+
+use super::{config, PciAddress, PciDevice, PciError};
+use crate::memory;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+// a list longer than this has a loop
+const MAX_CAPABILITIES: usize = 48;
+
+// MSI message control
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_CAPABLE_SHIFT: u16 = 1;
+const MSI_MULTIPLE_ENABLE_MASK: u16 = 0b111 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+// MSI-X message control, and the table
+const MSIX_TABLE_SIZE_MASK: u16 = 0x7ff;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_BIR_MASK: u32 = 0b111;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+const MSI_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability
+{
+    pub id: u8,
+    pub offset: u8,     // in configuration space
+}
+
+// walks a capability list; read gives the dword at an offset of configuration space
+pub struct CapabilityIter<F>
+{
+    read: F,
+    next: u8,
+    remaining: usize,
+}
+
+impl<F: Fn(u16) -> u32> CapabilityIter<F>
+{
+    // first: the capabilities pointer (0 for an empty list)
+    pub fn new(first: u8, read: F) -> Self
+    {
+        CapabilityIter { read, next: first & 0xfc, remaining: MAX_CAPABILITIES }
+    }
+}
+
+impl<F: Fn(u16) -> u32> Iterator for CapabilityIter<F>
+{
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability>
+    {
+        // the first 64 bytes are the header, no capability lives there
+        if self.next < 0x40 || self.remaining == 0
+        {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let offset = self.next;
+        let header = (self.read)(offset as u16);
+        self.next = (header >> 8) as u8 & 0xfc;
+
+        Some(Capability { id: header as u8, offset })
+    }
+}
+
+// MSI capability, as the device describes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiInfo
+{
+    pub offset: u8,
+    pub is_64: bool,
+    pub per_vector_masking: bool,
+    pub max_vectors: u8,        // the device asks for this many (only one is ever enabled here)
+}
+
+impl MsiInfo
+{
+    // from the first dword of the capability
+    pub fn decode(offset: u8, header: u32) -> Self
+    {
+        let control = (header >> 16) as u16;
+        MsiInfo
+        {
+            offset,
+            is_64: control & MSI_64_BIT != 0,
+            per_vector_masking: control & MSI_PER_VECTOR_MASKING != 0,
+            max_vectors: 1 << ((control >> MSI_MULTIPLE_CAPABLE_SHIFT) & 0b111).min(5),
+        }
+    }
+}
+
+// MSI-X capability: where the table and the pending bit array are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsixInfo
+{
+    pub offset: u8,
+    pub table_size: u16,        // number of entries
+    pub table_bar: u8,
+    pub table_offset: u32,      // in the BAR
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+impl MsixInfo
+{
+    // from the three dwords of the capability
+    pub fn decode(offset: u8, header: u32, table: u32, pba: u32) -> Self
+    {
+        MsixInfo
+        {
+            offset,
+            table_size: ((header >> 16) as u16 & MSIX_TABLE_SIZE_MASK) + 1,
+            table_bar: (table & MSIX_BIR_MASK) as u8,
+            table_offset: table & !MSIX_BIR_MASK,
+            pba_bar: (pba & MSIX_BIR_MASK) as u8,
+            pba_offset: pba & !MSIX_BIR_MASK,
+        }
+    }
+}
+
+// address and data of a message for vector on the CPU with apic_id
+pub fn msi_message(vector: u8, apic_id: u8) -> (u32, u32)
+{
+    (MSI_ADDRESS_BASE | (apic_id as u32) << 12, vector as u32)
+}
+
+// route the (single) MSI of device to vector on the CPU with apic_id, and turn it on
+pub(super) fn enable_msi(device: &PciDevice, msi: MsiInfo, vector: u8, apic_id: u8)
+{
+    let address = device.address;
+    let control_offset = msi.offset as u16 + 2;
+    let (message_address, message_data) = msi_message(vector, apic_id);
+
+    // off while the message changes
+    let control = config::read_u16(address, control_offset) & !(MSI_ENABLE | MSI_MULTIPLE_ENABLE_MASK);
+    config::write_u16(address, control_offset, control);
+
+    config::write_u32(address, msi.offset as u16 + 4, message_address);
+    let data_offset = if msi.is_64
+    {
+        config::write_u32(address, msi.offset as u16 + 8, 0);
+        msi.offset as u16 + 12
+    }
+    else
+    {
+        msi.offset as u16 + 8
+    };
+    config::write_u16(address, data_offset, message_data as u16);
+
+    config::write_u16(address, control_offset, control | MSI_ENABLE);
+}
+
+// the MSI-X table of a device, mapped; see PciDevice::enable_msix
+pub struct MsixTable
+{
+    address: PciAddress,
+    info: MsixInfo,
+    table: VirtAddr,
+}
+
+impl MsixTable
+{
+    // maps the table and enables MSI-X with every entry masked
+    pub(super) fn enable(device: &PciDevice, info: MsixInfo) -> Result<Self, PciError>
+    {
+        let bar_address = device.memory_bar(info.table_bar as usize)?;
+        let phys = PhysAddr::new(bar_address.as_u64() + info.table_offset as u64);
+        let table = memory::map_mmio(phys, info.table_size as u64 * MSIX_ENTRY_SIZE).map_err(|_| PciError::MappingFailed)?;
+
+        let msix = MsixTable { address: device.address, info, table };
+        let control_offset = info.offset as u16 + 2;
+
+        // function mask while the entries are set up, so none fires half written
+        let control = config::read_u16(msix.address, control_offset);
+        config::write_u16(msix.address, control_offset, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+        for entry in 0..info.table_size
+        {
+            msix.write(entry, 3, MSIX_ENTRY_MASKED);
+        }
+        config::write_u16(msix.address, control_offset, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+
+        Ok(msix)
+    }
+
+    pub fn len(&self) -> u16
+    {
+        self.info.table_size
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.info.table_size == 0
+    }
+
+    // send entry to vector on the CPU with apic_id, and unmask it
+    pub fn route(&self, entry: u16, vector: u8, apic_id: u8) -> Result<(), PciError>
+    {
+        self.check(entry)?;
+        let (message_address, message_data) = msi_message(vector, apic_id);
+
+        self.write(entry, 3, MSIX_ENTRY_MASKED);
+        self.write(entry, 0, message_address);
+        self.write(entry, 1, 0);
+        self.write(entry, 2, message_data);
+        self.write(entry, 3, 0);
+
+        Ok(())
+    }
+
+    pub fn set_masked(&self, entry: u16, masked: bool) -> Result<(), PciError>
+    {
+        self.check(entry)?;
+        self.write(entry, 3, if masked { MSIX_ENTRY_MASKED } else { 0 });
+        Ok(())
+    }
+
+    fn check(&self, entry: u16) -> Result<(), PciError>
+    {
+        if entry < self.info.table_size { Ok(()) } else { Err(PciError::NoSuchEntry(entry)) }
+    }
+
+    // dword index of entry: 0 address, 1 upper address, 2 data, 3 vector control
+    fn write(&self, entry: u16, dword: u64, value: u32)
+    {
+        let register = self.table + entry as u64 * MSIX_ENTRY_SIZE + dword * 4;
+        unsafe { register.as_mut_ptr::<u32>().write_volatile(value) }
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+// configuration space with two capabilities: MSI (64 bit, 4 vectors) at 0x50, then MSI-X at 0x60
+#[cfg(test)]
+fn fake_config(offset: u16) -> u32
+{
+    match offset
+    {
+        0x50 => 0x0084_6005,        // control 0x84: 64 bit, multiple capable 2 (4 vectors); next 0x60
+        0x60 => 0x0007_0011,        // 8 entries; end of list
+        0x64 => 0x0000_2001,        // table in BAR 1 at 0x2000
+        0x68 => 0x0000_3001,
+        _ => 0,
+    }
+}
+
+#[test_case]
+fn walks_capability_lists()
+{
+    let mut capabilities = CapabilityIter::new(0x50, fake_config);
+    assert_eq!(capabilities.next(), Some(Capability { id: CAP_MSI, offset: 0x50 }));
+    assert_eq!(capabilities.next(), Some(Capability { id: CAP_MSIX, offset: 0x60 }));
+    assert_eq!(capabilities.next(), None);
+
+    // a list pointing back at itself ends
+    assert_eq!(CapabilityIter::new(0x40, |_| 0x4005).count(), MAX_CAPABILITIES);
+    assert_eq!(CapabilityIter::new(0, fake_config).next(), None);
+}
+
+#[test_case]
+fn decodes_msi_capabilities()
+{
+    let msi = MsiInfo::decode(0x50, fake_config(0x50));
+    assert_eq!(msi, MsiInfo { offset: 0x50, is_64: true, per_vector_masking: false, max_vectors: 4 });
+
+    let msix = MsixInfo::decode(0x60, fake_config(0x60), fake_config(0x64), fake_config(0x68));
+    assert_eq!((msix.table_size, msix.table_bar, msix.table_offset), (8, 1, 0x2000));
+    assert_eq!((msix.pba_bar, msix.pba_offset), (1, 0x3000));
+
+    assert_eq!(msi_message(0x40, 3), (0xfee0_3000, 0x40));
+}
@@ -0,0 +1,189 @@
+// configuration space access: the legacy I/O ports (0xcf8 selects the register, 0xcfc reads or
+// writes it; 256 bytes per function, segment 0 only), or PCIe ECAM, where the MCFG lists memory
+// regions holding 4 KiB per function
+// buses of an ECAM region are mapped the first time they are touched (1 MiB each, most are empty)
+
+use super::PciAddress;
+use crate::acpi::{self, EcamRegion};
+use crate::memory;
+use crate::sync::IrqSafeMutex;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// configuration space of one function
+pub const LEGACY_CONFIG_SIZE: u16 = 256;
+pub const ECAM_CONFIG_SIZE: u16 = 4096;
+
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+// how configuration space is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigMechanism
+{
+    Legacy,
+    Ecam,
+}
+
+// an ECAM region, with the buses mapped so far
+struct EcamSegment
+{
+    region: EcamRegion,
+    buses: IrqSafeMutex<Vec<Option<VirtAddr>>>,     // indexed by bus - start_bus
+}
+
+// the ECAM regions of the MCFG; empty on machines without one
+static ECAM: OnceCell<Vec<EcamSegment>> = OnceCell::uninit();
+
+// the address and data ports are one register pair: whoever wrote the address owns the data port
+static LEGACY_LOCK: IrqSafeMutex<()> = IrqSafeMutex::new(());
+
+// where one configuration register is
+enum Target
+{
+    Mmio(VirtAddr),
+    Legacy(u32),        // value for CONFIG_ADDRESS; the register is at CONFIG_DATA + (offset & 3)
+}
+
+// look for the MCFG (once); ECAM is used for the buses it covers, the legacy ports for the rest
+pub(super) fn init() -> ConfigMechanism
+{
+    let segments = ECAM.get_or_init(||
+    {
+        acpi::mcfg().map(|mcfg| mcfg.regions.into_iter().map(|region| EcamSegment
+        {
+            region,
+            buses: IrqSafeMutex::new(alloc::vec![None; region.end_bus as usize - region.start_bus as usize + 1]),
+        }).collect()).unwrap_or_default()
+    });
+
+    if segments.is_empty() { ConfigMechanism::Legacy } else { ConfigMechanism::Ecam }
+}
+
+// the mechanism init picked (Legacy before init)
+pub fn mechanism() -> ConfigMechanism
+{
+    match ECAM.get()
+    {
+        Some(segments) if !segments.is_empty() => ConfigMechanism::Ecam,
+        _ => ConfigMechanism::Legacy,
+    }
+}
+
+// the PCI segments (domains) that can be reached: those of the MCFG, or just segment 0
+pub(super) fn segments() -> Vec<(u16, u8)>
+{
+    match ECAM.get()
+    {
+        Some(segments) if !segments.is_empty() => segments.iter().map(|segment| (segment.region.segment, segment.region.start_bus)).collect(),
+        _ => alloc::vec![(0, 0)],
+    }
+}
+
+// size of the configuration space of a function
+pub fn config_size(address: PciAddress) -> u16
+{
+    if ecam_segment(address).is_some() { ECAM_CONFIG_SIZE } else { LEGACY_CONFIG_SIZE }
+}
+
+fn ecam_segment(address: PciAddress) -> Option<&'static EcamSegment>
+{
+    ECAM.get()?.iter().find(|segment| segment.region.segment == address.segment
+        && (segment.region.start_bus..=segment.region.end_bus).contains(&address.bus))
+}
+
+// virtual address of the configuration space of address (mapping its bus if needed)
+fn ecam_address(segment: &EcamSegment, address: PciAddress) -> Option<VirtAddr>
+{
+    let index = (address.bus - segment.region.start_bus) as usize;
+    let mut buses = segment.buses.lock();
+
+    let bus = match buses[index]
+    {
+        Some(bus) => bus,
+        None =>
+        {
+            let phys = PhysAddr::new(segment.region.base.as_u64() + address.bus as u64 * ECAM_BUS_SIZE);
+            let bus = memory::map_mmio(phys, ECAM_BUS_SIZE).ok()?;
+            buses[index] = Some(bus);
+            bus
+        }
+    };
+
+    Some(bus + ((address.device as u64) << 15 | (address.function as u64) << 12))
+}
+
+fn target(address: PciAddress, offset: u16) -> Option<Target>
+{
+    if let Some(segment) = ecam_segment(address)
+    {
+        return (offset < ECAM_CONFIG_SIZE).then(|| ecam_address(segment, address)).flatten().map(|base| Target::Mmio(base + offset as u64));
+    }
+
+    if address.segment != 0 || offset >= LEGACY_CONFIG_SIZE
+    {
+        return None;
+    }
+
+    Some(Target::Legacy(CONFIG_ENABLE | (address.bus as u32) << 16 | (address.device as u32) << 11
+        | (address.function as u32) << 8 | (offset as u32 & 0xfc)))
+}
+
+// the registers are read and written with their own width: writing a whole dword to set a 16 bit
+// register would write the one next to it as well (and clear the status bits that are set)
+macro_rules! config_access
+{
+    ($read:ident, $write:ident, $type:ty) =>
+    {
+        // reads all ones (like a missing device) outside of the reachable configuration space
+        pub fn $read(address: PciAddress, offset: u16) -> $type
+        {
+            debug_assert!((offset as usize).is_multiple_of(size_of::<$type>()), "[ERR] Unaligned PCI configuration access");
+
+            match target(address, offset)
+            {
+                Some(Target::Mmio(virt)) => unsafe { virt.as_ptr::<$type>().read_volatile() },
+                Some(Target::Legacy(select)) =>
+                {
+                    let _guard = LEGACY_LOCK.lock();
+                    unsafe
+                    {
+                        Port::<u32>::new(CONFIG_ADDRESS).write(select);
+                        Port::<$type>::new(CONFIG_DATA + (offset & 3)).read()
+                    }
+                }
+                None => <$type>::MAX,
+            }
+        }
+
+        // ignored outside of the reachable configuration space
+        pub fn $write(address: PciAddress, offset: u16, value: $type)
+        {
+            debug_assert!((offset as usize).is_multiple_of(size_of::<$type>()), "[ERR] Unaligned PCI configuration access");
+
+            match target(address, offset)
+            {
+                Some(Target::Mmio(virt)) => unsafe { virt.as_mut_ptr::<$type>().write_volatile(value) },
+                Some(Target::Legacy(select)) =>
+                {
+                    let _guard = LEGACY_LOCK.lock();
+                    unsafe
+                    {
+                        Port::<u32>::new(CONFIG_ADDRESS).write(select);
+                        Port::<$type>::new(CONFIG_DATA + (offset & 3)).write(value);
+                    }
+                }
+                None => {}
+            }
+        }
+    };
+}
+
+config_access!(read_u8, write_u8, u8);
+config_access!(read_u16, write_u16, u16);
+config_access!(read_u32, write_u32, u32);
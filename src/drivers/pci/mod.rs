@@ -0,0 +1,552 @@
+// PCI: enumerates every function of every bus once (following PCI-to-PCI bridges), decodes the
+// header, BARs and capabilities, and keeps the result in a registry drivers pick their devices from
+// (by vendor/device ID or by class, see PciMatch)
+// configuration space is reached through ECAM where the MCFG has it, the legacy ports otherwise
+
+pub mod capability;
+pub mod config;
+
+use capability::{Capability, CapabilityIter, MsiInfo, MsixInfo, MsixTable};
+pub use config::ConfigMechanism;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::{PhysAddr, VirtAddr};
+
+// common header
+const VENDOR_ID: u16 = 0x00;        // device ID in the upper half
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+
+// type 1 (bridge) header
+const SECONDARY_BUS: u16 = 0x19;
+
+// no device answers with vendor ID all ones
+const NO_VENDOR: u16 = 0xffff;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+pub const HEADER_GENERAL: u8 = 0x00;
+pub const HEADER_PCI_BRIDGE: u8 = 0x01;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+// BAR low bits
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_MEMORY_MASK: u32 = !0xf;
+const BAR_IO_MASK: u32 = !0x3;
+
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const CLASS_NETWORK: u8 = 0x02;
+pub const CLASS_DISPLAY: u8 = 0x03;
+pub const CLASS_BRIDGE: u8 = 0x06;
+pub const SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+pub const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+const MAX_BARS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciError
+{
+    AlreadyInitialised,
+    NoSuchBar(usize),           // not implemented by the device, or past the last one
+    NotMemoryBar(usize),
+    NoMsi,
+    NoMsix,
+    NoSuchEntry(u16),           // of the MSI-X table
+    MappingFailed,
+}
+
+// segment (PCIe domain), bus, device, function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress
+{
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress
+{
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self
+    {
+        PciAddress { segment, bus, device, function }
+    }
+}
+
+// as lspci shows it
+impl fmt::Display for PciAddress
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+// a base address register, with the size of the region it decodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar
+{
+    Memory { address: PhysAddr, size: u64, prefetchable: bool, is_64: bool },
+    Io { port: u16, size: u16 },
+}
+
+impl Bar
+{
+    // low and high: the register(s) as found; the masks: what they read after writing all ones
+    // (high and high_mask are only used by 64 bit memory BARs)
+    // None if the BAR is not implemented
+    pub fn decode(low: u32, low_mask: u32, high: u32, high_mask: u32) -> Option<Bar>
+    {
+        if low & BAR_IO != 0
+        {
+            // the upper 16 bits may not be implemented
+            let size = (!(low_mask & BAR_IO_MASK)).wrapping_add(1) as u16;
+            return (low_mask & BAR_IO_MASK != 0).then_some(Bar::Io { port: (low & BAR_IO_MASK) as u16, size });
+        }
+
+        let is_64 = low & BAR_TYPE_MASK == BAR_TYPE_64;
+        let (address, mask) = if is_64
+        {
+            ((high as u64) << 32 | (low & BAR_MEMORY_MASK) as u64, (high_mask as u64) << 32 | (low_mask & BAR_MEMORY_MASK) as u64)
+        }
+        else
+        {
+            ((low & BAR_MEMORY_MASK) as u64, 0xffff_ffff_0000_0000 | (low_mask & BAR_MEMORY_MASK) as u64)
+        };
+
+        (low_mask & BAR_MEMORY_MASK != 0).then_some(Bar::Memory
+        {
+            address: PhysAddr::new(address),
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & BAR_PREFETCHABLE != 0,
+            is_64,
+        })
+    }
+}
+
+// a function, as enumeration found it
+#[derive(Debug, Clone)]
+pub struct PciDevice
+{
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,            // without the multi-function bit
+    pub bars: [Option<Bar>; MAX_BARS],
+    pub interrupt_line: u8,         // legacy IRQ the firmware routed INTx to (0xff: none)
+    pub interrupt_pin: u8,          // 1-4 for INTA-INTD, 0 if there is no INTx
+    pub capabilities: Vec<Capability>,
+    pub msi: Option<MsiInfo>,
+    pub msix: Option<MsixInfo>,
+}
+
+impl PciDevice
+{
+    // read everything there is to know about the function at address (which has to exist)
+    fn probe(address: PciAddress) -> Self
+    {
+        let id = config::read_u32(address, VENDOR_ID);
+        let class = config::read_u32(address, REVISION);
+        let header_type = config::read_u8(address, HEADER_TYPE) & HEADER_TYPE_MASK;
+        let interrupt = config::read_u16(address, INTERRUPT_LINE);
+
+        let bar_count = match header_type
+        {
+            HEADER_GENERAL => MAX_BARS,
+            HEADER_PCI_BRIDGE => 2,
+            _ => 0,
+        };
+
+        let capabilities: Vec<Capability> = if config::read_u16(address, STATUS) & STATUS_CAPABILITIES != 0
+        {
+            CapabilityIter::new(config::read_u8(address, CAPABILITIES_POINTER), |offset| config::read_u32(address, offset)).collect()
+        }
+        else
+        {
+            Vec::new()
+        };
+
+        let find = |id| capabilities.iter().find(|capability| capability.id == id).map(|capability| capability.offset as u16);
+        let msi = find(capability::CAP_MSI).map(|offset| MsiInfo::decode(offset as u8, config::read_u32(address, offset)));
+        let msix = find(capability::CAP_MSIX).map(|offset| MsixInfo::decode(offset as u8, config::read_u32(address, offset),
+            config::read_u32(address, offset + 4), config::read_u32(address, offset + 8)));
+
+        PciDevice
+        {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            bars: probe_bars(address, bar_count),
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            capabilities,
+            msi,
+            msix,
+        }
+    }
+
+    pub fn is_bridge(&self) -> bool
+    {
+        self.header_type == HEADER_PCI_BRIDGE && self.class == CLASS_BRIDGE && self.subclass == SUBCLASS_PCI_BRIDGE
+    }
+
+    // bus behind a PCI-to-PCI bridge
+    pub fn secondary_bus(&self) -> Option<u8>
+    {
+        self.is_bridge().then(|| config::read_u8(self.address, SECONDARY_BUS))
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability>
+    {
+        self.capabilities.iter().copied().find(|capability| capability.id == id)
+    }
+
+    pub fn bar(&self, index: usize) -> Result<Bar, PciError>
+    {
+        self.bars.get(index).copied().flatten().ok_or(PciError::NoSuchBar(index))
+    }
+
+    // physical address of a memory BAR
+    pub fn memory_bar(&self, index: usize) -> Result<PhysAddr, PciError>
+    {
+        match self.bar(index)?
+        {
+            Bar::Memory { address, .. } => Ok(address),
+            Bar::Io { .. } => Err(PciError::NotMemoryBar(index)),
+        }
+    }
+
+    // map a whole memory BAR (uncached) and turn on memory decoding
+    pub fn map_bar(&self, index: usize) -> Result<VirtAddr, PciError>
+    {
+        let Bar::Memory { address, size, .. } = self.bar(index)? else { return Err(PciError::NotMemoryBar(index)) };
+        let virt = crate::memory::map_mmio(address, size).map_err(|_| PciError::MappingFailed)?;
+        self.set_command(COMMAND_MEMORY_SPACE, true);
+        Ok(virt)
+    }
+
+    pub fn command(&self) -> u16
+    {
+        config::read_u16(self.address, COMMAND)
+    }
+
+    // set or clear bits (COMMAND_*) of the command register
+    pub fn set_command(&self, bits: u16, on: bool)
+    {
+        let command = self.command();
+        config::write_u16(self.address, COMMAND, if on { command | bits } else { command & !bits });
+    }
+
+    // let the device do DMA
+    pub fn enable_bus_master(&self)
+    {
+        self.set_command(COMMAND_BUS_MASTER, true);
+    }
+
+    pub fn enable_memory(&self)
+    {
+        self.set_command(COMMAND_MEMORY_SPACE, true);
+    }
+
+    pub fn enable_io(&self)
+    {
+        self.set_command(COMMAND_IO_SPACE, true);
+    }
+
+    // send the interrupt of the device to vector on the CPU with apic_id as an MSI (INTx is turned off)
+    pub fn enable_msi(&self, vector: u8, apic_id: u8) -> Result<(), PciError>
+    {
+        let msi = self.msi.ok_or(PciError::NoMsi)?;
+        self.set_command(COMMAND_INTERRUPT_DISABLE, true);
+        capability::enable_msi(self, msi, vector, apic_id);
+        Ok(())
+    }
+
+    // map the MSI-X table and enable MSI-X (INTx is turned off); every entry starts masked, see
+    // MsixTable::route
+    pub fn enable_msix(&self) -> Result<MsixTable, PciError>
+    {
+        let msix = self.msix.ok_or(PciError::NoMsix)?;
+        self.set_command(COMMAND_INTERRUPT_DISABLE, true);
+        MsixTable::enable(self, msix)
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32
+    {
+        config::read_u32(self.address, offset)
+    }
+
+    pub fn write_config_u32(&self, offset: u16, value: u32)
+    {
+        config::write_u32(self.address, offset, value)
+    }
+}
+
+// size the BARs: write all ones, read back which bits stuck, restore
+// decoding is off meanwhile, so the device does not claim the addresses the probe writes
+fn probe_bars(address: PciAddress, count: usize) -> [Option<Bar>; MAX_BARS]
+{
+    let mut bars = [None; MAX_BARS];
+    let command = config::read_u16(address, COMMAND);
+    config::write_u16(address, COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+    let size_mask = |offset: u16|
+    {
+        let value = config::read_u32(address, offset);
+        config::write_u32(address, offset, u32::MAX);
+        let mask = config::read_u32(address, offset);
+        config::write_u32(address, offset, value);
+        (value, mask)
+    };
+
+    let mut index = 0;
+    while index < count
+    {
+        let offset = BAR0 + index as u16 * 4;
+        let (low, low_mask) = size_mask(offset);
+
+        // a 64 bit BAR takes the next register as well
+        let is_64 = low & BAR_IO == 0 && low & BAR_TYPE_MASK == BAR_TYPE_64 && index + 1 < count;
+        let (high, high_mask) = if is_64 { size_mask(offset + 4) } else { (0, 0) };
+
+        bars[index] = Bar::decode(low, low_mask, high, high_mask);
+        index += if is_64 { 2 } else { 1 };
+    }
+
+    config::write_u16(address, COMMAND, command);
+    bars
+}
+
+// which devices a driver wants; unset fields match anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PciMatch
+{
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch
+{
+    pub const fn ids(vendor_id: u16, device_id: u16) -> Self
+    {
+        PciMatch { vendor_id: Some(vendor_id), device_id: Some(device_id), class: None, subclass: None, prog_if: None }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> Self
+    {
+        PciMatch { vendor_id: None, device_id: None, class: Some(class), subclass: Some(subclass), prog_if: None }
+    }
+
+    pub const fn with_prog_if(self, prog_if: u8) -> Self
+    {
+        PciMatch { prog_if: Some(prog_if), ..self }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool
+    {
+        fn field<T: PartialEq>(wanted: Option<T>, value: T) -> bool
+        {
+            wanted.is_none_or(|wanted| wanted == value)
+        }
+
+        field(self.vendor_id, device.vendor_id) && field(self.device_id, device.device_id) && field(self.class, device.class)
+            && field(self.subclass, device.subclass) && field(self.prog_if, device.prog_if)
+    }
+}
+
+// every function found by init, ordered by address
+static DEVICES: OnceCell<Vec<PciDevice>> = OnceCell::uninit();
+
+// enumerate all buses (needs the heap and the global memory for ECAM, see memory::init_global)
+// returns the number of functions found
+pub fn init() -> Result<usize, PciError>
+{
+    if DEVICES.is_initialized()
+    {
+        return Err(PciError::AlreadyInitialised);
+    }
+
+    config::init();
+    let devices = enumerate();
+    let count = devices.len();
+
+    DEVICES.try_init_once(|| devices).map_err(|_| PciError::AlreadyInitialised)?;
+    Ok(count)
+}
+
+// how configuration space is reached (after init)
+pub fn config_mechanism() -> ConfigMechanism
+{
+    config::mechanism()
+}
+
+// everything init found (nothing before)
+pub fn devices() -> &'static [PciDevice]
+{
+    DEVICES.get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+pub fn matching(filter: PciMatch) -> impl Iterator<Item = &'static PciDevice>
+{
+    devices().iter().filter(move |device| filter.matches(device))
+}
+
+pub fn find(filter: PciMatch) -> Option<&'static PciDevice>
+{
+    matching(filter).next()
+}
+
+pub fn device_at(address: PciAddress) -> Option<&'static PciDevice>
+{
+    devices().iter().find(|device| device.address == address)
+}
+
+fn enumerate() -> Vec<PciDevice>
+{
+    let mut devices = Vec::new();
+
+    for (segment, start_bus) in config::segments()
+    {
+        let mut scanned = [false; 256];
+
+        // a multi-function host bridge at the first bus means one host controller (and root bus) per function
+        let host = PciAddress::new(segment, start_bus, 0, 0);
+        if config::read_u16(host, VENDOR_ID) != NO_VENDOR && config::read_u8(host, HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0
+        {
+            for function in 0..FUNCTIONS_PER_DEVICE
+            {
+                if config::read_u16(PciAddress::new(segment, start_bus, 0, function), VENDOR_ID) != NO_VENDOR
+                {
+                    scan_bus(segment, start_bus.wrapping_add(function), &mut scanned, &mut devices);
+                }
+            }
+        }
+        else
+        {
+            scan_bus(segment, start_bus, &mut scanned, &mut devices);
+        }
+    }
+
+    devices.sort_by_key(|device| device.address);
+    devices
+}
+
+fn scan_bus(segment: u16, bus: u8, scanned: &mut [bool; 256], devices: &mut Vec<PciDevice>)
+{
+    // misconfigured bridges can point back at a bus already seen
+    if core::mem::replace(&mut scanned[bus as usize], true)
+    {
+        return;
+    }
+
+    for device in 0..DEVICES_PER_BUS
+    {
+        let first = PciAddress::new(segment, bus, device, 0);
+        if config::read_u16(first, VENDOR_ID) == NO_VENDOR
+        {
+            continue;
+        }
+
+        let functions = if config::read_u8(first, HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 { FUNCTIONS_PER_DEVICE } else { 1 };
+        for function in 0..functions
+        {
+            let address = PciAddress::new(segment, bus, device, function);
+            if config::read_u16(address, VENDOR_ID) == NO_VENDOR
+            {
+                continue;
+            }
+
+            let found = PciDevice::probe(address);
+            let secondary = found.secondary_bus();
+            devices.push(found);
+
+            if let Some(secondary) = secondary
+            {
+                scan_bus(segment, secondary, scanned, devices);
+            }
+        }
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[cfg(test)]
+fn test_device(vendor_id: u16, device_id: u16, class: u8, subclass: u8, prog_if: u8) -> PciDevice
+{
+    PciDevice
+    {
+        address: PciAddress::new(0, 0, 1, 0),
+        vendor_id, device_id, class, subclass, prog_if,
+        revision: 0,
+        header_type: HEADER_GENERAL,
+        bars: [None; MAX_BARS],
+        interrupt_line: 0xff,
+        interrupt_pin: 0,
+        capabilities: Vec::new(),       // does not allocate
+        msi: None,
+        msix: None,
+    }
+}
+
+#[test_case]
+fn decodes_bars()
+{
+    // 16 port I/O BAR, with and without the upper half implemented
+    assert_eq!(Bar::decode(0xc041, 0xffff_fff1, 0, 0), Some(Bar::Io { port: 0xc040, size: 16 }));
+    assert_eq!(Bar::decode(0xc041, 0x0000_fff1, 0, 0), Some(Bar::Io { port: 0xc040, size: 16 }));
+
+    // 32 bit 4 KiB memory BAR
+    assert_eq!(Bar::decode(0xfebf_0000, 0xffff_f000, 0, 0),
+        Some(Bar::Memory { address: PhysAddr::new(0xfebf_0000), size: 0x1000, prefetchable: false, is_64: false }));
+
+    // 64 bit prefetchable 16 KiB memory BAR above 4 GiB
+    assert_eq!(Bar::decode(0x0000_000c, 0xffff_c00c, 0x8, 0xffff_ffff),
+        Some(Bar::Memory { address: PhysAddr::new(0x8_0000_0000), size: 0x4000, prefetchable: true, is_64: true }));
+
+    // unimplemented
+    assert_eq!(Bar::decode(0, 0, 0, 0), None);
+}
+
+#[test_case]
+fn matches_devices()
+{
+    let ide = test_device(0x8086, 0x7010, CLASS_MASS_STORAGE, 0x01, 0x80);
+
+    assert!(PciMatch::default().matches(&ide));
+    assert!(PciMatch::ids(0x8086, 0x7010).matches(&ide));
+    assert!(!PciMatch::ids(0x8086, 0x7000).matches(&ide));
+    assert!(PciMatch::class(CLASS_MASS_STORAGE, 0x01).matches(&ide));
+    assert!(!PciMatch::class(CLASS_MASS_STORAGE, 0x01).with_prog_if(0x8f).matches(&ide));
+    assert!(!PciMatch::class(CLASS_BRIDGE, SUBCLASS_HOST_BRIDGE).matches(&ide));
+}
@@ -0,0 +1,612 @@
+// 8042 PS/2 controller: two ports, the first for the keyboard, the second (if there is one) for a mouse
+// init() runs the documented initialisation sequence with interrupts off, polling for every reply;
+// afterwards device bytes arrive through IRQ 1 / IRQ 12 and are read by the interrupt handlers
+// as drivers: the controller (PNP0303 on the ISA bus) adds a child device per port it found something on
+
+use super::model::{self, Device, DeviceKind, DeviceMatch, Driver, DriverError, Resource};
+use crate::sync::IrqSafeMutex;
+use alloc::vec;
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+pub const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;       // read
+const COMMAND_PORT: u16 = 0x64;      // write
+
+// status register
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;      // a byte waits in DATA_PORT
+const STATUS_INPUT_FULL: u8 = 1 << 1;       // the controller has not taken the last byte yet
+
+// controller commands
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_SECOND: u8 = 0xa7;
+const CMD_ENABLE_SECOND: u8 = 0xa8;
+const CMD_TEST_SECOND: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_FIRST: u8 = 0xab;
+const CMD_DISABLE_FIRST: u8 = 0xad;
+const CMD_ENABLE_FIRST: u8 = 0xae;
+const CMD_WRITE_SECOND: u8 = 0xd4;          // the next data byte goes to the second port
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const INTERFACE_TEST_PASSED: u8 = 0x00;
+
+// configuration byte
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// device commands and replies
+const DEV_RESET: u8 = 0xff;
+const DEV_IDENTIFY: u8 = 0xf2;
+const DEV_SET_SAMPLE_RATE: u8 = 0xf3;
+const DEV_SCANCODE_SET: u8 = 0xf0;
+const DEV_ENABLE_SCANNING: u8 = 0xf4;       // data reporting, for mice
+const DEV_DISABLE_SCANNING: u8 = 0xf5;
+pub const DEV_ACK: u8 = 0xfa;
+pub const DEV_RESEND: u8 = 0xfe;
+const DEV_RESET_PASSED: u8 = 0xaa;
+
+// a device asks for a byte again at most this often before the command fails
+const MAX_RESENDS: usize = 3;
+
+// status polls before giving up on the controller (a poll is an I/O port access, about a microsecond)
+const TIMEOUT_POLLS: usize = 100_000;
+// devices take up to about half a second for a reset
+const RESET_TIMEOUT_POLLS: usize = 1_000_000;
+
+// IntelliMouse detection: setting these sample rates in a row unlocks the 4 byte packets with the wheel
+// (and then with buttons 4 and 5); the mouse shows it in its ID
+const WHEEL_SAMPLE_RATES: [u8; 3] = [200, 100, 80];
+const FIVE_BUTTON_SAMPLE_RATES: [u8; 3] = [200, 200, 80];
+const MOUSE_SAMPLE_RATE: u8 = 100;      // packets per second
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error
+{
+    Timeout,
+    SelfTestFailed(u8),             // reply to CMD_SELF_TEST
+    NoWorkingPort,                  // both interface tests failed
+    UnexpectedReply(u8),            // from a device, to a command
+    TooManyResends,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortIndex
+{
+    First,
+    Second,
+}
+
+// what is attached to a port, from the identify reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType
+{
+    AtKeyboard,                 // replies nothing to identify
+    Mf2Keyboard,
+    StandardMouse,
+    ScrollWheelMouse,
+    FiveButtonMouse,
+    Unknown([u8; 2]),
+}
+
+impl DeviceType
+{
+    // from the (up to two) identify bytes
+    pub fn from_id(id: &[u8]) -> DeviceType
+    {
+        match id
+        {
+            [] => DeviceType::AtKeyboard,
+            [0xab, 0x41 | 0xc1 | 0x83 | 0x84 | 0x85 | 0x86 | 0x90 | 0x91 | 0x92 | 0xa1] => DeviceType::Mf2Keyboard,
+            [0x00] => DeviceType::StandardMouse,
+            [0x03] => DeviceType::ScrollWheelMouse,
+            [0x04] => DeviceType::FiveButtonMouse,
+            [first] => DeviceType::Unknown([*first, 0]),
+            [first, second, ..] => DeviceType::Unknown([*first, *second]),
+        }
+    }
+
+    pub fn is_keyboard(&self) -> bool
+    {
+        matches!(self, DeviceType::AtKeyboard | DeviceType::Mf2Keyboard)
+    }
+
+    pub fn is_mouse(&self) -> bool
+    {
+        matches!(self, DeviceType::StandardMouse | DeviceType::ScrollWheelMouse | DeviceType::FiveButtonMouse)
+    }
+
+    // bytes per movement packet of a mouse
+    pub fn packet_size(&self) -> usize
+    {
+        match self
+        {
+            DeviceType::ScrollWheelMouse | DeviceType::FiveButtonMouse => 4,
+            _ => 3,
+        }
+    }
+}
+
+// how keyboard bytes arrive on the first port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeMode
+{
+    Translated,     // the controller translates to scancode set 1 (what the BIOS leaves behind)
+    Set2,           // scancode set 2, untranslated
+}
+
+// what init() found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ps2Info
+{
+    pub dual_channel: bool,
+    pub devices: [Option<DeviceType>; 2],       // by port, None if the port failed or nothing answered
+    pub scancode_mode: ScancodeMode,
+}
+
+impl Ps2Info
+{
+    pub fn device(&self, port: PortIndex) -> Option<DeviceType>
+    {
+        self.devices[port as usize]
+    }
+}
+
+// controller ports; every command sequence holds the lock
+struct Controller
+{
+    data: Port<u8>,
+    status: Port<u8>,
+    command: Port<u8>,
+}
+
+static CONTROLLER: IrqSafeMutex<Controller> = IrqSafeMutex::new(Controller
+{
+    data: Port::new(DATA_PORT),
+    status: Port::new(STATUS_PORT),
+    command: Port::new(COMMAND_PORT),
+});
+
+static INFO: OnceCell<Ps2Info> = OnceCell::uninit();
+
+// initialise the controller and its devices (once; later calls return what the first one found)
+// device interrupts are enabled for the ports which work; on failure the BIOS configuration is restored
+pub fn init() -> Result<Ps2Info, Ps2Error>
+{
+    if let Some(info) = INFO.get()
+    {
+        return Ok(*info);
+    }
+
+    let info = interrupts::without_interrupts(|| CONTROLLER.lock().initialise())?;
+    INFO.init_once(|| info);
+    Ok(info)
+}
+
+// what init() found, None before it succeeded
+pub fn info() -> Option<Ps2Info>
+{
+    INFO.get().copied()
+}
+
+// how the keyboard task has to decode bytes from the first port
+pub fn scancode_mode() -> ScancodeMode
+{
+    info().map_or(ScancodeMode::Translated, |info| info.scancode_mode)
+}
+
+// read the byte waiting in the data port (from an interrupt handler: the IRQ says there is one)
+pub fn read_data() -> u8
+{
+    unsafe { Port::<u8>::new(DATA_PORT).read() }
+}
+
+// send a byte to a device without waiting for its reply (which arrives through the interrupt handler)
+pub fn write_device(port: PortIndex, byte: u8) -> Result<(), Ps2Error>
+{
+    CONTROLLER.lock().write_device(port, byte)
+}
+
+// stop (or restart) the clock of a port: its device holds back what it has to send meanwhile
+pub fn set_port_enabled(port: PortIndex, enabled: bool) -> Result<(), Ps2Error>
+{
+    let command = match (port, enabled)
+    {
+        (PortIndex::First, true) => CMD_ENABLE_FIRST,
+        (PortIndex::First, false) => CMD_DISABLE_FIRST,
+        (PortIndex::Second, true) => CMD_ENABLE_SECOND,
+        (PortIndex::Second, false) => CMD_DISABLE_SECOND,
+    };
+
+    CONTROLLER.lock().write_command(command)
+}
+
+// IRQ of the device on a port
+pub fn port_irq(port: PortIndex) -> u8
+{
+    match port
+    {
+        PortIndex::First => 1,
+        PortIndex::Second => 12,
+    }
+}
+
+// the controller: initialises it (see init) and adds a "ps2-keyboard" / "ps2-mouse" device for what
+// answered on its ports
+pub struct Ps2ControllerDriver;
+
+impl Driver for Ps2ControllerDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "i8042"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        &[DeviceMatch::Isa(model::PNP_PS2_CONTROLLER)]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        let info = init().map_err(DriverError::failed)?;
+
+        for port in [PortIndex::First, PortIndex::Second]
+        {
+            let name = match info.device(port)
+            {
+                Some(found) if found.is_keyboard() => "ps2-keyboard",
+                Some(found) if found.is_mouse() => "ps2-mouse",
+                _ => continue,
+            };
+            model::add_device(Some(device.id), name, DeviceKind::Platform(name), vec![Resource::Irq(port_irq(port))]);
+        }
+
+        Ok(())
+    }
+}
+
+// the keyboard: decoding is the keyboard task's job (task::keyboard), this stops and starts the port
+pub struct Ps2KeyboardDriver;
+
+impl Driver for Ps2KeyboardDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "ps2-keyboard"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        &[DeviceMatch::Platform("ps2-keyboard")]
+    }
+
+    fn probe(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        // init left the port enabled
+        Ok(())
+    }
+
+    fn remove(&self, _device: &Device)
+    {
+        let _ = set_port_enabled(PortIndex::First, false);
+    }
+
+    fn suspend(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        set_port_enabled(PortIndex::First, false).map_err(DriverError::failed)
+    }
+
+    fn resume(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        set_port_enabled(PortIndex::First, true).map_err(DriverError::failed)
+    }
+}
+
+impl Controller
+{
+    fn initialise(&mut self) -> Result<Ps2Info, Ps2Error>
+    {
+        // disable both ports, so no device interferes, and drop whatever is still buffered
+        self.write_command(CMD_DISABLE_FIRST)?;
+        self.write_command(CMD_DISABLE_SECOND)?;
+        self.flush_output();
+
+        let original = self.read_config()?;
+        match self.configure(original)
+        {
+            Ok(info) => Ok(info),
+            Err(error) =>
+            {
+                // back to what the BIOS left (translated keyboard on the first port), as far as it goes
+                let _ = self.write_config(original);
+                let _ = self.write_command(CMD_ENABLE_FIRST);
+                Err(error)
+            }
+        }
+    }
+
+    fn configure(&mut self, original: u8) -> Result<Ps2Info, Ps2Error>
+    {
+        // no interrupts and no translation while setting up
+        let mut config = original;
+        let maybe_dual = config & CONFIG_SECOND_CLOCK_DISABLED != 0;
+        config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        // some controllers reset themselves during the self test: write the configuration again
+        self.write_command(CMD_SELF_TEST)?;
+        match self.read_data_timeout(TIMEOUT_POLLS)?
+        {
+            SELF_TEST_PASSED => {}
+            reply => return Err(Ps2Error::SelfTestFailed(reply)),
+        }
+        self.write_config(config)?;
+
+        // a second port exists if enabling it starts its clock
+        let mut dual_channel = false;
+        if maybe_dual
+        {
+            self.write_command(CMD_ENABLE_SECOND)?;
+            dual_channel = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+            self.write_command(CMD_DISABLE_SECOND)?;
+        }
+
+        let first_ok = self.interface_test(CMD_TEST_FIRST)?;
+        let second_ok = dual_channel && self.interface_test(CMD_TEST_SECOND)?;
+        if !first_ok && !second_ok
+        {
+            return Err(Ps2Error::NoWorkingPort);
+        }
+
+        // enable the working ports, reset and identify what is attached
+        let mut devices = [None; 2];
+        if first_ok
+        {
+            self.write_command(CMD_ENABLE_FIRST)?;
+            devices[0] = self.reset_and_identify(PortIndex::First).ok();
+        }
+        if second_ok
+        {
+            self.write_command(CMD_ENABLE_SECOND)?;
+            devices[1] = self.reset_and_identify(PortIndex::Second).ok();
+        }
+
+        // scancode set 2 without translation; keyboards which refuse get set 1 through translation
+        let mut scancode_mode = ScancodeMode::Translated;
+        if devices[0].is_some_and(|device| device.is_keyboard())
+        {
+            if self.select_scancode_set_2().is_ok()
+            {
+                scancode_mode = ScancodeMode::Set2;
+            }
+            let _ = self.device_command(PortIndex::First, DEV_ENABLE_SCANNING);
+        }
+
+        // a mouse only reports once enabled; without it the port stays quiet
+        if let Some(mouse) = devices[1].filter(|device| device.is_mouse())
+        {
+            devices[1] = self.enable_mouse(mouse).ok();
+        }
+
+        let mut config = self.read_config()?;
+        if scancode_mode == ScancodeMode::Translated
+        {
+            config |= CONFIG_TRANSLATION;
+        }
+        if devices[0].is_some()
+        {
+            config |= CONFIG_FIRST_IRQ;
+        }
+        if devices[1].is_some()
+        {
+            config |= CONFIG_SECOND_IRQ;
+        }
+        self.write_config(config)?;
+
+        Ok(Ps2Info { dual_channel, devices, scancode_mode })
+    }
+
+    fn interface_test(&mut self, command: u8) -> Result<bool, Ps2Error>
+    {
+        self.write_command(command)?;
+        Ok(self.read_data_timeout(TIMEOUT_POLLS)? == INTERFACE_TEST_PASSED)
+    }
+
+    // reset the device on port (which leaves scanning off), then identify it
+    fn reset_and_identify(&mut self, port: PortIndex) -> Result<DeviceType, Ps2Error>
+    {
+        self.device_command(port, DEV_RESET)?;
+        match self.read_data_timeout(RESET_TIMEOUT_POLLS)?
+        {
+            DEV_RESET_PASSED => {}
+            reply => return Err(Ps2Error::UnexpectedReply(reply)),
+        }
+
+        // mice send their ID right after the reset result
+        let _ = self.read_data_timeout(TIMEOUT_POLLS / 10);
+        self.flush_output();
+
+        self.device_command(port, DEV_DISABLE_SCANNING)?;
+        self.identify(port)
+    }
+
+    fn identify(&mut self, port: PortIndex) -> Result<DeviceType, Ps2Error>
+    {
+        self.device_command(port, DEV_IDENTIFY)?;
+
+        let mut id = [0; 2];
+        let mut len = 0;
+        while len < id.len()
+        {
+            match self.read_data_timeout(TIMEOUT_POLLS)
+            {
+                Ok(byte) => { id[len] = byte; len += 1; }
+                Err(_) => break,
+            }
+        }
+
+        Ok(DeviceType::from_id(&id[..len]))
+    }
+
+    // switch the mouse on the second port to the best protocol it has, then start reporting
+    // returns what it is now (a wheel mouse once unlocked, see WHEEL_SAMPLE_RATES)
+    fn enable_mouse(&mut self, mut mouse: DeviceType) -> Result<DeviceType, Ps2Error>
+    {
+        for (rates, unlocks) in [(WHEEL_SAMPLE_RATES, DeviceType::ScrollWheelMouse), (FIVE_BUTTON_SAMPLE_RATES, DeviceType::FiveButtonMouse)]
+        {
+            for rate in rates
+            {
+                self.set_sample_rate(rate)?;
+            }
+
+            match self.identify(PortIndex::Second)?
+            {
+                device if device == unlocks => mouse = device,
+                _ => break,
+            }
+        }
+
+        self.set_sample_rate(MOUSE_SAMPLE_RATE)?;
+        self.device_command(PortIndex::Second, DEV_ENABLE_SCANNING)?;
+        Ok(mouse)
+    }
+
+    fn set_sample_rate(&mut self, rate: u8) -> Result<(), Ps2Error>
+    {
+        self.device_command(PortIndex::Second, DEV_SET_SAMPLE_RATE)?;
+        self.device_command(PortIndex::Second, rate)
+    }
+
+    fn select_scancode_set_2(&mut self) -> Result<(), Ps2Error>
+    {
+        self.device_command(PortIndex::First, DEV_SCANCODE_SET)?;
+        self.device_command(PortIndex::First, 2)?;
+
+        // sub-command 0 reads the current set back
+        self.device_command(PortIndex::First, DEV_SCANCODE_SET)?;
+        self.device_command(PortIndex::First, 0)?;
+        match self.read_data_timeout(TIMEOUT_POLLS)?
+        {
+            2 => Ok(()),
+            set => Err(Ps2Error::UnexpectedReply(set)),
+        }
+    }
+
+    // send a byte to the device on port and wait for its acknowledgement, sending it again on request
+    fn device_command(&mut self, port: PortIndex, byte: u8) -> Result<(), Ps2Error>
+    {
+        for _ in 0..=MAX_RESENDS
+        {
+            self.write_device(port, byte)?;
+            match self.read_data_timeout(TIMEOUT_POLLS)?
+            {
+                DEV_ACK => return Ok(()),
+                DEV_RESEND => continue,
+                reply => return Err(Ps2Error::UnexpectedReply(reply)),
+            }
+        }
+
+        Err(Ps2Error::TooManyResends)
+    }
+
+    fn write_device(&mut self, port: PortIndex, byte: u8) -> Result<(), Ps2Error>
+    {
+        if port == PortIndex::Second
+        {
+            self.write_command(CMD_WRITE_SECOND)?;
+        }
+        self.write_data(byte)
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error>
+    {
+        self.write_command(CMD_READ_CONFIG)?;
+        self.read_data_timeout(TIMEOUT_POLLS)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error>
+    {
+        self.write_command(CMD_WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    fn write_command(&mut self, command: u8) -> Result<(), Ps2Error>
+    {
+        self.wait_input_empty()?;
+        unsafe { self.command.write(command); }
+        Ok(())
+    }
+
+    fn write_data(&mut self, byte: u8) -> Result<(), Ps2Error>
+    {
+        self.wait_input_empty()?;
+        unsafe { self.data.write(byte); }
+        Ok(())
+    }
+
+    fn read_data_timeout(&mut self, polls: usize) -> Result<u8, Ps2Error>
+    {
+        for _ in 0..polls
+        {
+            if unsafe { self.status.read() } & STATUS_OUTPUT_FULL != 0
+            {
+                return Ok(unsafe { self.data.read() });
+            }
+            core::hint::spin_loop();
+        }
+
+        Err(Ps2Error::Timeout)
+    }
+
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error>
+    {
+        for _ in 0..TIMEOUT_POLLS
+        {
+            if unsafe { self.status.read() } & STATUS_INPUT_FULL == 0
+            {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+
+        Err(Ps2Error::Timeout)
+    }
+
+    fn flush_output(&mut self)
+    {
+        while unsafe { self.status.read() } & STATUS_OUTPUT_FULL != 0
+        {
+            unsafe { self.data.read(); }
+        }
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn device_types_from_identify_bytes()
+{
+    assert_eq!(DeviceType::from_id(&[]), DeviceType::AtKeyboard);
+    assert_eq!(DeviceType::from_id(&[0xab, 0x83]), DeviceType::Mf2Keyboard);
+    assert_eq!(DeviceType::from_id(&[0x03]), DeviceType::ScrollWheelMouse);
+    assert_eq!(DeviceType::from_id(&[0x12, 0x34]), DeviceType::Unknown([0x12, 0x34]));
+    assert!(DeviceType::Mf2Keyboard.is_keyboard() && !DeviceType::Mf2Keyboard.is_mouse());
+}
+
+#[test_case]
+fn controller_finds_the_keyboard()
+{
+    let info = init().expect("PS/2 controller initialisation failed");
+
+    assert_eq!(info.device(PortIndex::First).map(|device| device.is_keyboard()), Some(true));
+    assert_eq!(info.scancode_mode, ScancodeMode::Set2);
+    assert_eq!(scancode_mode(), ScancodeMode::Set2);
+    assert_eq!(init(), Ok(info));
+}
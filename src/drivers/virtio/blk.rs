@@ -0,0 +1,254 @@
+// virtio block devices, registered as block devices vda, vdb, ...
+// one request queue; a request is a descriptor chain of a header (type, sector), the data (a
+// descriptor per frame) and a status byte the device writes
+// completions come through MSI-X entry 0 when the local APIC is up (smp::init), else they are polled
+
+use super::queue::{self, Buffer, QueueError, Virtqueue};
+use super::{VirtioError, VirtioPci};
+use crate::drivers::block::{self, BlockDevice, BlockError};
+use crate::drivers::dma::DmaFrames;
+use crate::drivers::model::{Device, DeviceMatch, Driver, DriverError};
+use crate::drivers::pci::{PciDevice, PciMatch};
+use crate::memory::PAGE_SIZE;
+use crate::{apic, interrupts, thread};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// features
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+const FEATURES: u64 = F_RO | F_FLUSH;
+
+// request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+// request status (0xff until the device writes it)
+const S_OK: u8 = 0;
+const S_PENDING: u8 = 0xff;
+
+// device configuration: capacity in sectors
+const CONFIG_CAPACITY: usize = 0;
+
+// the device counts in 512 byte sectors whatever its physical block size
+pub const SECTOR_SIZE: usize = 512;
+
+const REQUEST_QUEUE: u16 = 0;
+const REQUEST_MSIX_ENTRY: u16 = 0;
+const HEADER_SIZE: usize = 16;
+const MAX_TRANSFER: usize = 16 * PAGE_SIZE;
+
+// disks probed so far (they are named in order)
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct VirtioBlk
+{
+    name: String,
+    transport: VirtioPci,
+    queue: Arc<Virtqueue>,
+    features: u64,
+    capacity: u64,
+}
+
+// the request queue's interrupt: argument is the queue (disks keep theirs for good)
+fn queue_interrupt(queue: usize)
+{
+    unsafe { &*(queue as *const Virtqueue) }.collect();
+}
+
+impl VirtioBlk
+{
+    // bring up the device: features, the request queue and its interrupt
+    pub fn new(device: &'static PciDevice, name: String) -> Result<Self, VirtioError>
+    {
+        let mut transport = VirtioPci::new(device)?;
+        match Self::setup(&mut transport)
+        {
+            Ok((features, queue)) =>
+            {
+                let capacity = transport.read_config::<u64>(CONFIG_CAPACITY);
+                transport.driver_ok();
+                Ok(VirtioBlk { name, transport, queue, features, capacity })
+            }
+            Err(error) =>
+            {
+                transport.fail();
+                Err(error)
+            }
+        }
+    }
+
+    fn setup(transport: &mut VirtioPci) -> Result<(u64, Arc<Virtqueue>), VirtioError>
+    {
+        let features = transport.negotiate(FEATURES)?;
+
+        // MSI-X goes to a local APIC, which has to be up
+        let msix = apic::is_initialised() && transport.enable_msix().is_some();
+        let queue = Arc::new(transport.setup_queue(REQUEST_QUEUE, queue::MAX_SIZE, msix.then_some(REQUEST_MSIX_ENTRY))?);
+
+        if let Some(table) = transport.msix().filter(|_| msix)
+        {
+            let vector = interrupts::allocate_vector(queue_interrupt, Arc::as_ptr(&queue) as usize).ok_or(VirtioError::NoVector)?;
+            if let Err(error) = table.route(REQUEST_MSIX_ENTRY, vector, apic::id())
+            {
+                interrupts::free_vector(vector);
+                return Err(error.into());
+            }
+            queue.set_interrupts(true);
+        }
+
+        Ok((features, queue))
+    }
+
+    pub fn pci(&self) -> &'static PciDevice
+    {
+        self.transport.device
+    }
+
+    // run a request with the first len bytes of data (read or written as the type says); returns the
+    // frames for reading out
+    fn request(&self, kind: u32, sector: u64, data: DmaFrames, len: usize) -> Result<DmaFrames, BlockError>
+    {
+        // header at the start of its frame, the status byte after it
+        let header = DmaFrames::new(1).map_err(|_| BlockError::OutOfMemory)?;
+        let mut bytes = [0u8; HEADER_SIZE + 1];
+        bytes[0..4].copy_from_slice(&kind.to_le_bytes());
+        bytes[8..16].copy_from_slice(&sector.to_le_bytes());
+        bytes[HEADER_SIZE] = S_PENDING;
+        header.write(&bytes);
+
+        let mut chain = Vec::with_capacity(data.len() + 2);
+        chain.push(Buffer { address: header.phys(0), len: HEADER_SIZE as u32, writable: false });
+        chain.extend(data.segments(len).map(|(address, len)| Buffer { address, len: len as u32, writable: kind == T_IN }));
+        chain.push(Buffer { address: header.phys(0) + HEADER_SIZE as u64, len: 1, writable: true });
+
+        let token = loop
+        {
+            match self.queue.submit(&chain)
+            {
+                Ok(token) => break token,
+                Err(_) =>
+                {
+                    // full: other requests have to finish first
+                    self.queue.collect();
+                    thread::yield_now();
+                }
+            }
+        };
+
+        if let Err(QueueError::Timeout) = self.queue.wait(token)
+        {
+            // the device may still write them
+            core::mem::forget(header);
+            core::mem::forget(data);
+            return Err(BlockError::Timeout);
+        }
+
+        header.read(&mut bytes);
+        match bytes[HEADER_SIZE]
+        {
+            S_OK => Ok(data),
+            status => Err(BlockError::DeviceError(status)),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk
+{
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize
+    {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64
+    {
+        self.capacity
+    }
+
+    fn is_read_only(&self) -> bool
+    {
+        self.features & F_RO != 0
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>
+    {
+        block::check_request(SECTOR_SIZE, self.capacity, lba, buffer.len())?;
+
+        for (index, chunk) in buffer.chunks_mut(MAX_TRANSFER).enumerate()
+        {
+            let data = DmaFrames::for_bytes(chunk.len()).map_err(|_| BlockError::OutOfMemory)?;
+            let sector = lba + (index * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            self.request(T_IN, sector, data, chunk.len())?.read(chunk);
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>
+    {
+        if self.is_read_only()
+        {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_request(SECTOR_SIZE, self.capacity, lba, buffer.len())?;
+
+        for (index, chunk) in buffer.chunks(MAX_TRANSFER).enumerate()
+        {
+            let data = DmaFrames::for_bytes(chunk.len()).map_err(|_| BlockError::OutOfMemory)?;
+            data.write(chunk);
+            let sector = lba + (index * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            self.request(T_OUT, sector, data, chunk.len())?;
+        }
+
+        Ok(())
+    }
+
+    // without the flush feature the device writes through
+    fn flush(&self) -> Result<(), BlockError>
+    {
+        if self.features & F_FLUSH == 0
+        {
+            return Ok(());
+        }
+
+        let none = DmaFrames::new(0).map_err(|_| BlockError::OutOfMemory)?;
+        self.request(T_FLUSH, 0, none, 0).map(|_| ())
+    }
+}
+
+pub struct VirtioBlkDriver;
+
+impl Driver for VirtioBlkDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "virtio-blk"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        const IDS: &[DeviceMatch] = &[
+            DeviceMatch::Pci(PciMatch::ids(super::VENDOR_ID, super::DEVICE_ID_TRANSITIONAL_BLOCK)),
+            DeviceMatch::Pci(PciMatch::ids(super::VENDOR_ID, super::DEVICE_ID_MODERN_BASE + super::TYPE_BLOCK)),
+        ];
+        IDS
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        let pci = device.pci().ok_or(DriverError::NoDevice)?;
+        let letter = (b'a' + DISKS.fetch_add(1, Ordering::Relaxed) as u8) as char;
+
+        let disk = VirtioBlk::new(pci, alloc::format!("vd{}", letter)).map_err(DriverError::failed)?;
+        block::register(Arc::new(disk));
+        Ok(())
+    }
+}
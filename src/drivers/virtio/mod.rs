@@ -0,0 +1,344 @@
+// virtio devices on PCI (virtio 1.0, "modern" interface)
+// the device describes where its register blocks are with vendor specific capabilities: common
+// configuration (features, status, queues), notification doorbells, ISR status and the device's own
+// configuration, each somewhere in a memory BAR
+// transitional devices (QEMU's default) have these too, next to the legacy I/O BAR, which is not used
+
+pub mod queue;
+pub mod blk;
+
+use super::pci::capability::{MsixTable, CAP_VENDOR_SPECIFIC};
+use super::pci::{PciDevice, PciError};
+use super::dma::OutOfMemory;
+use core::ptr;
+use queue::Virtqueue;
+use x86_64::VirtAddr;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+// device IDs: transitional ones are 0x1000 + (a legacy numbering), modern ones 0x1040 + the device type
+pub const DEVICE_ID_TRANSITIONAL_BLOCK: u16 = 0x1001;
+pub const DEVICE_ID_MODERN_BASE: u16 = 0x1040;
+pub const TYPE_BLOCK: u16 = 2;
+
+// feature bits every device type has
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// device status
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 7;
+
+// capability types (cfg_type; 3, the ISR status, is for INTx only)
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_DEVICE: u8 = 4;
+
+// common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_MSIX_CONFIG: usize = 0x10;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1a;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// MSI-X vector register value for none
+pub const NO_VECTOR: u16 = 0xffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError
+{
+    LegacyOnly,             // no virtio 1.0 capabilities
+    Pci(PciError),
+    FeaturesRejected,
+    NoQueue(u16),
+    NoVector,               // the device did not take an MSI-X vector
+    OutOfMemory,
+}
+
+impl From<PciError> for VirtioError
+{
+    fn from(error: PciError) -> Self
+    {
+        VirtioError::Pci(error)
+    }
+}
+
+impl From<OutOfMemory> for VirtioError
+{
+    fn from(_: OutOfMemory) -> Self
+    {
+        VirtioError::OutOfMemory
+    }
+}
+
+// a virtio capability: where in which BAR one of the register blocks is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioCap
+{
+    pub cfg_type: u8,
+    pub bar: u8,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl VirtioCap
+{
+    // from the first four dwords of the capability (id, next, length, cfg_type; bar; offset; length)
+    pub fn decode(dwords: [u32; 4]) -> Self
+    {
+        VirtioCap { cfg_type: (dwords[0] >> 24) as u8, bar: dwords[1] as u8, offset: dwords[2], length: dwords[3] }
+    }
+}
+
+// the features both sides want; a device without VERSION_1 only speaks the legacy interface
+pub fn negotiate(offered: u64, wanted: u64) -> Result<u64, VirtioError>
+{
+    if offered & F_VERSION_1 == 0
+    {
+        return Err(VirtioError::FeaturesRejected);
+    }
+
+    Ok(offered & wanted | F_VERSION_1)
+}
+
+// a virtio device's register blocks, mapped
+pub struct VirtioPci
+{
+    pub device: &'static PciDevice,
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    device_config: VirtAddr,
+    msix: Option<MsixTable>,
+}
+
+impl VirtioPci
+{
+    // map the register blocks and reset the device; it is then acknowledged, with a driver looking
+    // at it (negotiate features next)
+    pub fn new(device: &'static PciDevice) -> Result<Self, VirtioError>
+    {
+        let mut bars = [None; 6];
+        let mut map = |cap: VirtioCap| -> Result<VirtAddr, VirtioError>
+        {
+            let bar = match bars.get(cap.bar as usize).copied().flatten()
+            {
+                Some(bar) => bar,
+                None =>
+                {
+                    let bar = device.map_bar(cap.bar as usize)?;
+                    *bars.get_mut(cap.bar as usize).ok_or(PciError::NoSuchBar(cap.bar as usize))? = Some(bar);
+                    bar
+                }
+            };
+            Ok(bar + cap.offset as u64)
+        };
+
+        let (mut common, mut notify, mut device_config) = (None, None, None);
+        let mut notify_multiplier = 0;
+        for capability in device.capabilities.iter().filter(|capability| capability.id == CAP_VENDOR_SPECIFIC)
+        {
+            let offset = capability.offset as u16;
+            let cap = VirtioCap::decode(core::array::from_fn(|index| device.read_config_u32(offset + 4 * index as u16)));
+
+            // the first capability of a type is the one to use
+            match cap.cfg_type
+            {
+                CFG_COMMON if common.is_none() => common = Some(map(cap)?),
+                CFG_NOTIFY if notify.is_none() =>
+                {
+                    notify = Some(map(cap)?);
+                    notify_multiplier = device.read_config_u32(offset + 16);
+                }
+                CFG_DEVICE if device_config.is_none() => device_config = Some(map(cap)?),
+                _ => (),
+            }
+        }
+
+        let (Some(common), Some(notify)) = (common, notify) else { return Err(VirtioError::LegacyOnly) };
+        let virtio = VirtioPci { device, common, notify, notify_multiplier, device_config: device_config.unwrap_or(common), msix: None };
+
+        device.enable_bus_master();
+        virtio.reset();
+        virtio.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        Ok(virtio)
+    }
+
+    fn read_common<T: Copy>(&self, offset: usize) -> T
+    {
+        unsafe { ptr::read_volatile((self.common + offset as u64).as_ptr()) }
+    }
+
+    fn write_common<T: Copy>(&self, offset: usize, value: T)
+    {
+        unsafe { ptr::write_volatile((self.common + offset as u64).as_mut_ptr(), value) }
+    }
+
+    pub fn reset(&self)
+    {
+        self.write_common::<u8>(COMMON_DEVICE_STATUS, 0);
+
+        // done when the status reads 0 again
+        while self.read_common::<u8>(COMMON_DEVICE_STATUS) != 0
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn add_status(&self, bits: u8)
+    {
+        let status = self.read_common::<u8>(COMMON_DEVICE_STATUS);
+        self.write_common(COMMON_DEVICE_STATUS, status | bits);
+    }
+
+    // tell the device the driver gave up on it
+    pub fn fail(&self)
+    {
+        self.add_status(STATUS_FAILED);
+    }
+
+    pub fn device_features(&self) -> u64
+    {
+        (0..2).fold(0, |features, half|
+        {
+            self.write_common::<u32>(COMMON_DEVICE_FEATURE_SELECT, half);
+            features | (self.read_common::<u32>(COMMON_DEVICE_FEATURE) as u64) << (32 * half)
+        })
+    }
+
+    // agree on the wanted features the device offers; returns them
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError>
+    {
+        let features = negotiate(self.device_features(), wanted)?;
+        for half in 0..2
+        {
+            self.write_common::<u32>(COMMON_DRIVER_FEATURE_SELECT, half);
+            self.write_common(COMMON_DRIVER_FEATURE, (features >> (32 * half)) as u32);
+        }
+
+        // the device may still say no
+        self.add_status(STATUS_FEATURES_OK);
+        if self.read_common::<u8>(COMMON_DEVICE_STATUS) & STATUS_FEATURES_OK == 0
+        {
+            return Err(VirtioError::FeaturesRejected);
+        }
+
+        Ok(features)
+    }
+
+    // enable MSI-X, with configuration changes not signalled; None if the device has no MSI-X
+    pub fn enable_msix(&mut self) -> Option<&MsixTable>
+    {
+        if self.msix.is_none()
+        {
+            self.msix = Some(self.device.enable_msix().ok()?);
+            self.write_common(COMMON_MSIX_CONFIG, NO_VECTOR);
+        }
+        self.msix.as_ref()
+    }
+
+    pub fn msix(&self) -> Option<&MsixTable>
+    {
+        self.msix.as_ref()
+    }
+
+    pub fn queue_count(&self) -> u16
+    {
+        self.read_common(COMMON_NUM_QUEUES)
+    }
+
+    // set up queue index with at most max_size entries, signalling completions on MSI-X entry (if any)
+    pub fn setup_queue(&self, index: u16, max_size: u16, msix_entry: Option<u16>) -> Result<Virtqueue, VirtioError>
+    {
+        if index >= self.queue_count()
+        {
+            return Err(VirtioError::NoQueue(index));
+        }
+
+        self.write_common(COMMON_QUEUE_SELECT, index);
+        let device_size = self.read_common::<u16>(COMMON_QUEUE_SIZE);
+        if device_size == 0
+        {
+            return Err(VirtioError::NoQueue(index));
+        }
+
+        // split queue sizes are powers of two
+        let size = 1 << (device_size.min(max_size).max(1)).ilog2();
+        let notify_offset = self.read_common::<u16>(COMMON_QUEUE_NOTIFY_OFF) as u64 * self.notify_multiplier as u64;
+        let queue = Virtqueue::new(index, size, self.notify + notify_offset)?;
+
+        self.write_common(COMMON_QUEUE_SIZE, size);
+        let (desc, driver, device) = queue.addresses();
+        self.write_common(COMMON_QUEUE_DESC, desc.as_u64());
+        self.write_common(COMMON_QUEUE_DRIVER, driver.as_u64());
+        self.write_common(COMMON_QUEUE_DEVICE, device.as_u64());
+
+        if let Some(entry) = msix_entry
+        {
+            self.write_common(COMMON_QUEUE_MSIX_VECTOR, entry);
+            if self.read_common::<u16>(COMMON_QUEUE_MSIX_VECTOR) != entry
+            {
+                return Err(VirtioError::NoVector);
+            }
+        }
+
+        self.write_common::<u16>(COMMON_QUEUE_ENABLE, 1);
+        Ok(queue)
+    }
+
+    // the driver is set up, the device may start
+    pub fn driver_ok(&self)
+    {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    // read a field of the device's own configuration, consistently (the generation changes while the
+    // device updates it)
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T
+    {
+        loop
+        {
+            let generation = self.read_common::<u8>(COMMON_CONFIG_GENERATION);
+            let value = unsafe { ptr::read_volatile((self.device_config + offset as u64).as_ptr()) };
+            if self.read_common::<u8>(COMMON_CONFIG_GENERATION) == generation
+            {
+                return value;
+            }
+        }
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn decodes_capabilities()
+{
+    // QEMU: common configuration at offset 0 of BAR 4, 0x1000 bytes
+    let cap = VirtioCap::decode([0x0110_7009, 0x0000_0004, 0x0000_0000, 0x0000_1000]);
+    assert_eq!(cap, VirtioCap { cfg_type: CFG_COMMON, bar: 4, offset: 0, length: 0x1000 });
+}
+
+#[test_case]
+fn negotiates_features()
+{
+    const WANTED: u64 = 1 << 5 | 1 << 9;
+
+    assert_eq!(negotiate(F_VERSION_1 | 1 << 9 | 1 << 7, WANTED), Ok(F_VERSION_1 | 1 << 9));
+    assert_eq!(negotiate(1 << 9, WANTED), Err(VirtioError::FeaturesRejected));
+}
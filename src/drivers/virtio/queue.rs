@@ -0,0 +1,275 @@
+// split virtqueues: a descriptor table, the driver's (available) ring and the device's (used) ring,
+// a frame each (a queue has at most MAX_SIZE entries, so every part fits)
+// the driver puts a descriptor chain on the available ring and rings the doorbell; the device puts it
+// on the used ring with the number of bytes it wrote, and raises the queue's interrupt
+
+use super::super::dma::{DmaFrames, OutOfMemory};
+use crate::interrupts::TIMER_HZ;
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, ThreadId};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const MAX_SIZE: u16 = 256;
+
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;   // the device writes the buffer
+
+// frames of the parts
+const DESC_TABLE: usize = 0;
+const AVAIL_RING: usize = 1;
+const USED_RING: usize = 2;
+
+// both rings start with flags and an index (u16 each); used ring elements are an id and a length (u32)
+const RING_INDEX: usize = 2;
+const RING_ENTRIES: usize = 4;
+const USED_ELEMENT_SIZE: usize = 8;
+
+const TIMEOUT_POLLS: usize = 10_000_000;
+const IRQ_TIMEOUT_TICKS: u64 = 5 * TIMER_HZ;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor
+{
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// memory the device reads (or, writable, writes) for a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer
+{
+    pub address: PhysAddr,
+    pub len: u32,
+    pub writable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError
+{
+    Full,           // not enough free descriptors right now
+    Timeout,        // the chain is still the device's (its buffers too)
+}
+
+// a submitted chain (its head descriptor)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token(u16);
+
+// what became of the chain starting at a descriptor
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot
+{
+    chain_len: u16,
+    waiter: Option<ThreadId>,
+    used: Option<u32>,          // bytes written by the device, once it is done
+}
+
+struct QueueState
+{
+    free_head: u16,             // free descriptors, linked through next
+    free_count: u16,
+    avail_index: u16,
+    last_used: u16,             // used ring entries seen so far
+    slots: Vec<Slot>,
+}
+
+pub struct Virtqueue
+{
+    index: u16,
+    size: u16,
+    rings: DmaFrames,
+    notify: VirtAddr,           // doorbell: the queue index is written here
+    interrupts: AtomicBool,     // completions are collected by the interrupt handler
+    state: IrqSafeMutex<QueueState>,
+}
+
+impl Virtqueue
+{
+    // an empty queue of size entries (a power of two, at most MAX_SIZE)
+    pub fn new(index: u16, size: u16, notify: VirtAddr) -> Result<Self, OutOfMemory>
+    {
+        assert!(size.is_power_of_two() && size <= MAX_SIZE, "[ERR] Bad virtqueue size {}", size);
+
+        let queue = Virtqueue
+        {
+            index,
+            size,
+            rings: DmaFrames::new(3)?,
+            notify,
+            interrupts: AtomicBool::new(false),
+            state: IrqSafeMutex::new(QueueState { free_head: 0, free_count: size, avail_index: 0, last_used: 0, slots: vec![Slot::default(); size as usize] }),
+        };
+
+        for descriptor in 0..size
+        {
+            unsafe { (*queue.descriptor(descriptor)).next = descriptor + 1; }
+        }
+
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16
+    {
+        self.index
+    }
+
+    pub fn size(&self) -> u16
+    {
+        self.size
+    }
+
+    // device addresses of the descriptor table, the available and the used ring
+    pub fn addresses(&self) -> (PhysAddr, PhysAddr, PhysAddr)
+    {
+        (self.rings.phys(DESC_TABLE), self.rings.phys(AVAIL_RING), self.rings.phys(USED_RING))
+    }
+
+    // whether an interrupt handler calls collect (otherwise wait polls)
+    pub fn set_interrupts(&self, enabled: bool)
+    {
+        self.interrupts.store(enabled, Ordering::Release);
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor
+    {
+        self.rings.ptr::<Descriptor>(DESC_TABLE).wrapping_add(index as usize)
+    }
+
+    fn ring_u16(&self, ring: usize, offset: usize) -> *mut u16
+    {
+        self.rings.ptr::<u8>(ring).wrapping_add(offset).cast()
+    }
+
+    // put a chain on the available ring and notify the device
+    pub fn submit(&self, chain: &[Buffer]) -> Result<Token, QueueError>
+    {
+        assert!(!chain.is_empty() && chain.len() <= self.size as usize, "[ERR] Bad virtqueue chain length {}", chain.len());
+
+        let mut state = self.state.lock();
+        if (state.free_count as usize) < chain.len()
+        {
+            return Err(QueueError::Full);
+        }
+
+        let head = state.free_head;
+        let mut current = head;
+        for (position, buffer) in chain.iter().enumerate()
+        {
+            let descriptor = self.descriptor(current);
+            let next = unsafe { ptr::read_volatile(&(*descriptor).next) };
+            let last = position + 1 == chain.len();
+
+            let flags = if buffer.writable { DESC_F_WRITE } else { 0 } | if last { 0 } else { DESC_F_NEXT };
+            unsafe { ptr::write_volatile(descriptor, Descriptor { address: buffer.address.as_u64(), len: buffer.len, flags, next }); }
+
+            if last
+            {
+                state.free_head = next;
+            }
+            current = next;
+        }
+        state.free_count -= chain.len() as u16;
+        state.slots[head as usize] = Slot { chain_len: chain.len() as u16, waiter: None, used: None };
+
+        // the entry before the index that makes it visible, the index before the doorbell
+        let slot = state.avail_index % self.size;
+        unsafe { ptr::write_volatile(self.ring_u16(AVAIL_RING, RING_ENTRIES + 2 * slot as usize), head); }
+        fence(Ordering::SeqCst);
+        state.avail_index = state.avail_index.wrapping_add(1);
+        unsafe { ptr::write_volatile(self.ring_u16(AVAIL_RING, RING_INDEX), state.avail_index); }
+        fence(Ordering::SeqCst);
+        drop(state);
+
+        unsafe { ptr::write_volatile(self.notify.as_mut_ptr::<u16>(), self.index); }
+        Ok(Token(head))
+    }
+
+    // take what the device put on the used ring: mark the chains used, wake their waiters (a chain is
+    // freed by its waiter, so its slot stays until the result is taken)
+    // called by the interrupt handler; must not block or allocate!
+    pub fn collect(&self)
+    {
+        let mut state = self.state.lock();
+        loop
+        {
+            let used_index = unsafe { ptr::read_volatile(self.ring_u16(USED_RING, RING_INDEX)) };
+            if used_index == state.last_used
+            {
+                break;
+            }
+            fence(Ordering::SeqCst);
+
+            let element = self.rings.ptr::<u8>(USED_RING).wrapping_add(RING_ENTRIES + USED_ELEMENT_SIZE * (state.last_used % self.size) as usize);
+            let (head, len) = unsafe { (ptr::read_volatile(element.cast::<u32>()) as u16, ptr::read_volatile(element.cast::<u32>().wrapping_add(1))) };
+            state.last_used = state.last_used.wrapping_add(1);
+
+            // a broken device could name any descriptor
+            let Some(slot) = state.slots.get_mut(head as usize) else { continue };
+            slot.used = Some(len);
+            if let Some(waiter) = slot.waiter
+            {
+                thread::unpark(waiter);
+            }
+        }
+    }
+
+    fn is_used(&self, token: Token) -> bool
+    {
+        self.state.lock().slots[token.0 as usize].used.is_some()
+    }
+
+    // wait for the device to be done with a chain; returns the bytes it wrote
+    // sleeps until the interrupt (with interrupts set and enabled), polls otherwise
+    pub fn wait(&self, token: Token) -> Result<u32, QueueError>
+    {
+        self.state.lock().slots[token.0 as usize].waiter = thread::current_id();
+
+        if self.interrupts.load(Ordering::Acquire) && x86_64::instructions::interrupts::are_enabled()
+        {
+            // the interrupt may have come before there was a waiter to wake
+            self.collect();
+            thread::sleep_ticks_interruptible(IRQ_TIMEOUT_TICKS, || self.is_used(token));
+        }
+        else
+        {
+            for _ in 0..TIMEOUT_POLLS
+            {
+                self.collect();
+                if self.is_used(token)
+                {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        let mut state = self.state.lock();
+        let slot = &mut state.slots[token.0 as usize];
+        slot.waiter = None;
+        let len = slot.used.take().ok_or(QueueError::Timeout)?;
+
+        self.free_chain(&mut state, token.0);
+        Ok(len)
+    }
+
+    // put the chain starting at head back on the free list
+    fn free_chain(&self, state: &mut QueueState, head: u16)
+    {
+        let chain_len = state.slots[head as usize].chain_len;
+        let mut tail = head;
+        for _ in 1..chain_len
+        {
+            tail = unsafe { ptr::read_volatile(&(*self.descriptor(tail)).next) };
+        }
+
+        unsafe { (*self.descriptor(tail)).next = state.free_head; }
+        state.free_head = head;
+        state.free_count += chain_len;
+    }
+}
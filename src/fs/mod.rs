@@ -0,0 +1,44 @@
+use crate::loader;
+use crate::sync::IrqSafeMutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// read-only files for execve and spawn (there is no disk file system yet)
+// programs built into the kernel image; more can be added at runtime with register()
+static BUILTIN: &[(&str, &[u8])] =
+&[
+    ("/bin/hello", loader::HELLO_ELF),
+    ("/bin/proctest", loader::PROCTEST_ELF),
+    ("/bin/sigtest", loader::SIGTEST_ELF),
+    ("/bin/misbehave", loader::MISBEHAVE_ELF),
+];
+
+static REGISTERED: IrqSafeMutex<BTreeMap<String, &'static [u8]>> = IrqSafeMutex::new(BTreeMap::new());
+
+// add (or replace) a file
+pub fn register(path: &str, data: &'static [u8])
+{
+    REGISTERED.lock().insert(String::from(path), data);
+}
+
+// contents of the file at path
+pub fn lookup(path: &str) -> Option<&'static [u8]>
+{
+    if let Some(&data) = REGISTERED.lock().get(path)
+    {
+        return Some(data);
+    }
+
+    BUILTIN.iter().find(|(name, _)| *name == path).map(|&(_, data)| data)
+}
+
+// every path, sorted
+pub fn paths() -> Vec<String>
+{
+    let mut paths: Vec<String> = BUILTIN.iter().map(|(name, _)| String::from(*name)).collect();
+    paths.extend(REGISTERED.lock().keys().cloned());
+    paths.sort();
+    paths.dedup();
+    paths
+}
@@ -3,20 +3,35 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 use x86_64::structures::gdt::SegmentSelector;
 use lazy_static::lazy_static;
+use crate::percpu;
+use core::cell::UnsafeCell;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const PAGE_SIZE: usize = 4096;
 
-struct Selectors
+// kernel stack used on ring 3 -> ring 0 transitions by threads without their own (see default_kernel_stack)
+const PRIVILEGE_STACK_SIZE: usize = PAGE_SIZE * 5;
+
+pub struct Selectors
 {
-    code_selector: SegmentSelector,
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
     tss_selector: SegmentSelector
 }
 
+// the TSS is written to at runtime (RSP0 changes with every thread switch) -> interior mutability
+// every CPU has its own, found through its per-CPU area
+pub(crate) struct Tss(UnsafeCell<TaskStateSegment>);
+
+// only mutated through set_kernel_stack, by the CPU it belongs to, with interrupts disabled
+unsafe impl Sync for Tss {}
+
 // initialise a global TSS
 lazy_static!
 {
-    static ref TSS: TaskStateSegment = 
+    static ref TSS: Tss = 
     {   
         // new instance of tss
         let mut tss : TaskStateSegment = TaskStateSegment::new();
@@ -35,47 +50,131 @@ lazy_static!
             stack_end 
         };
 
-        tss
+        // set up RSP0, the stack the CPU switches to on interrupts/exceptions from ring 3
+        tss.privilege_stack_table[0] = default_kernel_stack();
+
+        Tss(UnsafeCell::new(tss))
     };
 }
 
 // initialise a global GDT
 lazy_static!
 {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = 
-    {
-        let mut gdt: GlobalDescriptorTable = GlobalDescriptorTable::new();
-        
-        // initialise selectors
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build_gdt(unsafe { &*TSS.0.get() });
+}
 
-        // add kernel code segment
-        let kcode_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+// GDT with the kernel and user segments and tss
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors)
+{
+    let mut gdt: GlobalDescriptorTable = GlobalDescriptorTable::new();
 
-        // add TSS
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+    // initialise selectors
+    // the order matters for SYSCALL/SYSRET: kernel data must follow kernel code,
+    // user code must follow user data
 
-        (gdt, Selectors{code_selector:kcode_selector, tss_selector})
-    };
+    // add kernel code and data segments
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+
+    // add user (ring 3) data and code segments
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+
+    // add TSS
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    (gdt, Selectors{kernel_code, kernel_data, user_data, user_code, tss_selector})
 }
 
 
 // init function
 pub fn init()
-{   
-    use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+{
+    // the BSP's per-CPU area (static, so this works before the heap), which keeps track of the GDT and TSS
+    percpu::init_bsp();
 
     // init gdt
     GDT.0.load();
 
+    unsafe { load_segments(&GDT.1); }
+
+    percpu::current().set_tables(&GDT.0, &TSS);
+}
+
+// GDT and TSS for an application processor (see smp): a TSS is marked busy while loaded, so every CPU
+// needs its own, with its own double fault stack; the selectors are the same as the bootstrap CPU's
+// needs the AP's per-CPU area (percpu::init_ap)
+pub fn init_ap()
+{
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    let stack_top = |size: usize| -> VirtAddr
+    {
+        let stack: &'static mut [u8] = vec![0; size].leak();
+        VirtAddr::from_ptr(stack.as_ptr()) + size as u64
+    };
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_top(PAGE_SIZE * 5);
+    tss.privilege_stack_table[0] = stack_top(PRIVILEGE_STACK_SIZE);
+
+    let tss: &'static Tss = Box::leak(Box::new(Tss(UnsafeCell::new(tss))));
+    let gdt: &'static (GlobalDescriptorTable, Selectors) = Box::leak(Box::new(build_gdt(unsafe { &*tss.0.get() })));
+
+    gdt.0.load();
+    unsafe { load_segments(&gdt.1); }
+
+    percpu::current().set_tables(&gdt.0, tss);
+}
+
+// reload the segment registers and the task register after loading a GDT
+unsafe fn load_segments(selectors: &Selectors)
+{
+    use x86_64::instructions::tables::load_tss;
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
+
     unsafe
     {
         // reload CS in GDT tuple
-        CS::set_reg(GDT.1.code_selector);
+        CS::set_reg(selectors.kernel_code);
+
+        // reload data segments (SS must match the kernel data selector for iretq/sysret)
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
 
         // load TSS in GDT tuple
-        load_tss(GDT.1.tss_selector);
+        load_tss(selectors.tss_selector);
     };
+}
 
+// segment selectors (with their RPL set, ready to be loaded)
+pub fn selectors() -> &'static Selectors
+{
+    &GDT.1
 }
 
+// RSP0 for threads without a stack of their own (the bootstrap thread)
+pub fn default_kernel_stack() -> VirtAddr
+{
+    static mut STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+    let stack_start = VirtAddr::from_ptr(&raw const STACK);
+
+    stack_start + PRIVILEGE_STACK_SIZE
+}
+
+// current RSP0 of this CPU: top of the running thread's kernel stack
+pub fn kernel_stack() -> VirtAddr
+{
+    unsafe { (*percpu::current().tss().0.get()).privilege_stack_table[0] }
+}
+
+// set RSP0 of this CPU: the stack it switches to when a ring 3 thread is interrupted
+pub fn set_kernel_stack(stack_top: VirtAddr)
+{
+    x86_64::instructions::interrupts::without_interrupts(||
+    {
+        unsafe { (*percpu::current().tss().0.get()).privilege_stack_table[0] = stack_top; }
+    });
+}
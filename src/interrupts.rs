@@ -1,23 +1,52 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{gdt, hlt_loop, print, println};
+use crate::{apic, gdt, hlt_loop, memory, percpu, println, syscall, thread, usermode};
+use crate::drivers::model::{self, Device, DeviceMatch, Driver, DriverError};
+use crate::process::signal;
+use crate::thread::context::SavedContext;
+use x86_64::PrivilegeLevel;
+use x86_64::VirtAddr;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-pub const PS2_KBD_DATA_PORT: u16 = 0x60;
+
+// PIT (8253/8254) channel 0 drives IRQ 0
+pub const TIMER_HZ: u64 = 100;
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+const PIT_CHANNEL0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+
+// vectors for device interrupts the local APIC delivers (MSI, MSI-X), handed out by allocate_vector
+pub const DEVICE_VECTOR_BASE: u8 = 0x50;
+pub const DEVICE_VECTORS: usize = 16;
+
+// called in interrupt context with the argument it was allocated with; must not block or allocate!
+pub type DeviceHandler = fn(usize);
+
+static DEVICE_HANDLERS: [IrqSafeMutex<Option<(DeviceHandler, usize)>>; DEVICE_VECTORS] = [const { IrqSafeMutex::new(None) }; DEVICE_VECTORS];
+
+// number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
 
 // def PICs
-pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe {ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)});
+// IrqSafeMutex: handlers send their EOI through it
+pub static PICS: IrqSafeMutex<ChainedPics> = IrqSafeMutex::new(unsafe {ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)});
 
 // def interrupt index enum
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum InterruptIndex 
 {
     Timer = PIC_1_OFFSET,   // 32
     Keyboard,               // 33
+    Com2 = PIC_1_OFFSET + 3,    // 35 (IRQ 3, shared with COM4)
+    Com1,                       // 36 (IRQ 4, shared with COM3)
+    Mouse = PIC_2_OFFSET + 4,   // 44 (IRQ 12)
+    PrimaryAta = PIC_2_OFFSET + 6,  // 46 (IRQ 14)
+    SecondaryAta,                   // 47 (IRQ 15)
 }
 
 impl InterruptIndex 
@@ -46,21 +75,68 @@ lazy_static!
         // set breakpoint handler
         idt.breakpoint.set_handler_fn(breakpoint_handler);
 
+        // set handlers for the other faults user code can cause (they become signals)
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+
         unsafe
         {
             // set double fault handler
             idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
 
-        // set timer interrupt handler
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        unsafe
+        {
+            // set timer interrupt handler (saves the full context, so it can switch threads)
+            idt[InterruptIndex::Timer.as_usize()].set_handler_addr(VirtAddr::from_ptr(timer_interrupt_entry as *const ()));
+
+            // set yield handler (thread::yield_now)
+            idt[usize::from(thread::YIELD_VECTOR)].set_handler_addr(VirtAddr::from_ptr(yield_interrupt_entry as *const ()));
+        }
 
         // set keyboard interrupt handler
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
 
+        // set mouse interrupt handler
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+
+        // set serial receive interrupt handlers
+        idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
+        idt[InterruptIndex::Com2.as_usize()].set_handler_fn(com2_interrupt_handler);
+
+        // set IDE channel interrupt handlers
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(secondary_ata_interrupt_handler);
+
+        // set device interrupt handlers (see allocate_vector)
+        for (index, handler) in DEVICE_INTERRUPT_HANDLERS.into_iter().enumerate()
+        {
+            idt[usize::from(DEVICE_VECTOR_BASE) + index].set_handler_fn(handler);
+        }
+
+        // set local APIC spurious interrupt handler
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+
+        // set TLB shootdown handler (IPIs from other CPUs, see memory::tlb)
+        idt[usize::from(memory::tlb::SHOOTDOWN_VECTOR)].set_handler_fn(memory::tlb::shootdown_interrupt_handler);
+
         // set page fault
         idt.page_fault.set_handler_fn(page_fault_handler);
 
+        // set legacy system call entry (int 0x80), callable from ring 3
+        unsafe
+        {
+            idt[usize::from(syscall::SYSCALL_INT_VECTOR)].set_handler_addr(VirtAddr::from_ptr(syscall::entry::int80_entry as *const ()))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+
         idt
     };
 }
@@ -81,6 +157,122 @@ pub fn init_pics()
     }
 }
 
+// a free device vector that calls handler(argument) when it fires; None if all are taken
+// the vector is delivered by the local APIC (point an MSI or MSI-X entry at it)
+pub fn allocate_vector(handler: DeviceHandler, argument: usize) -> Option<u8>
+{
+    DEVICE_HANDLERS.iter().position(|slot|
+    {
+        let mut slot = slot.lock();
+        let free = slot.is_none();
+        if free
+        {
+            *slot = Some((handler, argument));
+        }
+        free
+    }).map(|index| DEVICE_VECTOR_BASE + index as u8)
+}
+
+// give a vector from allocate_vector back (its device must not raise it anymore)
+pub fn free_vector(vector: u8)
+{
+    if let Some(slot) = DEVICE_HANDLERS.get(usize::from(vector.wrapping_sub(DEVICE_VECTOR_BASE)))
+    {
+        *slot.lock() = None;
+    }
+}
+
+// let the PICs deliver this interrupt (the BIOS masks the ones it does not use)
+pub fn unmask_irq(index: InterruptIndex)
+{
+    let irq = index.as_u8() - PIC_1_OFFSET;
+
+    let mut pics = PICS.lock();
+    let [mut mask1, mut mask2] = unsafe { pics.read_masks() };
+    if irq < 8
+    {
+        mask1 &= !(1 << irq);
+    }
+    else
+    {
+        // the second PIC is cascaded through IRQ 2
+        mask1 &= !(1 << 2);
+        mask2 &= !(1 << (irq - 8));
+    }
+    unsafe { pics.write_masks(mask1, mask2); }
+}
+
+// stop the PICs from delivering this interrupt (the cascade stays open for the others)
+pub fn mask_irq(index: InterruptIndex)
+{
+    let irq = index.as_u8() - PIC_1_OFFSET;
+
+    let mut pics = PICS.lock();
+    let [mut mask1, mut mask2] = unsafe { pics.read_masks() };
+    if irq < 8
+    {
+        mask1 |= 1 << irq;
+    }
+    else
+    {
+        mask2 |= 1 << (irq - 8);
+    }
+    unsafe { pics.write_masks(mask1, mask2); }
+}
+
+// program the PIT to fire at TIMER_HZ (instead of the BIOS default of ~18.2 Hz)
+pub fn init_pit()
+{
+    use x86_64::instructions::port::Port;
+
+    let divisor = (PIT_FREQUENCY_HZ / TIMER_HZ) as u16;
+
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel0: Port<u8> = Port::new(PIT_CHANNEL0_PORT);
+
+    unsafe
+    {
+        // channel 0, lobyte/hibyte access, mode 3 (square wave), binary
+        command.write(0x36);
+        channel0.write((divisor & 0xff) as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+// the PIT (PNP0100) as a driver: programmed by init already, again on resume
+pub struct PitDriver;
+
+impl Driver for PitDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "pit"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        &[DeviceMatch::Isa(model::PNP_PIT)]
+    }
+
+    fn probe(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        init_pit();
+        Ok(())
+    }
+
+    fn resume(&self, _device: &Device) -> Result<(), DriverError>
+    {
+        init_pit();
+        Ok(())
+    }
+}
+
+// timer ticks since boot (TIMER_HZ per second)
+pub fn ticks() -> u64
+{
+    TICKS.load(Ordering::Relaxed)
+}
+
 // enable interrupts
 pub fn enable_interrupts()
 {
@@ -90,11 +282,98 @@ pub fn enable_interrupts()
 
 
 // ---------- HANDLERS ----------
+// handlers for vectors that can interrupt ring 3 run their body in percpu::with_kernel_gs
+
 // breakpoint handler
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame)
+extern "x86-interrupt" fn breakpoint_handler(mut stack_frame: InterruptStackFrame)
+{
+    percpu::with_kernel_gs(&mut stack_frame, |stack_frame|
+    {
+        if signal_user_fault(stack_frame, signal::SIGTRAP)
+        {
+            return;
+        }
+
+        // print out the stack frame
+        println!("[EXCEPTION]: Breakpoint\n{:#?}", stack_frame);
+    });
+}
+
+// generates a handler for an exception which is a signal in ring 3 and a kernel bug in ring 0
+macro_rules! user_fault_handler
+{
+    ($name:ident, $description:literal, $signal:expr) =>
+    {
+        extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame)
+        {
+            percpu::with_kernel_gs(&mut stack_frame, |stack_frame|
+            {
+                if !signal_user_fault(stack_frame, $signal)
+                {
+                    panic!("[EXCEPTION]: {}\n{:#?}", $description, stack_frame);
+                }
+            });
+        }
+    };
+    ($name:ident, $description:literal, $signal:expr, error_code) =>
+    {
+        extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame, error_code: u64)
+        {
+            percpu::with_kernel_gs(&mut stack_frame, |stack_frame|
+            {
+                if !signal_user_fault(stack_frame, $signal)
+                {
+                    panic!("[EXCEPTION]: {} (error code {:#x})\n{:#?}", $description, error_code, stack_frame);
+                }
+            });
+        }
+    };
+}
+
+user_fault_handler!(divide_error_handler, "Divide Error", signal::SIGFPE);
+user_fault_handler!(overflow_handler, "Overflow", signal::SIGSEGV);
+user_fault_handler!(bound_range_exceeded_handler, "Bound Range Exceeded", signal::SIGSEGV);
+user_fault_handler!(invalid_opcode_handler, "Invalid Opcode", signal::SIGILL);
+user_fault_handler!(x87_floating_point_handler, "x87 Floating Point", signal::SIGFPE);
+user_fault_handler!(simd_floating_point_handler, "SIMD Floating Point", signal::SIGFPE);
+user_fault_handler!(stack_segment_fault_handler, "Stack Segment Fault", signal::SIGBUS, error_code);
+user_fault_handler!(general_protection_fault_handler, "General Protection Fault", signal::SIGSEGV, error_code);
+user_fault_handler!(alignment_check_handler, "Alignment Check", signal::SIGBUS, error_code);
+
+// exception in ring 3: post sig to the faulting process and leave through usermode::divert,
+// so the signal is handled before the faulting code runs again
+// returns false for exceptions in ring 0 (and ring 3 threads outside of a process)
+fn signal_user_fault(stack_frame: &mut InterruptStackFrame, sig: u32) -> bool
 {
-    // print out the stack frame
-    println!("[EXCEPTION]: Breakpoint\n{:#?}", stack_frame);
+    if stack_frame.code_segment & 3 != 3 || !signal::force(sig)
+    {
+        return false;
+    }
+
+    // the x86-interrupt ABI passes the frame in place, changes reach iretq
+    unsafe
+    {
+        stack_frame.as_mut().update(|value|
+        {
+            let mut frame = usermode::ReturnFrame
+            {
+                rip: value.instruction_pointer.as_u64(),
+                cs: value.code_segment,
+                rflags: value.cpu_flags,
+                rsp: value.stack_pointer.as_u64(),
+                ss: value.stack_segment,
+            };
+            usermode::divert(&mut frame);
+
+            value.instruction_pointer = VirtAddr::new(frame.rip);
+            value.code_segment = frame.cs;
+            value.cpu_flags = frame.rflags;
+            value.stack_pointer = VirtAddr::new(frame.rsp);
+            value.stack_segment = frame.ss;
+        });
+    }
+
+    true
 }
 
 // double fault handler
@@ -105,63 +384,253 @@ extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame,
 }
 
 // timer interrupt handler
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame)
+// called by timer_interrupt_entry with the saved context of the interrupted thread
+extern "C" fn timer_interrupt_handler(rsp: u64) -> u64
 {
-    // print!(".");
-
-    // send EOI
+    // send EOI (before switching, the next thread may not return here for a while)
     unsafe
     {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    // account the tick and preempt the running thread
+    let rsp = thread::scheduler::timer_tick(rsp);
+
+    // the thread we return to may be in ring 3 with signals pending (or its process exiting)
+    let context = unsafe { &mut *(rsp as *mut SavedContext) };
+    if context.cs & 3 == 3 && signal::needs_attention()
+    {
+        let mut frame = usermode::ReturnFrame { rip: context.rip, cs: context.cs, rflags: context.rflags, rsp: context.rsp, ss: context.ss };
+        usermode::divert(&mut frame);
+
+        context.rip = frame.rip;
+        context.cs = frame.cs;
+        context.rflags = frame.rflags;
+        context.rsp = frame.rsp;
+        context.ss = frame.ss;
+    }
+
+    rsp
+}
+
+// yield interrupt handler
+extern "C" fn yield_interrupt_handler(rsp: u64) -> u64
+{
+    thread::scheduler::schedule(rsp)
 }
 
 // keyboard intterrupt handler
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame)
+// only reads the scancode and queues it; decoding happens in the keyboard task (task::keyboard)
+extern "x86-interrupt" fn keyboard_interrupt_handler(mut stack_frame: InterruptStackFrame)
 {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
+    percpu::with_kernel_gs(&mut stack_frame, |_|
+    {
+        // read scancode
+        let scancode = crate::drivers::ps2::read_data();
+
+        // queue it for the keyboard task
+        crate::task::keyboard::add_scancode(scancode);
+
+        // send EOI
+        unsafe 
+        {
+            PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        }
+    });
+}
+
+// mouse interrupt handler
+// decodes the packet byte and queues complete packets (drivers::mouse)
+extern "x86-interrupt" fn mouse_interrupt_handler(mut stack_frame: InterruptStackFrame)
+{
+    percpu::with_kernel_gs(&mut stack_frame, |_|
+    {
+        crate::drivers::mouse::add_byte(crate::drivers::ps2::read_data());
+
+        // send EOI (to both PICs)
+        unsafe
+        {
+            PICS.lock().notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+        }
+    });
+}
+
+// serial interrupt handlers (IRQ 4 and IRQ 3)
+// queue the received bytes of the ports on the interrupt (serial::receive_interrupt)
+extern "x86-interrupt" fn com1_interrupt_handler(mut stack_frame: InterruptStackFrame)
+{
+    percpu::with_kernel_gs(&mut stack_frame, |_| serial_interrupt(InterruptIndex::Com1));
+}
+
+extern "x86-interrupt" fn com2_interrupt_handler(mut stack_frame: InterruptStackFrame)
+{
+    percpu::with_kernel_gs(&mut stack_frame, |_| serial_interrupt(InterruptIndex::Com2));
+}
 
+fn serial_interrupt(index: InterruptIndex)
+{
+    crate::serial::receive_interrupt(index);
 
-    // kbd instance
-    lazy_static! 
+    // send EOI
+    unsafe
     {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore));
+        PICS.lock().notify_end_of_interrupt(index.as_u8());
     }
+}
+
+// IDE channel interrupt handlers (IRQ 14 and IRQ 15)
+// acknowledge the drive and wake the thread waiting for the command (drivers::ata)
+extern "x86-interrupt" fn primary_ata_interrupt_handler(mut stack_frame: InterruptStackFrame)
+{
+    percpu::with_kernel_gs(&mut stack_frame, |_| ata_interrupt(InterruptIndex::PrimaryAta, 0));
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(mut stack_frame: InterruptStackFrame)
+{
+    percpu::with_kernel_gs(&mut stack_frame, |_| ata_interrupt(InterruptIndex::SecondaryAta, 1));
+}
 
-    // read scancode
-    let scancode: u8 = unsafe { port.read() };
+fn ata_interrupt(index: InterruptIndex, channel: usize)
+{
+    crate::drivers::ata::interrupt(channel);
 
-    // interpret keyevent
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) 
+    // send EOI (to both PICs)
+    unsafe
     {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key 
+        PICS.lock().notify_end_of_interrupt(index.as_u8());
+    }
+}
+
+// device interrupt handlers (DEVICE_VECTOR_BASE + n)
+// run the handler allocated for the vector, EOI to the local APIC
+macro_rules! device_interrupt_handlers
+{
+    ($($name:ident = $index:literal),*) =>
+    {
+        $(
+            extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame)
             {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+                percpu::with_kernel_gs(&mut stack_frame, |_| device_interrupt($index));
             }
-        }
-    }
+        )*
 
+        const DEVICE_INTERRUPT_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); DEVICE_VECTORS] = [$($name),*];
+    };
+}
 
-    // send EOI
-    unsafe 
+device_interrupt_handlers!(device_interrupt_0 = 0, device_interrupt_1 = 1, device_interrupt_2 = 2, device_interrupt_3 = 3,
+    device_interrupt_4 = 4, device_interrupt_5 = 5, device_interrupt_6 = 6, device_interrupt_7 = 7,
+    device_interrupt_8 = 8, device_interrupt_9 = 9, device_interrupt_10 = 10, device_interrupt_11 = 11,
+    device_interrupt_12 = 12, device_interrupt_13 = 13, device_interrupt_14 = 14, device_interrupt_15 = 15);
+
+fn device_interrupt(index: usize)
+{
+    // copied out: the handler may allocate or free vectors itself
+    let handler = *DEVICE_HANDLERS[index].lock();
+    if let Some((handler, argument)) = handler
     {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        handler(argument);
     }
+
+    apic::end_of_interrupt();
+}
+
+// spurious interrupt handler
+// the local APIC raises these when an interrupt goes away before it is delivered; no EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame)
+{
 }
 
+// ---------- CONTEXT SWITCHING STUBS ----------
+// generates a naked interrupt entry which saves all general purpose registers (thread::context::SavedContext),
+// calls $handler(rsp) -> new_rsp, and resumes whichever context the handler returned
+// the CPU aligns rsp to 16 bytes before pushing the 5-qword frame; with 15 registers pushed rsp is aligned again for the call
+// GS is swapped when the interrupted context is in ring 3, and when the resumed one is
+macro_rules! switching_interrupt_entry
+{
+    ($name:ident, $handler:ident) =>
+    {
+        #[unsafe(naked)]
+        extern "C" fn $name()
+        {
+            core::arch::naked_asm!(
+                "test byte ptr [rsp + 8], 3",
+                "jz 2f",
+                "swapgs",
+                "2:",
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov rdi, rsp",
+                "call {handler}",
+                "mov rsp, rax",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "test byte ptr [rsp + 8], 3",
+                "jz 3f",
+                "swapgs",
+                "3:",
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+}
+
+switching_interrupt_entry!(timer_interrupt_entry, timer_interrupt_handler);
+switching_interrupt_entry!(yield_interrupt_entry, yield_interrupt_handler);
+
 // page fault handler
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
+extern "x86-interrupt" fn page_fault_handler(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
+{
+    percpu::with_kernel_gs(&mut stack_frame, |stack_frame| handle_page_fault(stack_frame, error_code));
+}
+
+fn handle_page_fault(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode)
 {
     use x86_64::registers::control::Cr2;
 
+    // writes to copy on write pages (from user code, or the kernel writing to user memory)
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && let Some(address_space) = thread::current_address_space()
+        && address_space.resolve_copy_on_write(Cr2::read())
+    {
+        return;
+    }
+
+    // anything else from ring 3 is a segmentation fault
+    if signal_user_fault(stack_frame, signal::SIGSEGV)
+    {
+        return;
+    }
+
     // cr2 contains the faulting virtual address
     println!("[EXCEPTION] Page Fault");
     println!("Faulting Address: {:?}", Cr2::read());
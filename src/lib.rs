@@ -15,6 +15,19 @@ pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod task;
+pub mod thread;
+pub mod sync;
+pub mod usermode;
+pub mod syscall;
+pub mod loader;
+pub mod process;
+pub mod fs;
+pub mod acpi;
+pub mod apic;
+pub mod smp;
+pub mod percpu;
+pub mod drivers;
 
 extern crate alloc;
 
@@ -62,12 +75,18 @@ pub fn init()
     // init PICs
     interrupts::init_pics();
 
+    // init timer
+    interrupts::init_pit();
+
     // init idt
     interrupts::init_idt();
 
     // init gdt (with tss)
     gdt::init();
 
+    // init SYSCALL/SYSRET
+    syscall::init();
+
     // enable interrupts
     interrupts::enable_interrupts();
 
@@ -131,11 +150,21 @@ pub fn test_panic_handler(info: &PanicInfo) -> !
 
 #[cfg(test)]
 #[unsafe(no_mangle)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! 
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! 
 {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     // intialise IDT
     init();
 
+    // heap and global memory (some unit tests allocate)
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
     test_main();
     
     hlt_loop();
@@ -0,0 +1,364 @@
+// ELF64 file parsing: only what is needed to load statically linked x86-64 executables
+
+use crate::memory::PAGE_SIZE;
+use core::ops::Range;
+
+// the loadable segments may map at most this much memory (together)
+pub const MAX_IMAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+// e_ident
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+// e_type, e_machine
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const EM_X86_64: u16 = 0x3e;
+
+// p_type
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+// p_flags
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError
+{
+    TooShort,               // smaller than the headers it claims to have
+    BadMagic,
+    UnsupportedClass,       // not ELF64
+    UnsupportedEncoding,    // not little endian
+    UnsupportedVersion,
+    UnsupportedType,        // not ET_EXEC (shared objects and PIE are not supported)
+    UnsupportedMachine,     // not x86-64
+    BadProgramHeaders,      // wrong entry size, or the table is outside the file
+    BadSegment,             // a PT_LOAD segment is inconsistent, outside the file, or out of order
+    TooLarge,               // the PT_LOAD segments map more than MAX_IMAGE_SIZE
+    DynamicallyLinked,      // has a PT_INTERP segment
+    NoLoadableSegments,
+}
+
+// file header, as stored in the file
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FileHeader
+{
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+// program header, as stored in the file
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader
+{
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader
+{
+    pub fn is_load(&self) -> bool
+    {
+        self.p_type == PT_LOAD
+    }
+
+    pub fn is_writable(&self) -> bool
+    {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool
+    {
+        self.flags & PF_X != 0
+    }
+
+    // the pages the segment occupies in memory (checked by Elf::parse not to overflow)
+    pub fn pages(&self) -> Range<u64>
+    {
+        let page_size = PAGE_SIZE as u64;
+        self.vaddr & !(page_size - 1)..(self.vaddr + self.memsz).next_multiple_of(page_size)
+    }
+}
+
+// a validated ELF64 executable
+pub struct Elf<'a>
+{
+    data: &'a [u8],
+    header: FileHeader,
+}
+
+impl<'a> Elf<'a>
+{
+    // check the headers and every PT_LOAD segment
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError>
+    {
+        if data.len() < size_of::<FileHeader>()
+        {
+            return Err(ElfError::TooShort);
+        }
+
+        // the data has no alignment guarantee (e.g. include_bytes!)
+        let header = unsafe { (data.as_ptr() as *const FileHeader).read_unaligned() };
+
+        if header.ident[..4] != ELF_MAGIC
+        {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != ELFCLASS64
+        {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if header.ident[5] != ELFDATA2LSB
+        {
+            return Err(ElfError::UnsupportedEncoding);
+        }
+        if header.ident[6] != EV_CURRENT || header.version != EV_CURRENT as u32
+        {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        if header.elf_type != ET_EXEC
+        {
+            return Err(ElfError::UnsupportedType);
+        }
+        if header.machine != EM_X86_64
+        {
+            return Err(ElfError::UnsupportedMachine);
+        }
+
+        if header.phentsize as usize != size_of::<ProgramHeader>()
+        {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let table_end = (header.phnum as u64).checked_mul(header.phentsize as u64)
+            .and_then(|size| size.checked_add(header.phoff));
+        if !table_end.is_some_and(|end| end <= data.len() as u64)
+        {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        let elf = Elf { data, header };
+
+        // loadable segments have to be sorted by address and must not overlap (they may share a page)
+        let mut loadable = 0;
+        let mut previous_end = 0;
+        let mut mapped_size = 0;
+        for segment in elf.program_headers()
+        {
+            match segment.p_type
+            {
+                PT_INTERP => return Err(ElfError::DynamicallyLinked),
+                PT_LOAD =>
+                {
+                    elf.check_segment(&segment)?;
+                    loadable += 1;
+
+                    if segment.memsz == 0
+                    {
+                        continue;
+                    }
+                    if segment.vaddr < previous_end
+                    {
+                        return Err(ElfError::BadSegment);
+                    }
+                    previous_end = segment.vaddr + segment.memsz;
+
+                    let pages = segment.pages();
+                    mapped_size += pages.end - pages.start;
+                    if mapped_size > MAX_IMAGE_SIZE
+                    {
+                        return Err(ElfError::TooLarge);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if loadable == 0
+        {
+            return Err(ElfError::NoLoadableSegments);
+        }
+
+        Ok(elf)
+    }
+
+    fn check_segment(&self, segment: &ProgramHeader) -> Result<(), ElfError>
+    {
+        if segment.memsz > MAX_IMAGE_SIZE
+        {
+            return Err(ElfError::TooLarge);
+        }
+
+        let file_end = segment.offset.checked_add(segment.filesz);
+        let mem_end = segment.vaddr.checked_add(segment.memsz)
+            .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE as u64));
+
+        // the file offset and the address have to be congruent modulo the alignment
+        let aligned = segment.align <= 1
+            || (segment.align.is_power_of_two() && segment.vaddr % segment.align == segment.offset % segment.align);
+
+        let valid = segment.filesz <= segment.memsz
+            && file_end.is_some_and(|end| end <= self.data.len() as u64)
+            && mem_end.is_some()
+            && aligned;
+
+        if valid { Ok(()) } else { Err(ElfError::BadSegment) }
+    }
+
+    pub fn header(&self) -> &FileHeader
+    {
+        &self.header
+    }
+
+    pub fn entry(&self) -> u64
+    {
+        self.header.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_
+    {
+        (0..self.header.phnum as usize).map(move |index|
+        {
+            let offset = self.header.phoff as usize + index * size_of::<ProgramHeader>();
+            unsafe { (self.data[offset..].as_ptr() as *const ProgramHeader).read_unaligned() }
+        })
+    }
+
+    // loadable segments (checked by parse)
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + '_
+    {
+        self.program_headers().filter(ProgramHeader::is_load)
+    }
+
+    // file contents of a loadable segment (filesz bytes)
+    pub fn segment_data(&self, segment: &ProgramHeader) -> &'a [u8]
+    {
+        &self.data[segment.offset as usize..(segment.offset + segment.filesz) as usize]
+    }
+
+    // where the program headers are in memory once loaded (for AT_PHDR), if they are loaded
+    pub fn program_headers_address(&self) -> Option<u64>
+    {
+        if let Some(phdr) = self.program_headers().find(|segment| segment.p_type == PT_PHDR)
+        {
+            return Some(phdr.vaddr);
+        }
+
+        let phoff = self.header.phoff;
+        self.segments()
+            .find(|segment| phoff >= segment.offset && phoff < segment.offset + segment.filesz)
+            .map(|segment| segment.vaddr + (phoff - segment.offset))
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[cfg(test)]
+fn minimal_header() -> [u8; 64 + 56]
+{
+    let mut image = [0u8; 64 + 56];
+    image[..4].copy_from_slice(&ELF_MAGIC);
+    image[4] = ELFCLASS64;
+    image[5] = ELFDATA2LSB;
+    image[6] = EV_CURRENT;
+    image[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    image[20..24].copy_from_slice(&1u32.to_le_bytes());
+    image[32..40].copy_from_slice(&64u64.to_le_bytes());                // phoff
+    image[54..56].copy_from_slice(&56u16.to_le_bytes());                // phentsize
+    image[56..58].copy_from_slice(&1u16.to_le_bytes());                 // phnum
+
+    // one empty PT_LOAD segment
+    image[64..68].copy_from_slice(&PT_LOAD.to_le_bytes());
+    image
+}
+
+#[test_case]
+fn parses_minimal_executable()
+{
+    let image = minimal_header();
+    let elf = Elf::parse(&image).expect("minimal image rejected");
+    assert_eq!(elf.segments().count(), 1);
+}
+
+#[test_case]
+fn rejects_bad_headers()
+{
+    let mut image = minimal_header();
+    image[0] = 0;
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadMagic));
+
+    let mut image = minimal_header();
+    image[4] = 1;
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::UnsupportedClass));
+
+    let mut image = minimal_header();
+    image[16..18].copy_from_slice(&ET_DYN.to_le_bytes());
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::UnsupportedType));
+
+    let mut image = minimal_header();
+    image[56..58].copy_from_slice(&2u16.to_le_bytes());
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadProgramHeaders));
+
+    let image = minimal_header();
+    assert_eq!(Elf::parse(&image[..32]).err(), Some(ElfError::TooShort));
+}
+
+#[test_case]
+fn rejects_segment_outside_file()
+{
+    let mut image = minimal_header();
+    image[96..104].copy_from_slice(&0x100u64.to_le_bytes());            // filesz
+    image[104..112].copy_from_slice(&0x100u64.to_le_bytes());           // memsz
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadSegment));
+}
+
+#[test_case]
+fn rejects_misaligned_segment()
+{
+    let mut image = minimal_header();
+    image[80..88].copy_from_slice(&0x40_1001u64.to_le_bytes());         // vaddr
+    image[112..120].copy_from_slice(&0x1000u64.to_le_bytes());          // align
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadSegment));
+
+    image[80..88].copy_from_slice(&0x40_1000u64.to_le_bytes());
+    assert!(Elf::parse(&image).is_ok());
+}
+
+#[test_case]
+fn rejects_huge_segment()
+{
+    let mut image = minimal_header();
+    image[80..88].copy_from_slice(&0x40_0000u64.to_le_bytes());         // vaddr
+    image[104..112].copy_from_slice(&(MAX_IMAGE_SIZE + 1).to_le_bytes());     // memsz
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::TooLarge));
+
+    image[104..112].copy_from_slice(&(1u64 << 44).to_le_bytes());
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::TooLarge));
+}
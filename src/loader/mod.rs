@@ -0,0 +1,272 @@
+use crate::process::signal;
+use crate::memory::{AddressSpace, UnmappedAddress, PAGE_SIZE, USER_SPACE_END, USER_SPACE_START};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::VirtAddr;
+
+pub mod elf;
+
+pub use elf::{Elf, ElfError};
+
+// test program (user/hello.S): prints its arguments and a greeting, exits with argc
+pub static HELLO_ELF: &[u8] = include_bytes!("../../user/hello.elf");
+
+// test program (user/proctest.S): checks fork, execve and spawn, exits with 0 if they work
+pub static PROCTEST_ELF: &[u8] = include_bytes!("../../user/proctest.elf");
+
+// test program (user/sigtest.S): checks signal handlers, masks and sigreturn, exits with 0 if they work
+pub static SIGTEST_ELF: &[u8] = include_bytes!("../../user/sigtest.elf");
+
+// test program (user/misbehave.S): spins or faults, depending on its argument count
+pub static MISBEHAVE_ELF: &[u8] = include_bytes!("../../user/misbehave.elf");
+
+// initial user stack, just below the end of user space (the page above it holds the signal trampoline)
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE as u64;
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+
+// argument and environment strings may use at most this much of the stack
+const MAX_ARGUMENTS_SIZE: u64 = USER_STACK_SIZE / 4;
+
+// auxiliary vector keys
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError
+{
+    Elf(ElfError),
+    OutOfMemory,
+    SegmentOutOfRange,      // a segment (or the entry point) is not in user space
+    ArgumentsTooLarge,
+}
+
+impl From<ElfError> for LoadError
+{
+    fn from(error: ElfError) -> Self
+    {
+        LoadError::Elf(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for LoadError
+{
+    fn from(error: MapToError<Size4KiB>) -> Self
+    {
+        match error
+        {
+            MapToError::FrameAllocationFailed => LoadError::OutOfMemory,
+            _ => LoadError::SegmentOutOfRange,
+        }
+    }
+}
+
+impl From<UnmappedAddress> for LoadError
+{
+    fn from(_: UnmappedAddress) -> Self
+    {
+        LoadError::SegmentOutOfRange
+    }
+}
+
+// a program ready to enter ring 3 (see usermode::enter_user)
+#[derive(Debug)]
+pub struct LoadedImage
+{
+    pub address_space: Arc<AddressSpace>,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,    // points to argc
+    pub program_break: VirtAddr,    // first page after the highest segment
+}
+
+// load an executable into a new address space, with an initial stack holding argv and envp
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<LoadedImage, LoadError>
+{
+    let address_space = AddressSpace::new()?;
+    let (entry, stack_pointer, program_break) = load_into(&address_space, image, argv, envp)?;
+
+    Ok(LoadedImage
+    {
+        address_space: Arc::new(address_space),
+        entry,
+        stack_pointer,
+        program_break,
+    })
+}
+
+// load an executable into an (empty) address space
+// returns the entry point, the initial stack pointer and the program break
+pub fn load_into(address_space: &AddressSpace, image: &[u8], argv: &[&str], envp: &[&str])
+    -> Result<(VirtAddr, VirtAddr, VirtAddr), LoadError>
+{
+    let elf = Elf::parse(image)?;
+
+    if !in_user_space(elf.entry(), 1)
+    {
+        return Err(LoadError::SegmentOutOfRange);
+    }
+
+    // segments are sorted and do not overlap (checked by parse), but neighbours may share a page:
+    // the last page of each segment is held back until the next one is seen, so it is mapped once,
+    // with the union of the permissions of the segments in it
+    let page_size = PAGE_SIZE as u64;
+    let mut shared: Option<(u64, bool, bool)> = None;     // page, writable, executable
+    let mut program_break = 0;
+
+    for segment in elf.segments().filter(|segment| segment.memsz > 0)
+    {
+        if !in_user_space(segment.vaddr, segment.memsz)
+        {
+            return Err(LoadError::SegmentOutOfRange);
+        }
+
+        let pages = segment.pages();
+        let (writable, executable) = (segment.is_writable(), segment.is_executable());
+        let last = pages.end - page_size;
+        let mut first = pages.start;
+        program_break = program_break.max(pages.end);
+
+        if let Some((page, shared_writable, shared_executable)) = shared.take()
+        {
+            if page == first
+            {
+                let (writable, executable) = (shared_writable | writable, shared_executable | executable);
+                if page == last
+                {
+                    shared = Some((page, writable, executable));
+                    continue;
+                }
+
+                map_pages(address_space, page, page_size, writable, executable)?;
+                first += page_size;
+            }
+            else
+            {
+                map_pages(address_space, page, page_size, shared_writable, shared_executable)?;
+            }
+        }
+
+        if first < last
+        {
+            map_pages(address_space, first, last - first, writable, executable)?;
+        }
+        shared = Some((last, writable, executable));
+    }
+
+    if let Some((page, writable, executable)) = shared
+    {
+        map_pages(address_space, page, page_size, writable, executable)?;
+    }
+
+    // copy the file contents; the rest of each segment (.bss) is zero
+    for segment in elf.segments()
+    {
+        let start = VirtAddr::new(segment.vaddr);
+        address_space.write(start, elf.segment_data(&segment))?;
+        address_space.zero(start + segment.filesz, (segment.memsz - segment.filesz) as usize)?;
+    }
+
+    let mut auxv = Vec::new();
+    if let Some(phdr) = elf.program_headers_address()
+    {
+        auxv.push((AT_PHDR, phdr));
+    }
+    auxv.push((AT_PHENT, elf.header().phentsize as u64));
+    auxv.push((AT_PHNUM, elf.header().phnum as u64));
+    auxv.push((AT_PAGESZ, PAGE_SIZE as u64));
+    auxv.push((AT_ENTRY, elf.entry()));
+
+    let stack_pointer = setup_stack(address_space, argv, envp, &auxv)?;
+
+    // signal handlers return through it (see process::signal)
+    signal::map_trampoline(address_space)?;
+
+    Ok((VirtAddr::new(elf.entry()), stack_pointer, VirtAddr::new(program_break)))
+}
+
+// map [start, start + size) of user space for a segment
+fn map_pages(address_space: &AddressSpace, start: u64, size: u64, writable: bool, executable: bool) -> Result<(), LoadError>
+{
+    let mut flags = PageTableFlags::empty();
+    if writable
+    {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !executable
+    {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    address_space.map(VirtAddr::new(start), size, flags)?;
+    Ok(())
+}
+
+// map the user stack and build the System V initial process stack at its top:
+//
+//     argument and environment strings       <- USER_STACK_TOP
+//     (padding to 16 bytes)
+//     auxv pairs, terminated by AT_NULL
+//     envp pointers, 0
+//     argv pointers, 0
+//     argc                                   <- returned stack pointer (16 byte aligned)
+fn setup_stack(address_space: &AddressSpace, argv: &[&str], envp: &[&str], auxv: &[(u64, u64)]) -> Result<VirtAddr, LoadError>
+{
+    let strings_size: usize = argv.iter().chain(envp).map(|string| string.len() + 1).sum();
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 1);
+
+    if (strings_size + words * 8) as u64 > MAX_ARGUMENTS_SIZE
+    {
+        return Err(LoadError::ArgumentsTooLarge);
+    }
+
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    address_space.map(VirtAddr::new(stack_bottom), USER_STACK_SIZE, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+
+    let strings_start = USER_STACK_TOP - strings_size as u64;
+    let stack_pointer = (strings_start - (words * 8) as u64) & !0xf;
+
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut pointers = |list: &[&str]| -> Vec<u64>
+    {
+        list.iter().map(|string|
+        {
+            let address = strings_start + strings.len() as u64;
+            strings.extend_from_slice(string.as_bytes());
+            strings.push(0);
+            address
+        }).collect()
+    };
+    let argv_pointers = pointers(argv);
+    let envp_pointers = pointers(envp);
+
+    let mut stack: Vec<u64> = Vec::with_capacity(words);
+    stack.push(argv.len() as u64);
+    stack.extend(argv_pointers);
+    stack.push(0);
+    stack.extend(envp_pointers);
+    stack.push(0);
+    for &(key, value) in auxv
+    {
+        stack.push(key);
+        stack.push(value);
+    }
+    stack.push(AT_NULL);
+    stack.push(0);
+
+    let bytes: Vec<u8> = stack.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(stack_pointer), &bytes)?;
+    address_space.write(VirtAddr::new(strings_start), &strings)?;
+
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+fn in_user_space(start: u64, size: u64) -> bool
+{
+    start >= USER_SPACE_START && start.checked_add(size).is_some_and(|end| end <= USER_SPACE_END)
+}
@@ -11,6 +11,7 @@ use ferrix::*;
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
 use alloc::{boxed::Box, vec::Vec};
+use ferrix::task::{Task, executor::Executor, keyboard, serial_console};
 
 entry_point!(kernel_main);
 
@@ -77,6 +78,22 @@ fn kernel_main(boot_info: &'static BootInfo) -> !
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("[ERR] Heap initialisation Failed!");
 
     println!("   [OK] Heap initialised successfully");
+
+    // hand the page table and frame allocator over, everything after this maps through them
+    memory::init_global(mapper, frame_allocator);
+
+    // start the other CPUs (they report in over serial, then idle)
+    match smp::init()
+    {
+        Ok(count) => println!("   [OK] {} CPU(s) online", count),
+        Err(error) => println!("   [ERR] SMP startup failed: {:?} ({} CPU(s) online)", error, smp::cpu_count()),
+    }
+
+    // device tree: platform, ISA and PCI devices, with the built-in drivers (PIT, serial, PS/2) bound;
+    // before the keyboard task starts decoding, which needs the PS/2 controller in a known state
+    let (devices, bound) = drivers::init();
+    println!("   [OK] {} device(s) found, {} with a driver", devices, bound);
+    drivers::model::dump();
     println!(" ------------------------------------------------------------------------------ ");
     
     // alloc showcase
@@ -90,8 +107,24 @@ fn kernel_main(boot_info: &'static BootInfo) -> !
     // TESTS ENTRY POINT
     #[cfg(test)]
     test_main();
-    
-    hlt_loop();
+
+    // async showcase; the executor replaces the final hlt_loop (it halts by itself when idle)
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task()));
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(serial_console::run(serial::ComPort::Com1)));
+    executor.run();
+}
+
+async fn async_number() -> u32
+{
+    42
+}
+
+async fn example_task()
+{
+    let number = async_number().await;
+    println!("async number: {}", number);
 }
 
 // panic handlers (test and not test)
@@ -0,0 +1,463 @@
+use super::{allocate_zeroed_frame, free_frame, phys_to_virt, physical_memory_offset, kernel_page_table, with_memory};
+use super::{is_shared, release_frame, share_frame};
+use super::{PAGE_SIZE, USER_SPACE_END, USER_SPACE_START, TlbBatch};
+use crate::sync::IrqSafeMutex;
+use core::fmt;
+use core::ops::Range;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::{PhysAddr, VirtAddr};
+
+// level 4 entries owned by each address space; all others are shared with the kernel
+const USER_P4_ENTRIES: Range<usize> = (USER_SPACE_START >> 39) as usize..(USER_SPACE_END >> 39) as usize;
+
+// software-defined page table bit: the page is shared read-only, and copied on the first write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// a user address space not mapped (accessible) where it was expected to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnmappedAddress(pub VirtAddr);
+
+// a level 4 table with a private user half and the kernel half of the bootloader's table
+// (the kernel's level 4 entries are copied, so kernel mappings made later must stay inside
+// level 4 entries which existed when the address space was created, e.g. the heap)
+// all frames of the user half are freed when it is dropped
+pub struct AddressSpace
+{
+    p4: PhysFrame,
+    mapper: IrqSafeMutex<OffsetPageTable<'static>>,
+}
+
+impl AddressSpace
+{
+    pub fn new() -> Result<Self, MapToError<Size4KiB>>
+    {
+        let p4 = allocate_zeroed_frame().ok_or(MapToError::FrameAllocationFailed)?;
+
+        let table = unsafe { table_mut(p4) };
+        let kernel = unsafe { table_mut(kernel_page_table().expect("[ERR] Memory not initialised")) };
+        for (index, entry) in kernel.iter().enumerate()
+        {
+            if !USER_P4_ENTRIES.contains(&index)
+            {
+                table[index] = entry.clone();
+            }
+        }
+
+        let mapper = unsafe { OffsetPageTable::new(table, physical_memory_offset()) };
+
+        Ok(AddressSpace { p4, mapper: IrqSafeMutex::new(mapper) })
+    }
+
+    // frame to load into CR3
+    pub fn page_table(&self) -> PhysFrame
+    {
+        self.p4
+    }
+
+    pub fn is_active(&self) -> bool
+    {
+        Cr3::read().0 == self.p4
+    }
+
+    // map [start, start + size) to fresh, zeroed frames accessible from ring 3
+    // flags are added to PRESENT | USER_ACCESSIBLE; fails on pages which are mapped already
+    pub fn map(&self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>>
+    {
+        assert!(start.as_u64() >= USER_SPACE_START && start.as_u64() + size <= USER_SPACE_END, "[ERR] Not a user space range");
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + size - 1u64);
+
+        let mut mapper = self.mapper.lock();
+        for page in Page::range_inclusive(first, last)
+        {
+            if let Some((phys, _)) = translate_in(self.p4, page.start_address())
+            {
+                return Err(MapToError::PageAlreadyMapped(PhysFrame::containing_address(phys)));
+            }
+
+            let frame = allocate_zeroed_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            let result = with_memory(|_, frame_allocator| unsafe
+            {
+                mapper.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)
+            });
+
+            match result
+            {
+                // only visible in the TLB if this address space is active
+                Ok(flush) => flush.flush(),
+                Err(error) =>
+                {
+                    free_frame(frame);
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // physical address and effective flags of addr (see translate_active)
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)>
+    {
+        translate_in(self.p4, addr)
+    }
+
+    // map one page to a frame owned by someone else (e.g. code shared by every process)
+    // the frame is counted as shared, so dropping the address space never frees it
+    pub fn map_frame(&self, page: VirtAddr, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>>
+    {
+        assert!(page.as_u64() >= USER_SPACE_START && page.as_u64() < USER_SPACE_END, "[ERR] Not a user space address");
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let page = Page::<Size4KiB>::from_start_address(page).expect("[ERR] Page address not aligned");
+
+        let mut mapper = self.mapper.lock();
+        share_frame(frame);
+        let result = with_memory(|_, frame_allocator| unsafe
+        {
+            mapper.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)
+        });
+
+        match result
+        {
+            Ok(flush) =>
+            {
+                flush.flush();
+                Ok(())
+            }
+            Err(error) =>
+            {
+                release_frame(frame);
+                Err(error)
+            }
+        }
+    }
+
+    // copy of this address space for fork(): both map the same frames, writable pages become
+    // read-only + COPY_ON_WRITE in both, and are copied by whichever writes first
+    pub fn fork(&self) -> Result<AddressSpace, MapToError<Size4KiB>>
+    {
+        let child = AddressSpace::new()?;
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let result =
+        {
+            let _mapper = self.mapper.lock();
+            let mut child_mapper = child.mapper.lock();
+            let mut result = Ok(());
+
+            walk_user_tables(self.p4, |page, entry|
+            {
+                if result.is_err()
+                {
+                    return;
+                }
+
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE)
+                {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+
+                let frame = PhysFrame::containing_address(entry.addr());
+                share_frame(frame);
+
+                result = with_memory(|_, frame_allocator| unsafe
+                {
+                    child_mapper.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator).map(|flush| flush.ignore())
+                });
+
+                if result.is_err()
+                {
+                    release_frame(frame);
+                }
+            }, |_| {});
+
+            result
+        };
+
+        // pages may have become read-only, wherever the parent is active (flushed without the locks,
+        // other CPUs may wait for them with interrupts disabled)
+        let mut batch = TlbBatch::for_address_space(self.p4);
+        batch.add_all();
+        batch.flush();
+
+        // on failure the child releases what it mapped so far
+        result?;
+
+        Ok(child)
+    }
+
+    // handle a write fault at addr: give the page a private, writable copy if it is copy on write
+    // returns false if addr is not a copy on write page (a real protection fault)
+    pub fn resolve_copy_on_write(&self, addr: VirtAddr) -> bool
+    {
+        let mut batch = TlbBatch::for_address_space(self.p4);
+        let resolved = self.make_private(addr, &mut self.mapper.lock(), &mut batch);
+        batch.flush();
+        resolved
+    }
+
+    // (mapper lock held) the changed page is added to batch, to be flushed once the lock is released
+    fn make_private(&self, addr: VirtAddr, _mapper: &mut OffsetPageTable<'static>, batch: &mut TlbBatch) -> bool
+    {
+        if addr.as_u64() < USER_SPACE_START || addr.as_u64() >= USER_SPACE_END
+        {
+            return false;
+        }
+
+        let Some(entry) = leaf_entry(self.p4, addr) else { return false };
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT | COPY_ON_WRITE)
+        {
+            return false;
+        }
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        let private_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        if is_shared(frame)
+        {
+            let Some(copy) = with_memory(|_, frame_allocator| frame_allocator.allocate_frame()) else { return false };
+            unsafe
+            {
+                core::ptr::copy_nonoverlapping(
+                    phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                    phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                    PAGE_SIZE,
+                );
+            }
+
+            entry.set_addr(copy.start_address(), private_flags);
+            release_frame(frame);
+        }
+        else
+        {
+            // every other mapping is gone already
+            entry.set_flags(private_flags);
+        }
+
+        batch.add(Page::containing_address(addr));
+        true
+    }
+
+    // copy data to user memory at addr, through the physical memory mapping
+    // (works whether or not the address space is active, and ignores the user's permissions,
+    // copy on write pages are made private first)
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> Result<(), UnmappedAddress>
+    {
+        let mut written = 0;
+        self.for_each_chunk(addr, data.len(), true, |dest, len|
+        {
+            unsafe { core::ptr::copy_nonoverlapping(data[written..].as_ptr(), dest, len); }
+            written += len;
+        })
+    }
+
+    // fill [addr, addr + len) with zeroes
+    pub fn zero(&self, addr: VirtAddr, len: usize) -> Result<(), UnmappedAddress>
+    {
+        self.for_each_chunk(addr, len, true, |dest, len| unsafe { core::ptr::write_bytes(dest, 0, len) })
+    }
+
+    // copy user memory at addr to buf
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), UnmappedAddress>
+    {
+        let mut read = 0;
+        let len = buf.len();
+        self.for_each_chunk(addr, len, false, |src, len|
+        {
+            unsafe { core::ptr::copy_nonoverlapping(src, buf[read..].as_mut_ptr(), len); }
+            read += len;
+        })
+    }
+
+    // split [addr, addr + len) at page boundaries and call f with the kernel pointer to each piece
+    // checks that the whole range is mapped in user space before touching anything
+    fn for_each_chunk(&self, addr: VirtAddr, len: usize, write: bool, f: impl FnMut(*mut u8, usize)) -> Result<(), UnmappedAddress>
+    {
+        if len == 0
+        {
+            return Ok(());
+        }
+
+        let end = addr.as_u64().checked_add(len as u64).ok_or(UnmappedAddress(addr))?;
+        if addr.as_u64() < USER_SPACE_START || end > USER_SPACE_END
+        {
+            return Err(UnmappedAddress(addr));
+        }
+
+        let mut batch = TlbBatch::for_address_space(self.p4);
+        let result = self.for_each_chunk_locked(addr, end, write, &mut self.mapper.lock(), &mut batch, f);
+        batch.flush();
+        result
+    }
+
+    fn for_each_chunk_locked(&self, addr: VirtAddr, end: u64, write: bool, mapper: &mut OffsetPageTable<'static>, batch: &mut TlbBatch,
+        mut f: impl FnMut(*mut u8, usize)) -> Result<(), UnmappedAddress>
+    {
+        let first = Page::<Size4KiB>::containing_address(addr);
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+        for page in Page::range_inclusive(first, last)
+        {
+            if translate_in(self.p4, page.start_address()).is_none()
+            {
+                return Err(UnmappedAddress(page.start_address()));
+            }
+
+            if write
+            {
+                self.make_private(page.start_address(), mapper, batch);
+            }
+        }
+
+        let mut current = addr.as_u64();
+        while current < end
+        {
+            let page_end = (current / PAGE_SIZE as u64 + 1) * PAGE_SIZE as u64;
+            let chunk = (page_end.min(end) - current) as usize;
+
+            let (phys, _) = translate_in(self.p4, VirtAddr::new(current)).ok_or(UnmappedAddress(VirtAddr::new(current)))?;
+            f(phys_to_virt(phys).as_mut_ptr(), chunk);
+
+            current += chunk as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace
+{
+    fn drop(&mut self)
+    {
+        assert!(!self.is_active(), "[ERR] Dropping the active address space");
+
+        // free every frame and table of the user half, the kernel half is shared
+        walk_user_tables(self.p4, |_, entry| release_frame(PhysFrame::containing_address(entry.addr())), free_frame);
+        free_frame(self.p4);
+    }
+}
+
+impl fmt::Debug for AddressSpace
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("AddressSpace").field("p4", &self.p4).finish()
+    }
+}
+
+// physical address and effective flags of addr in the active address space
+// (USER_ACCESSIBLE and WRITABLE only if every level allows it, NO_EXECUTE if any level sets it)
+pub fn translate_active(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)>
+{
+    translate_in(Cr3::read().0, addr)
+}
+
+fn translate_in(p4: PhysFrame, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)>
+{
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let inherited = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+
+    let mut frame = p4;
+    let mut flags = inherited;
+    let mut no_execute = false;
+
+    for (level, &index) in indexes.iter().enumerate()
+    {
+        let table: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+        let entry = &table[index];
+        let entry_flags = entry.flags();
+
+        if !entry_flags.contains(PageTableFlags::PRESENT)
+        {
+            return None;
+        }
+
+        flags &= entry_flags | !inherited;
+        no_execute |= entry_flags.contains(PageTableFlags::NO_EXECUTE);
+
+        // the last level, or a 1 GiB / 2 MiB page
+        let page_size = match level
+        {
+            3 => Some(4096u64),
+            1 | 2 if entry_flags.contains(PageTableFlags::HUGE_PAGE) => Some(if level == 1 { 1 << 30 } else { 1 << 21 }),
+            _ => None,
+        };
+
+        if let Some(page_size) = page_size
+        {
+            let mut effective = (entry_flags - inherited - PageTableFlags::NO_EXECUTE) | flags;
+            if no_execute
+            {
+                effective |= PageTableFlags::NO_EXECUTE;
+            }
+            return Some((entry.addr() + (addr.as_u64() & (page_size - 1)), effective));
+        }
+
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+
+    None
+}
+
+// call leaf for every mapped page of the user half, then table for each page table below the
+// level 4 table (after its entries were visited)
+fn walk_user_tables(p4: PhysFrame, mut leaf: impl FnMut(Page, &mut PageTableEntry), mut table: impl FnMut(PhysFrame))
+{
+    let p4_table = unsafe { table_mut(p4) };
+    for p4_index in USER_P4_ENTRIES
+    {
+        let Some(p3) = present_table(&p4_table[p4_index]) else { continue };
+        for (p3_index, p3_entry) in unsafe { table_mut(p3) }.iter_mut().enumerate()
+        {
+            let Some(p2) = present_table(p3_entry) else { continue };
+            for (p2_index, p2_entry) in unsafe { table_mut(p2) }.iter_mut().enumerate()
+            {
+                let Some(p1) = present_table(p2_entry) else { continue };
+                for (p1_index, p1_entry) in unsafe { table_mut(p1) }.iter_mut().enumerate()
+                {
+                    if p1_entry.flags().contains(PageTableFlags::PRESENT)
+                    {
+                        let addr = (p4_index << 39) | (p3_index << 30) | (p2_index << 21) | (p1_index << 12);
+                        leaf(Page::containing_address(VirtAddr::new(addr as u64)), p1_entry);
+                    }
+                }
+                table(p1);
+            }
+            table(p2);
+        }
+        table(p3);
+    }
+}
+
+// level 1 entry of a user page, None if a table on the way is missing
+fn leaf_entry(p4: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry>
+{
+    let p3 = present_table(&unsafe { table_mut(p4) }[addr.p4_index()])?;
+    let p2 = present_table(&unsafe { table_mut(p3) }[addr.p3_index()])?;
+    let p1 = present_table(&unsafe { table_mut(p2) }[addr.p2_index()])?;
+    Some(&mut unsafe { table_mut(p1) }[addr.p1_index()])
+}
+
+// next level table of a user half entry
+fn present_table(entry: &PageTableEntry) -> Option<PhysFrame>
+{
+    let flags = entry.flags();
+    (flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE))
+        .then(|| PhysFrame::containing_address(entry.addr()))
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable
+{
+    unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr() }
+}
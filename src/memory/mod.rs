@@ -0,0 +1,398 @@
+use x86_64::structures::paging::{PageTable, OffsetPageTable, Mapper, Page, PhysFrame, Size4KiB, FrameAllocator, FrameDeallocator, PageTableFlags};
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::{VirtAddr, PhysAddr};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::IrqSafeMutex;
+
+pub mod address_space;
+pub mod tlb;
+
+pub use address_space::{translate_active, AddressSpace, UnmappedAddress, COPY_ON_WRITE};
+pub use tlb::TlbBatch;
+
+pub const PAGE_SIZE: usize = 4096;
+
+// user space lives in its own level 4 entries, away from everything the bootloader maps
+// (kernel image at P4[0], physical memory and boot stack in the next free entries, heap at P4[136])
+pub const USER_SPACE_START: u64 = 0x0000_1000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_2000_0000_0000;
+
+// physical memory below 1 MiB is kept out of the frame allocator, for code that needs real mode
+// addresses (the SMP trampoline, see allocate_low_frame)
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+// kernel virtual addresses for device memory (see map_mmio); next to the heap in P4[136], so the
+// mappings show up in every address space
+const MMIO_START: u64 = 0x_4444_8000_0000;
+const MMIO_END: u64 = MMIO_START + 0x4000_0000;
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+// virtual address at which the bootloader mapped all of physical memory
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// level 4 table set up by the bootloader; used by kernel threads, and the source of every
+// address space's kernel half
+static KERNEL_P4: AtomicU64 = AtomicU64::new(0);
+
+// global page table + frame allocator, for everything that maps memory after boot
+// (handed over by init_global once the heap is set up)
+static MEMORY: IrqSafeMutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = IrqSafeMutex::new(None);
+
+// frames mapped by more than one address space (copy on write), with the number of extra mappings
+static SHARED_FRAMES: IrqSafeMutex<BTreeMap<PhysFrame, usize>> = IrqSafeMutex::new(BTreeMap::new());
+
+// implementing an empty frame allocator
+pub struct EmptyFrameAllocator;
+
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> 
+    {
+        if let Some(frame) = self.free.pop()
+        {
+            return Some(frame);
+        }
+
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+
+        frame
+
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator
+{
+    // freed frames are reused before new ones are taken from the memory map
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>)
+    {
+        self.free.push(frame);
+    }
+}
+
+
+// proper frame allocator; returns usable frames from bootloader's mem_map
+pub struct BootInfoFrameAllocator
+{
+    mem_map: &'static MemoryMap,
+    next: usize,
+    next_low: usize,        // same as next, for frames below LOW_MEMORY_END
+    free: Vec<PhysFrame>,   // frames given back by deallocate_frame (only used once the heap exists)
+}
+
+impl BootInfoFrameAllocator
+{
+    // initialise allocator
+    pub unsafe fn init(mem_map: &'static MemoryMap) -> Self
+    {
+        BootInfoFrameAllocator
+        {
+            mem_map, 
+            next: 0,
+            next_low: 0,
+            free: Vec::new(),
+        }
+    }
+
+    // return iterator over usable frames in mem_map (above LOW_MEMORY_END)
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> 
+    {
+        self.all_usable_frames().filter(|frame| frame.start_address().as_u64() >= LOW_MEMORY_END)
+    }
+
+    // a usable frame below LOW_MEMORY_END (never the zero frame); these are not given back
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame>
+    {
+        let frame = self.all_usable_frames()
+            .filter(|frame| (PAGE_SIZE as u64..LOW_MEMORY_END).contains(&frame.start_address().as_u64()))
+            .nth(self.next_low);
+        self.next_low += 1;
+
+        frame
+    }
+
+    fn all_usable_frames(&self) -> impl Iterator<Item = PhysFrame>
+    {
+        // get usable regions from mem_map
+        let regions = self.mem_map.iter();
+        let usable_regions = regions.filter(|reg| reg.region_type == MemoryRegionType::Usable);
+
+        // get address range for each usable region
+        let addr_ranges = usable_regions.map(|reg| reg.range.start_addr()..reg.range.end_addr());
+
+        // start_addr and end_addr are page-aligned
+        // get every address at page boundary (aligned at PAGE_SIZE)
+        let frame_addrs = addr_ranges.flat_map(|reg| reg.step_by(PAGE_SIZE));
+
+        // create PhysFrame types
+        frame_addrs.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+}
+
+
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> 
+{
+    PHYS_MEM_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_P4.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+
+    // the kernel must fault on read-only user pages too, or its writes would skip copy on write
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)); }
+
+    unsafe 
+    {
+        let level_4_table = active_level_4_table(physical_memory_offset);
+        OffsetPageTable::new(level_4_table, physical_memory_offset)
+    }
+}
+
+
+// get virtual address of L4 page table
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable
+{
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    unsafe { &mut *page_table_ptr }
+}
+
+
+// make the mapper and frame allocator available globally (after init_heap)
+pub fn init_global(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator)
+{
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+}
+
+// run f with the global mapper and frame allocator
+// panics if init_global was not called
+pub fn with_memory<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R) -> R
+{
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("[ERR] Global memory not initialised");
+    f(mapper, frame_allocator)
+}
+
+// virtual address of a physical address, through the complete physical memory mapping
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr
+{
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed) + phys.as_u64())
+}
+
+pub fn physical_memory_offset() -> VirtAddr
+{
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+// the bootloader's level 4 table (see KERNEL_P4), None before init
+pub fn kernel_page_table() -> Option<PhysFrame>
+{
+    match KERNEL_P4.load(Ordering::Relaxed)
+    {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+// load `p4` into CR3, unless it is active already (reloading flushes the TLB)
+pub fn switch_page_table(p4: PhysFrame)
+{
+    let (active, flags) = Cr3::read();
+    if active != p4
+    {
+        unsafe { Cr3::write(p4, flags); }
+    }
+}
+
+// allocate a frame from the global allocator and zero it
+// frames are handed out as they are, so this keeps old contents from leaking (e.g. to user space)
+pub fn allocate_zeroed_frame() -> Option<PhysFrame>
+{
+    let frame = with_memory(|_, frame_allocator| frame_allocator.allocate_frame())?;
+    unsafe { core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGE_SIZE); }
+    Some(frame)
+}
+
+// give a frame back to the global allocator
+pub fn free_frame(frame: PhysFrame)
+{
+    with_memory(|_, frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+}
+
+// one more address space maps frame
+pub fn share_frame(frame: PhysFrame)
+{
+    *SHARED_FRAMES.lock().entry(frame).or_insert(0) += 1;
+}
+
+// whether frame is mapped by more than one address space
+pub fn is_shared(frame: PhysFrame) -> bool
+{
+    SHARED_FRAMES.lock().contains_key(&frame)
+}
+
+// an address space stops mapping frame; it is freed once nobody maps it
+pub fn release_frame(frame: PhysFrame)
+{
+    let mut shared = SHARED_FRAMES.lock();
+    match shared.get_mut(&frame)
+    {
+        Some(1) => { shared.remove(&frame); }
+        Some(count) => *count -= 1,
+        None =>
+        {
+            drop(shared);
+            free_frame(frame);
+        }
+    }
+}
+
+// map [start, start + size) to fresh, zeroed frames accessible from ring 3
+// flags are added to PRESENT | USER_ACCESSIBLE (e.g. WRITABLE, NO_EXECUTE)
+pub fn map_user_region(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>>
+{
+    assert!(start.as_u64() >= USER_SPACE_START && start.as_u64() + size <= USER_SPACE_END, "[ERR] Not a user space range");
+
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + size - 1u64);
+
+    with_memory(|mapper, frame_allocator|
+    {
+        for page in Page::range_inclusive(first, last)
+        {
+            let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+
+            // frames are handed out as they are, do not leak old contents to user space
+            unsafe { core::ptr::write_bytes(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGE_SIZE); }
+
+            unsafe { mapper.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)?.flush(); }
+        }
+
+        Ok(())
+    })
+}
+
+// map device memory at [phys, phys + size) into the kernel half, uncached
+// returns the virtual address of phys; mappings are never removed
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>>
+{
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let pages = PhysFrame::range_inclusive(first, last).count() as u64;
+
+    let start = NEXT_MMIO.fetch_add(pages * PAGE_SIZE as u64, Ordering::Relaxed);
+    if start + pages * PAGE_SIZE as u64 > MMIO_END
+    {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_EXECUTE;
+
+    with_memory(|mapper, frame_allocator|
+    {
+        for (index, frame) in PhysFrame::range_inclusive(first, last).enumerate()
+        {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start + (index * PAGE_SIZE) as u64));
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush(); }
+        }
+
+        Ok(VirtAddr::new(start + (phys.as_u64() - first.start_address().as_u64())))
+    })
+}
+
+// remove the kernel mappings of [start, start + size), flushing them from every CPU's TLB
+// the frames are not freed, they belong to whoever mapped them
+pub fn unmap_region(start: VirtAddr, size: u64) -> Result<(), UnmapError>
+{
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + size.max(1) - 1u64);
+
+    let mut batch = TlbBatch::kernel();
+    let result = with_memory(|mapper, _|
+    {
+        for page in Page::range_inclusive(first, last)
+        {
+            mapper.unmap(page)?.1.ignore();
+            batch.add(page);
+        }
+
+        Ok(())
+    });
+
+    // flushed after the lock is gone (other CPUs may be waiting for it with interrupts disabled);
+    // on failure too, for the pages unmapped before it
+    batch.flush();
+    result
+}
+
+// a frame below LOW_MEMORY_END from the global allocator (for real mode code), None if there is none
+pub fn allocate_low_frame() -> Option<PhysFrame>
+{
+    with_memory(|_, frame_allocator| frame_allocator.allocate_low_frame())
+}
+
+// example mapping function (to VGA base addr)
+pub fn create_example_mapping(page: Page, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>) 
+{
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+
+    let map_to_result = unsafe 
+    {
+        // FIXME: this is not safe, we do it only for testing
+        mapper.map_to(page, frame, flags, frame_allocator)
+    };
+
+    map_to_result.expect("map_to failed").flush();
+}
+
+// pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr>
+// {
+//     translate_addr_inner(addr, physical_memory_offset)
+// }
+
+// fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr>
+// {
+//     use x86_64::structures::paging::page_table::FrameError;
+//     use x86_64::registers::control::Cr3;
+
+//     // read the active level 4 frame from the CR3 register
+//     let (level_4_table_frame, _) = Cr3::read();
+
+//     let table_indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+//     let mut frame = level_4_table_frame;
+
+//     // traverse the multi-level page table
+//     for &index in &table_indexes 
+//     {
+//         // convert the frame into a page table reference
+//         let virt = physical_memory_offset + frame.start_address().as_u64();
+//         let table_ptr: *const PageTable = virt.as_ptr();
+//         let table = unsafe {&*table_ptr};
+
+//         // read the page table entry and update `frame`
+//         let entry = &table[index];
+//         frame = match entry.frame() 
+//         {
+//             Ok(frame) => frame,
+//             Err(FrameError::FrameNotPresent) => return None,
+//             Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+//         };
+//     }
+
+//     // calculate the physical address by adding the page offset
+//     Some(frame.start_address() + u64::from(addr.page_offset()))
+// }
\ No newline at end of file
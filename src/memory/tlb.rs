@@ -0,0 +1,304 @@
+// TLB shootdown: every CPU caches translations, so a mapping which is removed or loses permissions has to
+// be flushed on all of them, not just on the one that changed the page table
+// the changed pages are collected in a TlbBatch; flushing it invalidates them locally, then publishes
+// one request and interrupts the other CPUs, which flush the same pages and acknowledge
+
+use crate::{apic, percpu, smp};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{Page, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+// interrupt vector of shootdown requests (see interrupts::init_idt)
+pub const SHOOTDOWN_VECTOR: u8 = 0xfd;
+
+// a batch holds this many separate ranges, more make it flush everything
+const MAX_RANGES: usize = 8;
+
+// flushing everything is cheaper than invalidating more pages than this one by one
+const MAX_FLUSH_PAGES: u64 = 32;
+
+// scope of kernel mappings (shared by every address space); user mappings are tagged with their level 4 table
+const KERNEL_SCOPE: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PageRange
+{
+    start: u64,     // page aligned
+    pages: u64,
+}
+
+// pages whose mappings changed, in one address space (or the kernel half)
+// nothing is flushed until flush() is called, so changes can be batched while page tables are locked
+#[derive(Debug)]
+pub struct TlbBatch
+{
+    scope: u64,
+    ranges: [PageRange; MAX_RANGES],
+    len: usize,
+    everything: bool,
+}
+
+impl TlbBatch
+{
+    // for mappings in the kernel half, which every CPU may have cached
+    pub fn kernel() -> Self
+    {
+        Self::with_scope(KERNEL_SCOPE)
+    }
+
+    // for user mappings of the address space with level 4 table p4, only cached where it is active
+    pub fn for_address_space(p4: PhysFrame) -> Self
+    {
+        Self::with_scope(p4.start_address().as_u64())
+    }
+
+    fn with_scope(scope: u64) -> Self
+    {
+        TlbBatch
+        {
+            scope,
+            ranges: [PageRange { start: 0, pages: 0 }; MAX_RANGES],
+            len: 0,
+            everything: false,
+        }
+    }
+
+    // page's mapping changed; adjacent pages are merged into one range
+    pub fn add(&mut self, page: Page<Size4KiB>)
+    {
+        if self.everything
+        {
+            return;
+        }
+
+        let start = page.start_address().as_u64();
+        if let Some(last) = self.ranges[..self.len].last_mut() && last.start + last.pages * Page::<Size4KiB>::SIZE == start
+        {
+            last.pages += 1;
+            return;
+        }
+
+        if self.len == MAX_RANGES
+        {
+            self.everything = true;
+            return;
+        }
+
+        self.ranges[self.len] = PageRange { start, pages: 1 };
+        self.len += 1;
+    }
+
+    // every mapping of the scope may have changed
+    pub fn add_all(&mut self)
+    {
+        self.everything = true;
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len == 0 && !self.everything
+    }
+
+    // invalidate the pages on this CPU and every other one that is online
+    // waits until all of them have acknowledged, so the old translations are gone when this returns
+    pub fn flush(self)
+    {
+        if self.is_empty()
+        {
+            return;
+        }
+
+        // page tables are changed before this, so a CPU switching to the address space later is fine
+        invalidate(self.scope, self.everything, &self.ranges[..self.len]);
+
+        if smp::cpu_count() > 1 && apic::is_initialised()
+        {
+            shoot_down(&self);
+        }
+    }
+}
+
+// flush one page of the kernel half everywhere
+pub fn flush_kernel_page(page: Page<Size4KiB>)
+{
+    let mut batch = TlbBatch::kernel();
+    batch.add(page);
+    batch.flush();
+}
+
+// the request being served; only one at a time (REQUEST_LOCK)
+struct Request
+{
+    generation: AtomicU64,      // bumped for every request
+    scope: AtomicU64,
+    everything: AtomicBool,
+    len: AtomicUsize,
+    starts: [AtomicU64; MAX_RANGES],
+    pages: [AtomicU64; MAX_RANGES],
+    acks: AtomicUsize,          // CPUs which still have to flush
+}
+
+static REQUEST: Request = Request
+{
+    generation: AtomicU64::new(0),
+    scope: AtomicU64::new(KERNEL_SCOPE),
+    everything: AtomicBool::new(false),
+    len: AtomicUsize::new(0),
+    starts: [const { AtomicU64::new(0) }; MAX_RANGES],
+    pages: [const { AtomicU64::new(0) }; MAX_RANGES],
+    acks: AtomicUsize::new(0),
+};
+
+static REQUEST_LOCK: AtomicBool = AtomicBool::new(false);
+
+crate::percpu!
+{
+    // last request generation this CPU has served (or sent)
+    static SERVED_GENERATION: AtomicU64 = AtomicU64::new(0);
+}
+
+fn shoot_down(batch: &TlbBatch)
+{
+    // not preemptible while holding the request: a CPU waiting for it may have interrupts disabled
+    interrupts::without_interrupts(||
+    {
+        // two CPUs may shoot down at once: the one waiting serves the other's request meanwhile
+        while REQUEST_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err()
+        {
+            serve();
+            core::hint::spin_loop();
+        }
+
+        REQUEST.scope.store(batch.scope, Ordering::Relaxed);
+        REQUEST.everything.store(batch.everything, Ordering::Relaxed);
+        REQUEST.len.store(batch.len, Ordering::Relaxed);
+        for (index, range) in batch.ranges[..batch.len].iter().enumerate()
+        {
+            REQUEST.starts[index].store(range.start, Ordering::Relaxed);
+            REQUEST.pages[index].store(range.pages, Ordering::Relaxed);
+        }
+
+        let generation = REQUEST.generation.load(Ordering::Relaxed) + 1;
+        SERVED_GENERATION.get().store(generation, Ordering::Relaxed);
+        REQUEST.acks.store(smp::cpu_count() - 1, Ordering::Relaxed);
+        REQUEST.generation.store(generation, Ordering::Release);
+
+        apic::broadcast_vector(SHOOTDOWN_VECTOR);
+
+        while REQUEST.acks.load(Ordering::Acquire) != 0
+        {
+            core::hint::spin_loop();
+        }
+
+        REQUEST_LOCK.store(false, Ordering::Release);
+    });
+}
+
+// flush what the current request asks for, once
+fn serve()
+{
+    let generation = REQUEST.generation.load(Ordering::Acquire);
+    let served = SERVED_GENERATION.get();
+    if served.swap(generation, Ordering::Relaxed) == generation
+    {
+        return;
+    }
+
+    let len = REQUEST.len.load(Ordering::Relaxed);
+    let mut ranges = [PageRange { start: 0, pages: 0 }; MAX_RANGES];
+    for (index, range) in ranges[..len].iter_mut().enumerate()
+    {
+        range.start = REQUEST.starts[index].load(Ordering::Relaxed);
+        range.pages = REQUEST.pages[index].load(Ordering::Relaxed);
+    }
+    invalidate(REQUEST.scope.load(Ordering::Relaxed), REQUEST.everything.load(Ordering::Relaxed), &ranges[..len]);
+
+    // a CPU which came online after the request was sent is not counted
+    let _ = REQUEST.acks.fetch_update(Ordering::AcqRel, Ordering::Acquire, |acks| acks.checked_sub(1));
+}
+
+// invalidate on this CPU; user mappings only matter if their address space is active here
+fn invalidate(scope: u64, everything: bool, ranges: &[PageRange])
+{
+    if scope != KERNEL_SCOPE && Cr3::read().0.start_address().as_u64() != scope
+    {
+        return;
+    }
+
+    let pages: u64 = ranges.iter().map(|range| range.pages).sum();
+    if everything || pages > MAX_FLUSH_PAGES
+    {
+        tlb::flush_all();
+        return;
+    }
+
+    for range in ranges
+    {
+        for index in 0..range.pages
+        {
+            tlb::flush(VirtAddr::new(range.start + index * Page::<Size4KiB>::SIZE));
+        }
+    }
+}
+
+// last request generation sent by any CPU
+pub fn generation() -> u64
+{
+    REQUEST.generation.load(Ordering::Acquire)
+}
+
+// last request generation the CPU with this index has served
+pub fn served_generation(cpu: usize) -> u64
+{
+    SERVED_GENERATION.for_cpu(cpu).load(Ordering::Acquire)
+}
+
+// shootdown request from another CPU
+pub extern "x86-interrupt" fn shootdown_interrupt_handler(mut stack_frame: InterruptStackFrame)
+{
+    percpu::with_kernel_gs(&mut stack_frame, |_|
+    {
+        serve();
+        apic::end_of_interrupt();
+    });
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn batch_merges_adjacent_pages()
+{
+    let page = |n: u64| Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000 + n * 4096));
+
+    let mut batch = TlbBatch::kernel();
+    assert!(batch.is_empty());
+
+    batch.add(page(0));
+    batch.add(page(1));
+    batch.add(page(2));
+    batch.add(page(10));
+
+    assert_eq!(batch.len, 2);
+    assert_eq!(batch.ranges[0], PageRange { start: page(0).start_address().as_u64(), pages: 3 });
+    assert_eq!(batch.ranges[1], PageRange { start: page(10).start_address().as_u64(), pages: 1 });
+    assert!(!batch.everything);
+}
+
+#[test_case]
+fn batch_overflow_flushes_everything()
+{
+    let mut batch = TlbBatch::kernel();
+    for n in 0..=MAX_RANGES as u64
+    {
+        batch.add(Page::containing_address(VirtAddr::new(0x4000_0000 + n * 2 * 4096)));
+    }
+
+    assert!(batch.everything);
+    assert!(!batch.is_empty());
+}
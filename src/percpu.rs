@@ -0,0 +1,280 @@
+// per-CPU data: every CPU has a PerCpu area, and finds it through its GS base
+// the kernel runs with GS base = the area of its CPU, user code with its own GS base; they are exchanged
+// with SWAPGS on every switch between ring 3 and ring 0 (IA32_KERNEL_GS_BASE holds the inactive one)
+
+use crate::gdt::Tss;
+use crate::sync::IrqSafeMutex;
+use crate::thread::policy::SchedPolicy;
+use alloc::boxed::Box;
+use conquer_once::spin::OnceCell;
+use core::mem::offset_of;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::segmentation::GS;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+// per-CPU statics (see percpu!) have room for this many CPUs; smp does not start more
+pub const MAX_CPUS: usize = 16;
+
+// current_thread of a CPU which does not run threads (yet)
+const NO_THREAD: u64 = u64::MAX;
+
+// the first fields are used from assembly, at fixed offsets from GS (the *_OFFSET constants)
+#[repr(C)]
+pub struct PerCpu
+{
+    this: AtomicU64,                    // address of the area itself: gs:[0] gives a normal pointer
+    user_stack_scratch: AtomicU64,      // user rsp, while syscall_entry switches stacks
+    kernel_stack: AtomicU64,            // stack for SYSCALL, kept equal to TSS.RSP0
+    diverted_rip: AtomicU64,            // user return state parked by usermode::divert
+    diverted_rflags: AtomicU64,
+    diverted_rsp: AtomicU64,
+
+    index: AtomicUsize,                 // 0 for the BSP, then in MADT order (see smp)
+    apic_id: AtomicU8,
+    gdt: OnceCell<&'static GlobalDescriptorTable>,
+    tss: OnceCell<&'static Tss>,
+    current_thread: AtomicU64,          // ThreadId of the running thread
+    run_queue: IrqSafeMutex<Option<Box<dyn SchedPolicy>>>,     // ready threads (see thread::scheduler)
+}
+
+pub const USER_STACK_OFFSET: usize = offset_of!(PerCpu, user_stack_scratch);
+pub const KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, kernel_stack);
+pub const DIVERTED_RIP_OFFSET: usize = offset_of!(PerCpu, diverted_rip);
+pub const DIVERTED_RFLAGS_OFFSET: usize = offset_of!(PerCpu, diverted_rflags);
+pub const DIVERTED_RSP_OFFSET: usize = offset_of!(PerCpu, diverted_rsp);
+
+// the BSP's area is static: it is set up before the heap
+static BSP_AREA: PerCpu = PerCpu::new();
+
+// every CPU's area, by index
+static AREAS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+impl PerCpu
+{
+    const fn new() -> Self
+    {
+        PerCpu
+        {
+            this: AtomicU64::new(0),
+            user_stack_scratch: AtomicU64::new(0),
+            kernel_stack: AtomicU64::new(0),
+            diverted_rip: AtomicU64::new(0),
+            diverted_rflags: AtomicU64::new(0),
+            diverted_rsp: AtomicU64::new(0),
+            index: AtomicUsize::new(0),
+            apic_id: AtomicU8::new(0),
+            gdt: OnceCell::uninit(),
+            tss: OnceCell::uninit(),
+            current_thread: AtomicU64::new(NO_THREAD),
+            run_queue: IrqSafeMutex::new(None),
+        }
+    }
+
+    pub fn index(&self) -> usize
+    {
+        self.index.load(Ordering::Relaxed)
+    }
+
+    pub fn apic_id(&self) -> u8
+    {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    // the GDT and TSS loaded on this CPU (see gdt::init and gdt::init_ap)
+    pub fn gdt(&self) -> Option<&'static GlobalDescriptorTable>
+    {
+        self.gdt.get().copied()
+    }
+
+    pub(crate) fn tss(&self) -> &'static Tss
+    {
+        self.tss.get().expect("[ERR] No TSS loaded on this CPU")
+    }
+
+    pub(crate) fn set_tables(&self, gdt: &'static GlobalDescriptorTable, tss: &'static Tss)
+    {
+        self.gdt.init_once(|| gdt);
+        self.tss.init_once(|| tss);
+    }
+
+    pub(crate) fn set_kernel_stack(&self, stack_top: VirtAddr)
+    {
+        self.kernel_stack.store(stack_top.as_u64(), Ordering::Relaxed);
+    }
+
+    // park the user rip, rflags and rsp of an interrupt return diverted to ring 0 (usermode::divert)
+    pub(crate) fn park_diverted(&self, rip: u64, rflags: u64, rsp: u64)
+    {
+        self.diverted_rip.store(rip, Ordering::Relaxed);
+        self.diverted_rflags.store(rflags, Ordering::Relaxed);
+        self.diverted_rsp.store(rsp, Ordering::Relaxed);
+    }
+
+    // raw ThreadId of the running thread, None if the CPU runs no threads
+    pub(crate) fn current_thread(&self) -> Option<u64>
+    {
+        match self.current_thread.load(Ordering::Relaxed)
+        {
+            NO_THREAD => None,
+            id => Some(id),
+        }
+    }
+
+    pub(crate) fn set_current_thread(&self, id: u64)
+    {
+        self.current_thread.store(id, Ordering::Relaxed);
+    }
+
+    pub(crate) fn run_queue(&self) -> &IrqSafeMutex<Option<Box<dyn SchedPolicy>>>
+    {
+        &self.run_queue
+    }
+}
+
+// set up the BSP's area (gdt::init does this, before it registers the GDT and TSS)
+pub fn init_bsp()
+{
+    install(&BSP_AREA, 0);
+}
+
+// set up the area of an AP, first thing after the trampoline (see smp)
+pub fn init_ap(index: usize)
+{
+    install(Box::leak(Box::new(PerCpu::new())), index);
+}
+
+fn install(area: &'static PerCpu, index: usize)
+{
+    assert!(index < MAX_CPUS, "[ERR] CPU index {} out of range", index);
+
+    area.this.store(area as *const PerCpu as u64, Ordering::Relaxed);
+    area.index.store(index, Ordering::Relaxed);
+    area.apic_id.store(initial_apic_id(), Ordering::Relaxed);
+    AREAS[index].store(area as *const PerCpu as *mut PerCpu, Ordering::Release);
+
+    // the user's GS base starts out as 0, it is swapped in on the first return to ring 3
+    GsBase::write(VirtAddr::from_ptr(area));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+// the running CPU's area
+// only valid in ring 0 code running with the kernel GS base (everything after the entry points)
+#[inline]
+pub fn current() -> &'static PerCpu
+{
+    let this: u64;
+    unsafe
+    {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+        &*(this as *const PerCpu)
+    }
+}
+
+// the area of the CPU with this index, None if it is not running
+pub fn for_cpu(index: usize) -> Option<&'static PerCpu>
+{
+    let area = AREAS.get(index)?.load(Ordering::Acquire);
+    unsafe { area.as_ref() }
+}
+
+// x86-interrupt handlers start on the GS base of the code they interrupted: run f with the kernel's,
+// and leave the one that matches the ring the handler returns to (usermode::divert may change it)
+pub fn with_kernel_gs<R>(frame: &mut InterruptStackFrame, f: impl FnOnce(&mut InterruptStackFrame) -> R) -> R
+{
+    if frame.code_segment & 3 == 3
+    {
+        unsafe { GS::swap(); }
+    }
+
+    let result = f(frame);
+
+    if frame.code_segment & 3 == 3
+    {
+        unsafe { GS::swap(); }
+    }
+
+    result
+}
+
+// APIC ID the CPU was given at reset, from CPUID
+fn initial_apic_id() -> u8
+{
+    (core::arch::x86_64::__cpuid(1).ebx >> 24) as u8
+}
+
+// a static with one value per CPU (see percpu!); get() is the running CPU's
+pub struct PerCpuStatic<T>([T; MAX_CPUS]);
+
+impl<T> PerCpuStatic<T>
+{
+    pub const fn new(values: [T; MAX_CPUS]) -> Self
+    {
+        PerCpuStatic(values)
+    }
+
+    pub fn get(&self) -> &T
+    {
+        &self.0[current().index()]
+    }
+
+    pub fn for_cpu(&self, index: usize) -> &T
+    {
+        &self.0[index]
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, T>
+    {
+        self.0.iter()
+    }
+}
+
+// declares statics with one value per CPU, each starting out as the initialiser:
+//     percpu! { static INTERRUPTS: AtomicU64 = AtomicU64::new(0); }
+//     INTERRUPTS.get().fetch_add(1, Ordering::Relaxed);
+// the values are shared with other CPUs (for_cpu), so the type has to be Sync, like any static
+#[macro_export]
+macro_rules! percpu
+{
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $type:ty = $init:expr;)*) =>
+    {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpuStatic<$type> =
+                $crate::percpu::PerCpuStatic::new([const { $init }; $crate::percpu::MAX_CPUS]);
+        )*
+    };
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn bsp_area_is_at_gs_base()
+{
+    let area = current();
+
+    assert_eq!(area.index(), 0);
+    assert_eq!(GsBase::read(), VirtAddr::from_ptr(area));
+    assert!(ptr::eq(for_cpu(0).expect("no area for the BSP"), area));
+    assert!(area.tss.get().is_some());
+}
+
+#[test_case]
+fn percpu_statics_are_separate()
+{
+    percpu!
+    {
+        static COUNTER: AtomicU64 = AtomicU64::new(7);
+    }
+
+    COUNTER.get().fetch_add(1, Ordering::Relaxed);
+
+    assert_eq!(COUNTER.get().load(Ordering::Relaxed), 8);
+    assert_eq!(COUNTER.for_cpu(1).load(Ordering::Relaxed), 7);
+    assert_eq!(COUNTER.iter().count(), MAX_CPUS);
+}
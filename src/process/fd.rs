@@ -0,0 +1,130 @@
+use crate::print;
+use crate::syscall::SyscallError;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+// most open files per process
+pub const MAX_FDS: usize = 64;
+
+// anything a file descriptor can refer to
+// the defaults fail, so read-only or write-only files only implement one side
+pub trait File: Send + Sync
+{
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, SyscallError>
+    {
+        Err(SyscallError::BadFileDescriptor)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, SyscallError>
+    {
+        Err(SyscallError::BadFileDescriptor)
+    }
+}
+
+// VGA text console; reads return end of file (there is no line discipline yet)
+pub struct Console;
+
+impl File for Console
+{
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, SyscallError>
+    {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, SyscallError>
+    {
+        match core::str::from_utf8(buf)
+        {
+            Ok(text) => print!("{}", text),
+            Err(_) => buf.iter().for_each(|&byte| print!("{}", byte as char)),
+        }
+
+        Ok(buf.len())
+    }
+}
+
+// per-process table of open files, indexed by file descriptor
+// cloning it shares the open files (like fork)
+#[derive(Clone, Default)]
+pub struct FdTable
+{
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FdTable
+{
+    pub fn new() -> Self
+    {
+        FdTable::default()
+    }
+
+    // stdin, stdout and stderr on the console
+    pub fn with_console() -> Self
+    {
+        let console: Arc<dyn File> = Arc::new(Console);
+
+        let mut table = FdTable::new();
+        for _ in [STDIN, STDOUT, STDERR]
+        {
+            table.open(console.clone());
+        }
+        table
+    }
+
+    // install file at the lowest free descriptor, None if the table is full
+    pub fn open(&mut self, file: Arc<dyn File>) -> Option<usize>
+    {
+        match self.files.iter().position(Option::is_none)
+        {
+            Some(fd) =>
+            {
+                self.files[fd] = Some(file);
+                Some(fd)
+            }
+            None if self.files.len() < MAX_FDS =>
+            {
+                self.files.push(Some(file));
+                Some(self.files.len() - 1)
+            }
+            None => None,
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<dyn File>>
+    {
+        self.files.get(fd).cloned().flatten()
+    }
+
+    // returns the closed file, None if fd was not open
+    pub fn close(&mut self, fd: usize) -> Option<Arc<dyn File>>
+    {
+        self.files.get_mut(fd).and_then(Option::take)
+    }
+
+    pub fn open_count(&self) -> usize
+    {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn fd_table_reuses_lowest_descriptor()
+{
+    let mut table = FdTable::with_console();
+    assert_eq!(table.open_count(), 3);
+
+    assert!(table.close(STDOUT).is_some());
+    assert!(table.close(STDOUT).is_none());
+    assert!(table.get(STDOUT).is_none());
+
+    assert_eq!(table.open(Arc::new(Console)), Some(STDOUT));
+    assert_eq!(table.open(Arc::new(Console)), Some(3));
+}
@@ -0,0 +1,440 @@
+use crate::fs;
+use crate::loader::{self, LoadError};
+use crate::syscall::SyscallFrame;
+use crate::memory::AddressSpace;
+use crate::sync::{IrqSafeMutex, WaitQueue};
+use crate::thread::{self, ThreadId};
+use crate::usermode;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub mod fd;
+pub mod signal;
+
+pub use fd::{Console, FdTable, File};
+pub use signal::{SignalError, SignalState};
+
+// unique identifier for each process, never reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid
+{
+    fn new() -> Self
+    {
+        // pid 0 is reserved for the kernel
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self
+    {
+        Pid(pid)
+    }
+
+    pub fn as_u64(&self) -> u64
+    {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState
+{
+    Running,
+    Zombie(i32),    // exited with this code, waiting to be reaped by wait()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError
+{
+    NotAProcess,    // called from a kernel thread
+    NotFound,       // no such file
+    Busy,           // exec from a process with more than one thread
+    OutOfMemory,
+    Load(LoadError),
+}
+
+impl From<LoadError> for ProcessError
+{
+    fn from(error: LoadError) -> Self
+    {
+        ProcessError::Load(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError
+{
+    NoSuchChild,    // not a child of the caller (or already reaped)
+    Interrupted,    // a signal arrived while waiting
+}
+
+// a user program: an address space, the threads running in it, open files and its family
+pub struct Process
+{
+    pid: Pid,
+    parent: Option<Pid>,    // None: started by (or orphaned to) the kernel, which may wait for it
+    name: String,
+    state: ProcessState,
+    address_space: Option<Arc<AddressSpace>>,   // released when the process becomes a zombie
+    threads: BTreeSet<ThreadId>,
+    children: BTreeSet<Pid>,
+    files: FdTable,
+    signals: SignalState,
+    exit_code: Option<i32>,     // set by exit(), the remaining threads leave at their next system call (or interrupt)
+}
+
+impl Process
+{
+    pub fn pid(&self) -> Pid
+    {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Option<Pid>
+    {
+        self.parent
+    }
+
+    pub fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    pub fn state(&self) -> ProcessState
+    {
+        self.state
+    }
+
+    pub fn address_space(&self) -> Option<&Arc<AddressSpace>>
+    {
+        self.address_space.as_ref()
+    }
+
+    pub fn thread_count(&self) -> usize
+    {
+        self.threads.len()
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Pid> + '_
+    {
+        self.children.iter().copied()
+    }
+
+    pub fn files(&self) -> &FdTable
+    {
+        &self.files
+    }
+
+    pub fn files_mut(&mut self) -> &mut FdTable
+    {
+        &mut self.files
+    }
+
+    pub fn signals(&self) -> &SignalState
+    {
+        &self.signals
+    }
+}
+
+// every process which has not been reaped yet
+static PROCESSES: IrqSafeMutex<BTreeMap<Pid, Process>> = IrqSafeMutex::new(BTreeMap::new());
+
+// parents sleep here until a process exits; EXITS lets them check for new zombies without the table lock
+static CHILD_EXITED: WaitQueue = WaitQueue::new();
+static EXITS: AtomicU64 = AtomicU64::new(0);
+
+// process which gets SIGINT for Ctrl+C (0: none)
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+
+// load an executable into a new process and start its main thread in ring 3
+// the new process is a child of the calling thread's process (or of the kernel)
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, LoadError>
+{
+    let loaded = loader::load(image, argv, envp)?;
+    let name = argv.first().copied().unwrap_or("?");
+
+    let context = SyscallFrame::new_user(loaded.entry, loaded.stack_pointer);
+
+    Ok(start(name, loaded.address_space, context, FdTable::with_console(), SignalState::new()))
+}
+
+// spawn() for a program from the file system
+pub fn spawn_path(path: &str, argv: &[&str], envp: &[&str]) -> Result<Pid, ProcessError>
+{
+    let image = fs::lookup(path).ok_or(ProcessError::NotFound)?;
+    Ok(spawn(image, argv, envp)?)
+}
+
+// duplicate the calling process: the child gets a copy on write copy of the address space,
+// the same open files, and one thread resuming at context with rax = 0
+pub fn fork(context: &SyscallFrame) -> Result<Pid, ProcessError>
+{
+    let pid = current().ok_or(ProcessError::NotAProcess)?;
+    let (name, address_space, files, signals) = with_process(pid, |process|
+    {
+        (process.name.clone(), process.address_space.clone(), process.files.clone(), process.signals.fork())
+    }).ok_or(ProcessError::NotAProcess)?;
+
+    let address_space = address_space.ok_or(ProcessError::NotAProcess)?;
+    let child_space = address_space.fork().map_err(|_| ProcessError::OutOfMemory)?;
+
+    let mut child_context = *context;
+    child_context.rax = 0;
+
+    Ok(start(&name, Arc::new(child_space), child_context, files, signals))
+}
+
+// replace the program of the calling process with a new image; on success the calling
+// thread continues at the new entry point (context is rewritten), nothing of the old program is kept
+pub fn exec(context: &mut SyscallFrame, path: &str, argv: &[&str], envp: &[&str]) -> Result<(), ProcessError>
+{
+    let pid = current().ok_or(ProcessError::NotAProcess)?;
+    let image = fs::lookup(path).ok_or(ProcessError::NotFound)?;
+
+    // other threads would keep running the old program
+    if with_process(pid, |process| process.threads.len()) != Some(1)
+    {
+        return Err(ProcessError::Busy);
+    }
+
+    let loaded = loader::load(image, argv, envp)?;
+
+    let old = with_process(pid, |process|
+    {
+        process.name = String::from(argv.first().copied().unwrap_or(path));
+        process.signals.reset_handlers();
+        process.address_space.replace(loaded.address_space.clone())
+    }).flatten();
+
+    // switches CR3 right away, so the old address space can be freed here
+    let old_thread_space = thread::replace_address_space(loaded.address_space);
+    drop(old);
+    drop(old_thread_space);
+
+    *context = SyscallFrame::new_user(loaded.entry, loaded.stack_pointer);
+    Ok(())
+}
+
+// register a process and run its first thread with context
+fn start(name: &str, address_space: Arc<AddressSpace>, context: SyscallFrame, files: FdTable, signals: SignalState) -> Pid
+{
+    let pid = Pid::new();
+    let parent = current();
+
+    // the thread is only added once the process exists, and cannot run before the table is unlocked
+    let mut processes = PROCESSES.lock();
+
+    if let Some(parent) = parent.and_then(|parent| processes.get_mut(&parent))
+    {
+        parent.children.insert(pid);
+    }
+
+    let handle = thread::Builder::new()
+        .address_space(address_space.clone())
+        .process(pid)
+        .spawn(move ||
+        {
+            unsafe { usermode::resume(&context) }
+        });
+
+    processes.insert(pid, Process
+    {
+        pid,
+        parent,
+        name: String::from(name),
+        state: ProcessState::Running,
+        address_space: Some(address_space),
+        threads: BTreeSet::from([handle.id()]),
+        children: BTreeSet::new(),
+        files,
+        signals,
+        exit_code: None,
+    });
+
+    pid
+}
+
+// process of the running thread, None for kernel threads
+pub fn current() -> Option<Pid>
+{
+    thread::current_process()
+}
+
+// run f on a process, None if it does not exist (or was reaped)
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Option<R>
+{
+    PROCESSES.lock().get_mut(&pid).map(f)
+}
+
+pub fn state(pid: Pid) -> Option<ProcessState>
+{
+    with_process(pid, |process| process.state)
+}
+
+// send SIGINT to this process on Ctrl+C (see task::keyboard), None for nobody
+pub fn set_foreground(pid: Option<Pid>)
+{
+    FOREGROUND.store(pid.map_or(0, |pid| pid.as_u64()), Ordering::Relaxed);
+}
+
+pub fn foreground() -> Option<Pid>
+{
+    match FOREGROUND.load(Ordering::Relaxed)
+    {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+// pids of all processes, including zombies
+pub fn pids() -> Vec<Pid>
+{
+    PROCESSES.lock().keys().copied().collect()
+}
+
+// whether the current thread belongs to a process which called exit()
+pub fn exit_pending() -> bool
+{
+    current().and_then(|pid| with_process(pid, |process| process.exit_code.is_some())).unwrap_or(false)
+}
+
+// end the current process with code: the calling thread exits now, the others at their next
+// system call; the last one to leave turns the process into a zombie
+pub fn exit(code: i32) -> !
+{
+    if let Some(pid) = current()
+    {
+        with_process(pid, |process| { process.exit_code.get_or_insert(code); });
+    }
+
+    exit_thread();
+}
+
+// remove the current thread from its process, and exit it
+pub fn exit_thread() -> !
+{
+    let (Some(pid), Some(tid)) = (current(), thread::current_id()) else { thread::exit() };
+
+    let mut released = None;
+    let mut parent = None;
+    let exited =
+    {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).expect("[ERR] Thread of a process which does not exist");
+
+        process.threads.remove(&tid);
+        if process.threads.is_empty()
+        {
+            process.state = ProcessState::Zombie(process.exit_code.unwrap_or(0));
+
+            // free the memory now, the zombie only keeps its exit code
+            released = process.address_space.take();
+            process.files = FdTable::new();
+
+            parent = process.parent;
+            let children = core::mem::take(&mut process.children);
+            orphan(&mut processes, &children);
+            true
+        }
+        else
+        {
+            false
+        }
+    };
+
+    // this thread still runs in the address space; it is freed when the thread is reaped
+    drop(released);
+
+    if exited
+    {
+        EXITS.fetch_add(1, Ordering::Release);
+        CHILD_EXITED.notify_all();
+
+        if let Some(parent) = parent
+        {
+            // the parent may have exited in the meantime
+            let _ = signal::send(parent, signal::SIGCHLD);
+        }
+    }
+
+    thread::exit();
+}
+
+// hand the children of an exited process to the kernel; zombies among them are reaped right away
+fn orphan(processes: &mut BTreeMap<Pid, Process>, children: &BTreeSet<Pid>)
+{
+    for child in children
+    {
+        let zombie = match processes.get_mut(child)
+        {
+            Some(process) =>
+            {
+                process.parent = None;
+                matches!(process.state, ProcessState::Zombie(_))
+            }
+            None => false,
+        };
+
+        if zombie
+        {
+            processes.remove(child);
+        }
+    }
+}
+
+// wait for a child of the calling process (or of the kernel) to exit, and reap it (interrupted by signals)
+// pid None waits for any child; returns the pid and exit code
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, i32), WaitError>
+{
+    let parent = current();
+
+    loop
+    {
+        let seen = EXITS.load(Ordering::Acquire);
+
+        if let Some(result) = try_reap(parent, pid)
+        {
+            return result;
+        }
+
+        // signals wake the thread up (see signal::send), the system call returns EINTR
+        if signal::interrupted()
+        {
+            return Err(WaitError::Interrupted);
+        }
+
+        CHILD_EXITED.wait_if(|| EXITS.load(Ordering::Acquire) == seen);
+    }
+}
+
+// reap a zombie child of parent; None if there are matching children but none has exited yet
+fn try_reap(parent: Option<Pid>, pid: Option<Pid>) -> Option<Result<(Pid, i32), WaitError>>
+{
+    let mut processes = PROCESSES.lock();
+
+    let matches = |process: &Process| process.parent == parent && pid.is_none_or(|pid| process.pid == pid);
+
+    if !processes.values().any(matches)
+    {
+        return Some(Err(WaitError::NoSuchChild));
+    }
+
+    let (child, code) = processes.values().filter(|process| matches(process)).find_map(|process| match process.state
+    {
+        ProcessState::Zombie(code) => Some((process.pid, code)),
+        ProcessState::Running => None,
+    })?;
+
+    processes.remove(&child);
+    if let Some(parent) = parent.and_then(|parent| processes.get_mut(&parent))
+    {
+        parent.children.remove(&child);
+    }
+
+    Some(Ok((child, code)))
+}
@@ -0,0 +1,653 @@
+// POSIX-style signals: every process has a pending set, a mask of blocked signals and an action per
+// signal; pending signals are delivered when one of its threads is about to return to ring 3
+// (end of a system call, or an interrupt / exception from ring 3, see usermode::divert)
+
+use super::{current, exit, Pid, ProcessState, PROCESSES};
+use crate::memory::{AddressSpace, PAGE_SIZE, USER_SPACE_END};
+use crate::syscall::{self, SyscallFrame, SYS_SIGRETURN};
+use crate::thread;
+use crate::usermode;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+// ---------- SIGNAL NUMBERS ----------
+// same numbers as Linux x86-64
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+
+// valid signals are 1..NSIG
+pub const NSIG: u32 = 32;
+
+// sigaction handler values which are not addresses
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// sigprocmask operations
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+// page with the code signal handlers return to (see map_trampoline), just above the initial user stack
+pub const TRAMPOLINE_ADDRESS: u64 = USER_SPACE_END - PAGE_SIZE as u64;
+
+// the handler frame goes below the red zone of the interrupted code (System V ABI)
+const RED_ZONE: u64 = 128;
+
+// flags the user context may change on sigreturn: CF, PF, AF, ZF, SF, TF, DF, OF
+const USER_CHANGEABLE_FLAGS: u64 = 0xdd5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError
+{
+    InvalidSignal,      // out of range, or SIGKILL/SIGSTOP for sigaction
+    NoSuchProcess,
+    NotAProcess,        // called from a kernel thread
+}
+
+// set of signals, bit (sig - 1) for sig (the Linux sigset_t layout)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SignalSet(u64);
+
+// cannot be blocked, ignored or caught
+const UNBLOCKABLE: SignalSet = SignalSet(1 << (SIGKILL - 1) | 1 << (SIGSTOP - 1));
+const STOP_SIGNALS: SignalSet = SignalSet(1 << (SIGSTOP - 1) | 1 << (SIGTSTP - 1) | 1 << (SIGTTIN - 1) | 1 << (SIGTTOU - 1));
+
+impl SignalSet
+{
+    pub const fn empty() -> Self
+    {
+        SignalSet(0)
+    }
+
+    // bits outside of 1..NSIG are dropped
+    pub const fn from_bits(bits: u64) -> Self
+    {
+        SignalSet(bits & ((1 << (NSIG - 1)) - 1))
+    }
+
+    pub const fn bits(&self) -> u64
+    {
+        self.0
+    }
+
+    pub fn contains(&self, sig: u32) -> bool
+    {
+        is_valid(sig) && self.0 & bit(sig) != 0
+    }
+
+    pub fn insert(&mut self, sig: u32)
+    {
+        if is_valid(sig)
+        {
+            self.0 |= bit(sig);
+        }
+    }
+
+    pub fn remove(&mut self, sig: u32)
+    {
+        if is_valid(sig)
+        {
+            self.0 &= !bit(sig);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.0 == 0
+    }
+
+    pub fn union(self, other: SignalSet) -> SignalSet
+    {
+        SignalSet(self.0 | other.0)
+    }
+
+    pub fn difference(self, other: SignalSet) -> SignalSet
+    {
+        SignalSet(self.0 & !other.0)
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = u32>
+    {
+        (1..NSIG).filter(move |&sig| self.contains(sig))
+    }
+}
+
+fn bit(sig: u32) -> u64
+{
+    1 << (sig - 1)
+}
+
+fn is_valid(sig: u32) -> bool
+{
+    (1..NSIG).contains(&sig)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Handler
+{
+    #[default]
+    Default,
+    Ignore,
+    User(VirtAddr),     // called as handler(sig) in ring 3
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SigAction
+{
+    pub handler: Handler,
+    pub mask: SignalSet,    // blocked (with the signal itself) while the handler runs
+}
+
+// what happens to a signal with Handler::Default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction
+{
+    Terminate,  // exit code 128 + sig
+    Ignore,
+    Stop,       // until SIGCONT
+    Continue,   // resume a stopped process
+}
+
+pub fn default_action(sig: u32) -> DefaultAction
+{
+    match sig
+    {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+// exit code of a process killed by sig (what a shell reports)
+pub fn exit_code(sig: u32) -> i32
+{
+    128 + sig as i32
+}
+
+// per-process signal state
+#[derive(Debug, Clone)]
+pub struct SignalState
+{
+    pending: SignalSet,
+    mask: SignalSet,                    // never contains SIGKILL or SIGSTOP
+    actions: [SigAction; NSIG as usize],
+    stopped: bool,
+}
+
+impl Default for SignalState
+{
+    fn default() -> Self
+    {
+        SignalState::new()
+    }
+}
+
+impl SignalState
+{
+    pub fn new() -> Self
+    {
+        SignalState
+        {
+            pending: SignalSet::empty(),
+            mask: SignalSet::empty(),
+            actions: [SigAction::default(); NSIG as usize],
+            stopped: false,
+        }
+    }
+
+    pub fn pending(&self) -> SignalSet
+    {
+        self.pending
+    }
+
+    pub fn mask(&self) -> SignalSet
+    {
+        self.mask
+    }
+
+    pub fn is_stopped(&self) -> bool
+    {
+        self.stopped
+    }
+
+    pub fn action(&self, sig: u32) -> SigAction
+    {
+        self.actions[sig as usize]
+    }
+
+    // state of a forked child: same actions and mask, nothing pending
+    pub(super) fn fork(&self) -> Self
+    {
+        SignalState { pending: SignalSet::empty(), stopped: false, ..self.clone() }
+    }
+
+    // execve: the handlers are gone with the old program, ignored signals stay ignored
+    pub(super) fn reset_handlers(&mut self)
+    {
+        for action in &mut self.actions
+        {
+            if let Handler::User(_) = action.handler
+            {
+                *action = SigAction::default();
+            }
+        }
+    }
+
+    fn is_ignored(&self, sig: u32) -> bool
+    {
+        match self.actions[sig as usize].handler
+        {
+            Handler::Ignore => true,
+            Handler::Default => matches!(default_action(sig), DefaultAction::Ignore | DefaultAction::Continue),
+            Handler::User(_) => false,
+        }
+    }
+
+    fn post(&mut self, sig: u32)
+    {
+        // stop and continue cancel each other right away, even while blocked
+        if sig == SIGCONT || sig == SIGKILL
+        {
+            self.stopped = false;
+            self.pending = self.pending.difference(STOP_SIGNALS);
+        }
+        else if STOP_SIGNALS.contains(sig)
+        {
+            self.pending.remove(SIGCONT);
+        }
+
+        // ignored signals are discarded, unless blocked (the action may change before they are unblocked)
+        if self.is_ignored(sig) && !self.mask.contains(sig)
+        {
+            return;
+        }
+
+        self.pending.insert(sig);
+    }
+
+    // signals which would be delivered now
+    fn deliverable(&self) -> SignalSet
+    {
+        self.pending.difference(self.mask)
+    }
+
+    // whether a delivery would do anything (run a handler, stop or terminate)
+    fn has_deliverable(&self) -> bool
+    {
+        self.deliverable().iter().any(|sig| !self.is_ignored(sig))
+    }
+
+    // dequeue the lowest deliverable signal; returns it with its action and the mask before delivery
+    // blocks the handler's mask, or marks the process stopped for a default stop
+    fn next(&mut self) -> Option<(u32, SigAction, SignalSet)>
+    {
+        let sig = self.deliverable().iter().next()?;
+        self.pending.remove(sig);
+
+        let action = self.actions[sig as usize];
+        let old_mask = self.mask;
+
+        match action.handler
+        {
+            Handler::User(_) =>
+            {
+                let mut mask = self.mask.union(action.mask);
+                mask.insert(sig);
+                self.mask = mask.difference(UNBLOCKABLE);
+            }
+            Handler::Default if default_action(sig) == DefaultAction::Stop => self.stopped = true,
+            _ => {}
+        }
+
+        Some((sig, action, old_mask))
+    }
+}
+
+// pushed on the user stack for a handler; the handler's return pops return_address,
+// which leaves rsp pointing at signal (see sigreturn)
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct SignalFrame
+{
+    return_address: u64,    // TRAMPOLINE_ADDRESS
+    signal: u64,
+    mask: u64,              // restored by sigreturn
+    context: SyscallFrame,  // interrupted user context
+}
+
+
+// ---------- SENDING ----------
+// post sig to a process and wake its threads, so blocked system calls can return EINTR
+// sig 0 only checks that the process exists
+pub fn send(pid: Pid, sig: u32) -> Result<(), SignalError>
+{
+    if sig != 0 && !is_valid(sig)
+    {
+        return Err(SignalError::InvalidSignal);
+    }
+
+    let threads: Vec<thread::ThreadId> =
+    {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).ok_or(SignalError::NoSuchProcess)?;
+
+        // zombies cannot receive anything
+        if sig == 0 || process.state != ProcessState::Running
+        {
+            return Ok(());
+        }
+
+        process.signals.post(sig);
+        process.threads.iter().copied().collect()
+    };
+
+    for thread in threads
+    {
+        thread::unpark(thread);
+    }
+
+    Ok(())
+}
+
+// post a signal caused by the current thread (an exception): it cannot be blocked or ignored,
+// otherwise the faulting instruction would run again; returns false outside of a process
+// only takes the process table lock, safe to call from exception handlers
+pub fn force(sig: u32) -> bool
+{
+    let Some(pid) = current() else { return false };
+
+    PROCESSES.lock().get_mut(&pid).map(|process|
+    {
+        let signals = &mut process.signals;
+        if signals.mask.contains(sig) || signals.actions[sig as usize].handler == Handler::Ignore
+        {
+            signals.mask.remove(sig);
+            signals.actions[sig as usize] = SigAction::default();
+        }
+        signals.post(sig);
+    }).is_some()
+}
+
+
+// ---------- SYSTEM CALL SIDE ----------
+// change the action for sig in the calling process, returns the old one
+pub fn set_action(sig: u32, action: SigAction) -> Result<SigAction, SignalError>
+{
+    if !is_valid(sig) || UNBLOCKABLE.contains(sig)
+    {
+        return Err(SignalError::InvalidSignal);
+    }
+
+    let pid = current().ok_or(SignalError::NotAProcess)?;
+    let mut processes = PROCESSES.lock();
+    let signals = &mut processes.get_mut(&pid).ok_or(SignalError::NotAProcess)?.signals;
+
+    let old = core::mem::replace(&mut signals.actions[sig as usize], action);
+
+    // POSIX: setting a signal to ignored discards it if pending
+    if signals.is_ignored(sig)
+    {
+        signals.pending.remove(sig);
+    }
+
+    Ok(old)
+}
+
+// sigprocmask(how, set) for the calling process, returns the old mask
+// newly unblocked signals are delivered on the way back to ring 3
+pub fn change_mask(how: u64, set: SignalSet) -> Result<SignalSet, SignalError>
+{
+    let pid = current().ok_or(SignalError::NotAProcess)?;
+    let mut processes = PROCESSES.lock();
+    let signals = &mut processes.get_mut(&pid).ok_or(SignalError::NotAProcess)?.signals;
+
+    let old = signals.mask;
+    let mask = match how
+    {
+        SIG_BLOCK => old.union(set),
+        SIG_UNBLOCK => old.difference(set),
+        SIG_SETMASK => set,
+        _ => return Err(SignalError::InvalidSignal),
+    };
+    signals.mask = mask.difference(UNBLOCKABLE);
+
+    Ok(old)
+}
+
+// whether the calling thread should stop blocking and return to ring 3 (EINTR)
+pub fn interrupted() -> bool
+{
+    current().is_some_and(|pid| PROCESSES.lock().get(&pid)
+        .is_some_and(|process| process.exit_code.is_some() || process.signals.has_deliverable()))
+}
+
+// interrupt-safe version of interrupted() for the process of the thread about to be resumed:
+// true if it has to go through interrupted_entry before returning to ring 3
+pub fn needs_attention() -> bool
+{
+    let Some(pid) = current() else { return false };
+
+    // the table is never held across an interrupt on a single core, but do not spin in one
+    PROCESSES.try_lock().is_some_and(|processes| processes.get(&pid)
+        .is_some_and(|process| process.exit_code.is_some() || process.signals.has_deliverable()))
+}
+
+
+// ---------- DELIVERY ----------
+// deliver the pending signals of the calling process before frame is resumed in ring 3:
+// ignored ones are dropped, default actions terminate or stop the process, and the first one
+// with a handler rewrites frame to run it (the rest waits until it returns)
+pub fn deliver(frame: &mut SyscallFrame)
+{
+    let Some(pid) = current() else { return };
+
+    loop
+    {
+        let next = PROCESSES.lock().get_mut(&pid).and_then(|process| process.signals.next());
+        let Some((sig, action, old_mask)) = next else { return };
+
+        match action.handler
+        {
+            Handler::Ignore => {}
+            Handler::Default => match default_action(sig)
+            {
+                DefaultAction::Terminate => exit(exit_code(sig)),
+                DefaultAction::Stop => wait_while_stopped(pid),
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+            },
+            Handler::User(handler) =>
+            {
+                // no room for the frame on the user stack: nothing else can be done
+                if setup_frame(frame, sig, handler, old_mask).is_err()
+                {
+                    exit(exit_code(SIGSEGV));
+                }
+                return;
+            }
+        }
+    }
+}
+
+// park until SIGCONT (or SIGKILL, or exit) ends the stop
+fn wait_while_stopped(pid: Pid)
+{
+    loop
+    {
+        let stopped = PROCESSES.lock().get(&pid).is_some_and(|process|
+        {
+            process.signals.stopped && process.exit_code.is_none() && !process.signals.pending.contains(SIGKILL)
+        });
+
+        if !stopped
+        {
+            return;
+        }
+
+        // send() unparks every thread of the process
+        thread::park();
+    }
+}
+
+// push a SignalFrame and point frame at the handler: handler(sig) with rsp % 16 == 8 like after a call,
+// returning into the trampoline page
+fn setup_frame(frame: &mut SyscallFrame, sig: u32, handler: VirtAddr, old_mask: SignalSet) -> Result<(), syscall::SyscallError>
+{
+    let size = size_of::<SignalFrame>() as u64;
+    let sp = frame.rsp.checked_sub(RED_ZONE + size).ok_or(syscall::SyscallError::BadAddress)?;
+    let sp = (sp & !0xf) - 8;
+
+    syscall::validate_user_range(sp, size, true)?;
+
+    let signal_frame = SignalFrame
+    {
+        return_address: TRAMPOLINE_ADDRESS,
+        signal: sig as u64,
+        mask: old_mask.bits(),
+        context: *frame,
+    };
+
+    // the active address space is the process's; copy on write pages are resolved by the page fault handler
+    unsafe { (sp as *mut SignalFrame).write(signal_frame); }
+
+    frame.rip = handler.as_u64();
+    frame.rsp = sp;
+    frame.rdi = sig as u64;
+    frame.rsi = 0;
+    frame.rdx = sp + core::mem::offset_of!(SignalFrame, context) as u64;
+    frame.rflags &= !(1 << 10);     // DF clear on function entry (System V ABI)
+
+    Ok(())
+}
+
+// sigreturn: called by the trampoline once a handler returns (rsp just above the return address)
+// restores the interrupted context and mask, and resumes it directly (rax, rcx and r11 included)
+pub fn sigreturn(frame: &mut SyscallFrame) -> !
+{
+    let address = frame.rsp.wrapping_sub(8);
+    let size = size_of::<SignalFrame>() as u64;
+
+    let signal_frame = syscall::validate_user_range(address, size, false)
+        .map(|_| unsafe { (address as *const SignalFrame).read() });
+
+    // a handler which wrecked its frame cannot be resumed
+    let Ok(signal_frame) = signal_frame else { exit(exit_code(SIGSEGV)) };
+
+    let mut context = signal_frame.context;
+    if context.rip >= USER_SPACE_END || context.rsp > USER_SPACE_END
+    {
+        exit(exit_code(SIGSEGV));
+    }
+    context.rflags = (context.rflags & USER_CHANGEABLE_FLAGS) | usermode::USER_RFLAGS;
+
+    if let Some(pid) = current()
+        && let Some(process) = PROCESSES.lock().get_mut(&pid)
+    {
+        process.signals.mask = SignalSet::from_bits(signal_frame.mask).difference(UNBLOCKABLE);
+    }
+
+    *frame = context;
+
+    // the restored mask may unblock pending signals
+    deliver(frame);
+    if super::exit_pending()
+    {
+        super::exit_thread();
+    }
+
+    unsafe { usermode::resume(frame) }
+}
+
+
+// ---------- TRAMPOLINE ----------
+// handlers return into this code: mov eax, SYS_SIGRETURN; syscall; ud2
+// user stacks are not executable, so unlike traditional Unix it cannot be pushed with the frame;
+// one read-only page holds it, mapped at TRAMPOLINE_ADDRESS in every process
+static TRAMPOLINE: OnceCell<PhysFrame> = OnceCell::uninit();
+
+fn trampoline_code() -> [u8; 9]
+{
+    let number = (SYS_SIGRETURN as u32).to_le_bytes();
+    [0xb8, number[0], number[1], number[2], number[3], 0x0f, 0x05, 0x0f, 0x0b]
+}
+
+// map the trampoline page into an address space (done by the loader for every program)
+pub fn map_trampoline(address_space: &AddressSpace) -> Result<(), MapToError<Size4KiB>>
+{
+    let frame = *TRAMPOLINE.try_get_or_init(||
+    {
+        let frame = crate::memory::allocate_zeroed_frame().expect("[ERR] No memory for the signal trampoline");
+        let code = trampoline_code();
+        let page = crate::memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), page, code.len()); }
+        frame
+    }).expect("[ERR] Signal trampoline initialised concurrently");
+
+    address_space.map_frame(VirtAddr::new(TRAMPOLINE_ADDRESS), frame, PageTableFlags::empty())
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn signal_state_delivery_order()
+{
+    let mut state = SignalState::new();
+
+    // ignored by default -> discarded
+    state.post(SIGCHLD);
+    assert!(state.pending().is_empty());
+
+    // blocked signals wait, the lowest unblocked one goes first
+    state.mask.insert(SIGUSR1);
+    state.post(SIGUSR1);
+    state.post(SIGTERM);
+    state.post(SIGINT);
+    assert_eq!(state.next().map(|(sig, ..)| sig), Some(SIGINT));
+    assert_eq!(state.next().map(|(sig, ..)| sig), Some(SIGTERM));
+    assert!(state.next().is_none());
+
+    state.mask.remove(SIGUSR1);
+    assert_eq!(state.next().map(|(sig, ..)| sig), Some(SIGUSR1));
+}
+
+#[test_case]
+fn signal_state_stop_and_continue()
+{
+    let mut state = SignalState::new();
+
+    state.post(SIGSTOP);
+    assert_eq!(state.next().map(|(sig, ..)| sig), Some(SIGSTOP));
+    assert!(state.is_stopped());
+
+    // SIGCONT resumes when posted, and discards pending stops
+    state.post(SIGTSTP);
+    state.post(SIGCONT);
+    assert!(!state.is_stopped());
+    assert!(!state.pending().contains(SIGTSTP));
+}
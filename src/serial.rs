@@ -1,24 +1,481 @@
-use uart_16550::SerialPort;
-use spin::Mutex;
-use lazy_static::lazy_static;
-
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+// 16550 UARTs on the four legacy COM ports: output by polling, input through the receive interrupt
+// (IRQ 4 for COM1/COM3, IRQ 3 for COM2/COM4) into a queue per port
+// COM1 carries the serial_print! output (and the test results); console() mirrors the VGA text to a port
+// as a driver it takes the PNP0501 devices of the ISA bus, one per port, if the UART is there
+
+use crate::drivers::model::{self, Device, DeviceMatch, Driver, DriverError};
+use crate::interrupts::{self, InterruptIndex};
+use crate::sync::IrqSafeMutex;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+
+// the divisor latch counts down from this
+const UART_CLOCK: u32 = 115_200;
+pub const DEFAULT_BAUD_RATE: u32 = 38_400;
+
+// max number of received bytes buffered per port between the interrupt handler and the reader
+const INPUT_QUEUE_SIZE: usize = 256;
+
+// registers (offsets from the base port); DLAB selects the divisor latch at 0 and 1
+const REG_DATA: u16 = 0;
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+
+const INTERRUPT_RECEIVED: u8 = 1 << 0;
+const FIFO_ENABLE_CLEAR_14: u8 = 0xc7;          // enable, clear both, interrupt at 14 bytes
+const LINE_8N1: u8 = 0x03;
+const LINE_DLAB: u8 = 1 << 7;
+const MODEM_DTR_RTS_OUT2: u8 = 0x0b;            // OUT2 gates the interrupt line
+const MODEM_LOOPBACK: u8 = 0x1e;
+const STATUS_DATA_READY: u8 = 1 << 0;
+const STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// sent in loopback mode by init, to see whether there is a UART at all
+const LOOPBACK_TEST_BYTE: u8 = 0xae;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort
+{
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort
+{
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    pub fn base(self) -> u16
+    {
+        match self
+        {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    pub fn interrupt(self) -> InterruptIndex
+    {
+        match self
+        {
+            ComPort::Com1 | ComPort::Com3 => InterruptIndex::Com1,
+            ComPort::Com2 | ComPort::Com4 => InterruptIndex::Com2,
+        }
+    }
+
+    fn index(self) -> usize
+    {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError
+{
+    BadBaudRate(u32),       // 0, above UART_CLOCK, or not a whole divisor of it
+    NotPresent,             // failed the loopback test
+}
+
+pub struct Uart
+{
+    port: ComPort,
+    baud_rate: u32,         // 0 before init
+    absent: bool,           // the last init found no UART
+}
+
+impl Uart
+{
+    pub const fn new(port: ComPort) -> Self
+    {
+        Uart { port, baud_rate: 0, absent: false }
+    }
+
+    pub fn is_initialised(&self) -> bool
+    {
+        self.baud_rate != 0
+    }
+
+    // init failed the loopback test; stays set until an init succeeds
+    pub fn is_absent(&self) -> bool
+    {
+        self.absent
+    }
+
+    pub fn baud_rate(&self) -> u32
+    {
+        self.baud_rate
+    }
+
+    // set up 8N1 at baud_rate with the FIFOs on, checking that the UART answers (in loopback mode)
+    pub fn init(&mut self, baud_rate: u32) -> Result<(), SerialError>
+    {
+        let divisor = divisor(baud_rate)?;
+
+        self.write(REG_INTERRUPT_ENABLE, 0);
+        self.set_divisor(divisor);
+        self.write(REG_FIFO_CONTROL, FIFO_ENABLE_CLEAR_14);
+
+        self.write(REG_MODEM_CONTROL, MODEM_LOOPBACK);
+        self.write(REG_DATA, LOOPBACK_TEST_BYTE);
+        if self.read(REG_DATA) != LOOPBACK_TEST_BYTE
+        {
+            self.baud_rate = 0;
+            self.absent = true;
+            return Err(SerialError::NotPresent);
+        }
+
+        self.write(REG_MODEM_CONTROL, MODEM_DTR_RTS_OUT2);
+        self.baud_rate = baud_rate;
+        self.absent = false;
+        Ok(())
+    }
+
+    pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), SerialError>
+    {
+        let divisor = divisor(baud_rate)?;
+
+        // the byte being sent would be garbled
+        self.wait_transmit_empty();
+        self.set_divisor(divisor);
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    pub fn send(&mut self, byte: u8)
+    {
+        self.wait_transmit_empty();
+        self.write(REG_DATA, byte);
+    }
+
+    // a received byte, if one is waiting
+    pub fn try_receive(&mut self) -> Option<u8>
+    {
+        (self.read(REG_LINE_STATUS) & STATUS_DATA_READY != 0).then(|| self.read(REG_DATA))
+    }
+
+    fn enable_receive_interrupt(&mut self)
+    {
+        self.write(REG_INTERRUPT_ENABLE, INTERRUPT_RECEIVED);
+    }
+
+    fn disable_interrupts(&mut self)
+    {
+        self.write(REG_INTERRUPT_ENABLE, 0);
+    }
+
+    fn set_divisor(&mut self, divisor: u16)
+    {
+        self.write(REG_LINE_CONTROL, LINE_8N1 | LINE_DLAB);
+        self.write(REG_DATA, divisor as u8);
+        self.write(REG_INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(REG_LINE_CONTROL, LINE_8N1);
+    }
+
+    fn wait_transmit_empty(&mut self)
+    {
+        while self.read(REG_LINE_STATUS) & STATUS_TRANSMIT_EMPTY == 0
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn read(&mut self, register: u16) -> u8
+    {
+        unsafe { Port::new(self.port.base() + register).read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8)
+    {
+        unsafe { Port::new(self.port.base() + register).write(value) }
+    }
+}
+
+impl fmt::Write for Uart
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for byte in s.bytes()
+        {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+fn divisor(baud_rate: u32) -> Result<u16, SerialError>
+{
+    if baud_rate == 0 || baud_rate > UART_CLOCK || !UART_CLOCK.is_multiple_of(baud_rate)
+    {
+        return Err(SerialError::BadBaudRate(baud_rate));
+    }
+
+    Ok((UART_CLOCK / baud_rate) as u16)
+}
+
+// the UARTs, by ComPort
+static PORTS: [IrqSafeMutex<Uart>; 4] =
+[
+    IrqSafeMutex::new(Uart::new(ComPort::Com1)),
+    IrqSafeMutex::new(Uart::new(ComPort::Com2)),
+    IrqSafeMutex::new(Uart::new(ComPort::Com3)),
+    IrqSafeMutex::new(Uart::new(ComPort::Com4)),
+];
+
+// received bytes, filled by the interrupt handler (allocated by enable_input, not in the handler)
+static INPUT_QUEUES: [OnceCell<ArrayQueue<u8>>; 4] = [const { OnceCell::uninit() }; 4];
+static INPUT_WAKERS: [AtomicWaker; 4] = [const { AtomicWaker::new() }; 4];
+
+// number of received bytes lost because a queue was full
+static DROPPED_INPUT: AtomicU64 = AtomicU64::new(0);
+
+// port mirroring the VGA console (an index into PORTS), NO_CONSOLE if none
+const NO_CONSOLE: u8 = u8::MAX;
+static CONSOLE: AtomicU8 = AtomicU8::new(NO_CONSOLE);
+
+// the UART of a port; initialise it (init) before using it, COM1 is initialised by the first serial_print!
+pub fn port(port: ComPort) -> &'static IrqSafeMutex<Uart>
+{
+    &PORTS[port.index()]
+}
+
+// initialise a port (again, if it was) with this baud rate
+pub fn init(port: ComPort, baud_rate: u32) -> Result<(), SerialError>
+{
+    PORTS[port.index()].lock().init(baud_rate)
+}
+
+// start queueing what the port receives (see SerialInput), initialising it at DEFAULT_BAUD_RATE if
+// it was not yet
+pub fn enable_input(port: ComPort) -> Result<(), SerialError>
+{
+    let mut uart = PORTS[port.index()].lock();
+    if !uart.is_initialised()
+    {
+        uart.init(DEFAULT_BAUD_RATE)?;
+    }
+
+    INPUT_QUEUES[port.index()].init_once(|| ArrayQueue::new(INPUT_QUEUE_SIZE));
+    uart.enable_receive_interrupt();
+    drop(uart);
+
+    interrupts::unmask_irq(port.interrupt());
+    Ok(())
+}
+
+// stop the receive interrupt of a port (what it received so far stays queued); enable_input restarts it
+pub fn disable_input(port: ComPort)
+{
+    PORTS[port.index()].lock().disable_interrupts();
+}
+
+// the UARTs: probe checks that one answers at the device's ports (COM1 may be in use already)
+pub struct SerialDriver;
+
+impl SerialDriver
+{
+    fn com_port(device: &Device) -> Result<ComPort, DriverError>
+    {
+        let base = device.io_base().ok_or(DriverError::NoDevice)?;
+        ComPort::ALL.into_iter().find(|port| port.base() == base).ok_or(DriverError::NoDevice)
+    }
+}
+
+impl Driver for SerialDriver
+{
+    fn name(&self) -> &'static str
+    {
+        "serial"
+    }
+
+    fn ids(&self) -> &'static [DeviceMatch]
+    {
+        &[DeviceMatch::Isa(model::PNP_UART)]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        let mut uart = PORTS[Self::com_port(device)?.index()].lock();
+        if uart.is_initialised()
+        {
+            return Ok(());
+        }
+
+        match uart.init(DEFAULT_BAUD_RATE)
+        {
+            Ok(()) => Ok(()),
+            Err(SerialError::NotPresent) => Err(DriverError::NoDevice),
+            Err(error) => Err(DriverError::failed(error)),
+        }
+    }
+
+    fn remove(&self, device: &Device)
+    {
+        if let Ok(port) = Self::com_port(device)
+        {
+            disable_input(port);
+        }
+    }
+
+    fn suspend(&self, device: &Device) -> Result<(), DriverError>
+    {
+        disable_input(Self::com_port(device)?);
+        Ok(())
+    }
+
+    fn resume(&self, device: &Device) -> Result<(), DriverError>
+    {
+        let port = Self::com_port(device)?;
+        if INPUT_QUEUES[port.index()].is_initialized()
+        {
+            enable_input(port).map_err(DriverError::failed)?;
+        }
+        Ok(())
+    }
+}
+
+// mirror everything printed on the VGA console to this port (with CR LF line ends), None to stop
+pub fn set_console(port: Option<ComPort>)
+{
+    CONSOLE.store(port.map_or(NO_CONSOLE, |port| port as u8), Ordering::Release);
+}
+
+pub fn console() -> Option<ComPort>
+{
+    ComPort::ALL.get(CONSOLE.load(Ordering::Acquire) as usize).copied()
+}
+
+// total number of received bytes dropped so far
+pub fn dropped_input() -> u64
+{
+    DROPPED_INPUT.load(Ordering::Relaxed)
+}
+
+// called by the COM interrupt handlers, for the ports sharing the interrupt
+// must not block or allocate!
+pub(crate) fn receive_interrupt(index: InterruptIndex)
+{
+    for port in ComPort::ALL.into_iter().filter(|port| port.interrupt() == index)
+    {
+        let Ok(queue) = INPUT_QUEUES[port.index()].try_get() else { continue };
+
+        let mut uart = PORTS[port.index()].lock();
+        while let Some(byte) = uart.try_receive()
+        {
+            if queue.push(byte).is_err()
+            {
+                DROPPED_INPUT.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        INPUT_WAKERS[port.index()].wake();
+    }
+}
+
+// async stream of the bytes a port receives
+pub struct SerialInput
+{
+    port: ComPort,
+}
+
+impl SerialInput
+{
+    // enable_input has to be called for the port first
+    pub fn new(port: ComPort) -> Self
+    {
+        assert!(INPUT_QUEUES[port.index()].is_initialized(), "[ERR] Serial input not enabled on {:?}", port);
+        SerialInput { port }
+    }
+}
+
+impl Stream for SerialInput
+{
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>>
+    {
+        let index = self.port.index();
+        let queue = INPUT_QUEUES[index].try_get().expect("[ERR] Serial input queue not initialised");
+
+        // fast path, avoids registering the waker
+        if let Some(byte) = queue.pop()
+        {
+            return Poll::Ready(Some(byte));
+        }
+
+        INPUT_WAKERS[index].register(cx.waker());
+
+        // check again: a byte may have been pushed before the waker was registered
+        match queue.pop()
+        {
+            Some(byte) =>
+            {
+                INPUT_WAKERS[index].take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
-    })
+    // interrupts are disabled while the lock is held, so nothing here may panic: the panic handler
+    // prints through the same lock
+    // without a UART on COM1 the output goes nowhere (the loopback test is only run once)
+    let mut com1 = PORTS[ComPort::Com1.index()].lock();
+    if com1.is_absent() || (!com1.is_initialised() && com1.init(DEFAULT_BAUD_RATE).is_err())
+    {
+        return;
+    }
+    let _ = com1.write_fmt(args);
+}
+
+// VGA output, mirrored to the console port (vga_buffer::_print)
+#[doc(hidden)]
+pub fn _print_console(args: fmt::Arguments)
+{
+    use core::fmt::Write;
 
+    let Some(port) = console() else { return };
+    let mut uart = PORTS[port.index()].lock();
+    if uart.is_absent() || !uart.is_initialised()
+    {
+        return;
+    }
+    let _ = ConsoleWriter(&mut uart).write_fmt(args);
+}
+
+// terminal line ends: \n becomes \r\n
+struct ConsoleWriter<'a>(&'a mut Uart);
+
+impl fmt::Write for ConsoleWriter<'_>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for byte in s.bytes()
+        {
+            if byte == b'\n'
+            {
+                self.0.send(b'\r');
+            }
+            self.0.send(byte);
+        }
+        Ok(())
+    }
 }
 
 /// Prints to the host through the serial interface.
@@ -36,4 +493,28 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+
+
+// ---------- TESTS ----------
+
+#[test_case]
+fn baud_rate_divisors()
+{
+    assert_eq!(divisor(115_200), Ok(1));
+    assert_eq!(divisor(38_400), Ok(3));
+    assert_eq!(divisor(9_600), Ok(12));
+    assert_eq!(divisor(0), Err(SerialError::BadBaudRate(0)));
+    assert_eq!(divisor(100_000), Err(SerialError::BadBaudRate(100_000)));
+}
+
+#[test_case]
+fn com1_is_initialised_by_printing()
+{
+    serial_print!("");
+
+    let com1 = port(ComPort::Com1).lock();
+    assert!(com1.is_initialised());
+    assert_eq!(com1.baud_rate(), DEFAULT_BAUD_RATE);
+}
@@ -0,0 +1,349 @@
+// symmetric multiprocessing: start the application processors (APs) listed in the MADT
+// each AP is woken with INIT-SIPI-SIPI, runs the trampoline below from real mode up to long mode,
+// loads its own GDT/TSS and the shared IDT, and then idles; the bootstrap processor (BSP) is CPU 0
+
+use crate::{acpi, apic, gdt, hlt_loop, interrupts, memory, percpu, serial_println, syscall, thread};
+use crate::acpi::AcpiError;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use conquer_once::spin::OnceCell;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::Translate;
+use x86_64::{PhysAddr, VirtAddr};
+
+// stack of an AP's idle loop (interrupts from ring 3 use the TSS stacks, see gdt::init_ap)
+const AP_STACK_SIZE: usize = 4096 * 4;
+
+// how long to wait for an AP after each step of the startup sequence
+const INIT_DELAY_MS: u64 = 10;
+const STARTUP_DELAY_MS: u64 = 10;
+const STARTUP_TIMEOUT_MS: u64 = 100;
+
+// CPUs running kernel code (the BSP included)
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+// set by an AP once it no longer needs the trampoline
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+static CPUS: OnceCell<Vec<Cpu>> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu
+{
+    pub index: usize,   // 0 for the BSP, then the APs in MADT order
+    pub apic_id: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError
+{
+    Acpi(AcpiError),
+    NoLowMemory,                // no frame below 1 MiB for the trampoline
+    MappingFailed,
+    StartupTimeout(u8),         // the AP with this APIC ID did not come up
+    AlreadyStarted,
+}
+
+impl From<AcpiError> for SmpError
+{
+    fn from(error: AcpiError) -> Self
+    {
+        SmpError::Acpi(error)
+    }
+}
+
+// AP startup code, copied to a frame below 1 MiB (see boot_aps)
+// position independent: the AP starts with CS = frame >> 4, so the frame's physical address is CS << 4;
+// the GDT pointer and the far jump targets are patched with it before they are used
+// the parameters are the 4 quads at the end: CR3, stack top, entry point and its argument
+core::arch::global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    xor ebx, ebx",
+    "    mov bx, ax",
+    "    shl ebx, 4",
+    // patch the GDT pointer and the far jumps with physical addresses
+    "    lea eax, [ebx + AP_GDT]",
+    "    mov dword ptr [AP_GDT_POINTER + 2], eax",
+    "    lea eax, [ebx + AP_PROTECTED_MODE]",
+    "    mov dword ptr [AP_JUMP_32], eax",
+    "    lea eax, [ebx + AP_LONG_MODE]",
+    "    mov dword ptr [AP_JUMP_64], eax",
+    "    lgdt [AP_GDT_POINTER]",
+    // protected mode; the far jump takes a 32 bit offset (operand size prefix)
+    "    mov eax, cr0",
+    "    or eax, 1",
+    "    mov cr0, eax",
+    "    .byte 0x66",
+    "    ljmp dword ptr [AP_JUMP_32]",
+    ".code32",
+    ".Lap_protected_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    // PAE, the kernel's page table, long mode and no-execute, paging and write protect
+    "    mov eax, cr4",
+    "    or eax, 1 << 5",
+    "    mov cr4, eax",
+    "    mov eax, [ebx + AP_PARAMETERS]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xc0000080",
+    "    rdmsr",
+    "    or eax, (1 << 8) | (1 << 11)",
+    "    wrmsr",
+    "    mov eax, cr0",
+    "    or eax, 0x80010000",
+    "    mov cr0, eax",
+    "    ljmp dword ptr [ebx + AP_JUMP_64]",
+    ".code64",
+    ".Lap_long_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov ebx, ebx",
+    "    mov rsp, [rbx + AP_PARAMETERS + 8]",
+    "    mov rdi, [rbx + AP_PARAMETERS + 24]",
+    "    mov rax, [rbx + AP_PARAMETERS + 16]",
+    "    call rax",
+    "    ud2",
+    // null, 32 bit code, data, 64 bit code
+    ".balign 8",
+    ".Lap_gdt:",
+    "    .quad 0",
+    "    .quad 0x00cf9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "    .quad 0x00af9a000000ffff",
+    ".Lap_gdt_pointer:",
+    "    .word .Lap_gdt_pointer - .Lap_gdt - 1",
+    "    .long 0",
+    ".balign 4",
+    ".Lap_jump_32:",
+    "    .long 0",
+    "    .word 0x08",
+    ".Lap_jump_64:",
+    "    .long 0",
+    "    .word 0x18",
+    ".balign 8",
+    ".Lap_parameters:",
+    "    .quad 0",
+    "    .quad 0",
+    "    .quad 0",
+    "    .quad 0",
+    "ap_trampoline_end:",
+    // offsets from the start (memory operands take only one symbol)
+    ".set AP_GDT, .Lap_gdt - ap_trampoline_start",
+    ".set AP_GDT_POINTER, .Lap_gdt_pointer - ap_trampoline_start",
+    ".set AP_JUMP_32, .Lap_jump_32 - ap_trampoline_start",
+    ".set AP_JUMP_64, .Lap_jump_64 - ap_trampoline_start",
+    ".set AP_PROTECTED_MODE, .Lap_protected_mode - ap_trampoline_start",
+    ".set AP_LONG_MODE, .Lap_long_mode - ap_trampoline_start",
+    ".set AP_PARAMETERS, .Lap_parameters - ap_trampoline_start",
+    ".popsection",
+);
+
+unsafe extern "C"
+{
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+}
+
+// trampoline parameters, in the order of the quads at its end
+#[repr(C)]
+struct TrampolineParameters
+{
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+}
+
+// start every enabled AP in the MADT, one at a time
+// needs interrupts enabled (the delays count timer ticks) and the global memory (memory::init_global)
+// returns the number of CPUs online
+pub fn init() -> Result<usize, SmpError>
+{
+    if CPUS.is_initialized()
+    {
+        return Err(SmpError::AlreadyStarted);
+    }
+
+    let madt = acpi::madt()?;
+    apic::init(madt.local_apic_address).map_err(|_| SmpError::MappingFailed)?;
+    // the BSP receives IPIs too (TLB shootdowns)
+    apic::enable();
+
+    // the BSP first, then the APs in MADT order (as many as there are per-CPU slots for)
+    let bsp_id = apic::id();
+    let mut cpus = alloc::vec![Cpu { index: 0, apic_id: bsp_id }];
+    cpus.extend(madt.processors.iter()
+        .filter(|processor| processor.enabled && processor.apic_id != bsp_id)
+        .take(percpu::MAX_CPUS - 1)
+        .enumerate()
+        .map(|(index, processor)| Cpu { index: index + 1, apic_id: processor.apic_id }));
+
+    let result = boot_aps(&cpus[1..]);
+    CPUS.init_once(|| cpus);
+
+    result.map(|_| cpu_count())
+}
+
+// CPUs online
+pub fn cpu_count() -> usize
+{
+    ONLINE.load(Ordering::Acquire)
+}
+
+// the CPUs found by init (only the BSP before)
+pub fn cpus() -> &'static [Cpu]
+{
+    CPUS.get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn boot_aps(aps: &[Cpu]) -> Result<(), SmpError>
+{
+    if aps.is_empty()
+    {
+        return Ok(());
+    }
+
+    let frame = memory::allocate_low_frame().ok_or(SmpError::NoLowMemory)?;
+    let identity_mapped = identity_map(frame)?;
+
+    let trampoline = unsafe
+    {
+        let start = &raw const ap_trampoline_start;
+        let len = (&raw const ap_trampoline_end).offset_from(start) as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    let code = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    unsafe { core::ptr::copy_nonoverlapping(trampoline.as_ptr(), code, trampoline.len()); }
+
+    let parameters = unsafe { &mut *(code.add(trampoline.len() - size_of::<TrampolineParameters>()) as *mut TrampolineParameters) };
+    let cr3 = memory::kernel_page_table().expect("[ERR] Memory not initialised").start_address().as_u64();
+    assert!(cr3 < u32::MAX as u64, "[ERR] Kernel page table above 4 GiB");
+
+    let mut result = Ok(());
+    for cpu in aps
+    {
+        let stack: &'static mut [u8] = alloc::vec![0; AP_STACK_SIZE].leak();
+
+        *parameters = TrampolineParameters
+        {
+            cr3,
+            stack_top: (VirtAddr::from_ptr(stack.as_ptr()) + AP_STACK_SIZE as u64).align_down(16u64).as_u64(),
+            entry: ap_entry as *const () as u64,
+            argument: cpu.index as u64,
+        };
+        AP_STARTED.store(false, Ordering::SeqCst);
+
+        if !start_ap(cpu.apic_id, frame)
+        {
+            // a late AP would run on the next one's parameters -> give up on the rest
+            result = Err(SmpError::StartupTimeout(cpu.apic_id));
+            break;
+        }
+    }
+
+    if identity_mapped
+    {
+        memory::unmap_region(VirtAddr::new(frame.start_address().as_u64()), memory::PAGE_SIZE as u64)
+            .map_err(|_| SmpError::MappingFailed)?;
+    }
+    if result.is_ok()
+    {
+        memory::free_frame(frame);
+    }
+
+    result
+}
+
+// INIT-SIPI-SIPI, then wait for the AP to report in; false on timeout
+fn start_ap(apic_id: u8, trampoline: PhysFrame) -> bool
+{
+    let page = (trampoline.start_address().as_u64() / 4096) as u8;
+
+    apic::send_init(apic_id);
+    thread::sleep_ms(INIT_DELAY_MS);
+
+    // a second SIPI only if the first one was missed
+    apic::send_startup(apic_id, page);
+    if !wait_started(STARTUP_DELAY_MS)
+    {
+        apic::send_startup(apic_id, page);
+    }
+
+    wait_started(STARTUP_TIMEOUT_MS)
+}
+
+fn wait_started(ms: u64) -> bool
+{
+    let deadline = interrupts::ticks() + ms.div_ceil(1000 / interrupts::TIMER_HZ);
+
+    while !AP_STARTED.load(Ordering::Acquire)
+    {
+        if interrupts::ticks() > deadline
+        {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+
+    true
+}
+
+// the trampoline's code and paging have to agree while paging is switched on -> map the frame at its
+// physical address (in the kernel page table, which the APs start with)
+// returns whether a mapping was added
+fn identity_map(frame: PhysFrame) -> Result<bool, SmpError>
+{
+    let address = VirtAddr::new(frame.start_address().as_u64());
+
+    memory::with_memory(|mapper, frame_allocator|
+    {
+        match mapper.translate(address)
+        {
+            TranslateResult::Mapped { frame: mapped, .. } if mapped.start_address() == PhysAddr::new(address.as_u64()) => Ok(false),
+            TranslateResult::NotMapped =>
+            {
+                let page = Page::<Size4KiB>::containing_address(address);
+                unsafe
+                {
+                    mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, frame_allocator)
+                        .map_err(|_| SmpError::MappingFailed)?
+                        .flush();
+                }
+                Ok(true)
+            }
+            _ => Err(SmpError::MappingFailed),
+        }
+    })
+}
+
+// first Rust code of an AP, called by the trampoline on its own stack
+extern "C" fn ap_entry(index: u64) -> !
+{
+    percpu::init_ap(index as usize);
+    gdt::init_ap();
+    interrupts::init_idt();
+    syscall::init();
+    apic::enable();
+
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    AP_STARTED.store(true, Ordering::Release);
+
+    serial_println!("[SMP] CPU {} online (APIC ID {})", percpu::current().index(), apic::id());
+
+    // nothing to run yet: idle, woken by IPIs only (the PICs deliver to the BSP)
+    interrupts::enable_interrupts();
+    hlt_loop();
+}
@@ -0,0 +1,71 @@
+use super::{MutexGuard, WaitQueue};
+use crate::thread;
+
+// condition variable, used together with a sync::Mutex
+pub struct Condvar
+{
+    waiters: WaitQueue,
+}
+
+impl Condvar
+{
+    pub const fn new() -> Self
+    {
+        Condvar { waiters: WaitQueue::new() }
+    }
+
+    // atomically unlock the mutex and sleep until notified, then re-lock it
+    // wakeups can be spurious -> use wait_while, or re-check in a loop
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T>
+    {
+        let mutex = guard.mutex();
+
+        // queued before unlocking: a notify right after the unlock finds us
+        match self.waiters.enqueue_current()
+        {
+            Some(id) =>
+            {
+                drop(guard);
+                thread::park();
+                self.waiters.remove_thread(id);
+            }
+            None =>
+            {
+                // no threads yet, nobody could notify us anyway
+                drop(guard);
+                core::hint::spin_loop();
+            }
+        }
+
+        mutex.lock()
+    }
+
+    // wait until condition() is false
+    pub fn wait_while<'a, T>(&self, mut guard: MutexGuard<'a, T>, mut condition: impl FnMut(&mut T) -> bool) -> MutexGuard<'a, T>
+    {
+        while condition(&mut *guard)
+        {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub fn notify_one(&self) -> bool
+    {
+        self.waiters.notify_one()
+    }
+
+    pub fn notify_all(&self) -> usize
+    {
+        self.waiters.notify_all()
+    }
+}
+
+impl Default for Condvar
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
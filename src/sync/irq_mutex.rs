@@ -0,0 +1,154 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+// spinlock for data shared with interrupt handlers
+// interrupts are disabled while the lock is held (and restored to their previous state on unlock),
+// so a handler can never interrupt the holder and spin on the lock forever
+// ticket lock: waiters get the lock in the order they asked for it
+pub struct IrqSafeMutex<T: ?Sized>
+{
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for IrqSafeMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for IrqSafeMutex<T> {}
+
+impl<T> IrqSafeMutex<T>
+{
+    pub const fn new(data: T) -> Self
+    {
+        IrqSafeMutex
+        {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T
+    {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T>
+{
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T>
+    {
+        // disable first: being interrupted between taking a ticket and being served would stall every waiter
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket
+        {
+            core::hint::spin_loop();
+        }
+
+        IrqSafeMutexGuard { mutex: self, interrupts_enabled }
+    }
+
+    // only succeeds if nobody holds or waits for the lock
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>>
+    {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        let acquired = self.next_ticket
+            .compare_exchange(serving, serving + 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        if acquired
+        {
+            Some(IrqSafeMutexGuard { mutex: self, interrupts_enabled })
+        }
+        else
+        {
+            if interrupts_enabled
+            {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool
+    {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T
+    {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for IrqSafeMutex<T>
+{
+    fn default() -> Self
+    {
+        Self::new(T::default())
+    }
+}
+
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized>
+{
+    mutex: &'a IrqSafeMutex<T>,
+    interrupts_enabled: bool,   // interrupt state before lock()
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T>
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T>
+{
+    fn drop(&mut self)
+    {
+        self.mutex.now_serving.fetch_add(1, Ordering::Release);
+
+        if self.interrupts_enabled
+        {
+            interrupts::enable();
+        }
+    }
+}
+
+
+// --------- TEST CASES ----------
+#[test_case]
+fn test_irq_safe_mutex_disables_interrupts()
+{
+    let mutex = IrqSafeMutex::new(0);
+    let were_enabled = interrupts::are_enabled();
+
+    {
+        let mut guard = mutex.lock();
+        assert!(!interrupts::are_enabled());
+        assert!(mutex.try_lock().is_none());
+        *guard += 1;
+    }
+
+    assert_eq!(interrupts::are_enabled(), were_enabled);
+    assert_eq!(*mutex.lock(), 1);
+}
@@ -0,0 +1,137 @@
+// lock dependency checker (debug builds only)
+// every blocking lock reports its acquisitions/releases here; locks are identified by address
+// detects re-entrant acquisition (a thread locking a lock it already holds, which would sleep forever)
+// and lock-order inversions (A -> B in one place, B -> ... -> A in another), and panics with both locks
+
+#[cfg(debug_assertions)]
+mod checker
+{
+    use crate::thread::{self, ThreadId};
+    use alloc::collections::{BTreeMap, BTreeSet};
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use crate::sync::IrqSafeMutex;
+
+    struct LockGraph
+    {
+        held: BTreeMap<ThreadId, Vec<usize>>,   // locks held by each thread, in acquisition order
+        order: BTreeSet<(usize, usize)>,        // (a, b): b was acquired while holding a
+    }
+
+    static GRAPH: IrqSafeMutex<LockGraph> = IrqSafeMutex::new(LockGraph { held: BTreeMap::new(), order: BTreeSet::new() });
+
+    impl LockGraph
+    {
+        // is there a path from -> ... -> to in the order graph?
+        fn reachable(&self, from: usize, to: usize) -> bool
+        {
+            let mut stack = alloc::vec![from];
+            let mut visited = BTreeSet::new();
+
+            while let Some(lock) = stack.pop()
+            {
+                if lock == to
+                {
+                    return true;
+                }
+
+                if visited.insert(lock)
+                {
+                    let next = self.order.range((lock, 0)..=(lock, usize::MAX)).map(|&(_, b)| b);
+                    stack.extend(next);
+                }
+            }
+
+            false
+        }
+
+        // add lock to the locks held by id, returns a description of the violation if there is one
+        fn record(&mut self, id: ThreadId, lock: usize, name: &'static str, check: bool) -> Option<String>
+        {
+            let held = self.held.get(&id).cloned().unwrap_or_default();
+
+            if check && held.contains(&lock)
+            {
+                return Some(alloc::format!("re-entrant acquisition of {} {:#x} by thread {}", name, lock, id.as_u64()));
+            }
+
+            for &outer in held.iter().filter(|_| check)
+            {
+                if self.reachable(lock, outer)
+                {
+                    return Some(alloc::format!("lock order inversion: {} {:#x} acquired while holding {:#x} (thread {}), \
+                        but the opposite order was seen before", name, lock, outer, id.as_u64()));
+                }
+            }
+
+            for &outer in held.iter()
+            {
+                self.order.insert((outer, lock));
+            }
+            self.held.entry(id).or_default().push(lock);
+
+            None
+        }
+    }
+
+    pub fn acquire(lock: usize, name: &'static str)
+    {
+        record(lock, name, true);
+    }
+
+    // try_lock never sleeps, so it cannot deadlock: only remember that the lock is held
+    pub fn acquire_try(lock: usize, name: &'static str)
+    {
+        record(lock, name, false);
+    }
+
+    fn record(lock: usize, name: &'static str, check: bool)
+    {
+        let Some(id) = thread::current_id() else { return };
+
+        let violation = GRAPH.lock().record(id, lock, name, check);
+
+        // panic outside of the graph lock
+        if let Some(message) = violation
+        {
+            panic!("[ERR] Lockdep: {}", message);
+        }
+    }
+
+    pub fn release(lock: usize)
+    {
+        let Some(id) = thread::current_id() else { return };
+
+        let mut graph = GRAPH.lock();
+        if let Some(held) = graph.held.get_mut(&id)
+        {
+            // locks are not always released in reverse order
+            if let Some(index) = held.iter().rposition(|&held_lock| held_lock == lock)
+            {
+                held.remove(index);
+            }
+
+            if held.is_empty()
+            {
+                graph.held.remove(&id);
+            }
+        }
+    }
+
+    // the lock is being destroyed, its address may be reused by an unrelated lock
+    pub fn forget(lock: usize)
+    {
+        GRAPH.lock().order.retain(|&(a, b)| a != lock && b != lock);
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod checker
+{
+    pub fn acquire(_lock: usize, _name: &'static str) {}
+    pub fn acquire_try(_lock: usize, _name: &'static str) {}
+    pub fn release(_lock: usize) {}
+    pub fn forget(_lock: usize) {}
+}
+
+pub use checker::{acquire, acquire_try, forget, release};
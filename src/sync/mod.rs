@@ -0,0 +1,19 @@
+// synchronisation primitives
+// the blocking ones put the waiting thread to sleep (thread::park) instead of burning the CPU,
+// so they must not be used from interrupt handlers (except for the notify/unlock side);
+// data shared with interrupt handlers goes behind an IrqSafeMutex
+
+pub mod wait_queue;
+pub mod mutex;
+pub mod semaphore;
+pub mod condvar;
+pub mod rwlock;
+pub mod lockdep;
+pub mod irq_mutex;
+
+pub use wait_queue::WaitQueue;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use condvar::Condvar;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use irq_mutex::{IrqSafeMutex, IrqSafeMutexGuard};
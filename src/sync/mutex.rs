@@ -0,0 +1,161 @@
+use super::{lockdep, WaitQueue};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// sleeping mutex: a thread that finds it locked parks until the holder unlocks it
+pub struct Mutex<T: ?Sized>
+{
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T>
+{
+    pub const fn new(data: T) -> Self
+    {
+        Mutex
+        {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T
+    {
+        // Drop is implemented -> move the data out by hand
+        let this = core::mem::ManuallyDrop::new(self);
+        lockdep::forget(this.addr());
+        unsafe { core::ptr::read(&this.data).into_inner() }
+    }
+}
+
+impl<T: ?Sized> Mutex<T>
+{
+    fn addr(&self) -> usize
+    {
+        self as *const Self as *const u8 as usize
+    }
+
+    fn try_acquire(&self) -> bool
+    {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    // lock, sleeping while another thread holds the mutex
+    pub fn lock(&self) -> MutexGuard<'_, T>
+    {
+        lockdep::acquire(self.addr(), "Mutex");
+
+        while !self.try_acquire()
+        {
+            self.waiters.wait_if(|| self.locked.load(Ordering::Relaxed));
+        }
+
+        MutexGuard { mutex: self, tracked: true }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>>
+    {
+        if self.try_acquire()
+        {
+            lockdep::acquire_try(self.addr(), "Mutex");
+            Some(MutexGuard { mutex: self, tracked: true })
+        }
+        else
+        {
+            None
+        }
+    }
+
+    // lock from an async task; the task (not the executor thread) waits
+    // not tracked by lockdep, since tasks do not map to threads
+    pub async fn lock_async(&self) -> MutexGuard<'_, T>
+    {
+        while !self.try_acquire()
+        {
+            self.waiters.wait_if_async(|| self.locked.load(Ordering::Relaxed)).await;
+        }
+
+        MutexGuard { mutex: self, tracked: false }
+    }
+
+    pub fn is_locked(&self) -> bool
+    {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T
+    {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for Mutex<T>
+{
+    fn drop(&mut self)
+    {
+        lockdep::forget(self.addr());
+    }
+}
+
+impl<T: Default> Default for Mutex<T>
+{
+    fn default() -> Self
+    {
+        Self::new(T::default())
+    }
+}
+
+
+pub struct MutexGuard<'a, T: ?Sized>
+{
+    mutex: &'a Mutex<T>,
+    tracked: bool,  // acquisition was reported to lockdep
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T>
+{
+    // the mutex this guard belongs to (Condvar needs it to re-lock)
+    pub(super) fn mutex(&self) -> &'a Mutex<T>
+    {
+        self.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T>
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T>
+{
+    fn drop(&mut self)
+    {
+        if self.tracked
+        {
+            lockdep::release(self.mutex.addr());
+        }
+
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.notify_one();
+    }
+}
@@ -0,0 +1,170 @@
+use super::{lockdep, WaitQueue};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// state: number of readers, or WRITER when write-locked
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+// sleeping reader-writer lock
+// readers are preferred: a steady stream of readers can starve writers
+pub struct RwLock<T: ?Sized>
+{
+    state: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T>
+{
+    pub const fn new(data: T) -> Self
+    {
+        RwLock
+        {
+            state: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T>
+{
+    fn addr(&self) -> usize
+    {
+        self as *const Self as *const u8 as usize
+    }
+
+    fn try_acquire_read(&self) -> bool
+    {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| (state & WRITER == 0).then_some(state + 1))
+            .is_ok()
+    }
+
+    fn try_acquire_write(&self) -> bool
+    {
+        self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    // shared access, sleeping while a writer holds the lock
+    // (taking it twice on the same thread is reported by lockdep: a queued writer in between would deadlock)
+    pub fn read(&self) -> RwLockReadGuard<'_, T>
+    {
+        lockdep::acquire(self.addr(), "RwLock");
+
+        while !self.try_acquire_read()
+        {
+            self.waiters.wait_if(|| self.state.load(Ordering::Relaxed) & WRITER != 0);
+        }
+
+        RwLockReadGuard { lock: self }
+    }
+
+    // exclusive access, sleeping while there are readers or a writer
+    pub fn write(&self) -> RwLockWriteGuard<'_, T>
+    {
+        lockdep::acquire(self.addr(), "RwLock");
+
+        while !self.try_acquire_write()
+        {
+            self.waiters.wait_if(|| self.state.load(Ordering::Relaxed) != 0);
+        }
+
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>>
+    {
+        self.try_acquire_read().then(||
+        {
+            lockdep::acquire_try(self.addr(), "RwLock");
+            RwLockReadGuard { lock: self }
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>>
+    {
+        self.try_acquire_write().then(||
+        {
+            lockdep::acquire_try(self.addr(), "RwLock");
+            RwLockWriteGuard { lock: self }
+        })
+    }
+}
+
+impl<T: ?Sized> Drop for RwLock<T>
+{
+    fn drop(&mut self)
+    {
+        lockdep::forget(self.addr());
+    }
+}
+
+
+pub struct RwLockReadGuard<'a, T: ?Sized>
+{
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T>
+{
+    fn drop(&mut self)
+    {
+        lockdep::release(self.lock.addr());
+
+        // the last reader lets writers in
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1
+        {
+            self.lock.waiters.notify_all();
+        }
+    }
+}
+
+
+pub struct RwLockWriteGuard<'a, T: ?Sized>
+{
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T>
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T>
+{
+    fn drop(&mut self)
+    {
+        lockdep::release(self.lock.addr());
+
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.notify_all();
+    }
+}
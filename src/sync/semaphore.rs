@@ -0,0 +1,53 @@
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// counting semaphore
+pub struct Semaphore
+{
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore
+{
+    pub const fn new(permits: usize) -> Self
+    {
+        Semaphore { permits: AtomicUsize::new(permits), waiters: WaitQueue::new() }
+    }
+
+    pub fn try_acquire(&self) -> bool
+    {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| permits.checked_sub(1))
+            .is_ok()
+    }
+
+    // take a permit, sleeping until one is available
+    pub fn acquire(&self)
+    {
+        while !self.try_acquire()
+        {
+            self.waiters.wait_if(|| self.permits.load(Ordering::Relaxed) == 0);
+        }
+    }
+
+    pub async fn acquire_async(&self)
+    {
+        while !self.try_acquire()
+        {
+            self.waiters.wait_if_async(|| self.permits.load(Ordering::Relaxed) == 0).await;
+        }
+    }
+
+    // give a permit back (can be called from interrupt handlers)
+    pub fn release(&self)
+    {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn available(&self) -> usize
+    {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,207 @@
+use crate::thread::{self, ThreadId};
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use super::IrqSafeMutex;
+
+// something waiting on a queue: a kernel thread or an async task
+enum Waiter
+{
+    Thread(ThreadId),
+    Task(Waker),
+}
+
+impl Waiter
+{
+    fn wake(self)
+    {
+        match self
+        {
+            Waiter::Thread(id) => thread::unpark(id),
+            Waiter::Task(waker) => waker.wake(),
+        }
+    }
+}
+
+// FIFO queue of sleeping threads/tasks, the building block of every other primitive
+// the waiter list is an IrqSafeMutex, so notify_* can be called from interrupt handlers
+pub struct WaitQueue
+{
+    waiters: IrqSafeMutex<VecDeque<Waiter>>,
+}
+
+impl WaitQueue
+{
+    pub const fn new() -> Self
+    {
+        WaitQueue { waiters: IrqSafeMutex::new(VecDeque::new()) }
+    }
+
+    // sleep until notified, if condition() holds
+    // condition is checked under the queue lock, so a notify after the check cannot be missed;
+    // it runs with interrupts off and must not block (atomics only)
+    // returns whether the thread slept; wakeups can be spurious, callers re-check in a loop
+    pub fn wait_if(&self, condition: impl FnOnce() -> bool) -> bool
+    {
+        // before the first thread is spawned there is nobody to switch to -> spin
+        let Some(id) = thread::current_id() else
+        {
+            if condition()
+            {
+                core::hint::spin_loop();
+            }
+            return false;
+        };
+
+        let enqueued =
+        {
+            let mut waiters = self.waiters.lock();
+            if condition()
+            {
+                waiters.push_back(Waiter::Thread(id));
+                true
+            }
+            else
+            {
+                false
+            }
+        };
+
+        if enqueued
+        {
+            thread::park();
+            self.remove_thread(id);
+        }
+
+        enqueued
+    }
+
+    // add the current thread to the queue without sleeping (see Condvar::wait)
+    // the caller must park() and then call remove_thread()
+    pub(super) fn enqueue_current(&self) -> Option<ThreadId>
+    {
+        let id = thread::current_id()?;
+        self.waiters.lock().push_back(Waiter::Thread(id));
+        Some(id)
+    }
+
+    // drop a thread's entry if it is still queued (i.e. it woke up without being notified)
+    // a stale entry would otherwise swallow a later notify_one
+    pub(super) fn remove_thread(&self, id: ThreadId)
+    {
+        self.waiters.lock().retain(|waiter| !matches!(waiter, Waiter::Thread(queued) if *queued == id));
+    }
+
+    // async version of wait_if: resolves once notified (or immediately if condition() is false)
+    pub fn wait_if_async<C: FnMut() -> bool + Unpin>(&self, condition: C) -> WaitFuture<'_, C>
+    {
+        WaitFuture { queue: self, condition, registered: None }
+    }
+
+    // wake the oldest waiter, returns false if there was none
+    pub fn notify_one(&self) -> bool
+    {
+        let waiter = self.waiters.lock().pop_front();
+
+        match waiter
+        {
+            Some(waiter) =>
+            {
+                waiter.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // wake every waiter, returns how many were woken
+    pub fn notify_all(&self) -> usize
+    {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+
+        for waiter in waiters
+        {
+            waiter.wake();
+        }
+
+        count
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+
+// future returned by WaitQueue::wait_if_async
+pub struct WaitFuture<'a, C>
+{
+    queue: &'a WaitQueue,
+    condition: C,
+    registered: Option<Waker>,  // waker put on the queue by the first poll
+}
+
+impl<C: FnMut() -> bool + Unpin> Future for WaitFuture<'_, C>
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>
+    {
+        // polled again after being woken
+        if self.registered.is_some()
+        {
+            return Poll::Ready(());
+        }
+
+        let this = &mut *self;
+        let waiters_lock = &this.queue.waiters;
+        let condition = &mut this.condition;
+
+        let registered =
+        {
+            let mut waiters = waiters_lock.lock();
+            if condition()
+            {
+                waiters.push_back(Waiter::Task(cx.waker().clone()));
+                true
+            }
+            else
+            {
+                false
+            }
+        };
+
+        if registered
+        {
+            this.registered = Some(cx.waker().clone());
+            Poll::Pending
+        }
+        else
+        {
+            Poll::Ready(())
+        }
+    }
+}
+
+impl<C> Drop for WaitFuture<'_, C>
+{
+    // same as remove_thread: a stale entry would swallow a later notify_one
+    fn drop(&mut self)
+    {
+        if let Some(waker) = self.registered.take()
+        {
+            self.queue.waiters.lock().retain(|waiter| !matches!(waiter, Waiter::Task(queued) if queued.will_wake(&waker)));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use super::{dispatch, SyscallFrame};
+use crate::{gdt, percpu};
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+// legacy software interrupt entry (int 0x80), same ABI as SYSCALL
+pub const SYSCALL_INT_VECTOR: u8 = 0x80;
+
+// enable SYSCALL/SYSRET and point them at syscall_entry (the MSRs are per CPU, every CPU runs this)
+// needs the CPU's GDT and TSS (gdt::init, gdt::init_ap)
+pub fn init()
+{
+    let selectors = gdt::selectors();
+
+    Star::write(selectors.user_code, selectors.user_data, selectors.kernel_code, selectors.kernel_data)
+        .expect("[ERR] GDT layout does not allow SYSRET");
+
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+
+    // cleared on entry: no interrupts until we are on the kernel stack, no single-stepping, DF = 0 for the ABI
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+
+    unsafe
+    {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+
+    set_kernel_stack(gdt::kernel_stack());
+}
+
+// kernel stack for SYSCALL: unlike interrupts, SYSCALL does not switch stacks by itself
+// kept equal to TSS.RSP0: called on every thread switch, together with gdt::set_kernel_stack
+pub fn set_kernel_stack(stack_top: VirtAddr)
+{
+    percpu::current().set_kernel_stack(stack_top);
+}
+
+extern "C" fn dispatch_frame(frame: *mut SyscallFrame) -> u64
+{
+    dispatch(unsafe { &mut *frame })
+}
+
+// SYSCALL entry: rcx = user rip, r11 = user rflags, rsp = user stack (not switched!), GS = user's
+// saves the user context on the kernel stack as a SyscallFrame, and returns with SYSRET
+#[unsafe(naked)]
+pub(super) extern "C" fn syscall_entry()
+{
+    core::arch::naked_asm!(
+        // switch to the per-CPU area, and through it to the kernel stack (interrupts are masked)
+        "swapgs",
+        "mov gs:[{user_stack}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        "push qword ptr gs:[{user_stack}]",
+        "push r11",
+        "push rcx",
+
+        // rest of the SyscallFrame: user rcx and r11 (already clobbered by SYSCALL), then the registers
+        // (18 qwords pushed in total, the stack stays 16-byte aligned)
+        "push r11",
+        "push rcx",
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push rbp",
+        "push rbx",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+
+        "mov rdi, rsp",
+        "sti",
+        "call {dispatch}",
+        "cli",
+
+        // restore everything but rax, which holds the result
+        // (the handler may have changed the frame, e.g. execve)
+        "add rsp, 8",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "pop rbx",
+        "pop rbp",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+
+        // SYSRET takes rip and rflags from rcx and r11
+        "add rsp, 16",
+        "pop rcx",
+        "pop r11",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        user_stack = const percpu::USER_STACK_OFFSET,
+        kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+        dispatch = sym dispatch_frame,
+    );
+}
+
+// int 0x80 entry: the CPU already switched to RSP0 and pushed an interrupt frame
+// swaps GS if it came from ring 3 (and goes back to it)
+#[unsafe(naked)]
+pub(crate) extern "C" fn int80_entry()
+{
+    core::arch::naked_asm!(
+        "test byte ptr [rsp + 8], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
+
+        // SyscallFrame: copies of rsp, rflags and rip from the interrupt frame, then the registers
+        // (5 + 1 padding + 18 qwords pushed, the stack stays 16-byte aligned)
+        "sub rsp, 8",
+        "push qword ptr [rsp + 32]",
+        "push qword ptr [rsp + 32]",
+        "push qword ptr [rsp + 24]",
+        "push r11",
+        "push rcx",
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push rbp",
+        "push rbx",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+
+        "mov rdi, rsp",
+        "sti",
+        "call {dispatch}",
+        "cli",
+
+        "add rsp, 8",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "pop rbx",
+        "pop rbp",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+
+        // write rip, rflags and rsp back to the interrupt frame
+        "mov rcx, [rsp + 16]",
+        "mov [rsp + 48], rcx",
+        "mov rcx, [rsp + 24]",
+        "mov [rsp + 64], rcx",
+        "mov rcx, [rsp + 32]",
+        "mov [rsp + 72], rcx",
+
+        "pop rcx",
+        "pop r11",
+        "add rsp, 32",
+        "test byte ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "iretq",
+        dispatch = sym dispatch_frame,
+    );
+}
@@ -0,0 +1,580 @@
+use crate::memory::{self, USER_SPACE_END, USER_SPACE_START};
+use crate::loader::LoadError;
+use crate::process::{self, fd::Console, signal, File, Pid, ProcessError, SignalError};
+use crate::{thread, usermode};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::VirtAddr;
+
+pub mod entry;
+
+pub use entry::{init, SYSCALL_INT_VECTOR};
+
+// ---------- SYSCALL NUMBERS ----------
+// ABI (same as Linux x86-64): number in rax, arguments in rdi, rsi, rdx, r10, r8, r9, result in rax
+// a negative result is an error (-errno)
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_WRITE: u64 = 2;
+pub const SYS_GETPID: u64 = 3;
+pub const SYS_SLEEP: u64 = 4;
+pub const SYS_MMAP: u64 = 5;
+pub const SYS_YIELD: u64 = 6;
+pub const SYS_WAIT: u64 = 7;
+pub const SYS_GETPPID: u64 = 8;
+pub const SYS_CLOSE: u64 = 9;
+pub const SYS_FORK: u64 = 10;
+pub const SYS_EXECVE: u64 = 11;
+pub const SYS_SPAWN: u64 = 12;
+pub const SYS_KILL: u64 = 13;
+pub const SYS_SIGACTION: u64 = 14;
+pub const SYS_SIGPROCMASK: u64 = 15;
+pub const SYS_SIGRETURN: u64 = 16;
+
+// mmap protection bits
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// wait(WAIT_ANY, ...) waits for any child
+pub const WAIT_ANY: u64 = u64::MAX;
+
+// mmap(0, ...) hands out addresses from here upwards
+const MMAP_BASE: u64 = USER_SPACE_START + 0x0800_0000_0000;
+static NEXT_MMAP: AtomicU64 = AtomicU64::new(MMAP_BASE);
+
+// largest single write/mmap, keeps a bad length from tying up the kernel
+const MAX_WRITE_LEN: u64 = 1024 * 1024;
+const MAX_MMAP_LEN: u64 = 64 * 1024 * 1024;
+
+// limits for strings and string arrays (paths, argv, envp) read from user memory
+const MAX_STRING_LEN: u64 = 4096;
+const MAX_STRINGS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError
+{
+    NoSuchFile = 2,         // ENOENT
+    NoSuchProcess = 3,      // ESRCH
+    Interrupted = 4,        // EINTR
+    ArgumentsTooLarge = 7,  // E2BIG
+    NotExecutable = 8,      // ENOEXEC
+    BadFileDescriptor = 9,  // EBADF
+    NoChild = 10,           // ECHILD
+    OutOfMemory = 12,       // ENOMEM
+    BadAddress = 14,        // EFAULT
+    Busy = 16,              // EBUSY
+    InvalidArgument = 22,   // EINVAL
+    NoSuchSyscall = 38,     // ENOSYS
+}
+
+impl SyscallError
+{
+    // value returned in rax
+    pub fn as_return_value(self) -> u64
+    {
+        (-(self as i64)) as u64
+    }
+}
+
+impl From<ProcessError> for SyscallError
+{
+    fn from(error: ProcessError) -> Self
+    {
+        match error
+        {
+            ProcessError::NotAProcess => SyscallError::InvalidArgument,
+            ProcessError::NotFound => SyscallError::NoSuchFile,
+            ProcessError::Busy => SyscallError::Busy,
+            ProcessError::OutOfMemory | ProcessError::Load(LoadError::OutOfMemory) => SyscallError::OutOfMemory,
+            ProcessError::Load(LoadError::ArgumentsTooLarge) => SyscallError::ArgumentsTooLarge,
+            ProcessError::Load(_) => SyscallError::NotExecutable,
+        }
+    }
+}
+
+impl From<SignalError> for SyscallError
+{
+    fn from(error: SignalError) -> Self
+    {
+        match error
+        {
+            SignalError::InvalidSignal | SignalError::NotAProcess => SyscallError::InvalidArgument,
+            SignalError::NoSuchProcess => SyscallError::NoSuchProcess,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+// user context saved by the entry stubs; the layout matches their push order
+// handlers may change it (except rax, which gets the result) to resume somewhere else
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SyscallFrame
+{
+    pub rax: u64,   // syscall number
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rcx: u64,   // clobbered by SYSCALL, only meaningful for interrupted user code
+    pub r11: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame
+{
+    // fresh context for a program starting at entry (see usermode::resume)
+    pub fn new_user(entry: VirtAddr, stack_pointer: VirtAddr) -> Self
+    {
+        SyscallFrame
+        {
+            rip: entry.as_u64(),
+            rsp: stack_pointer.as_u64(),
+            rflags: usermode::USER_RFLAGS,
+            ..SyscallFrame::default()
+        }
+    }
+
+    pub fn args(&self) -> [u64; 6]
+    {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+type SyscallHandler = fn(&mut SyscallFrame) -> SyscallResult;
+
+// dispatch table, indexed by syscall number
+static SYSCALL_TABLE: [Option<SyscallHandler>; 17] =
+[
+    None,
+    Some(sys_exit),     // SYS_EXIT
+    Some(sys_write),    // SYS_WRITE
+    Some(sys_getpid),   // SYS_GETPID
+    Some(sys_sleep),    // SYS_SLEEP
+    Some(sys_mmap),     // SYS_MMAP
+    Some(sys_yield),    // SYS_YIELD
+    Some(sys_wait),     // SYS_WAIT
+    Some(sys_getppid),  // SYS_GETPPID
+    Some(sys_close),    // SYS_CLOSE
+    Some(sys_fork),     // SYS_FORK
+    Some(sys_execve),   // SYS_EXECVE
+    Some(sys_spawn),    // SYS_SPAWN
+    Some(sys_kill),         // SYS_KILL
+    Some(sys_sigaction),    // SYS_SIGACTION
+    Some(sys_sigprocmask),  // SYS_SIGPROCMASK
+    Some(sys_sigreturn),    // SYS_SIGRETURN
+];
+
+// called by both entry stubs (syscall and int 0x80) with interrupts enabled
+// returns the value for rax (the frame's rax, signal delivery may have replaced the context)
+pub(crate) extern "C" fn dispatch(frame: &mut SyscallFrame) -> u64
+{
+    let handler = usize::try_from(frame.rax).ok()
+        .and_then(|number| SYSCALL_TABLE.get(number).copied().flatten());
+
+    let result = match handler
+    {
+        Some(handler) => handler(frame),
+        None => Err(SyscallError::NoSuchSyscall),
+    };
+
+    frame.rax = match result
+    {
+        Ok(value) => value,
+        Err(error) => error.as_return_value(),
+    };
+
+    // pending signals run their handlers first; the result is part of the saved context
+    signal::deliver(frame);
+
+    // another thread of the process called exit() -> do not return to ring 3
+    if process::exit_pending()
+    {
+        process::exit_thread();
+    }
+
+    frame.rax
+}
+
+
+// ---------- ARGUMENT VALIDATION ----------
+// check that [ptr, ptr + len) is mapped and accessible from ring 3 (and writable, if required)
+pub(crate) fn validate_user_range(ptr: u64, len: u64, writable: bool) -> Result<(), SyscallError>
+{
+    if len == 0
+    {
+        return Ok(());
+    }
+
+    let end = ptr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    if ptr < USER_SPACE_START || end > USER_SPACE_END
+    {
+        return Err(SyscallError::BadAddress);
+    }
+
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(ptr));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+
+    // the caller's address space is the active one
+    for page in Page::range_inclusive(first, last)
+    {
+        match memory::translate_active(page.start_address())
+        {
+            // copy on write pages become writable on the first write (see the page fault handler)
+            Some((_, flags)) if flags.contains(required)
+                && (!writable || flags.intersects(PageTableFlags::WRITABLE | memory::COPY_ON_WRITE)) => {}
+            _ => return Err(SyscallError::BadAddress),
+        }
+    }
+
+    Ok(())
+}
+
+// borrow a validated user buffer
+fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], SyscallError>
+{
+    validate_user_range(ptr, len, false)?;
+
+    if len == 0
+    {
+        return Ok(&[]);
+    }
+
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+
+// copy a NUL terminated string out of user memory
+fn user_string(ptr: u64) -> Result<String, SyscallError>
+{
+    let mut bytes = Vec::new();
+    loop
+    {
+        if bytes.len() as u64 >= MAX_STRING_LEN
+        {
+            return Err(SyscallError::ArgumentsTooLarge);
+        }
+
+        let address = ptr.checked_add(bytes.len() as u64).ok_or(SyscallError::BadAddress)?;
+        match user_slice(address, 1)?[0]
+        {
+            0 => break,
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)
+}
+
+// copy a NULL terminated array of strings (argv, envp) out of user memory; ptr 0 is an empty array
+fn user_strings(ptr: u64) -> Result<Vec<String>, SyscallError>
+{
+    let mut strings = Vec::new();
+    if ptr == 0
+    {
+        return Ok(strings);
+    }
+
+    loop
+    {
+        if strings.len() >= MAX_STRINGS
+        {
+            return Err(SyscallError::ArgumentsTooLarge);
+        }
+
+        let address = ptr.checked_add(8 * strings.len() as u64).ok_or(SyscallError::BadAddress)?;
+        let entry = user_slice(address, 8)?;
+        match u64::from_le_bytes(entry.try_into().unwrap())
+        {
+            0 => break,
+            string => strings.push(user_string(string)?),
+        }
+    }
+
+    Ok(strings)
+}
+
+// open file behind fd, in the calling process
+// threads outside of a process (ring 3 tests) only have stdout and stderr, on the console
+fn file(fd: u64) -> Result<Arc<dyn File>, SyscallError>
+{
+    match process::current()
+    {
+        Some(pid) => process::with_process(pid, |process| process.files().get(fd as usize))
+            .flatten()
+            .ok_or(SyscallError::BadFileDescriptor),
+        None if fd == STDOUT || fd == STDERR => Ok(Arc::new(Console)),
+        None => Err(SyscallError::BadFileDescriptor),
+    }
+}
+
+
+// ---------- HANDLERS ----------
+// exit(code)
+fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult
+{
+    process::exit(frame.rdi as i32);
+}
+
+// write(fd, buf, len) -> bytes written
+fn sys_write(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let [fd, buf, len, ..] = frame.args();
+
+    let file = file(fd)?;
+
+    if len > MAX_WRITE_LEN
+    {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let bytes = user_slice(buf, len)?;
+    file.write(bytes).map(|written| written as u64)
+}
+
+// getpid() -> id of the calling process (of the calling thread, outside of a process)
+fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult
+{
+    if let Some(pid) = process::current()
+    {
+        return Ok(pid.as_u64());
+    }
+
+    thread::current_id().map(|id| id.as_u64()).ok_or(SyscallError::InvalidArgument)
+}
+
+// getppid() -> id of the parent process, 0 for the kernel
+fn sys_getppid(_frame: &mut SyscallFrame) -> SyscallResult
+{
+    let parent = process::current().and_then(|pid| process::with_process(pid, |process| process.parent())).flatten();
+    Ok(parent.map_or(0, |parent| parent.as_u64()))
+}
+
+// wait(pid, status) -> pid of the reaped child
+// pid WAIT_ANY waits for any child; the exit code is stored as an i32 at status, unless it is 0
+fn sys_wait(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let [pid, status, ..] = frame.args();
+
+    if status != 0
+    {
+        validate_user_range(status, size_of::<i32>() as u64, true)?;
+    }
+
+    let pid = (pid != WAIT_ANY).then(|| Pid::from_u64(pid));
+    let (child, code) = process::wait(pid).map_err(|error| match error
+    {
+        process::WaitError::NoSuchChild => SyscallError::NoChild,
+        process::WaitError::Interrupted => SyscallError::Interrupted,
+    })?;
+
+    if status != 0
+    {
+        unsafe { (status as *mut i32).write_unaligned(code); }
+    }
+
+    Ok(child.as_u64())
+}
+
+// close(fd)
+fn sys_close(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let pid = process::current().ok_or(SyscallError::BadFileDescriptor)?;
+
+    let closed = process::with_process(pid, |process| process.files_mut().close(frame.rdi as usize)).flatten();
+
+    // drop the file outside of the process table lock
+    closed.map(|_| 0).ok_or(SyscallError::BadFileDescriptor)
+}
+
+// sleep(ms), fails with EINTR if a signal arrives first
+fn sys_sleep(frame: &mut SyscallFrame) -> SyscallResult
+{
+    if thread::sleep_ms_interruptible(frame.rdi, signal::interrupted)
+    {
+        Ok(0)
+    }
+    else
+    {
+        Err(SyscallError::Interrupted)
+    }
+}
+
+// mmap(addr, len, prot) -> address of new zeroed, anonymous memory
+// addr 0 lets the kernel choose; otherwise it must be page aligned and unmapped
+fn sys_mmap(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let [addr, len, prot, ..] = frame.args();
+
+    if len == 0 || len > MAX_MMAP_LEN || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+    {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let len = len.next_multiple_of(memory::PAGE_SIZE as u64);
+
+    let start = if addr == 0
+    {
+        NEXT_MMAP.fetch_add(len, Ordering::Relaxed)
+    }
+    else
+    {
+        addr
+    };
+
+    if start % memory::PAGE_SIZE as u64 != 0
+    {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let end = start.checked_add(len).ok_or(SyscallError::InvalidArgument)?;
+    if start < USER_SPACE_START || end > USER_SPACE_END
+    {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0
+    {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0
+    {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    // threads without an address space of their own map into the kernel's user half
+    let result = match thread::current_address_space()
+    {
+        Some(address_space) => address_space.map(VirtAddr::new(start), len, flags),
+        None => memory::map_user_region(VirtAddr::new(start), len, flags),
+    };
+
+    result.map_err(|error| match error
+    {
+        MapToError::FrameAllocationFailed => SyscallError::OutOfMemory,
+        _ => SyscallError::InvalidArgument,     // (partly) mapped already
+    })?;
+
+    Ok(start)
+}
+
+// fork() -> pid of the child in the parent, 0 in the child
+fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let child = process::fork(frame)?;
+    Ok(child.as_u64())
+}
+
+// execve(path, argv, envp): does not return on success
+fn sys_execve(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let [path, argv, envp, ..] = frame.args();
+
+    // everything has to be copied before the old address space goes away
+    let path = user_string(path)?;
+    let argv = user_strings(argv)?;
+    let envp = user_strings(envp)?;
+
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+
+    process::exec(frame, &path, &argv, &envp)?;
+
+    // the new program starts with rax = 0, like every other register
+    Ok(0)
+}
+
+// spawn(path, argv, envp) -> pid of a new child running the program (fork + execve without the copy)
+fn sys_spawn(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let [path, argv, envp, ..] = frame.args();
+
+    let path = user_string(path)?;
+    let argv = user_strings(argv)?;
+    let envp = user_strings(envp)?;
+
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+
+    let child = process::spawn_path(&path, &argv, &envp)?;
+    Ok(child.as_u64())
+}
+
+// yield()
+fn sys_yield(_frame: &mut SyscallFrame) -> SyscallResult
+{
+    thread::yield_now();
+    Ok(0)
+}
+
+// kill(pid, sig): send a signal; sig 0 only checks that pid exists
+fn sys_kill(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let [pid, sig, ..] = frame.args();
+
+    let sig = u32::try_from(sig).map_err(|_| SyscallError::InvalidArgument)?;
+    signal::send(Pid::from_u64(pid), sig)?;
+    Ok(0)
+}
+
+// sigaction(sig, handler, mask) -> previous handler
+// handler is SIG_DFL, SIG_IGN or the address of handler(sig); mask is blocked while it runs
+fn sys_sigaction(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let [sig, handler, mask, ..] = frame.args();
+
+    let sig = u32::try_from(sig).map_err(|_| SyscallError::InvalidArgument)?;
+    let handler = match handler
+    {
+        signal::SIG_DFL => signal::Handler::Default,
+        signal::SIG_IGN => signal::Handler::Ignore,
+        address if (USER_SPACE_START..USER_SPACE_END).contains(&address) => signal::Handler::User(VirtAddr::new(address)),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+
+    let old = signal::set_action(sig, signal::SigAction { handler, mask: signal::SignalSet::from_bits(mask) })?;
+
+    Ok(match old.handler
+    {
+        signal::Handler::Default => signal::SIG_DFL,
+        signal::Handler::Ignore => signal::SIG_IGN,
+        signal::Handler::User(address) => address.as_u64(),
+    })
+}
+
+// sigprocmask(how, set) -> previous mask (SIG_BLOCK, SIG_UNBLOCK or SIG_SETMASK)
+fn sys_sigprocmask(frame: &mut SyscallFrame) -> SyscallResult
+{
+    let [how, set, ..] = frame.args();
+
+    let old = signal::change_mask(how, signal::SignalSet::from_bits(set))?;
+    Ok(old.bits())
+}
+
+// sigreturn(): only called by the signal trampoline, does not return
+fn sys_sigreturn(frame: &mut SyscallFrame) -> SyscallResult
+{
+    signal::sigreturn(frame);
+}
@@ -0,0 +1,147 @@
+use super::{Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+
+// max number of task ids that can be waiting in the ready queue
+const TASK_QUEUE_SIZE: usize = 100;
+
+// waker-based executor: only tasks that were woken up are polled again
+pub struct Executor
+{
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,    // shared with the wakers (which can be called from interrupt handlers)
+    waker_cache: BTreeMap<TaskId, Waker>,   // reuse the same waker for each poll of a task
+}
+
+impl Executor
+{
+    pub fn new() -> Self
+    {
+        Executor
+        {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task)
+    {
+        let task_id = task.id;
+
+        if self.tasks.insert(task.id, task).is_some()
+        {
+            panic!("[ERR] Task with same ID already in tasks");
+        }
+
+        // newly spawned tasks are ready to be polled
+        self.task_queue.push(task_id).expect("[ERR] Task queue full");
+    }
+
+    // poll every task in the ready queue once
+    fn run_ready_tasks(&mut self)
+    {
+        // destructure self to avoid borrowing it twice
+        let Self { tasks, task_queue, waker_cache } = self;
+
+        while let Some(task_id) = task_queue.pop()
+        {
+            let task = match tasks.get_mut(&task_id)
+            {
+                Some(task) => task,
+                None => continue,   // task no longer exists
+            };
+
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
+
+            let mut context = Context::from_waker(waker);
+
+            match task.poll(&mut context)
+            {
+                Poll::Ready(()) =>
+                {
+                    // task done -> remove it and its cached waker
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    // halt the CPU if there is nothing to do
+    fn sleep_if_idle(&self)
+    {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        // an interrupt could push to the queue right after the check, and we would
+        // sleep through it -> disable interrupts for the check, re-enable atomically with hlt
+        interrupts::disable();
+
+        if self.task_queue.is_empty()
+        {
+            enable_and_hlt();
+        }
+        else
+        {
+            interrupts::enable();
+        }
+    }
+
+    // run the executor forever
+    pub fn run(&mut self) -> !
+    {
+        loop
+        {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+}
+
+impl Default for Executor
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+
+// waker that pushes its task id back into the ready queue
+struct TaskWaker
+{
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker
+{
+    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker
+    {
+        Waker::from(Arc::new(TaskWaker { task_id, task_queue }))
+    }
+
+    // called from interrupt handlers -> must not panic on a full queue, the wake is dropped instead
+    // (harmless for a task that is already queued, which is how the queue usually fills up)
+    fn wake_task(&self)
+    {
+        let _ = self.task_queue.push(self.task_id);
+    }
+}
+
+impl Wake for TaskWaker
+{
+    fn wake(self: Arc<Self>)
+    {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>)
+    {
+        self.wake_task();
+    }
+}
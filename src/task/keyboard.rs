@@ -0,0 +1,275 @@
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use crate::{print, println, process};
+use crate::drivers::ps2::{self, PortIndex};
+use crate::process::signal;
+use super::keymap::{self, KeyDecoder, KeyPress};
+
+// max number of raw scancodes buffered between the interrupt handler and the keyboard task
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+// keyboard command: the next byte sets the lock LEDs (see keymap::LED_*)
+const KEYBOARD_SET_LEDS: u8 = 0xed;
+
+// times a byte of an LED update is sent again on RESEND before the update is given up
+const MAX_LED_RESENDS: u8 = 3;
+
+// lock-free bounded queue, filled by the interrupt handler
+// OnceCell -> initialised by ScancodeStream::new (allocating in the handler is not allowed)
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+// waker of the task currently waiting on the stream
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// number of scancodes lost because the queue was full (or not initialised yet)
+static DROPPED_SCANCODES: AtomicU64 = AtomicU64::new(0);
+
+// called by the keyboard interrupt handler
+// must not block or allocate!
+pub(crate) fn add_scancode(scancode: u8)
+{
+    if let Ok(queue) = SCANCODE_QUEUE.try_get()
+    {
+        if queue.push(scancode).is_err()
+        {
+            DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+        }
+        else
+        {
+            WAKER.wake();
+        }
+    }
+    else
+    {
+        DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// total number of scancodes dropped so far
+pub fn dropped_scancodes() -> u64
+{
+    DROPPED_SCANCODES.load(Ordering::Relaxed)
+}
+
+
+// async stream of raw scancodes
+pub struct ScancodeStream
+{
+    _private: (),   // prevent construction without new()
+}
+
+impl ScancodeStream
+{
+    // only one stream may exist, since there is only one queue (and one waker)
+    pub fn new() -> Self
+    {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+            .expect("[ERR] ScancodeStream::new should only be called once");
+
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream
+{
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>>
+    {
+        let queue = SCANCODE_QUEUE.try_get().expect("[ERR] Scancode queue not initialised");
+
+        // fast path, avoids registering the waker
+        if let Some(scancode) = queue.pop()
+        {
+            return Poll::Ready(Some(scancode));
+        }
+
+        WAKER.register(cx.waker());
+
+        // check again: a scancode may have been pushed before the waker was registered
+        match queue.pop()
+        {
+            Some(scancode) =>
+            {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+
+// keyboard task: decodes scancodes from the stream (in the layout set with keymap::set_layout) and
+// handles the key presses (handle_key)
+pub async fn print_keypresses()
+{
+    let mut scancodes = ScancodeStream::new();
+    let mut decoder = KeyDecoder::new(keymap::layout(), ps2::scancode_mode());
+    let mut leds = LedUpdate::new();
+    let mut last_dropped = 0;
+
+    leds.set(decoder.modifiers().leds());
+
+    while let Some(scancode) = scancodes.next().await
+    {
+        // report lost input (once per overflow, not per scancode)
+        let dropped = dropped_scancodes();
+        if dropped != last_dropped
+        {
+            println!("[WARN] Scancode queue full; {} scancode(s) dropped", dropped - last_dropped);
+            last_dropped = dropped;
+        }
+
+        // replies to the LED commands come in between the scancodes
+        if scancode == ps2::DEV_ACK || scancode == ps2::DEV_RESEND
+        {
+            leds.reply(scancode);
+            continue;
+        }
+
+        leds.scancode();
+        decoder.set_layout(keymap::layout());
+        let key = decoder.add_byte(scancode);
+        leds.set(decoder.modifiers().leds());
+
+        if let Some(key) = key
+        {
+            handle_key(&key);
+        }
+    }
+}
+
+// a key press from the keyboard (or the serial console): prints the character; Ctrl/Alt combinations
+// and keys without a character stay off the console
+// Ctrl+C sends SIGINT to the foreground process (see process::set_foreground)
+pub(crate) fn handle_key(key: &KeyPress)
+{
+    if key.is_ctrl('c')
+    {
+        print!("^C");
+        if let Some(pid) = process::foreground()
+        {
+            // it may have exited already
+            let _ = signal::send(pid, signal::SIGINT);
+        }
+    }
+    else if let Some(character) = key.printable()
+    {
+        print!("{}", character);
+    }
+}
+
+// sets the lock LEDs: KEYBOARD_SET_LEDS, then (once the keyboard acknowledged it) the LED byte
+// the replies arrive through the scancode queue, so this follows them instead of waiting
+// a lost reply must not stall it: a scancode arriving instead drops the byte waiting for it
+struct LedUpdate
+{
+    shown: Option<u8>,      // last LED byte the keyboard acknowledged
+    wanted: u8,
+    sent: Option<u8>,       // byte waiting for its reply
+    resends: u8,            // of the byte in sent
+}
+
+impl LedUpdate
+{
+    fn new() -> Self
+    {
+        LedUpdate { shown: None, wanted: 0, sent: None, resends: 0 }
+    }
+
+    fn set(&mut self, leds: u8)
+    {
+        self.wanted = leds;
+        if self.sent.is_none() && self.shown != Some(leds)
+        {
+            self.send(KEYBOARD_SET_LEDS);
+        }
+    }
+
+    fn reply(&mut self, reply: u8)
+    {
+        let Some(sent) = self.sent.take() else { return };
+
+        match (reply, sent)
+        {
+            (ps2::DEV_RESEND, byte) if self.resends < MAX_LED_RESENDS =>
+            {
+                let resends = self.resends + 1;
+                self.send(byte);
+                self.resends = resends;
+            }
+            (ps2::DEV_RESEND, _) =>
+            {
+                // give up on these LEDs; the next change of the locks tries again
+                self.shown = Some(self.wanted);
+            }
+            (_, KEYBOARD_SET_LEDS) => self.send(self.wanted),
+            (_, leds) =>
+            {
+                self.shown = Some(leds);
+
+                // the locks may have changed again meanwhile
+                self.set(self.wanted);
+            }
+        }
+    }
+
+    // a scancode came in: a reply still missing by now is lost, so drop the update (set sends it
+    // again)
+    fn scancode(&mut self)
+    {
+        if self.sent.take().is_some()
+        {
+            self.shown = None;
+        }
+    }
+
+    fn send(&mut self, byte: u8)
+    {
+        self.resends = 0;
+        if ps2::write_device(PortIndex::First, byte).is_ok()
+        {
+            self.sent = Some(byte);
+        }
+    }
+}
+
+// --------- TEST CASES ----------
+#[test_case]
+fn test_scancode_queue_overflow()
+{
+    let _stream = ScancodeStream::new();
+    let queue = SCANCODE_QUEUE.try_get().unwrap();
+    let dropped_before = dropped_scancodes();
+
+    // interrupts could queue real scancodes in between
+    x86_64::instructions::interrupts::without_interrupts(||
+    {
+        while queue.pop().is_some() {}
+
+        for scancode in 0..(SCANCODE_QUEUE_SIZE + 5)
+        {
+            add_scancode(scancode as u8);
+        }
+
+        assert_eq!(queue.len(), SCANCODE_QUEUE_SIZE);
+        assert_eq!(dropped_scancodes() - dropped_before, 5);
+
+        while queue.pop().is_some() {}
+    });
+}
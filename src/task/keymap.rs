@@ -0,0 +1,331 @@
+// scancode decoding for the keyboard task: the selected layout, modifier and lock key state, and
+// key presses which carry the modifiers they were made with
+// pc-keyboard does the translation; it does not track Alt and Scroll Lock, or show its own state,
+// so the modifiers are followed here from the same key events
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{layouts, DecodedKey, EventDecoder, HandleControl, KeyCode, KeyEvent, KeyState, ScancodeSet, ScancodeSet1, ScancodeSet2};
+use crate::drivers::ps2::ScancodeMode;
+
+// lock key LEDs, as the "set LEDs" keyboard command expects them
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout
+{
+    Us,
+    Uk,
+    De,
+    Dvorak,
+    Azerty,
+}
+
+impl Layout
+{
+    pub const ALL: [Layout; 5] = [Layout::Us, Layout::Uk, Layout::De, Layout::Dvorak, Layout::Azerty];
+
+    pub fn name(self) -> &'static str
+    {
+        match self
+        {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+            Layout::Dvorak => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    // the layout with this name (as name() gives it), None if there is none
+    pub fn from_name(name: &str) -> Option<Layout>
+    {
+        Self::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Layout
+    {
+        Self::ALL.get(value as usize).copied().unwrap_or(Layout::Us)
+    }
+
+    fn keymap(self) -> layouts::AnyLayout
+    {
+        match self
+        {
+            Layout::Us => layouts::AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk => layouts::AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::De => layouts::AnyLayout::De105Key(layouts::De105Key),
+            Layout::Dvorak => layouts::AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+            Layout::Azerty => layouts::AnyLayout::Azerty(layouts::Azerty),
+        }
+    }
+}
+
+// layout of the keyboard task, picked up with the next scancode
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+pub fn set_layout(layout: Layout)
+{
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+pub fn layout() -> Layout
+{
+    Layout::from_u8(LAYOUT.load(Ordering::Relaxed))
+}
+
+// held modifier keys (left or right) and lock states
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers
+{
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers
+{
+    // the LED byte for the current lock states
+    pub fn leds(&self) -> u8
+    {
+        let mut leds = 0;
+        if self.scroll_lock { leds |= LED_SCROLL_LOCK; }
+        if self.num_lock { leds |= LED_NUM_LOCK; }
+        if self.caps_lock { leds |= LED_CAPS_LOCK; }
+        leds
+    }
+}
+
+// a key pressed with the modifiers held at the time
+// letters stay letters with Ctrl and Alt held (Ctrl+C is Unicode('c') with ctrl set), keys without a
+// character are RawKey; modifier and lock keys themselves are not reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress
+{
+    pub key: DecodedKey,
+    pub modifiers: Modifiers,
+}
+
+impl KeyPress
+{
+    // the character to show for this key, None for raw keys and Ctrl/Alt combinations
+    pub fn printable(&self) -> Option<char>
+    {
+        match self.key
+        {
+            DecodedKey::Unicode(character) if !self.modifiers.ctrl && !self.modifiers.alt => Some(character),
+            _ => None,
+        }
+    }
+
+    pub fn is_ctrl(&self, letter: char) -> bool
+    {
+        self.modifiers.ctrl && self.key == DecodedKey::Unicode(letter)
+    }
+}
+
+// the scancode decoder for a ScancodeMode
+enum Scancodes
+{
+    Set1(ScancodeSet1),
+    Set2(ScancodeSet2),
+}
+
+impl Scancodes
+{
+    fn new(mode: ScancodeMode) -> Self
+    {
+        match mode
+        {
+            ScancodeMode::Translated => Scancodes::Set1(ScancodeSet1::new()),
+            ScancodeMode::Set2 => Scancodes::Set2(ScancodeSet2::new()),
+        }
+    }
+
+    fn advance_state(&mut self, byte: u8) -> Option<KeyEvent>
+    {
+        match self
+        {
+            Scancodes::Set1(set) => set.advance_state(byte).ok()?,
+            Scancodes::Set2(set) => set.advance_state(byte).ok()?,
+        }
+    }
+}
+
+// scancode bytes in, key presses out
+// (the layout can change without losing the lock states, which a new pc-keyboard Keyboard would)
+pub struct KeyDecoder
+{
+    scancodes: Scancodes,
+    events: EventDecoder<layouts::AnyLayout>,
+    layout: Layout,
+    modifiers: Modifiers,
+    // both shift and both control keys are tracked, so releasing one keeps the other held
+    shift: [bool; 2],
+    ctrl: [bool; 2],
+}
+
+impl KeyDecoder
+{
+    pub fn new(layout: Layout, mode: ScancodeMode) -> Self
+    {
+        KeyDecoder
+        {
+            scancodes: Scancodes::new(mode),
+            events: EventDecoder::new(layout.keymap(), HandleControl::Ignore),
+            layout,
+            // pc-keyboard starts out with num lock on
+            modifiers: Modifiers { num_lock: true, ..Modifiers::default() },
+            shift: [false; 2],
+            ctrl: [false; 2],
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers
+    {
+        self.modifiers
+    }
+
+    pub fn layout(&self) -> Layout
+    {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: Layout)
+    {
+        if layout != self.layout
+        {
+            self.events.change_layout(layout.keymap());
+            self.layout = layout;
+        }
+    }
+
+    // feed one byte; Some once it completes a key press
+    pub fn add_byte(&mut self, scancode: u8) -> Option<KeyPress>
+    {
+        let event = self.scancodes.advance_state(scancode)?;
+        self.track_held(&event);
+
+        match self.events.process_keyevent(event)?
+        {
+            DecodedKey::RawKey(KeyCode::CapsLock) => self.modifiers.caps_lock = !self.modifiers.caps_lock,
+            DecodedKey::RawKey(KeyCode::NumpadLock) => self.modifiers.num_lock = !self.modifiers.num_lock,
+            DecodedKey::RawKey(KeyCode::ScrollLock) => self.modifiers.scroll_lock = !self.modifiers.scroll_lock,
+            DecodedKey::RawKey(KeyCode::LShift | KeyCode::RShift | KeyCode::LControl | KeyCode::RControl
+                | KeyCode::RControl2 | KeyCode::LAlt | KeyCode::RAltGr) => {}
+            key => return Some(KeyPress { key, modifiers: self.modifiers }),
+        }
+
+        None
+    }
+
+    fn track_held(&mut self, event: &KeyEvent)
+    {
+        let down = event.state == KeyState::Down;
+        match event.code
+        {
+            KeyCode::LShift => self.shift[0] = down,
+            KeyCode::RShift => self.shift[1] = down,
+            KeyCode::LControl => self.ctrl[0] = down,
+            KeyCode::RControl => self.ctrl[1] = down,
+            KeyCode::LAlt => self.modifiers.alt = down,
+            KeyCode::RAltGr => self.modifiers.alt_gr = down,
+            _ => return,
+        }
+
+        self.modifiers.shift = self.shift[0] || self.shift[1];
+        self.modifiers.ctrl = self.ctrl[0] || self.ctrl[1];
+    }
+}
+
+
+
+// --------- TEST CASES ----------
+// feed scancodes, the last key press they make (if any)
+#[cfg(test)]
+fn press(decoder: &mut KeyDecoder, scancodes: &[u8]) -> Option<KeyPress>
+{
+    scancodes.iter().fold(None, |pressed, &scancode| decoder.add_byte(scancode).or(pressed))
+}
+
+#[test_case]
+fn test_shift_and_caps_lock()
+{
+    let mut decoder = KeyDecoder::new(Layout::Us, ScancodeMode::Translated);
+
+    // a, Shift+a, Caps Lock, a
+    assert_eq!(press(&mut decoder, &[0x1e, 0x9e]).and_then(|key| key.printable()), Some('a'));
+    assert_eq!(press(&mut decoder, &[0x2a, 0x1e]).and_then(|key| key.printable()), Some('A'));
+    assert!(decoder.modifiers().shift);
+    assert_eq!(press(&mut decoder, &[0x9e, 0xaa]), None);
+    assert!(!decoder.modifiers().shift);
+
+    assert_eq!(press(&mut decoder, &[0x3a, 0xba]), None);
+    assert_eq!(decoder.modifiers().leds(), LED_CAPS_LOCK | LED_NUM_LOCK);
+    assert_eq!(press(&mut decoder, &[0x1e]).and_then(|key| key.printable()), Some('A'));
+}
+
+#[test_case]
+fn test_ctrl_and_alt_combinations()
+{
+    let mut decoder = KeyDecoder::new(Layout::Us, ScancodeMode::Translated);
+
+    // Ctrl+C, then Alt+x: reported with the modifier, not printed
+    let ctrl_c = press(&mut decoder, &[0x1d, 0x2e]).expect("no key press");
+    assert!(ctrl_c.is_ctrl('c'));
+    assert_eq!(ctrl_c.printable(), None);
+    press(&mut decoder, &[0xae, 0x9d]);
+
+    let alt_x = press(&mut decoder, &[0x38, 0x2d]).expect("no key press");
+    assert!(alt_x.modifiers.alt && !alt_x.modifiers.ctrl);
+    assert_eq!(alt_x.key, DecodedKey::Unicode('x'));
+    press(&mut decoder, &[0xad, 0xb8]);
+    assert_eq!(decoder.modifiers(), Modifiers { num_lock: true, ..Modifiers::default() });
+}
+
+#[test_case]
+fn test_raw_keys_and_scroll_lock()
+{
+    let mut decoder = KeyDecoder::new(Layout::Us, ScancodeMode::Translated);
+
+    // modifiers alone are not key presses, F1 is a raw one
+    assert_eq!(press(&mut decoder, &[0x2a, 0xaa]), None);
+    assert_eq!(press(&mut decoder, &[0x3b]).map(|key| key.key), Some(DecodedKey::RawKey(KeyCode::F1)));
+    assert_eq!(press(&mut decoder, &[0x3b]).and_then(|key| key.printable()), None);
+
+    assert_eq!(press(&mut decoder, &[0x46, 0xc6]), None);
+    assert!(decoder.modifiers().scroll_lock);
+}
+
+#[test_case]
+fn test_layout_selection()
+{
+    let mut decoder = KeyDecoder::new(Layout::Us, ScancodeMode::Translated);
+    assert_eq!(press(&mut decoder, &[0x15]).map(|key| key.key), Some(DecodedKey::Unicode('y')));
+
+    // QWERTZ: y and z swap, AZERTY: q is where a is
+    decoder.set_layout(Layout::De);
+    assert_eq!(press(&mut decoder, &[0x15]).map(|key| key.key), Some(DecodedKey::Unicode('z')));
+    decoder.set_layout(Layout::Azerty);
+    assert_eq!(press(&mut decoder, &[0x1e]).map(|key| key.key), Some(DecodedKey::Unicode('q')));
+
+    assert_eq!(Layout::from_name("dvorak"), Some(Layout::Dvorak));
+    assert_eq!(Layout::from_name("qwerty"), None);
+}
+
+#[test_case]
+fn test_scancode_set_2()
+{
+    let mut decoder = KeyDecoder::new(Layout::Us, ScancodeMode::Set2);
+
+    // Shift+a, released with F0 prefixes
+    assert_eq!(press(&mut decoder, &[0x12, 0x1c]).and_then(|key| key.printable()), Some('A'));
+    assert_eq!(press(&mut decoder, &[0xf0, 0x1c, 0xf0, 0x12]), None);
+    assert_eq!(press(&mut decoder, &[0x1c]).and_then(|key| key.printable()), Some('a'));
+}
@@ -0,0 +1,55 @@
+use core::{future::Future, pin::Pin};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use alloc::boxed::Box;
+
+pub mod simple_executor;
+pub mod executor;
+pub mod keyboard;
+pub mod keymap;
+pub mod serial_console;
+
+// unique identifier for each task (used as the key in the executor's task map)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId
+{
+    fn new() -> Self
+    {
+        // ids are handed out once, they only need to be unique (not ordered across cores)
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+// a task is a heap-allocated, pinned future that returns nothing
+// pinning is required since async blocks can be self-referential
+pub struct Task
+{
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task
+{
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task
+    {
+        Task
+        {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId
+    {
+        self.id
+    }
+
+    // poll the underlying future once
+    fn poll(&mut self, context: &mut Context) -> Poll<()>
+    {
+        self.future.as_mut().poll(context)
+    }
+}
@@ -0,0 +1,201 @@
+// serial console input: bytes from a terminal (e.g. QEMU -serial stdio) become the key presses the
+// keyboard would have made, and go through the same handling (task::keyboard::handle_key)
+// the output side is the VGA mirror, see serial::set_console
+
+use futures_util::stream::StreamExt;
+use pc_keyboard::{DecodedKey, KeyCode};
+use crate::println;
+use crate::serial::{self, ComPort, SerialInput};
+use super::keyboard;
+use super::keymap::{KeyPress, Modifiers};
+
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+// what the decoder is in the middle of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState
+{
+    Ground,
+    Escape,                 // after ESC
+    Sequence(Option<u8>),   // after ESC [, with the number so far (ESC [ n ~)
+}
+
+// terminal bytes in, key presses out
+// control characters are Ctrl+letter, ESC followed by a character is Alt+character (the usual meta
+// convention, so a lone ESC is only reported with the next byte: ESC ESC is the Escape key), and the
+// VT100 / xterm sequences are the arrow and editing keys
+pub struct SerialKeyDecoder
+{
+    state: EscapeState,
+}
+
+impl SerialKeyDecoder
+{
+    pub const fn new() -> Self
+    {
+        SerialKeyDecoder { state: EscapeState::Ground }
+    }
+
+    // feed one byte; Some once it completes a key press
+    pub fn add_byte(&mut self, byte: u8) -> Option<KeyPress>
+    {
+        match (self.state, byte)
+        {
+            (EscapeState::Ground, ESC) =>
+            {
+                self.state = EscapeState::Escape;
+                None
+            }
+            (EscapeState::Ground, _) => Some(decode_byte(byte)),
+
+            (EscapeState::Escape, b'[') =>
+            {
+                self.state = EscapeState::Sequence(None);
+                None
+            }
+            (EscapeState::Escape, ESC) =>
+            {
+                self.state = EscapeState::Ground;
+                Some(plain(DecodedKey::Unicode(ESC as char)))
+            }
+            (EscapeState::Escape, _) =>
+            {
+                self.state = EscapeState::Ground;
+                let mut key = decode_byte(byte);
+                key.modifiers.alt = true;
+                Some(key)
+            }
+
+            (EscapeState::Sequence(number), b'0'..=b'9') =>
+            {
+                let digit = byte - b'0';
+                self.state = EscapeState::Sequence(Some(number.unwrap_or(0).saturating_mul(10).saturating_add(digit)));
+                None
+            }
+            (EscapeState::Sequence(number), _) =>
+            {
+                self.state = EscapeState::Ground;
+                sequence_key(number, byte).map(plain)
+            }
+        }
+    }
+}
+
+impl Default for SerialKeyDecoder
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+fn plain(key: DecodedKey) -> KeyPress
+{
+    KeyPress { key, modifiers: Modifiers::default() }
+}
+
+// a byte outside of escape sequences
+fn decode_byte(byte: u8) -> KeyPress
+{
+    match byte
+    {
+        // terminals send CR for Enter, and DEL (or BS) for Backspace
+        b'\r' | b'\n' => plain(DecodedKey::Unicode('\n')),
+        DEL | 0x08 => plain(DecodedKey::Unicode('\u{8}')),
+        b'\t' => plain(DecodedKey::Unicode('\t')),
+        0x01..=0x1a =>
+        {
+            let letter = (b'a' + byte - 1) as char;
+            KeyPress { key: DecodedKey::Unicode(letter), modifiers: Modifiers { ctrl: true, ..Modifiers::default() } }
+        }
+        _ => plain(DecodedKey::Unicode(byte as char)),
+    }
+}
+
+// the key of ESC [ (number) final, None for sequences without a key equivalent
+fn sequence_key(number: Option<u8>, last: u8) -> Option<DecodedKey>
+{
+    let code = match (number, last)
+    {
+        (None, b'A') => KeyCode::ArrowUp,
+        (None, b'B') => KeyCode::ArrowDown,
+        (None, b'C') => KeyCode::ArrowRight,
+        (None, b'D') => KeyCode::ArrowLeft,
+        (None, b'H') | (Some(1), b'~') => KeyCode::Home,
+        (None, b'F') | (Some(4), b'~') => KeyCode::End,
+        (Some(2), b'~') => KeyCode::Insert,
+        (Some(3), b'~') => return Some(DecodedKey::Unicode(DEL as char)),     // as the keyboard layouts map Delete
+        (Some(5), b'~') => KeyCode::PageUp,
+        (Some(6), b'~') => KeyCode::PageDown,
+        _ => return None,
+    };
+
+    Some(DecodedKey::RawKey(code))
+}
+
+// console task: enables input on port, mirrors the VGA console to it and handles what it receives
+pub async fn run(port: ComPort)
+{
+    if let Err(error) = serial::enable_input(port)
+    {
+        println!("[ERR] No serial console on {:?}: {:?}", port, error);
+        return;
+    }
+    serial::set_console(Some(port));
+
+    let mut input = SerialInput::new(port);
+    let mut decoder = SerialKeyDecoder::new();
+    let mut last_dropped = 0;
+
+    while let Some(byte) = input.next().await
+    {
+        // report lost input (once per overflow, not per byte)
+        let dropped = serial::dropped_input();
+        if dropped != last_dropped
+        {
+            println!("[WARN] Serial input queue full; {} byte(s) dropped", dropped - last_dropped);
+            last_dropped = dropped;
+        }
+
+        if let Some(key) = decoder.add_byte(byte)
+        {
+            keyboard::handle_key(&key);
+        }
+    }
+}
+
+
+
+// --------- TEST CASES ----------
+#[cfg(test)]
+fn decode_all(bytes: &[u8]) -> Option<KeyPress>
+{
+    let mut decoder = SerialKeyDecoder::new();
+    bytes.iter().fold(None, |pressed, &byte| decoder.add_byte(byte).or(pressed))
+}
+
+#[test_case]
+fn test_plain_and_control_bytes()
+{
+    assert_eq!(decode_all(b"x"), Some(plain(DecodedKey::Unicode('x'))));
+    assert_eq!(decode_all(b"\r"), Some(plain(DecodedKey::Unicode('\n'))));
+    assert_eq!(decode_all(&[DEL]), Some(plain(DecodedKey::Unicode('\u{8}'))));
+
+    let ctrl_c = decode_all(&[0x03]).expect("no key press");
+    assert!(ctrl_c.is_ctrl('c'));
+}
+
+#[test_case]
+fn test_escape_sequences()
+{
+    assert_eq!(decode_all(b"\x1b[A").map(|key| key.key), Some(DecodedKey::RawKey(KeyCode::ArrowUp)));
+    assert_eq!(decode_all(b"\x1b[6~").map(|key| key.key), Some(DecodedKey::RawKey(KeyCode::PageDown)));
+    assert_eq!(decode_all(b"\x1b[3~").map(|key| key.key), Some(DecodedKey::Unicode('\u{7f}')));
+    assert_eq!(decode_all(b"\x1b[99z"), None);
+
+    let alt_x = decode_all(b"\x1bx").expect("no key press");
+    assert!(alt_x.modifiers.alt);
+    assert_eq!(alt_x.key, DecodedKey::Unicode('x'));
+    assert_eq!(decode_all(b"\x1b\x1b").map(|key| key.key), Some(DecodedKey::Unicode('\u{1b}')));
+}
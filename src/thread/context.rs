@@ -0,0 +1,97 @@
+use alloc::boxed::Box;
+use alloc::vec;
+
+// kernel thread stack size (allocated on the heap)
+pub const STACK_SIZE: usize = 4096 * 4;
+
+// RFLAGS for new threads: IF (interrupts enabled) + reserved bit 1
+const INITIAL_RFLAGS: u64 = 0x202;
+
+// register state saved on a thread's stack when it is switched out
+// layout must match the push/pop order of the switch stubs in interrupts.rs (r15 is pushed last)
+// followed by the interrupt stack frame pushed by the CPU
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct SavedContext
+{
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    // pushed by the CPU on interrupt entry, consumed by iretq
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// heap-allocated kernel stack
+pub struct Stack
+{
+    memory: Box<[u8]>,
+}
+
+impl Stack
+{
+    pub fn new() -> Self
+    {
+        Stack { memory: vec![0; STACK_SIZE].into_boxed_slice() }
+    }
+
+    // 16-byte aligned top of the stack (stacks grow down)
+    pub fn top(&self) -> u64
+    {
+        let end = self.memory.as_ptr() as u64 + self.memory.len() as u64;
+        end & !0xf
+    }
+
+    // prepare the stack so that the first switch to it "returns" into entry(arg)
+    // returns the saved stack pointer for the scheduler
+    pub fn init(&mut self, entry: extern "C" fn(u64) -> !, arg: u64) -> u64
+    {
+        use x86_64::instructions::segmentation::{CS, Segment};
+
+        let top = self.top();
+
+        // fake return address slot: entry() sees rsp % 16 == 8, just like after a call
+        let entry_rsp = top - 8;
+        unsafe { *(entry_rsp as *mut u64) = 0; }
+
+        let context = SavedContext
+        {
+            rdi: arg,   // first argument (SysV ABI)
+            rip: entry as usize as u64,
+            cs: u64::from(CS::get_reg().0),
+            rflags: INITIAL_RFLAGS,
+            rsp: entry_rsp,
+            ss: 0,  // null selector is valid for ring 0 in long mode
+            ..SavedContext::default()
+        };
+
+        let context_ptr = (entry_rsp - core::mem::size_of::<SavedContext>() as u64) as *mut SavedContext;
+        unsafe { context_ptr.write(context); }
+
+        context_ptr as u64
+    }
+}
+
+impl Default for Stack
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
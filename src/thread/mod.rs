@@ -0,0 +1,436 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+pub mod context;
+pub mod policy;
+pub mod scheduler;
+
+use context::Stack;
+use policy::{SchedParams, NICE_MAX, NICE_MIN, PRIORITY_LEVELS};
+use scheduler::{with_scheduler, ThreadStats, SCHEDULER};
+use crate::memory::AddressSpace;
+use crate::process::Pid;
+use crate::sync::WaitQueue;
+
+// software interrupt used by yield_now to enter the scheduler
+pub const YIELD_VECTOR: u8 = 0x30;
+
+// unique identifier for each kernel thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId
+{
+    fn new() -> Self
+    {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64
+    {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState
+{
+    Ready,
+    Running,
+    Blocked,    // parked, waiting for unpark()
+    Finished,
+}
+
+pub struct Thread
+{
+    id: ThreadId,
+    state: ThreadState,
+    rsp: u64,               // saved stack pointer (points to a SavedContext) while switched out
+    params: SchedParams,
+    stats: ThreadStats,
+    unpark_token: bool,     // set by unpark() when the thread was not parked yet
+    kernel_stack_top: Option<VirtAddr>,     // RSP0 while this thread runs (used when it is in ring 3)
+    address_space: Option<Arc<AddressSpace>>,   // loaded into CR3 while it runs, None = kernel page table
+    process: Option<Pid>,   // None for kernel threads
+    exit_hook: Option<Box<dyn FnOnce() + Send>>,    // run by exit() (completes the JoinHandle)
+    _stack: Option<Stack>,  // None for the bootstrap thread, which runs on the boot stack
+}
+
+impl Thread
+{
+    fn new(entry: extern "C" fn(u64) -> !, arg: u64, params: SchedParams) -> Self
+    {
+        let mut stack = Stack::new();
+        let rsp = stack.init(entry, arg);
+
+        Thread
+        {
+            id: ThreadId::new(),
+            state: ThreadState::Ready,
+            rsp,
+            params,
+            stats: ThreadStats::default(),
+            unpark_token: false,
+            kernel_stack_top: Some(VirtAddr::new(stack.top())),
+            address_space: None,
+            process: None,
+            exit_hook: None,
+            _stack: Some(stack),
+        }
+    }
+
+    // the thread that was running before the scheduler existed (kernel_main)
+    // its context is saved by the first switch away from it
+    fn bootstrap() -> Self
+    {
+        Thread
+        {
+            id: ThreadId::new(),
+            state: ThreadState::Running,
+            rsp: 0,
+            params: SchedParams::default(),
+            stats: ThreadStats::default(),
+            unpark_token: false,
+            kernel_stack_top: None, // gdt::default_kernel_stack
+            address_space: None,
+            process: None,
+            exit_hook: None,
+            _stack: None,
+        }
+    }
+
+    pub fn id(&self) -> ThreadId
+    {
+        self.id
+    }
+
+    pub fn state(&self) -> ThreadState
+    {
+        self.state
+    }
+
+    pub fn params(&self) -> SchedParams
+    {
+        self.params
+    }
+
+    pub fn address_space(&self) -> Option<&Arc<AddressSpace>>
+    {
+        self.address_space.as_ref()
+    }
+
+    pub fn process(&self) -> Option<Pid>
+    {
+        self.process
+    }
+}
+
+
+// result slot shared between a thread and its JoinHandle
+struct Packet<T>
+{
+    result: Mutex<Option<T>>,
+    finished: AtomicBool,
+    joiners: WaitQueue,
+}
+
+// handle to wait for a spawned thread and get its return value
+pub struct JoinHandle<T>
+{
+    id: ThreadId,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T>
+{
+    pub fn id(&self) -> ThreadId
+    {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool
+    {
+        self.packet.finished.load(Ordering::Acquire)
+    }
+
+    // block until the thread exits (by returning, or by calling exit())
+    pub fn wait(&self)
+    {
+        while !self.is_finished()
+        {
+            self.packet.joiners.wait_if(|| !self.packet.finished.load(Ordering::Acquire));
+        }
+    }
+
+    // block until the thread returns, and get its return value
+    // panics if the thread called exit() instead of returning
+    pub fn join(self) -> T
+    {
+        self.wait();
+        self.packet.result.lock().take().expect("[ERR] Thread exited without returning a value")
+    }
+}
+
+
+// thread configuration, for threads which need non-default scheduling parameters
+#[derive(Debug, Default, Clone)]
+pub struct Builder
+{
+    params: SchedParams,
+    address_space: Option<Arc<AddressSpace>>,
+    process: Option<Pid>,
+}
+
+impl Builder
+{
+    pub fn new() -> Self
+    {
+        Builder::default()
+    }
+
+    // fixed priority level, 0 (lowest) to PRIORITY_LEVELS - 1
+    pub fn priority(mut self, priority: u8) -> Self
+    {
+        self.params.priority = priority.min(PRIORITY_LEVELS as u8 - 1);
+        self
+    }
+
+    // nice value for the fair-share policy, -20 (biggest share) to 19
+    pub fn nice(mut self, nice: i8) -> Self
+    {
+        self.params.nice = nice.clamp(NICE_MIN, NICE_MAX);
+        self
+    }
+
+    // run the thread in a user address space (for threads which enter ring 3)
+    pub fn address_space(mut self, address_space: Arc<AddressSpace>) -> Self
+    {
+        self.address_space = Some(address_space);
+        self
+    }
+
+    // make the thread part of a process (see process::spawn)
+    pub fn process(mut self, pid: Pid) -> Self
+    {
+        self.process = Some(pid);
+        self
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet = Arc::new(Packet
+        {
+            result: Mutex::new(None),
+            finished: AtomicBool::new(false),
+            joiners: WaitQueue::new(),
+        });
+        let thread_packet = packet.clone();
+
+        // the closure (and where to put its result) is passed to the new thread as a raw pointer
+        let result_packet = packet.clone();
+        let main: Box<dyn FnOnce() + Send> = Box::new(move ||
+        {
+            let value = f();
+            *result_packet.result.lock() = Some(value);
+        });
+        let arg = Box::into_raw(Box::new(main)) as u64;
+
+        let mut thread = Thread::new(thread_entry, arg, self.params);
+        thread.address_space = self.address_space;
+        thread.process = self.process;
+        thread.exit_hook = Some(Box::new(move ||
+        {
+            thread_packet.finished.store(true, Ordering::Release);
+            thread_packet.joiners.notify_all();
+        }));
+        let id = thread.id;
+
+        let finished = with_scheduler(|scheduler|
+        {
+            scheduler.add(thread);
+            scheduler.take_finished()
+        });
+
+        // free the stacks of exited threads outside the scheduler lock
+        drop(finished);
+
+        JoinHandle { id, packet }
+    }
+}
+
+// spawn a new kernel thread running f
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f)
+}
+
+// first function run by every spawned thread
+extern "C" fn thread_entry(arg: u64) -> !
+{
+    let main = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>) };
+    main();
+
+    exit();
+}
+
+// terminate the current thread
+pub fn exit() -> !
+{
+    // run the hook outside of the scheduler lock, waking the joiners locks it again
+    let exit_hook = SCHEDULER.lock().as_mut().and_then(|scheduler| scheduler.take_exit_hook());
+    if let Some(exit_hook) = exit_hook
+    {
+        exit_hook();
+    }
+
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() && let Some(current) = scheduler.current()
+    {
+        scheduler.set_state(current, ThreadState::Finished);
+    }
+
+    // finished threads are never scheduled again
+    loop
+    {
+        yield_now();
+    }
+}
+
+// give up the rest of the time slice
+pub fn yield_now()
+{
+    unsafe
+    {
+        core::arch::asm!("int {vector}", vector = const YIELD_VECTOR);
+    }
+}
+
+// block the current thread for at least `ticks` timer ticks
+pub fn sleep_ticks(ticks: u64)
+{
+    sleep_ticks_interruptible(ticks, || false);
+}
+
+// sleep_ticks, but give up early if interrupted() holds after a wakeup (an unpark)
+// returns false if the sleep was cut short
+pub fn sleep_ticks_interruptible(ticks: u64, interrupted: impl Fn() -> bool) -> bool
+{
+    let deadline = crate::interrupts::ticks() + ticks;
+
+    // an unpark can end the sleep early -> loop until the deadline
+    while crate::interrupts::ticks() < deadline
+    {
+        if interrupted()
+        {
+            return false;
+        }
+
+        without_interrupts(||
+        {
+            if with_scheduler(|scheduler| scheduler.sleep_current(deadline))
+            {
+                yield_now();
+            }
+        });
+    }
+
+    true
+}
+
+pub fn sleep_ms(ms: u64)
+{
+    sleep_ticks(ms_to_ticks(ms));
+}
+
+pub fn sleep_ms_interruptible(ms: u64, interrupted: impl Fn() -> bool) -> bool
+{
+    sleep_ticks_interruptible(ms_to_ticks(ms), interrupted)
+}
+
+fn ms_to_ticks(ms: u64) -> u64
+{
+    ms.div_ceil(1000 / crate::interrupts::TIMER_HZ)
+}
+
+// block the current thread until unpark() is called for it
+// returns immediately if unpark() was already called since the last park (wakeups are never lost),
+// callers must still re-check their condition: a stale unpark can end a later park early
+pub fn park()
+{
+    without_interrupts(||
+    {
+        // the switch happens with interrupts off; they are restored from the saved RFLAGS on return
+        if with_scheduler(|scheduler| scheduler.park_current())
+        {
+            yield_now();
+        }
+    });
+}
+
+// wake a parked thread (or make its next park() return immediately)
+// safe to call from interrupt handlers
+pub fn unpark(id: ThreadId)
+{
+    with_scheduler(|scheduler| scheduler.unpark(id));
+}
+
+// id of the running thread (None before the first spawn, and on CPUs without threads)
+// kept in the per-CPU area, no need to lock the scheduler
+pub fn current_id() -> Option<ThreadId>
+{
+    crate::percpu::current().current_thread().map(ThreadId)
+}
+
+// address space of the running thread (None for kernel threads)
+pub fn current_address_space() -> Option<Arc<AddressSpace>>
+{
+    SCHEDULER.lock().as_ref()
+        .and_then(|scheduler| scheduler.threads.get(&scheduler.current()?))
+        .and_then(|thread| thread.address_space.clone())
+}
+
+// give the running thread a new address space, and switch to it (execve)
+// returns the old one, which can be dropped now that it is no longer active
+pub fn replace_address_space(address_space: Arc<AddressSpace>) -> Option<Arc<AddressSpace>>
+{
+    with_scheduler(|scheduler|
+    {
+        let current = scheduler.current().expect("[ERR] No thread running on this CPU");
+        let thread = scheduler.threads.get_mut(&current).expect("[ERR] Current thread does not exist");
+
+        crate::memory::switch_page_table(address_space.page_table());
+        thread.address_space.replace(address_space)
+    })
+}
+
+// process of the running thread (None for kernel threads)
+pub fn current_process() -> Option<Pid>
+{
+    SCHEDULER.lock().as_ref()
+        .and_then(|scheduler| scheduler.threads.get(&scheduler.current()?))
+        .and_then(|thread| thread.process)
+}
+
+// change the fixed priority of a thread, returns false if it does not exist
+pub fn set_priority(id: ThreadId, priority: u8) -> bool
+{
+    let priority = priority.min(PRIORITY_LEVELS as u8 - 1);
+    with_scheduler(|scheduler| scheduler.set_params(id, |params| params.priority = priority))
+}
+
+// change the nice value of a thread, returns false if it does not exist
+pub fn set_nice(id: ThreadId, nice: i8) -> bool
+{
+    let nice = nice.clamp(NICE_MIN, NICE_MAX);
+    with_scheduler(|scheduler| scheduler.set_params(id, |params| params.nice = nice))
+}
@@ -0,0 +1,104 @@
+use super::{SchedParams, SchedPolicy, NICE_MAX, NICE_MIN};
+use crate::thread::ThreadId;
+use alloc::vec::Vec;
+
+// weight of a nice 0 thread
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+// CFS weights for nice -20..=19 (each step is ~1.25x)
+const NICE_TO_WEIGHT: [u64; 40] =
+[
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+
+pub fn nice_to_weight(nice: i8) -> u64
+{
+    let nice = nice.clamp(NICE_MIN, NICE_MAX);
+    NICE_TO_WEIGHT[(nice - NICE_MIN) as usize]
+}
+
+// virtual runtime charged for running `ticks` ticks at the given nice value
+// (scaled by 1000, so that heavy threads still advance)
+pub fn vruntime_delta(ticks: u64, nice: i8) -> u64
+{
+    ticks * 1000 * NICE_0_WEIGHT / nice_to_weight(nice)
+}
+
+// CFS-like policy: runs the ready thread with the smallest virtual runtime
+// vruntime grows slower for heavier (lower nice) threads, so they get a bigger share
+pub struct FairShare
+{
+    ready: Vec<(u64, ThreadId)>,
+    min_vruntime: u64,  // monotonic, new threads start here instead of at 0
+}
+
+impl FairShare
+{
+    pub fn new() -> Self
+    {
+        FairShare { ready: Vec::new(), min_vruntime: 0 }
+    }
+}
+
+impl Default for FairShare
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for FairShare
+{
+    fn name(&self) -> &'static str
+    {
+        "fair-share"
+    }
+
+    fn reserve(&mut self, threads: usize)
+    {
+        self.ready.reserve(threads.saturating_sub(self.ready.len()));
+    }
+
+    fn enqueue(&mut self, id: ThreadId, params: &SchedParams)
+    {
+        // a thread that slept for a long time must not monopolise the CPU when it wakes up
+        let vruntime = params.vruntime.max(self.min_vruntime);
+        self.ready.push((vruntime, id));
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId>
+    {
+        // few threads -> a linear scan is cheaper than keeping a tree balanced
+        let (index, &(vruntime, id)) = self.ready.iter().enumerate().min_by_key(|(_, entry)| **entry)?;
+
+        self.ready.swap_remove(index);
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+
+        Some(id)
+    }
+
+    fn remove(&mut self, id: ThreadId) -> bool
+    {
+        let len = self.ready.len();
+        self.ready.retain(|&(_, queued)| queued != id);
+        self.ready.len() != len
+    }
+
+    fn drain(&mut self) -> Vec<ThreadId>
+    {
+        self.ready.drain(..).map(|(_, id)| id).collect()
+    }
+
+    fn min_vruntime(&self) -> u64
+    {
+        self.min_vruntime
+    }
+}
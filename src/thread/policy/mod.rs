@@ -0,0 +1,132 @@
+use super::ThreadId;
+use alloc::vec::Vec;
+
+pub mod round_robin;
+pub mod priority;
+pub mod fair;
+
+pub use round_robin::RoundRobin;
+pub use priority::FixedPriority;
+pub use fair::FairShare;
+
+// number of fixed priority levels (0 = lowest)
+pub const PRIORITY_LEVELS: usize = 8;
+pub const DEFAULT_PRIORITY: u8 = 4;
+
+// nice values, as in unix: lower is a bigger share of the CPU
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+// per-thread scheduling parameters, as seen by a policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedParams
+{
+    pub priority: u8,
+    pub nice: i8,
+    pub vruntime: u64,
+}
+
+impl Default for SchedParams
+{
+    fn default() -> Self
+    {
+        SchedParams { priority: DEFAULT_PRIORITY, nice: 0, vruntime: 0 }
+    }
+}
+
+// a scheduling policy only decides the order of ready threads
+// switching, accounting and thread states are handled by the scheduler
+// note: called from the timer interrupt, implementations must not block or allocate
+pub trait SchedPolicy: Send
+{
+    fn name(&self) -> &'static str;
+
+    // make room for `threads` ready threads, so that enqueue never has to grow a collection
+    // called by the scheduler outside interrupt context (when threads are added, or the policy changes)
+    fn reserve(&mut self, threads: usize);
+
+    // a thread became ready (there is room for it, see reserve)
+    fn enqueue(&mut self, id: ThreadId, params: &SchedParams);
+
+    // pick (and remove) the next thread to run
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    // remove a ready thread (e.g. its parameters changed)
+    fn remove(&mut self, id: ThreadId) -> bool;
+
+    // remove every ready thread (used when switching policies)
+    fn drain(&mut self) -> Vec<ThreadId>;
+
+    // lowest virtual runtime a thread may be (re)queued with
+    fn min_vruntime(&self) -> u64
+    {
+        0
+    }
+}
+
+
+// --------- TEST CASES ----------
+#[cfg(test)]
+fn id(n: u64) -> ThreadId
+{
+    ThreadId(n)
+}
+
+#[test_case]
+fn test_round_robin_is_fifo()
+{
+    let mut policy = RoundRobin::new();
+    for n in 0..3
+    {
+        policy.enqueue(id(n), &SchedParams::default());
+    }
+
+    assert_eq!(policy.pick_next(), Some(id(0)));
+    assert_eq!(policy.pick_next(), Some(id(1)));
+    assert!(policy.remove(id(2)));
+    assert_eq!(policy.pick_next(), None);
+}
+
+#[test_case]
+fn test_fixed_priority_picks_highest_first()
+{
+    let mut policy = FixedPriority::new();
+    let params = |priority| SchedParams { priority, ..SchedParams::default() };
+
+    policy.enqueue(id(0), &params(1));
+    policy.enqueue(id(1), &params(6));
+    policy.enqueue(id(2), &params(6));
+    policy.enqueue(id(3), &params(3));
+
+    assert_eq!(policy.pick_next(), Some(id(1)));
+    assert_eq!(policy.pick_next(), Some(id(2)));
+    assert_eq!(policy.pick_next(), Some(id(3)));
+    assert_eq!(policy.pick_next(), Some(id(0)));
+    assert_eq!(policy.pick_next(), None);
+}
+
+#[test_case]
+fn test_fair_share_picks_smallest_vruntime()
+{
+    let mut policy = FairShare::new();
+    let params = |vruntime| SchedParams { vruntime, ..SchedParams::default() };
+
+    policy.enqueue(id(0), &params(300));
+    policy.enqueue(id(1), &params(100));
+    policy.enqueue(id(2), &params(200));
+
+    assert_eq!(policy.pick_next(), Some(id(1)));
+    assert_eq!(policy.min_vruntime(), 100);
+    assert_eq!(policy.pick_next(), Some(id(2)));
+    assert_eq!(policy.pick_next(), Some(id(0)));
+    assert_eq!(policy.pick_next(), None);
+}
+
+#[test_case]
+fn test_nice_weights()
+{
+    // nice 0 -> reference weight, lower nice -> heavier (slower vruntime)
+    assert_eq!(fair::nice_to_weight(0), fair::NICE_0_WEIGHT);
+    assert!(fair::vruntime_delta(1, -5) < fair::vruntime_delta(1, 0));
+    assert!(fair::vruntime_delta(1, 5) > fair::vruntime_delta(1, 0));
+}
@@ -0,0 +1,73 @@
+use super::{SchedParams, SchedPolicy, PRIORITY_LEVELS};
+use crate::thread::ThreadId;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+// always runs the highest priority ready thread, round-robin within a level
+// lower levels starve while a higher level is busy (interactive threads should get high levels)
+pub struct FixedPriority
+{
+    levels: [VecDeque<ThreadId>; PRIORITY_LEVELS],
+}
+
+impl FixedPriority
+{
+    pub fn new() -> Self
+    {
+        FixedPriority { levels: core::array::from_fn(|_| VecDeque::new()) }
+    }
+}
+
+impl Default for FixedPriority
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for FixedPriority
+{
+    fn name(&self) -> &'static str
+    {
+        "fixed-priority"
+    }
+
+    // every thread could end up on the same level
+    fn reserve(&mut self, threads: usize)
+    {
+        for level in self.levels.iter_mut()
+        {
+            level.reserve(threads.saturating_sub(level.len()));
+        }
+    }
+
+    fn enqueue(&mut self, id: ThreadId, params: &SchedParams)
+    {
+        let level = usize::from(params.priority).min(PRIORITY_LEVELS - 1);
+        self.levels[level].push_back(id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId>
+    {
+        self.levels.iter_mut().rev().find_map(|level| level.pop_front())
+    }
+
+    fn remove(&mut self, id: ThreadId) -> bool
+    {
+        let mut removed = false;
+        for level in self.levels.iter_mut()
+        {
+            let len = level.len();
+            level.retain(|&queued| queued != id);
+            removed |= level.len() != len;
+        }
+
+        removed
+    }
+
+    fn drain(&mut self) -> Vec<ThreadId>
+    {
+        self.levels.iter_mut().rev().flat_map(|level| level.drain(..)).collect()
+    }
+}
@@ -0,0 +1,61 @@
+use super::{SchedParams, SchedPolicy};
+use crate::thread::ThreadId;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+// every ready thread gets one tick in turn, priorities are ignored
+pub struct RoundRobin
+{
+    queue: VecDeque<ThreadId>,
+}
+
+impl RoundRobin
+{
+    pub fn new() -> Self
+    {
+        RoundRobin { queue: VecDeque::new() }
+    }
+}
+
+impl Default for RoundRobin
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for RoundRobin
+{
+    fn name(&self) -> &'static str
+    {
+        "round-robin"
+    }
+
+    fn reserve(&mut self, threads: usize)
+    {
+        self.queue.reserve(threads.saturating_sub(self.queue.len()));
+    }
+
+    fn enqueue(&mut self, id: ThreadId, _params: &SchedParams)
+    {
+        self.queue.push_back(id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId>
+    {
+        self.queue.pop_front()
+    }
+
+    fn remove(&mut self, id: ThreadId) -> bool
+    {
+        let len = self.queue.len();
+        self.queue.retain(|&queued| queued != id);
+        self.queue.len() != len
+    }
+
+    fn drain(&mut self) -> Vec<ThreadId>
+    {
+        self.queue.drain(..).collect()
+    }
+}
@@ -0,0 +1,425 @@
+use super::policy::{self, RoundRobin, SchedParams, SchedPolicy};
+use super::{Thread, ThreadId, ThreadState};
+use crate::interrupts::{ticks, TIMER_HZ};
+use crate::memory;
+use crate::serial_println;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::{percpu, sync::IrqSafeMutex};
+
+// global scheduler, created on the first spawn (or policy change)
+// IrqSafeMutex: the timer interrupt locks it too
+pub(super) static SCHEDULER: IrqSafeMutex<Option<Scheduler>> = IrqSafeMutex::new(None);
+
+// the running thread and the ready threads are per CPU (percpu::PerCpu); so is the idle thread
+crate::percpu!
+{
+    static IDLE_THREAD: AtomicU64 = AtomicU64::new(NO_IDLE_THREAD);
+}
+
+const NO_IDLE_THREAD: u64 = u64::MAX;
+
+// per-thread accounting, in timer ticks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadStats
+{
+    pub runtime: u64,       // ticks during which the thread was running
+    pub switches: u64,      // number of times the thread was switched to
+    pub wait_time: u64,     // ticks spent ready but not running
+    ready_since: u64,
+}
+
+// mechanism: thread states, switching and accounting
+// the order of ready threads is left to the policy of each CPU's run queue
+pub(super) struct Scheduler
+{
+    pub(super) threads: BTreeMap<ThreadId, Thread>,
+    sleepers: Vec<(u64, ThreadId)>,    // (wake-up tick, thread) of threads in sleep_ticks
+    switches: u64,
+}
+
+impl Scheduler
+{
+    // the flow of execution calling this becomes the bootstrap thread, running on this CPU
+    pub(super) fn new() -> Self
+    {
+        let bootstrap = Thread::bootstrap();
+        let idle = Thread::new(idle_thread_entry, 0, SchedParams::default());
+
+        percpu::current().set_current_thread(bootstrap.id.0);
+        IDLE_THREAD.get().store(idle.id.0, Ordering::Relaxed);
+
+        let mut threads = BTreeMap::new();
+        threads.insert(bootstrap.id, bootstrap);
+        threads.insert(idle.id, idle);
+
+        Scheduler
+        {
+            threads,
+            sleepers: Vec::new(),
+            switches: 0,
+        }
+    }
+
+    // the thread running on this CPU, None if it runs no threads
+    pub(super) fn current(&self) -> Option<ThreadId>
+    {
+        percpu::current().current_thread().map(ThreadId)
+    }
+
+    pub(super) fn add(&mut self, thread: Thread)
+    {
+        let id = thread.id;
+        self.threads.insert(id, thread);
+        self.reserve_run_queues();
+        self.make_ready(id);
+    }
+
+    // give every running CPU's run queue room for all threads: a thread can be made ready on any
+    // CPU, and the timer interrupt must not allocate (neither must the yield and unpark paths)
+    fn reserve_run_queues(&mut self)
+    {
+        let threads = self.threads.len();
+        for cpu in (0..percpu::MAX_CPUS).filter_map(percpu::for_cpu)
+        {
+            cpu.run_queue().lock().get_or_insert_with(|| Box::new(RoundRobin::new())).reserve(threads);
+        }
+    }
+
+    // remove finished threads (except the current one, whose stack is still in use)
+    // returns them so they can be dropped after the lock is released
+    pub(super) fn take_finished(&mut self) -> Vec<Thread>
+    {
+        let current = self.current();
+        let finished: Vec<ThreadId> = self.threads.values()
+            .filter(|thread| thread.state == ThreadState::Finished && Some(thread.id) != current)
+            .map(|thread| thread.id)
+            .collect();
+
+        finished.iter().filter_map(|id| self.threads.remove(id)).collect()
+    }
+
+    pub(super) fn set_state(&mut self, id: ThreadId, state: ThreadState)
+    {
+        if let Some(thread) = self.threads.get_mut(&id)
+        {
+            thread.state = state;
+        }
+    }
+
+    // block the current thread, unless an unpark is pending
+    // returns whether the caller has to switch away
+    pub(super) fn park_current(&mut self) -> bool
+    {
+        let Some(thread) = self.current().and_then(|current| self.threads.get_mut(&current)) else { return false };
+
+        if thread.unpark_token
+        {
+            thread.unpark_token = false;
+            return false;
+        }
+
+        thread.state = ThreadState::Blocked;
+        true
+    }
+
+    // block the current thread until the given tick
+    pub(super) fn sleep_current(&mut self, deadline: u64) -> bool
+    {
+        let Some(current) = self.current() else { return false };
+        let Some(thread) = self.threads.get_mut(&current) else { return false };
+
+        thread.state = ThreadState::Blocked;
+        self.sleepers.push((deadline, current));
+        true
+    }
+
+    // wake sleepers whose deadline has passed
+    fn wake_sleepers(&mut self, now: u64)
+    {
+        let mut index = 0;
+        while index < self.sleepers.len()
+        {
+            let (deadline, id) = self.sleepers[index];
+            if deadline <= now
+            {
+                self.sleepers.swap_remove(index);
+
+                // the thread may have been woken up (and blocked again on something else) in the meantime;
+                // waking it then is a spurious wakeup, which every blocking primitive tolerates
+                if self.threads.get(&id).is_some_and(|thread| thread.state == ThreadState::Blocked)
+                {
+                    self.make_ready(id);
+                }
+            }
+            else
+            {
+                index += 1;
+            }
+        }
+    }
+
+    pub(super) fn take_exit_hook(&mut self) -> Option<Box<dyn FnOnce() + Send>>
+    {
+        self.current().and_then(|current| self.threads.get_mut(&current)).and_then(|thread| thread.exit_hook.take())
+    }
+
+    pub(super) fn unpark(&mut self, id: ThreadId)
+    {
+        let Some(thread) = self.threads.get_mut(&id) else { return };
+
+        match thread.state
+        {
+            ThreadState::Blocked => self.make_ready(id),
+            ThreadState::Finished => {}
+            _ => thread.unpark_token = true,
+        }
+    }
+
+    // change a thread's scheduling parameters, requeueing it if it is waiting to run
+    pub(super) fn set_params(&mut self, id: ThreadId, update: impl FnOnce(&mut SchedParams)) -> bool
+    {
+        let Some(thread) = self.threads.get_mut(&id) else { return false };
+        update(&mut thread.params);
+
+        if thread.state == ThreadState::Ready && !is_idle(id) && with_run_queue(|queue| queue.remove(id))
+        {
+            self.make_ready(id);
+        }
+
+        true
+    }
+
+    // replaces this CPU's run queue
+    fn set_policy(&mut self, mut policy: Box<dyn SchedPolicy>) -> Box<dyn SchedPolicy>
+    {
+        policy.reserve(self.threads.len());
+        let mut old = percpu::current().run_queue().lock().replace(policy).unwrap_or_else(|| Box::new(RoundRobin::new()));
+
+        for id in old.drain()
+        {
+            self.make_ready(id);
+        }
+
+        old
+    }
+
+    fn make_ready(&mut self, id: ThreadId)
+    {
+        if let Some(thread) = self.threads.get_mut(&id)
+        {
+            with_run_queue(|queue|
+            {
+                thread.state = ThreadState::Ready;
+                thread.params.vruntime = thread.params.vruntime.max(queue.min_vruntime());
+                thread.stats.ready_since = ticks();
+
+                queue.enqueue(id, &thread.params);
+            });
+        }
+    }
+
+    // charge the running thread for one timer tick
+    fn charge_tick(&mut self)
+    {
+        if let Some(thread) = self.current().and_then(|current| self.threads.get_mut(&current))
+        {
+            thread.stats.runtime += 1;
+            thread.params.vruntime += policy::fair::vruntime_delta(1, thread.params.nice);
+        }
+    }
+
+    // save the current thread's stack pointer and pick the next thread
+    // returns the stack pointer to switch to
+    fn switch(&mut self, rsp: u64) -> u64
+    {
+        // CPUs without threads keep running what they run
+        let (Some(current), Some(idle)) = (self.current(), idle_thread()) else { return rsp };
+
+        if let Some(thread) = self.threads.get_mut(&current)
+        {
+            thread.rsp = rsp;
+
+            // preempted threads go back to the policy (the idle thread is never queued)
+            if thread.state == ThreadState::Running
+            {
+                if current == idle
+                {
+                    thread.state = ThreadState::Ready;
+                }
+                else
+                {
+                    self.make_ready(current);
+                }
+            }
+        }
+
+        // nothing else to run -> idle thread
+        let next = with_run_queue(|queue| queue.pick_next()).unwrap_or(idle);
+        let thread = self.threads.get_mut(&next).expect("[ERR] Queued thread does not exist");
+
+        if next != idle
+        {
+            thread.stats.wait_time += ticks() - thread.stats.ready_since;
+        }
+
+        if next != current
+        {
+            thread.stats.switches += 1;
+            self.switches += 1;
+        }
+
+        thread.state = ThreadState::Running;
+        percpu::current().set_current_thread(next.0);
+
+        // interrupts from ring 3 must land on the new thread's kernel stack
+        let stack_top = thread.kernel_stack_top.unwrap_or_else(crate::gdt::default_kernel_stack);
+        crate::gdt::set_kernel_stack(stack_top);
+        crate::syscall::entry::set_kernel_stack(stack_top);
+
+        // the kernel half is the same in every address space, so this is safe on the old stack
+        let page_table = thread.address_space.as_ref().map(|space| space.page_table()).or_else(memory::kernel_page_table);
+        if let Some(page_table) = page_table
+        {
+            memory::switch_page_table(page_table);
+        }
+
+        thread.rsp
+    }
+}
+
+// called from the yield interrupt stub with the saved context of the current thread
+// returns the saved context of the thread to resume
+pub fn schedule(rsp: u64) -> u64
+{
+    with_scheduler_in_interrupt(rsp, |scheduler| scheduler.switch(rsp))
+}
+
+// called from the timer interrupt stub: account the tick, then preempt
+pub fn timer_tick(rsp: u64) -> u64
+{
+    with_scheduler_in_interrupt(rsp, |scheduler|
+    {
+        scheduler.charge_tick();
+        scheduler.wake_sleepers(ticks());
+        scheduler.switch(rsp)
+    })
+}
+
+fn with_scheduler_in_interrupt(rsp: u64, f: impl FnOnce(&mut Scheduler) -> u64) -> u64
+{
+    // threads hold the scheduler lock with interrupts off, so this cannot fail on a single core;
+    // try_lock anyway, spinning here would hang the machine
+    match SCHEDULER.try_lock()
+    {
+        Some(mut guard) => match guard.as_mut()
+        {
+            Some(scheduler) => f(scheduler),
+            None => rsp,    // no threads spawned yet
+        },
+        None => rsp,
+    }
+}
+
+// run f on the global scheduler (created if needed), with interrupts off
+pub(super) fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R
+{
+    let mut scheduler = SCHEDULER.lock();
+    f(scheduler.get_or_insert_with(Scheduler::new))
+}
+
+// replace the scheduling policy; ready threads are moved over to the new one
+// returns the old policy
+pub fn set_policy(policy: Box<dyn SchedPolicy>) -> Box<dyn SchedPolicy>
+{
+    with_scheduler(|scheduler| scheduler.set_policy(policy))
+}
+
+pub fn policy_name() -> &'static str
+{
+    with_scheduler(|_| with_run_queue(|queue| queue.name()))
+}
+
+// accounting of a single thread
+pub fn thread_stats(id: ThreadId) -> Option<ThreadStats>
+{
+    with_scheduler(|scheduler| scheduler.threads.get(&id).map(|thread| thread.stats))
+}
+
+// print per-thread scheduler statistics to serial
+pub fn dump_stats()
+{
+    struct Row
+    {
+        id: ThreadId,
+        state: ThreadState,
+        params: SchedParams,
+        stats: ThreadStats,
+        idle: bool,
+    }
+
+    // snapshot first, printing with the scheduler locked would keep interrupts off for too long
+    let (policy, switches, rows) = with_scheduler(|scheduler|
+    {
+        let rows: Vec<Row> = scheduler.threads.values().map(|thread| Row
+        {
+            id: thread.id,
+            state: thread.state,
+            params: thread.params,
+            stats: thread.stats,
+            idle: is_idle(thread.id),
+        }).collect();
+
+        (with_run_queue(|queue| queue.name()), scheduler.switches, rows)
+    });
+
+    let now = ticks();
+    let to_ms = |ticks: u64| ticks * 1000 / TIMER_HZ;
+
+    serial_println!("[SCHED] policy: {}, uptime: {} ms, context switches: {}", policy, to_ms(now), switches);
+    serial_println!("{:>5} {:<9} {:>4} {:>4} {:>12} {:>9} {:>10}", "TID", "STATE", "PRIO", "NICE", "RUNTIME(ms)", "SWITCHES", "WAIT(ms)");
+
+    for row in rows.iter()
+    {
+        serial_println!("{:>5} {:<9} {:>4} {:>4} {:>12} {:>9} {:>10}{}",
+            row.id.as_u64(), alloc::format!("{:?}", row.state), row.params.priority, row.params.nice,
+            to_ms(row.stats.runtime), row.stats.switches, to_ms(row.stats.wait_time),
+            if row.idle { "  (idle)" } else { "" });
+    }
+
+    if let Some(idle) = rows.iter().find(|row| row.idle) && now > 0
+    {
+        serial_println!("[SCHED] idle: {}%", idle.stats.runtime * 100 / now);
+    }
+}
+
+// this CPU's ready threads, round robin until a policy is set
+// only called with the scheduler locked (the queue lock nests inside it)
+fn with_run_queue<R>(f: impl FnOnce(&mut dyn SchedPolicy) -> R) -> R
+{
+    let mut queue = percpu::current().run_queue().lock();
+    f(queue.get_or_insert_with(|| Box::new(RoundRobin::new())).as_mut())
+}
+
+// the idle thread of this CPU
+fn idle_thread() -> Option<ThreadId>
+{
+    match IDLE_THREAD.get().load(Ordering::Relaxed)
+    {
+        NO_IDLE_THREAD => None,
+        id => Some(ThreadId(id)),
+    }
+}
+
+// idle thread of any CPU (they are never queued)
+fn is_idle(id: ThreadId) -> bool
+{
+    IDLE_THREAD.iter().any(|idle| idle.load(Ordering::Relaxed) == id.0)
+}
+
+extern "C" fn idle_thread_entry(_arg: u64) -> !
+{
+    crate::hlt_loop();
+}
@@ -0,0 +1,188 @@
+use crate::{gdt, percpu};
+use crate::process::{self, signal};
+use crate::syscall::SyscallFrame;
+use core::mem::offset_of;
+use x86_64::VirtAddr;
+
+// RFLAGS for ring 3: IF (interrupts enabled, so user code can be preempted) + reserved bit 1
+pub const USER_RFLAGS: u64 = 0x202;
+
+/// Drops to ring 3 at `entry`, running on the given user stack.
+/// The thread only comes back to the kernel through interrupts and system calls (on its RSP0 stack).
+///
+/// # Safety
+/// `entry` and `stack` must be mapped `USER_ACCESSIBLE` (see `memory::map_user_region`).
+pub unsafe fn enter_user(entry: VirtAddr, stack: VirtAddr) -> !
+{
+    let selectors = gdt::selectors();
+    let user_code = u64::from(selectors.user_code.0);
+    let user_data = u64::from(selectors.user_data.0);
+
+    // build the frame iretq expects: SS, RSP, RFLAGS, CS, RIP
+    // the user GS base goes in last, no interrupt may see it in ring 0 (iretq enables them again)
+    unsafe
+    {
+        core::arch::asm!(
+            "cli",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "push {data}",
+            "push {stack}",
+            "push {rflags}",
+            "push {code}",
+            "push {entry}",
+            "swapgs",
+            "iretq",
+            data = in(reg) user_data,
+            stack = in(reg) stack.as_u64(),
+            rflags = in(reg) USER_RFLAGS,
+            code = in(reg) user_code,
+            entry = in(reg) entry.as_u64(),
+            options(noreturn),
+        );
+    }
+}
+
+/// Returns to ring 3 with every register taken from `context` (a forked child, or a new program).
+///
+/// # Safety
+/// `context.rip` and `context.rsp` must be mapped `USER_ACCESSIBLE` in the thread's address space.
+pub unsafe fn resume(context: &SyscallFrame) -> !
+{
+    let selectors = gdt::selectors();
+    let user_code = u64::from(selectors.user_code.0);
+    let user_data = u64::from(selectors.user_data.0);
+
+    // iretq frame first, then load the registers; rax (the context pointer) goes last, then the user GS base
+    unsafe
+    {
+        core::arch::asm!(
+            "cli",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "push {data}",
+            "push qword ptr [rax + {rsp}]",
+            "push qword ptr [rax + {rflags}]",
+            "push {code}",
+            "push qword ptr [rax + {rip}]",
+            "mov rcx, [rax + {rcx}]",
+            "mov r11, [rax + {r11}]",
+            "mov rdi, [rax + {rdi}]",
+            "mov rsi, [rax + {rsi}]",
+            "mov rdx, [rax + {rdx}]",
+            "mov r10, [rax + {r10}]",
+            "mov r8, [rax + {r8}]",
+            "mov r9, [rax + {r9}]",
+            "mov rbx, [rax + {rbx}]",
+            "mov rbp, [rax + {rbp}]",
+            "mov r12, [rax + {r12}]",
+            "mov r13, [rax + {r13}]",
+            "mov r14, [rax + {r14}]",
+            "mov r15, [rax + {r15}]",
+            "mov rax, [rax + {rax}]",
+            "swapgs",
+            "iretq",
+            in("rax") context as *const SyscallFrame,
+            data = in(reg) user_data,
+            code = in(reg) user_code,
+            rax = const offset_of!(SyscallFrame, rax),
+            rdi = const offset_of!(SyscallFrame, rdi),
+            rsi = const offset_of!(SyscallFrame, rsi),
+            rdx = const offset_of!(SyscallFrame, rdx),
+            r10 = const offset_of!(SyscallFrame, r10),
+            r8 = const offset_of!(SyscallFrame, r8),
+            r9 = const offset_of!(SyscallFrame, r9),
+            rbx = const offset_of!(SyscallFrame, rbx),
+            rbp = const offset_of!(SyscallFrame, rbp),
+            r12 = const offset_of!(SyscallFrame, r12),
+            r13 = const offset_of!(SyscallFrame, r13),
+            r14 = const offset_of!(SyscallFrame, r14),
+            r15 = const offset_of!(SyscallFrame, r15),
+            rcx = const offset_of!(SyscallFrame, rcx),
+            r11 = const offset_of!(SyscallFrame, r11),
+            rip = const offset_of!(SyscallFrame, rip),
+            rflags = const offset_of!(SyscallFrame, rflags),
+            rsp = const offset_of!(SyscallFrame, rsp),
+            options(noreturn),
+        );
+    }
+}
+
+// return state of an interrupt: the frame the CPU pushed, and iretq pops
+#[derive(Debug, Clone, Copy)]
+pub struct ReturnFrame
+{
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Makes an interrupt (or exception) from ring 3 return to `interrupted_entry` in ring 0 instead,
+/// on the thread's kernel stack, so pending signals (or exit) are handled before the user code continues.
+/// The general purpose registers must still hold the user's values when iretq runs, and GS the kernel's.
+pub fn divert(frame: &mut ReturnFrame)
+{
+    let selectors = gdt::selectors();
+
+    // user rip, rflags and rsp, parked in the per-CPU area until interrupted_entry saves them
+    // (interrupts stay off in between)
+    percpu::current().park_diverted(frame.rip, frame.rflags, frame.rsp);
+
+    // the interrupt frame itself is on that stack, but it is consumed by iretq
+    frame.rip = interrupted_entry as *const () as u64;
+    frame.cs = u64::from(selectors.kernel_code.0);
+    frame.rflags = 0x2;     // interrupts off until the parked values are saved
+    frame.rsp = gdt::kernel_stack().as_u64();
+    frame.ss = u64::from(selectors.kernel_data.0);
+}
+
+// saves the diverted user context as a SyscallFrame (same layout as the system call entry) and
+// hands it to interrupted_return (18 qwords pushed, the kernel stack top stays 16-byte aligned)
+#[unsafe(naked)]
+extern "C" fn interrupted_entry()
+{
+    core::arch::naked_asm!(
+        "push qword ptr gs:[{rsp}]",
+        "push qword ptr gs:[{rflags}]",
+        "push qword ptr gs:[{rip}]",
+        "push r11",
+        "push rcx",
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push rbp",
+        "push rbx",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+        "mov rdi, rsp",
+        "sti",
+        "call {handler}",
+        "ud2",
+        rsp = const percpu::DIVERTED_RSP_OFFSET,
+        rflags = const percpu::DIVERTED_RFLAGS_OFFSET,
+        rip = const percpu::DIVERTED_RIP_OFFSET,
+        handler = sym interrupted_return,
+    );
+}
+
+extern "C" fn interrupted_return(frame: *mut SyscallFrame) -> !
+{
+    let frame = unsafe { &mut *frame };
+
+    // another thread of the process called exit() (or a signal killed it)
+    if process::exit_pending()
+    {
+        process::exit_thread();
+    }
+
+    signal::deliver(frame);
+    unsafe { resume(frame) }
+}
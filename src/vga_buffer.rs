@@ -1,7 +1,7 @@
 use volatile::Volatile;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 
 // VGA buffer implemetation
 const VGA_WIDTH: usize = 80;
@@ -16,9 +16,9 @@ const VGA_ADDRESS: usize = 0xb8000;
 // global Writer instance
 // since a reference (&mut Buffer) can only be validated at compile time, need a lazy static instance (inits at runtime)
 // since this static is currently immutable and not usable for writing, we can wrap this in Mutex
-// spinlocks for now...
+// IrqSafeMutex: interrupt handlers print too, they must not interrupt a holder of the lock
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
         row_pos: 0,
         col_pos: 0,
         color_code: ColorCode::new(Color::LightRed, Color::Black),
@@ -56,12 +56,11 @@ macro_rules! println
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    // disable interrupts when writing
-    use x86_64::instructions::interrupts;
+    // interrupts are disabled while the lock is held
+    WRITER.lock().write_fmt(args).unwrap();
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    })
+    // and on the serial console, if there is one
+    crate::serial::_print_console(args);
 }
 
 
@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+// ---------- TESTS ----------
+
+// QEMU has an AHCI HBA (-device ahci) with a disk on port 0: test-disks/ahci-test.img (8 MiB, created by build.rs)
+
+#[path = "common/block.rs"]
+mod common;
+
+use alloc::sync::Arc;
+use ferrix::drivers::block::{self, BlockDevice};
+use ferrix::drivers::{ahci, pci};
+use ferrix::drivers::pci::PciMatch;
+
+fn disk() -> Arc<dyn BlockDevice>
+{
+    block::find("sda").expect("no AHCI disk (test-disks/ahci-test.img)")
+}
+
+#[test_case]
+fn finds_the_disk()
+{
+    common::init_drivers();
+
+    let hba = PciMatch::class(pci::CLASS_MASS_STORAGE, ahci::SUBCLASS_SATA).with_prog_if(ahci::PROG_IF_AHCI);
+    assert_eq!(common::pci_driver(hba), Some("ahci"));
+
+    let disk = disk();
+    assert_eq!((disk.block_size(), disk.block_count()), (ahci::SECTOR_SIZE, 8 * 1024 * 1024 / 512));
+}
+
+#[test_case]
+fn writes_and_reads_sectors()
+{
+    let disk = disk();
+    common::round_trip(&*disk, 0, 1, 0x11);
+    common::round_trip(&*disk, 9, 5, 0x22);
+    disk.flush().unwrap();
+}
+
+#[test_case]
+fn transfers_across_commands()
+{
+    // more than one command's worth, not page aligned
+    common::round_trip(&*disk(), 200, 150, 0x33);
+}
+
+#[test_case]
+fn queues_commands_with_ncq()
+{
+    // the concrete disk, for its settings
+    let disk = ahci::disk("sda").unwrap();
+    if !disk.set_ncq(true)
+    {
+        return;
+    }
+
+    common::round_trip(&*disk, 400, 1, 0x44);
+    common::round_trip(&*disk, 500, 100, 0x55);
+    disk.set_ncq(false);
+}
+
+#[test_case]
+fn checks_the_disk_end()
+{
+    common::check_end(&*disk(), 0x66);
+}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+
+
+// ---------- TESTS ----------
+
+use alloc::rc::Rc;
+use core::cell::Cell;
+use ferrix::task::{Task, simple_executor::SimpleExecutor};
+
+async fn add(a: u64, b: u64) -> u64
+{
+    a + b
+}
+
+#[test_case]
+fn simple_executor_runs_to_completion()
+{
+    let result = Rc::new(Cell::new(0));
+    let out = result.clone();
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        out.set(add(40, 2).await);
+    }));
+    executor.run();
+
+    assert_eq!(result.get(), 42);
+}
+
+#[test_case]
+fn simple_executor_runs_all_tasks()
+{
+    let counter = Rc::new(Cell::new(0));
+
+    let mut executor = SimpleExecutor::new();
+    for _ in 0..10
+    {
+        let counter = counter.clone();
+        executor.spawn(Task::new(async move {
+            counter.set(counter.get() + 1);
+        }));
+    }
+    executor.run();
+
+    assert_eq!(counter.get(), 10);
+}
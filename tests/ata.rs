@@ -0,0 +1,139 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+// ---------- TESTS ----------
+
+// QEMU's PIIX3 IDE controller: the boot image as primary master, test-disks/ata-test.img (created by build.rs) as
+// primary slave, the empty CD-ROM drive as secondary master
+
+#[path = "common/block.rs"]
+mod common;
+
+use alloc::vec;
+use ferrix::drivers::{self, ata, block};
+use ferrix::drivers::ata::{DriveKind, LbaMode, WaitMode};
+use ferrix::drivers::block::{BlockDevice, BlockError};
+
+const SCRATCH: &str = "hdb";
+
+fn scratch() -> alloc::sync::Arc<ata::AtaDrive>
+{
+    ata::drive(SCRATCH).expect("no scratch disk (test-disks/ata-test.img)")
+}
+
+#[test_case]
+fn detects_the_drives()
+{
+    drivers::init();
+
+    // other disk drivers register theirs too
+    let names: alloc::vec::Vec<_> = block::devices().iter().map(|device| alloc::string::String::from(device.name()))
+        .filter(|name| name.starts_with("hd")).collect();
+    assert_eq!(names, ["hda", "hdb", "hdc"]);
+
+    let hda = ata::drive("hda").unwrap();
+    assert_eq!(hda.kind(), DriveKind::Ata);
+    assert!(hda.info().model().starts_with("QEMU HARDDISK"));
+
+    let hdb = scratch();
+    assert_eq!((hdb.block_size(), hdb.block_count()), (512, 8 * 1024 * 1024 / 512));
+
+    let hdc = ata::drive("hdc").unwrap();
+    assert_eq!(hdc.kind(), DriveKind::Atapi);
+    assert_eq!(hdc.block_size(), ata::ATAPI_SECTOR_SIZE);
+    assert!(hdc.is_read_only());
+}
+
+#[test_case]
+fn reads_the_boot_sector()
+{
+    let mut sector = [0u8; ata::SECTOR_SIZE];
+    ata::drive("hda").unwrap().read_blocks(0, &mut sector).unwrap();
+    assert_eq!(sector[510..], [0x55, 0xaa]);
+}
+
+#[test_case]
+fn writes_and_reads_with_interrupts()
+{
+    let drive = scratch();
+    drive.set_wait_mode(WaitMode::Irq);
+    common::round_trip(&*drive, 1, 1, 0x11);
+    common::round_trip(&*drive, 100, 300, 0x22);
+}
+
+#[test_case]
+fn writes_and_reads_polling()
+{
+    let drive = scratch();
+    drive.set_wait_mode(WaitMode::Polling);
+    common::round_trip(&*drive, 2, 1, 0x33);
+    common::round_trip(&*drive, 500, 300, 0x44);
+    drive.set_wait_mode(WaitMode::Irq);
+}
+
+#[test_case]
+fn writes_and_reads_with_48_bit_lba()
+{
+    let drive = scratch();
+    assert!(drive.info().lba48);
+
+    drive.force_lba_mode(Some(LbaMode::Lba48));
+    common::round_trip(&*drive, 1000, 2, 0x55);
+    drive.force_lba_mode(None);
+
+    // what 48 bit wrote, 28 bit reads
+    let mut sector = [0u8; ata::SECTOR_SIZE];
+    drive.read_blocks(1000, &mut sector).unwrap();
+    assert!(sector[..] == common::pattern(ata::SECTOR_SIZE, 0x55));
+}
+
+#[test_case]
+fn checks_the_disk_end()
+{
+    common::check_end(&*scratch(), 0x66);
+}
+
+#[test_case]
+fn reports_an_empty_cd_drive()
+{
+    let drive = ata::drive("hdc").unwrap();
+    let mut sector = vec![0u8; ata::ATAPI_SECTOR_SIZE];
+    assert_eq!(drive.read_blocks(0, &mut sector), Err(BlockError::NoMedium));
+    assert_eq!(drive.write_blocks(0, &sector), Err(BlockError::ReadOnly));
+}
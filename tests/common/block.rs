@@ -0,0 +1,54 @@
+// checks shared by the disk driver tests: a device is found through drivers::init, then only the
+// block device trait is used (each test binary uses a part of this)
+#![allow(dead_code)]
+
+use alloc::vec;
+use alloc::vec::Vec;
+use ferrix::drivers::block::{BlockDevice, BlockError};
+use ferrix::drivers::pci::{self, PciMatch};
+use ferrix::drivers::{self, model};
+use ferrix::smp;
+
+// bring up the drivers; the local APIC first, MSI and MSI-X completions go to it
+pub fn init_drivers()
+{
+    let _ = smp::init();
+    drivers::init();
+}
+
+// name of the driver bound to the first PCI function that matches
+pub fn pci_driver(matching: PciMatch) -> Option<&'static str>
+{
+    let function = pci::find(matching)?;
+    model::find(&alloc::format!("{}", function.address)).and_then(model::driver_name)
+}
+
+// the test data: len bytes, different for every seed
+pub fn pattern(len: usize, seed: u8) -> Vec<u8>
+{
+    (0..len).map(|index| (index as u8).wrapping_mul(7) ^ seed).collect()
+}
+
+// write a pattern to count blocks at lba and read it back
+pub fn round_trip(device: &dyn BlockDevice, lba: u64, count: usize, seed: u8)
+{
+    let data = pattern(count * device.block_size(), seed);
+    device.write_blocks(lba, &data).expect("write failed");
+
+    let mut read = vec![0u8; data.len()];
+    device.read_blocks(lba, &mut read).expect("read failed");
+    assert!(read == data);
+}
+
+// the last block can be written and read, a transfer past it or of a partial block is refused
+pub fn check_end(device: &dyn BlockDevice, seed: u8)
+{
+    let last = device.block_count() - 1;
+    round_trip(device, last, 1, seed);
+
+    let mut blocks = vec![0u8; 2 * device.block_size()];
+    assert_eq!(device.read_blocks(last, &mut blocks), Err(BlockError::OutOfRange));
+
+    let partial = device.block_size() - 12;
+    assert_eq!(device.write_blocks(0, &blocks[..partial]), Err(BlockError::BadBufferSize(partial)));
+}
@@ -0,0 +1,59 @@
+// for test binaries that expect a panic (harness = false): the panic handler checks its message
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use ferrix::{hlt_loop, qemu_close, serial_println, QemuExitCode};
+
+const PREFIX_LEN: usize = 128;
+
+// the start of a panic message; formatting must not allocate (the heap may be what panicked)
+struct Prefix
+{
+    bytes: [u8; PREFIX_LEN],
+    len: usize,
+}
+
+impl Write for Prefix
+{
+    fn write_str(&mut self, text: &str) -> fmt::Result
+    {
+        let count = text.len().min(PREFIX_LEN - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&text.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+// succeed if the panic message contains expected
+pub fn check(info: &PanicInfo, expected: &str) -> !
+{
+    let mut prefix = Prefix { bytes: [0; PREFIX_LEN], len: 0 };
+    let _ = write!(prefix, "{}", info.message());
+
+    // the prefix may end in the middle of a character
+    let bytes = &prefix.bytes[..prefix.len];
+    let message = core::str::from_utf8(bytes).unwrap_or_else(|error| core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap());
+
+    if message.contains(expected)
+    {
+        serial_println!("\x1b[32m[ok]\x1b[0m\n");
+        qemu_close(QemuExitCode::Success);
+    }
+    else
+    {
+        serial_println!("\x1b[31m[failed]\x1b[0m\n");
+        serial_println!("Error: expected a panic with \"{}\", got: {}\n", expected, info);
+        qemu_close(QemuExitCode::Failure);
+    }
+
+    hlt_loop();
+}
+
+// the test returned instead of panicking
+pub fn no_panic() -> !
+{
+    serial_println!("\x1b[31m[failed]\x1b[0m\n");
+    serial_println!("Error: the test did not panic\n");
+    qemu_close(QemuExitCode::Failure);
+    hlt_loop();
+}
@@ -0,0 +1,196 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    ferrix::test_panic_handler(info)
+}
+
+// ---------- TESTS ----------
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use ferrix::drivers::{self, model};
+use ferrix::drivers::model::{Device, DeviceKind, DeviceMatch, DeviceState, Driver, DriverError, Resource};
+use ferrix::sync::IrqSafeMutex;
+
+// what the test drivers were asked to do, in order
+static EVENTS: IrqSafeMutex<Vec<String>> = IrqSafeMutex::new(Vec::new());
+
+fn record(event: &str, device: &Device)
+{
+    EVENTS.lock().push(format!("{} {}", event, device.name));
+}
+
+fn take_events() -> Vec<String>
+{
+    core::mem::take(&mut *EVENTS.lock())
+}
+
+// binds "test-parent" devices and adds a "test-child" below each
+struct ParentDriver;
+
+impl Driver for ParentDriver
+{
+    fn name(&self) -> &'static str { "parent-driver" }
+    fn ids(&self) -> &'static [DeviceMatch] { &[DeviceMatch::Platform("test-parent")] }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        record("probe", device);
+        model::add_device(Some(device.id), "child", DeviceKind::Platform("test-child"), alloc::vec![Resource::Irq(5)]);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) { record("remove", device); }
+    fn suspend(&self, device: &Device) -> Result<(), DriverError> { record("suspend", device); Ok(()) }
+    fn resume(&self, device: &Device) -> Result<(), DriverError> { record("resume", device); Ok(()) }
+}
+
+struct ChildDriver;
+
+impl Driver for ChildDriver
+{
+    fn name(&self) -> &'static str { "child-driver" }
+    fn ids(&self) -> &'static [DeviceMatch] { &[DeviceMatch::Platform("test-child")] }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        record("probe", device);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) { record("remove", device); }
+    fn suspend(&self, device: &Device) -> Result<(), DriverError> { record("suspend", device); Ok(()) }
+    fn resume(&self, device: &Device) -> Result<(), DriverError> { record("resume", device); Ok(()) }
+}
+
+// finds nothing, or fails, depending on the device
+struct PickyDriver;
+
+impl Driver for PickyDriver
+{
+    fn name(&self) -> &'static str { "picky-driver" }
+    fn ids(&self) -> &'static [DeviceMatch] { &[DeviceMatch::Platform("test-missing"), DeviceMatch::Platform("test-broken")] }
+
+    fn probe(&self, device: &Device) -> Result<(), DriverError>
+    {
+        match device.kind
+        {
+            DeviceKind::Platform("test-missing") => Err(DriverError::NoDevice),
+            _ => Err(DriverError::failed("broken")),
+        }
+    }
+}
+
+#[test_case]
+fn binds_matching_drivers()
+{
+    let bus = model::add_device(None, "test-bus", DeviceKind::Bus, Vec::new());
+    let parent = model::add_device(Some(bus), "parent", DeviceKind::Platform("test-parent"), Vec::new());
+    let missing = model::add_device(Some(bus), "missing", DeviceKind::Platform("test-missing"), Vec::new());
+    let broken = model::add_device(Some(bus), "broken", DeviceKind::Platform("test-broken"), Vec::new());
+    assert_eq!(model::state(parent), Some(DeviceState::Unbound));
+
+    // drivers registered after the devices get them; the child added by probe waits for its driver
+    model::register_driver(&ParentDriver);
+    model::register_driver(&PickyDriver);
+    let child = model::find("child").expect("probe did not add the child");
+    assert_eq!(model::children(Some(parent)), [child]);
+    assert_eq!(model::state(child), Some(DeviceState::Unbound));
+
+    model::register_driver(&ChildDriver);
+    assert_eq!(model::driver_name(parent), Some("parent-driver"));
+    assert_eq!(model::driver_name(child), Some("child-driver"));
+    assert_eq!(model::device(child).map(|device| device.irqs().collect::<Vec<_>>()), Some(alloc::vec![5]));
+    assert_eq!(take_events(), ["probe parent", "probe child"]);
+
+    assert_eq!(model::state(missing), Some(DeviceState::Unbound));
+    assert_eq!(model::state(broken), Some(DeviceState::Failed(DriverError::Failed(String::from("\"broken\"")))));
+
+    let mut tree = String::new();
+    model::write_tree(&mut tree).unwrap();
+    assert!(tree.contains("test-bus [bus]\n"));
+    assert!(tree.contains("  parent [test-parent] -> parent-driver\n"));
+    assert!(tree.contains("    child [test-child] -> child-driver: irq 5\n"));
+    assert!(tree.contains("  missing [test-missing] (no driver)\n"));
+    assert!(tree.contains("(probe failed: \"broken\")"));
+}
+
+#[test_case]
+fn suspends_children_first()
+{
+    take_events();
+
+    assert_eq!(model::suspend_all(), Ok(()));
+    assert_eq!(take_events(), ["suspend child", "suspend parent"]);
+    assert_eq!(model::find("parent").and_then(model::state), Some(DeviceState::Suspended));
+
+    assert_eq!(model::resume_all(), Ok(()));
+    assert_eq!(take_events(), ["resume parent", "resume child"]);
+    assert_eq!(model::find("child").and_then(model::state), Some(DeviceState::Bound));
+}
+
+#[test_case]
+fn removes_children_first()
+{
+    take_events();
+    let bus = model::find("test-bus").expect("no test bus");
+
+    assert!(model::remove_device(bus));
+    assert_eq!(take_events(), ["remove child", "remove parent"]);
+    assert_eq!(model::find("parent"), None);
+    assert!(!model::remove_device(bus));
+}
+
+#[test_case]
+fn builds_the_device_tree()
+{
+    drivers::init();
+
+    // the built-in drivers took the legacy devices QEMU has
+    let driver = |name| model::find(name).and_then(model::driver_name);
+    assert_eq!(driver("pit"), Some("pit"));
+    assert_eq!(driver("serial0"), Some("serial"));
+    assert_eq!(driver("i8042"), Some("i8042"));
+    assert_eq!(driver("pic"), None);
+
+    // PCI functions hang below their segment, with their BARs as resources (the PIIX3 IDE controller)
+    let ide = model::find("0000:00:01.1").and_then(model::device).expect("no IDE controller");
+    assert_eq!(ide.parent, model::find("pci0000"));
+    assert!(ide.resources.iter().any(|resource| matches!(resource, Resource::IoPorts { len: 16, .. })));
+
+    model::dump();
+}
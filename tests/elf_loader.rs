@@ -0,0 +1,156 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+
+// ---------- TESTS ----------
+
+use alloc::vec;
+use ferrix::loader::{self, ElfError, LoadError, USER_STACK_TOP};
+use ferrix::{thread, usermode};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+// layout of user/hello.elf (nm user/hello.elf)
+const HELLO_ENTRY: u64 = 0x1000_0040_0000;
+const HELLO_RODATA: u64 = 0x1000_0040_1000;
+const HELLO_RUN_COUNT: u64 = 0x1000_0040_2000;     // .data
+const HELLO_SCRATCH: u64 = 0x1000_0040_2008;       // .bss, 8 KiB
+
+fn read_u64(image: &loader::LoadedImage, addr: u64) -> u64
+{
+    let mut bytes = [0; 8];
+    image.address_space.read(VirtAddr::new(addr), &mut bytes).expect("address not mapped");
+    u64::from_le_bytes(bytes)
+}
+
+#[test_case]
+fn segments_mapped_with_permissions()
+{
+    let image = loader::load(loader::HELLO_ELF, &["hello"], &[]).expect("loading failed");
+    assert_eq!(image.entry.as_u64(), HELLO_ENTRY);
+
+    let flags = |addr: u64| image.address_space.translate(VirtAddr::new(addr)).expect("segment not mapped").1;
+
+    let text = flags(HELLO_ENTRY);
+    assert!(text.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!text.contains(PageTableFlags::WRITABLE) && !text.contains(PageTableFlags::NO_EXECUTE));
+
+    let rodata = flags(HELLO_RODATA);
+    assert!(!rodata.contains(PageTableFlags::WRITABLE) && rodata.contains(PageTableFlags::NO_EXECUTE));
+
+    let data = flags(HELLO_RUN_COUNT);
+    assert!(data.contains(PageTableFlags::WRITABLE) && data.contains(PageTableFlags::NO_EXECUTE));
+
+    // .bss spans into a page with no file contents
+    let bss_end = HELLO_SCRATCH + 8192 - 1;
+    assert!(flags(bss_end).contains(PageTableFlags::WRITABLE));
+    assert!(image.program_break.as_u64() > bss_end);
+
+    let mut bss = vec![0xffu8; 8192];
+    image.address_space.read(VirtAddr::new(HELLO_SCRATCH), &mut bss).expect("bss not mapped");
+    assert!(bss.iter().all(|&byte| byte == 0));
+
+    // nothing is mapped into the kernel's page table
+    assert!(ferrix::memory::translate_active(VirtAddr::new(HELLO_ENTRY)).is_none());
+}
+
+#[test_case]
+fn initial_stack_layout()
+{
+    let image = loader::load(loader::HELLO_ELF, &["hello", "world"], &["TERM=ferrix"]).expect("loading failed");
+    let sp = image.stack_pointer.as_u64();
+
+    assert_eq!(sp % 16, 0);
+    assert!(sp < USER_STACK_TOP);
+
+    let read_string = |addr: u64, len: usize|
+    {
+        let mut bytes = vec![0; len];
+        image.address_space.read(VirtAddr::new(addr), &mut bytes).expect("string not mapped");
+        bytes
+    };
+
+    assert_eq!(read_u64(&image, sp), 2);                                        // argc
+    assert_eq!(read_string(read_u64(&image, sp + 8), 6), b"hello\0");           // argv[0]
+    assert_eq!(read_string(read_u64(&image, sp + 16), 6), b"world\0");          // argv[1]
+    assert_eq!(read_u64(&image, sp + 24), 0);
+    assert_eq!(read_string(read_u64(&image, sp + 32), 12), b"TERM=ferrix\0");   // envp[0]
+    assert_eq!(read_u64(&image, sp + 40), 0);
+
+    // auxv: find AT_ENTRY before AT_NULL
+    let mut addr = sp + 48;
+    let mut entry = None;
+    while read_u64(&image, addr) != loader::AT_NULL
+    {
+        if read_u64(&image, addr) == loader::AT_ENTRY
+        {
+            entry = Some(read_u64(&image, addr + 8));
+        }
+        addr += 16;
+    }
+    assert_eq!(entry, Some(HELLO_ENTRY));
+}
+
+#[test_case]
+fn runs_in_ring3()
+{
+    let image = loader::load(loader::HELLO_ELF, &["hello", "from", "the", "loader"], &[]).expect("loading failed");
+    let (entry, stack) = (image.entry, image.stack_pointer);
+
+    let handle = thread::Builder::new().address_space(image.address_space.clone()).spawn(move ||
+    {
+        unsafe { usermode::enter_user(entry, stack) }
+    });
+    handle.wait();
+
+    // the program counts its runs in .data and stores argc in .bss
+    assert_eq!(read_u64(&image, HELLO_RUN_COUNT), 1);
+    assert_eq!(read_u64(&image, HELLO_SCRATCH), 4);
+}
+
+#[test_case]
+fn rejects_invalid_images()
+{
+    assert_eq!(loader::load(&[0; 16], &[], &[]).err(), Some(LoadError::Elf(ElfError::TooShort)));
+    assert_eq!(loader::load(&[0; 128], &[], &[]).err(), Some(LoadError::Elf(ElfError::BadMagic)));
+
+    let huge_argument = vec![b'a'; 64 * 1024];
+    let huge_argument = core::str::from_utf8(&huge_argument).unwrap();
+    assert_eq!(loader::load(loader::HELLO_ELF, &[huge_argument], &[]).err(), Some(LoadError::ArgumentsTooLarge));
+}
@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ferrix::{serial_print, serial_println};
+
+#[path = "common/expect_panic.rs"]
+mod expect_panic;
+
+// no test harness: the test passes when lockdep panics (lockdep is only built with debug assertions)
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    serial_println!("\nRunning 1 test:");
+    serial_print!("lockdep_reentrant::reacquiring_a_held_lock_panics ... ");
+    reacquiring_a_held_lock_panics();
+
+    expect_panic::no_panic();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    expect_panic::check(info, "re-entrant acquisition")
+}
+
+
+
+// ---------- TESTS ----------
+
+use ferrix::sync::Mutex;
+use ferrix::thread;
+
+fn reacquiring_a_held_lock_panics()
+{
+    // lockdep tracks threads: start the scheduler (this becomes the bootstrap thread)
+    thread::spawn(|| ()).join();
+
+    let lock = Mutex::new(());
+    let _held = lock.lock();
+    let _again = lock.lock();
+}
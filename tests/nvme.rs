@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+
+
+// ---------- TESTS ----------
+
+// QEMU has an NVMe controller (-device nvme) with one namespace: test-disks/nvme-test.img (8 MiB, created by build.rs)
+
+#[path = "common/block.rs"]
+mod common;
+
+use alloc::sync::Arc;
+use ferrix::drivers::block::{self, BlockDevice};
+use ferrix::drivers::{nvme, pci};
+use ferrix::drivers::pci::PciMatch;
+use ferrix::memory::PAGE_SIZE;
+
+fn disk() -> Arc<dyn BlockDevice>
+{
+    block::find("nvme0n1").expect("no NVMe namespace (test-disks/nvme-test.img)")
+}
+
+#[test_case]
+fn finds_the_namespace()
+{
+    common::init_drivers();
+
+    let controller = PciMatch::class(pci::CLASS_MASS_STORAGE, nvme::SUBCLASS_NVM).with_prog_if(nvme::PROG_IF_NVME);
+    assert_eq!(common::pci_driver(controller), Some("nvme"));
+
+    let disk = disk();
+    assert_eq!(disk.block_count() * disk.block_size() as u64, 8 * 1024 * 1024);
+}
+
+#[test_case]
+fn identifies_the_controller()
+{
+    let namespace = nvme::namespace("nvme0n1").unwrap();
+    assert_eq!(namespace.id(), 1);
+
+    let info = namespace.controller().info().expect("controller not identified");
+    assert_eq!(info.serial, "ferrix0");
+    assert!(info.namespaces >= 1);
+}
+
+#[test_case]
+fn writes_and_reads_blocks()
+{
+    let disk = disk();
+    common::round_trip(&*disk, 0, 1, 0x11);
+    common::round_trip(&*disk, 9, 3, 0x22);
+    disk.flush().unwrap();
+}
+
+#[test_case]
+fn uses_prp_lists()
+{
+    // two pages fit in PRP1 and PRP2, more need a PRP list; the largest transfers take more than one command
+    let disk = disk();
+    let blocks = |pages: usize| pages * PAGE_SIZE / disk.block_size();
+    common::round_trip(&*disk, 100, blocks(2), 0x33);
+    common::round_trip(&*disk, 200, blocks(3), 0x44);
+    common::round_trip(&*disk, 400, blocks(80) + 1, 0x55);
+}
+
+#[test_case]
+fn checks_the_disk_end()
+{
+    common::check_end(&*disk(), 0x66);
+}
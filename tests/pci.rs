@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    ferrix::test_panic_handler(info)
+}
+
+// ---------- TESTS ----------
+
+use alloc::vec::Vec;
+use ferrix::acpi::{self, AcpiError, EcamRegion, Mcfg};
+use ferrix::drivers::pci::{self, Bar, ConfigMechanism, PciAddress, PciError, PciMatch};
+use x86_64::PhysAddr;
+
+// QEMU's default machine is the i440FX: host bridge at 00:00.0, PIIX3 ISA bridge at 00:01.0 and its
+// IDE controller at 00:01.1, no MCFG
+
+#[test_case]
+fn mcfg_lists_ecam_regions()
+{
+    let mut table = alloc::vec![0u8; 36 + 8];
+    table[..4].copy_from_slice(acpi::MCFG_SIGNATURE);
+
+    table.extend_from_slice(&0xb000_0000u64.to_le_bytes());
+    table.extend_from_slice(&[0, 0, 0, 0xff, 0, 0, 0, 0]);
+    table.extend_from_slice(&0xe000_0000u64.to_le_bytes());
+    table.extend_from_slice(&[1, 0, 0x10, 0x1f, 0, 0, 0, 0]);
+
+    let length = table.len() as u32;
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    let sum = table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    table[9] = 0u8.wrapping_sub(sum);
+
+    let mcfg = Mcfg::parse(&table).expect("valid MCFG rejected");
+    assert_eq!(mcfg.regions, [
+        EcamRegion { base: PhysAddr::new(0xb000_0000), segment: 0, start_bus: 0, end_bus: 0xff },
+        EcamRegion { base: PhysAddr::new(0xe000_0000), segment: 1, start_bus: 0x10, end_bus: 0x1f },
+    ]);
+
+    table[9] = table[9].wrapping_add(1);
+    assert_eq!(Mcfg::parse(&table).err(), Some(AcpiError::BadChecksum(*acpi::MCFG_SIGNATURE)));
+}
+
+#[test_case]
+fn enumerates_once()
+{
+    let count = pci::init().expect("PCI enumeration failed");
+    assert_eq!(count, pci::devices().len());
+    assert!(count >= 3);
+    assert_eq!(pci::init(), Err(PciError::AlreadyInitialised));
+
+    // ECAM only with an MCFG
+    let expected = if acpi::mcfg().is_ok() { ConfigMechanism::Ecam } else { ConfigMechanism::Legacy };
+    assert_eq!(pci::config_mechanism(), expected);
+
+    // every function once, in order
+    let addresses: Vec<_> = pci::devices().iter().map(|device| device.address).collect();
+    assert!(addresses.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test_case]
+fn finds_the_host_bridge()
+{
+    let _ = pci::init();
+
+    let host = pci::device_at(PciAddress::new(0, 0, 0, 0)).expect("no host bridge");
+    assert_eq!((host.class, host.subclass), (pci::CLASS_BRIDGE, pci::SUBCLASS_HOST_BRIDGE));
+    assert!(pci::matching(PciMatch::class(pci::CLASS_BRIDGE, pci::SUBCLASS_HOST_BRIDGE)).any(|device| device.address == host.address));
+}
+
+#[test_case]
+fn sizes_the_ide_bars()
+{
+    let _ = pci::init();
+
+    // PIIX3 IDE: bus master registers in BAR 4, 16 ports
+    let ide = pci::find(PciMatch::ids(0x8086, 0x7010)).expect("no PIIX3 IDE controller");
+    assert_eq!((ide.class, ide.subclass), (pci::CLASS_MASS_STORAGE, 0x01));
+    assert!(matches!(ide.bar(4), Ok(Bar::Io { size: 16, .. })));
+    assert_eq!(ide.memory_bar(4), Err(PciError::NotMemoryBar(4)));
+
+    // the probe put everything back
+    let Ok(Bar::Io { port, .. }) = ide.bar(4) else { unreachable!() };
+    assert_eq!(ide.read_config_u32(0x20) & !0x3, port as u32);
+}
@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+
+// ---------- TESTS ----------
+
+use ferrix::loader::{self, LoadError, ElfError};
+use ferrix::process::{self, ProcessError, ProcessState, WaitError};
+
+// user/hello.elf exits with its argument count
+
+#[test_case]
+fn wait_returns_exit_code()
+{
+    let pid = process::spawn(loader::HELLO_ELF, &["hello", "a", "b"], &[]).expect("spawn failed");
+
+    let (reaped, code) = process::wait(Some(pid)).expect("not a child");
+    assert_eq!(reaped, pid);
+    assert_eq!(code, 3);
+
+    // reaped processes are gone, and cannot be waited for twice
+    assert_eq!(process::state(pid), None);
+    assert_eq!(process::wait(Some(pid)), Err(WaitError::NoSuchChild));
+}
+
+#[test_case]
+fn pids_are_unique()
+{
+    let first = process::spawn(loader::HELLO_ELF, &["first"], &[]).expect("spawn failed");
+    let second = process::spawn(loader::HELLO_ELF, &["second"], &[]).expect("spawn failed");
+    assert_ne!(first, second);
+    assert!(first.as_u64() > 0 && second.as_u64() > 0);
+
+    for pid in [first, second]
+    {
+        assert!(process::wait(Some(pid)).is_ok());
+    }
+}
+
+#[test_case]
+fn wait_any_reaps_every_child()
+{
+    let pids = [
+        process::spawn(loader::HELLO_ELF, &["one"], &[]).expect("spawn failed"),
+        process::spawn(loader::HELLO_ELF, &["two", "2"], &[]).expect("spawn failed"),
+        process::spawn(loader::HELLO_ELF, &["three", "3", "3"], &[]).expect("spawn failed"),
+    ];
+
+    let mut codes = [0; 3];
+    for _ in 0..pids.len()
+    {
+        let (pid, code) = process::wait(None).expect("child missing");
+        let index = pids.iter().position(|&spawned| spawned == pid).expect("unknown child");
+        codes[index] = code;
+    }
+
+    assert_eq!(codes, [1, 2, 3]);
+    assert_eq!(process::wait(None), Err(WaitError::NoSuchChild));
+}
+
+#[test_case]
+fn zombie_keeps_exit_code_until_reaped()
+{
+    let pid = process::spawn(loader::HELLO_ELF, &["zombie"], &[]).expect("spawn failed");
+
+    while process::state(pid) == Some(ProcessState::Running)
+    {
+        ferrix::thread::yield_now();
+    }
+
+    assert_eq!(process::state(pid), Some(ProcessState::Zombie(1)));
+    assert!(process::with_process(pid, |process| process.address_space().is_none() && process.thread_count() == 0).unwrap());
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 1)));
+}
+
+#[test_case]
+fn spawn_rejects_invalid_images()
+{
+    assert_eq!(process::spawn(&[0; 128], &["bad"], &[]).err(), Some(LoadError::Elf(ElfError::BadMagic)));
+}
+
+#[test_case]
+fn fork_exec_and_spawn_from_ring3()
+{
+    // user/proctest.S exits with the number of the first failed check
+    let pid = process::spawn_path("/bin/proctest", &["proctest"], &[]).expect("spawn failed");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+}
+
+#[test_case]
+fn forked_address_space_is_private()
+{
+    use ferrix::memory::COPY_ON_WRITE;
+    use x86_64::VirtAddr;
+    use x86_64::structures::paging::PageTableFlags;
+
+    let image = loader::load(loader::HELLO_ELF, &["hello"], &[]).expect("loading failed");
+    let parent = image.address_space;
+    let data = VirtAddr::new(0x1000_0040_2000);     // .data of user/hello.elf
+
+    parent.write(data, &[1]).unwrap();
+    let child = parent.fork().expect("fork failed");
+
+    // both share the frame read-only until one of them writes
+    let (parent_frame, parent_flags) = parent.translate(data).unwrap();
+    let (child_frame, child_flags) = child.translate(data).unwrap();
+    assert_eq!(parent_frame, child_frame);
+    assert!(parent_flags.contains(COPY_ON_WRITE) && !parent_flags.contains(PageTableFlags::WRITABLE));
+    assert!(child_flags.contains(COPY_ON_WRITE) && !child_flags.contains(PageTableFlags::WRITABLE));
+
+    child.write(data, &[2]).unwrap();
+    assert_ne!(child.translate(data).unwrap().0, parent_frame);
+    assert!(child.translate(data).unwrap().1.contains(PageTableFlags::WRITABLE));
+
+    let mut byte = [0];
+    parent.read(data, &mut byte).unwrap();
+    assert_eq!(byte, [1]);
+    child.read(data, &mut byte).unwrap();
+    assert_eq!(byte, [2]);
+
+    // the last sharer gets the original frame back without copying
+    parent.write(data, &[3]).unwrap();
+    assert_eq!(parent.translate(data).unwrap().0, parent_frame);
+}
+
+#[test_case]
+fn spawn_path_looks_up_files()
+{
+    assert_eq!(process::spawn_path("/bin/missing", &["missing"], &[]), Err(ProcessError::NotFound));
+
+    ferrix::fs::register("/bin/copy-of-hello", loader::HELLO_ELF);
+    let pid = process::spawn_path("/bin/copy-of-hello", &["copy", "x"], &[]).expect("spawn failed");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 2)));
+}
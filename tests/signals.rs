@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+
+// ---------- TESTS ----------
+
+use ferrix::loader;
+use ferrix::process::{self, signal, ProcessState, SignalError};
+use ferrix::thread;
+
+// user/misbehave.elf spins with one argument, and faults with more
+
+#[test_case]
+fn handlers_masks_and_sigreturn_from_ring3()
+{
+    let pid = process::spawn(loader::SIGTEST_ELF, &["sigtest"], &[]).expect("spawn failed");
+
+    // the number of the failed check, see user/sigtest.S
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+}
+
+#[test_case]
+fn kill_interrupts_user_code()
+{
+    let pid = process::spawn(loader::MISBEHAVE_ELF, &["misbehave"], &[]).expect("spawn failed");
+    thread::sleep_ms(20);
+
+    // it never makes a system call: delivered on the way back from the timer interrupt
+    signal::send(pid, signal::SIGTERM).expect("no such process");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, signal::exit_code(signal::SIGTERM))));
+
+    assert_eq!(signal::send(pid, signal::SIGTERM), Err(SignalError::NoSuchProcess));
+}
+
+#[test_case]
+fn stop_and_continue()
+{
+    let pid = process::spawn(loader::MISBEHAVE_ELF, &["misbehave"], &[]).expect("spawn failed");
+    let stopped = || process::with_process(pid, |process| process.signals().is_stopped()).unwrap_or(false);
+
+    signal::send(pid, signal::SIGSTOP).expect("no such process");
+    while !stopped()
+    {
+        thread::sleep_ms(10);
+    }
+
+    signal::send(pid, signal::SIGCONT).expect("no such process");
+    assert!(!stopped());
+    assert_eq!(process::state(pid), Some(ProcessState::Running));
+
+    signal::send(pid, signal::SIGKILL).expect("no such process");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, signal::exit_code(signal::SIGKILL))));
+}
+
+#[test_case]
+fn faults_terminate_with_signal()
+{
+    let cases =
+    [
+        (&["misbehave", "segv"][..], signal::SIGSEGV),
+        (&["misbehave", "ill", "x"][..], signal::SIGILL),
+        (&["misbehave", "fpe", "x", "x"][..], signal::SIGFPE),
+    ];
+
+    for (argv, sig) in cases
+    {
+        let pid = process::spawn(loader::MISBEHAVE_ELF, argv, &[]).expect("spawn failed");
+        assert_eq!(process::wait(Some(pid)), Ok((pid, signal::exit_code(sig))));
+    }
+}
+
+#[test_case]
+fn invalid_signals_are_rejected()
+{
+    let pid = process::spawn(loader::HELLO_ELF, &["hello"], &[]).expect("spawn failed");
+
+    assert_eq!(signal::send(pid, signal::NSIG), Err(SignalError::InvalidSignal));
+    assert_eq!(signal::send(pid, 0), Ok(()));
+
+    process::wait(Some(pid)).expect("not a child");
+}
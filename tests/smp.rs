@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+// ---------- TESTS ----------
+
+use ferrix::{acpi, memory, percpu, smp};
+use ferrix::memory::tlb;
+use ferrix::acpi::{AcpiError, Madt};
+use ferrix::smp::SmpError;
+use x86_64::PhysAddr;
+
+// QEMU runs with -smp 4 (see test-args in Cargo.toml)
+
+#[test_case]
+fn madt_lists_processors()
+{
+    let mut table = alloc::vec![0u8; 36 + 8];
+    table[..4].copy_from_slice(acpi::MADT_SIGNATURE);
+    table[36..40].copy_from_slice(&0xfee0_0000u32.to_le_bytes());
+
+    // two enabled processors, one disabled (and not online capable), one I/O APIC
+    for (apic_id, flags) in [(0u8, 1u32), (1, 1), (2, 0)]
+    {
+        table.extend_from_slice(&[0, 8, apic_id, apic_id]);
+        table.extend_from_slice(&flags.to_le_bytes());
+    }
+    table.extend_from_slice(&[1, 12, 7, 0]);
+    table.extend_from_slice(&0xfec0_0000u32.to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+
+    let length = table.len() as u32;
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    let sum = table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    table[9] = 0u8.wrapping_sub(sum);
+
+    let madt = Madt::parse(&table).expect("valid MADT rejected");
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xfee0_0000));
+    assert_eq!(madt.processors.iter().map(|cpu| cpu.apic_id).collect::<alloc::vec::Vec<_>>(), [0, 1]);
+    assert_eq!(madt.io_apics.len(), 1);
+
+    table[9] = table[9].wrapping_add(1);
+    assert_eq!(Madt::parse(&table).err(), Some(AcpiError::BadChecksum(*acpi::MADT_SIGNATURE)));
+}
+
+#[test_case]
+fn madt_lists_the_bootstrap_cpu()
+{
+    let madt = acpi::madt().expect("no MADT");
+    assert!(madt.processors.iter().any(|cpu| cpu.enabled));
+}
+
+#[test_case]
+fn every_cpu_comes_online()
+{
+    let enabled = acpi::madt().expect("no MADT").processors.iter().filter(|cpu| cpu.enabled).count();
+
+    let online = smp::init().expect("SMP startup failed");
+    assert_eq!(online, enabled);
+    assert_eq!(smp::cpu_count(), enabled);
+
+    // the BSP is CPU 0, every CPU has its own APIC ID
+    let cpus = smp::cpus();
+    assert_eq!(cpus.len(), enabled);
+    assert!(cpus.iter().enumerate().all(|(index, cpu)| cpu.index == index));
+    assert!(cpus.iter().all(|cpu| cpus.iter().filter(|other| other.apic_id == cpu.apic_id).count() == 1));
+
+    // every CPU set up its per-CPU area
+    for cpu in cpus
+    {
+        let area = percpu::for_cpu(cpu.index).expect("no per-CPU area");
+        assert_eq!(area.index(), cpu.index);
+        assert_eq!(area.apic_id(), cpu.apic_id);
+    }
+
+    // APs are started once
+    assert_eq!(smp::init(), Err(SmpError::AlreadyStarted));
+}
+
+#[test_case]
+fn unmapping_shoots_down_every_cpu()
+{
+    // the CPUs are up after every_cpu_comes_online
+    let _ = smp::init();
+    assert!(smp::cpu_count() > 1);
+
+    let vga = memory::map_mmio(PhysAddr::new(0xb8000), 4096).expect("mapping failed");
+    let before = tlb::generation();
+
+    memory::unmap_region(vga, 4096).expect("unmapping failed");
+
+    // one request, acknowledged by every CPU before unmap_region returned
+    assert_eq!(tlb::generation(), before + 1);
+    assert!((0..smp::cpu_count()).all(|cpu| tlb::served_generation(cpu) == before + 1));
+    assert_eq!(memory::translate_active(vga), None);
+}
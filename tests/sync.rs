@@ -0,0 +1,186 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use ferrix::sync::{Condvar, Mutex, RwLock, Semaphore};
+use ferrix::thread;
+
+#[test_case]
+fn mutex_protects_counter()
+{
+    const THREADS: usize = 4;
+    const INCREMENTS: usize = 500;
+
+    let counter = Arc::new(Mutex::new(0));
+
+    let handles: Vec<_> = (0..THREADS).map(|_|
+    {
+        let counter = counter.clone();
+        thread::spawn(move ||
+        {
+            for _ in 0..INCREMENTS
+            {
+                let mut value = counter.lock();
+                let read = *value;
+                thread::yield_now();    // make the other threads run into the held lock
+                *value = read + 1;
+            }
+        })
+    }).collect();
+
+    for handle in handles
+    {
+        handle.join();
+    }
+
+    assert_eq!(*counter.lock(), THREADS * INCREMENTS);
+}
+
+#[test_case]
+fn semaphore_limits_concurrency()
+{
+    static INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static MAX_INSIDE: AtomicUsize = AtomicUsize::new(0);
+
+    let semaphore = Arc::new(Semaphore::new(2));
+
+    let handles: Vec<_> = (0..5).map(|_|
+    {
+        let semaphore = semaphore.clone();
+        thread::spawn(move ||
+        {
+            semaphore.acquire();
+            let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_INSIDE.fetch_max(inside, Ordering::SeqCst);
+
+            for _ in 0..10
+            {
+                thread::yield_now();
+            }
+
+            INSIDE.fetch_sub(1, Ordering::SeqCst);
+            semaphore.release();
+        })
+    }).collect();
+
+    for handle in handles
+    {
+        handle.join();
+    }
+
+    assert!(MAX_INSIDE.load(Ordering::SeqCst) <= 2);
+    assert_eq!(semaphore.available(), 2);
+}
+
+#[test_case]
+fn condvar_wakes_consumer()
+{
+    let shared = Arc::new((Mutex::new(Vec::new()), Condvar::new()));
+
+    let consumer_shared = shared.clone();
+    let consumer = thread::spawn(move ||
+    {
+        let (queue, ready) = &*consumer_shared;
+        let mut received = 0;
+
+        while received < 10
+        {
+            let mut items = ready.wait_while(queue.lock(), |items: &mut Vec<u32>| items.is_empty());
+            received += items.drain(..).count();
+        }
+
+        received
+    });
+
+    let (queue, ready) = &*shared;
+    for item in 0..10
+    {
+        queue.lock().push(item);
+        ready.notify_one();
+        thread::yield_now();
+    }
+
+    assert_eq!(consumer.join(), 10);
+}
+
+#[test_case]
+fn rwlock_allows_readers_and_one_writer()
+{
+    let lock = Arc::new(RwLock::new(0u64));
+
+    {
+        let first = lock.read();
+        assert!(lock.try_read().is_some());
+        assert!(lock.try_write().is_none());
+        assert_eq!(*first, 0);
+    }
+
+    let writers: Vec<_> = (0..3).map(|_|
+    {
+        let lock = lock.clone();
+        thread::spawn(move ||
+        {
+            for _ in 0..100
+            {
+                *lock.write() += 1;
+            }
+        })
+    }).collect();
+
+    for writer in writers
+    {
+        writer.join();
+    }
+
+    assert_eq!(*lock.read(), 300);
+}
+
+#[test_case]
+fn consistent_lock_order_is_accepted()
+{
+    let a = Mutex::new(());
+    let b = Mutex::new(());
+
+    for _ in 0..3
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+}
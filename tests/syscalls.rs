@@ -0,0 +1,221 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+
+
+// ---------- TESTS ----------
+
+use ferrix::memory::{map_user_region, USER_SPACE_START, USER_SPACE_END};
+use ferrix::syscall::{SyscallError, SYS_EXIT, SYS_GETPID, SYS_MMAP, SYS_SLEEP, SYS_WRITE, SYS_YIELD};
+use ferrix::{thread, usermode};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+// ring 3 routine calling every system call through SYSCALL, storing each result (rax) in syscall_results
+core::arch::global_asm!(
+    ".global syscall_routine_start",
+    ".global syscall_routine_end",
+    ".global syscall_results",
+    "syscall_routine_start:",
+    // write(1, msg, 6)
+    "    mov rax, {write}",
+    "    mov rdi, 1",
+    "    lea rsi, [rip + syscall_message]",
+    "    mov rdx, 6",
+    "    syscall",
+    "    mov [rip + syscall_results], rax",
+    // getpid()
+    "    mov rax, {getpid}",
+    "    syscall",
+    "    mov [rip + syscall_results + 8], rax",
+    // yield()
+    "    mov rax, {yield}",
+    "    syscall",
+    "    mov [rip + syscall_results + 16], rax",
+    // sleep(20)
+    "    mov rax, {sleep}",
+    "    mov rdi, 20",
+    "    syscall",
+    "    mov [rip + syscall_results + 24], rax",
+    // mmap(0, 4096, PROT_READ | PROT_WRITE), then touch the new page
+    "    mov rax, {mmap}",
+    "    xor rdi, rdi",
+    "    mov rsi, 4096",
+    "    mov rdx, 3",
+    "    syscall",
+    "    mov [rip + syscall_results + 32], rax",
+    "    mov byte ptr [rax], 0x42",
+    // unknown syscall
+    "    mov rax, 999",
+    "    syscall",
+    "    mov [rip + syscall_results + 40], rax",
+    // write from a kernel address
+    "    mov rax, {write}",
+    "    mov rdi, 1",
+    "    mov rsi, 0x1000",
+    "    mov rdx, 4",
+    "    syscall",
+    "    mov [rip + syscall_results + 48], rax",
+    // write to a bad file descriptor
+    "    mov rax, {write}",
+    "    mov rdi, 7",
+    "    lea rsi, [rip + syscall_message]",
+    "    mov rdx, 1",
+    "    syscall",
+    "    mov [rip + syscall_results + 56], rax",
+    // exit(0)
+    "    mov rax, {exit}",
+    "    xor rdi, rdi",
+    "    syscall",
+    "    ud2",
+    "syscall_message:",
+    "    .ascii \"hello\\n\"",
+    "    .balign 8",
+    "syscall_results:",
+    "    .skip 64",
+    "syscall_routine_end:",
+    write = const SYS_WRITE,
+    getpid = const SYS_GETPID,
+    yield = const SYS_YIELD,
+    sleep = const SYS_SLEEP,
+    mmap = const SYS_MMAP,
+    exit = const SYS_EXIT,
+);
+
+// same, through the int 0x80 fallback
+core::arch::global_asm!(
+    ".global int80_routine_start",
+    ".global int80_routine_end",
+    ".global int80_results",
+    "int80_routine_start:",
+    "    mov rax, {getpid}",
+    "    int 0x80",
+    "    mov [rip + int80_results], rax",
+    "    mov rax, {exit}",
+    "    xor rdi, rdi",
+    "    int 0x80",
+    "    ud2",
+    "    .balign 8",
+    "int80_results:",
+    "    .skip 8",
+    "int80_routine_end:",
+    getpid = const SYS_GETPID,
+    exit = const SYS_EXIT,
+);
+
+unsafe extern "C"
+{
+    static syscall_routine_start: u8;
+    static syscall_routine_end: u8;
+    static syscall_results: u8;
+    static int80_routine_start: u8;
+    static int80_routine_end: u8;
+    static int80_results: u8;
+}
+
+const USER_STACK_SIZE: u64 = 0x2000;
+
+// copy a routine into fresh user pages at `code`, run it in a new thread until it exits,
+// and return the results it stored (found at the same offset in the copy)
+fn run_user_routine(code: u64, start: *const u8, end: *const u8, results: *const u8, count: usize) -> (u64, [u64; 8])
+{
+    let len = end as usize - start as usize;
+    let stack = code + 0x10000;
+
+    map_user_region(VirtAddr::new(code), len as u64, PageTableFlags::WRITABLE).expect("mapping user code failed");
+    map_user_region(VirtAddr::new(stack), USER_STACK_SIZE, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        .expect("mapping user stack failed");
+
+    unsafe { core::ptr::copy_nonoverlapping(start, code as *mut u8, len); }
+
+    let handle = thread::spawn(move ||
+    {
+        unsafe { usermode::enter_user(VirtAddr::new(code), VirtAddr::new(stack + USER_STACK_SIZE)) }
+    });
+    let tid = handle.id().as_u64();
+    handle.wait();
+
+    let copied_results = (code + (results as u64 - start as u64)) as *const u64;
+    let mut values = [0; 8];
+    for (index, value) in values.iter_mut().enumerate().take(count)
+    {
+        *value = unsafe { copied_results.add(index).read_volatile() };
+    }
+
+    (tid, values)
+}
+
+#[test_case]
+fn syscalls_from_ring3()
+{
+    let (tid, results) = run_user_routine(
+        USER_SPACE_START + 0x100000,
+        &raw const syscall_routine_start,
+        &raw const syscall_routine_end,
+        &raw const syscall_results,
+        8,
+    );
+
+    assert_eq!(results[0], 6);                  // write
+    assert_eq!(results[1], tid);                // getpid
+    assert_eq!(results[2], 0);                  // yield
+    assert_eq!(results[3], 0);                  // sleep
+
+    let mapped = results[4];                    // mmap
+    assert!(mapped >= USER_SPACE_START && mapped < USER_SPACE_END);
+    assert_eq!(unsafe { *(mapped as *const u8) }, 0x42);
+
+    assert_eq!(results[5], SyscallError::NoSuchSyscall.as_return_value());
+    assert_eq!(results[6], SyscallError::BadAddress.as_return_value());
+    assert_eq!(results[7], SyscallError::BadFileDescriptor.as_return_value());
+}
+
+#[test_case]
+fn int80_fallback_from_ring3()
+{
+    let (tid, results) = run_user_routine(
+        USER_SPACE_START + 0x200000,
+        &raw const int80_routine_start,
+        &raw const int80_routine_end,
+        &raw const int80_results,
+        1,
+    );
+
+    assert_eq!(results[0], tid);
+}
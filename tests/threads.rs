@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+
+
+// ---------- TESTS ----------
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use ferrix::thread;
+
+#[test_case]
+fn join_returns_value()
+{
+    let handle = thread::spawn(|| 6 * 7);
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn preemption_lets_all_threads_progress()
+{
+    const THREADS: usize = 4;
+    const PROGRESS: u64 = 1000;
+
+    static COUNTERS: [AtomicU64; THREADS] = [const { AtomicU64::new(0) }; THREADS];
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    // the threads never yield, so they only make progress if the timer preempts them
+    let handles: Vec<_> = (0..THREADS).map(|i| thread::spawn(move ||
+    {
+        while !STOP.load(Ordering::Relaxed)
+        {
+            COUNTERS[i].fetch_add(1, Ordering::Relaxed);
+        }
+    })).collect();
+
+    while COUNTERS.iter().any(|counter| counter.load(Ordering::Relaxed) < PROGRESS)
+    {
+        core::hint::spin_loop();
+    }
+
+    STOP.store(true, Ordering::Relaxed);
+
+    for handle in handles
+    {
+        handle.join();
+    }
+}
+
+#[test_case]
+fn exited_threads_are_finished()
+{
+    let handle = thread::spawn(|| {});
+
+    while !handle.is_finished()
+    {
+        thread::yield_now();
+    }
+
+    handle.join();
+}
+
+#[test_case]
+fn fair_share_favours_lower_nice()
+{
+    use alloc::boxed::Box;
+    use ferrix::interrupts::ticks;
+    use ferrix::thread::policy::{FairShare, RoundRobin};
+    use ferrix::thread::scheduler;
+
+    static HEAVY: AtomicU64 = AtomicU64::new(0);
+    static LIGHT: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    scheduler::set_policy(Box::new(FairShare::new()));
+
+    let heavy = thread::Builder::new().nice(-5).spawn(||
+    {
+        while !STOP.load(Ordering::Relaxed) { HEAVY.fetch_add(1, Ordering::Relaxed); }
+    });
+    let light = thread::Builder::new().nice(5).spawn(||
+    {
+        while !STOP.load(Ordering::Relaxed) { LIGHT.fetch_add(1, Ordering::Relaxed); }
+    });
+
+    // let them compete for a while (the test thread itself only waits)
+    let start = ticks();
+    while ticks() - start < 50
+    {
+        thread::yield_now();
+    }
+    STOP.store(true, Ordering::Relaxed);
+
+    let (heavy_id, light_id) = (heavy.id(), light.id());
+    heavy.join();
+    light.join();
+
+    let heavy_runtime = scheduler::thread_stats(heavy_id).unwrap().runtime;
+    let light_runtime = scheduler::thread_stats(light_id).unwrap().runtime;
+    assert!(heavy_runtime > light_runtime);
+    assert!(HEAVY.load(Ordering::Relaxed) > LIGHT.load(Ordering::Relaxed));
+
+    scheduler::dump_stats();
+    scheduler::set_policy(Box::new(RoundRobin::new()));
+}
@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+
+
+// ---------- TESTS ----------
+
+use ferrix::memory::{map_user_region, USER_SPACE_START};
+use ferrix::syscall::SYS_EXIT;
+use ferrix::{thread, usermode};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+// position independent ring 3 routine, copied into a user page by the test
+// exits its thread through the int 0x80 system call entry
+core::arch::global_asm!(
+    ".global user_trap_routine_start",
+    ".global user_trap_routine_end",
+    "user_trap_routine_start:",
+    "    mov rax, {exit}",
+    "    xor rdi, rdi",
+    "    int 0x80",
+    "    ud2",
+    "user_trap_routine_end:",
+    exit = const SYS_EXIT,
+);
+
+unsafe extern "C"
+{
+    static user_trap_routine_start: u8;
+    static user_trap_routine_end: u8;
+}
+
+const USER_CODE: u64 = USER_SPACE_START;
+const USER_STACK: u64 = USER_SPACE_START + 0x10000;
+const USER_STACK_SIZE: u64 = 0x2000;
+
+#[test_case]
+fn ring3_routine_traps_back()
+{
+    let start = &raw const user_trap_routine_start;
+    let end = &raw const user_trap_routine_end;
+    let len = end as usize - start as usize;
+
+    map_user_region(VirtAddr::new(USER_CODE), len as u64, PageTableFlags::WRITABLE).expect("mapping user code failed");
+    map_user_region(VirtAddr::new(USER_STACK), USER_STACK_SIZE, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        .expect("mapping user stack failed");
+
+    unsafe { core::ptr::copy_nonoverlapping(start, USER_CODE as *mut u8, len); }
+
+    let handle = thread::spawn(||
+    {
+        unsafe { usermode::enter_user(VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK + USER_STACK_SIZE)) }
+    });
+
+    // the exit system call ends the thread from ring 3 (it never returns a value to join)
+    handle.wait();
+    assert!(handle.is_finished());
+}
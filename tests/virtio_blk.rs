@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ferrix::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! 
+{
+    use ferrix::allocator;
+    use ferrix::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    ferrix::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! 
+{
+    ferrix::test_panic_handler(info)
+}
+
+// ---------- TESTS ----------
+
+// QEMU has a virtio-blk-pci disk on test-disks/virtio-test.img (8 MiB, created by build.rs)
+
+#[path = "common/block.rs"]
+mod common;
+
+use alloc::sync::Arc;
+use ferrix::drivers::block::{self, BlockDevice};
+use ferrix::drivers::pci::PciMatch;
+use ferrix::drivers::virtio;
+
+fn disk() -> Arc<dyn BlockDevice>
+{
+    block::find("vda").expect("no virtio disk (test-disks/virtio-test.img)")
+}
+
+#[test_case]
+fn finds_the_disk()
+{
+    common::init_drivers();
+
+    let disk = disk();
+    assert_eq!((disk.block_size(), disk.block_count()), (virtio::blk::SECTOR_SIZE, 8 * 1024 * 1024 / 512));
+    assert!(!disk.is_read_only());
+
+    // QEMU's virtio-blk-pci is transitional
+    assert_eq!(common::pci_driver(PciMatch::ids(virtio::VENDOR_ID, virtio::DEVICE_ID_TRANSITIONAL_BLOCK)), Some("virtio-blk"));
+}
+
+#[test_case]
+fn writes_and_reads_sectors()
+{
+    let disk = disk();
+    common::round_trip(&*disk, 0, 1, 0x11);
+    common::round_trip(&*disk, 7, 3, 0x22);
+    disk.flush().unwrap();
+}
+
+#[test_case]
+fn transfers_across_requests()
+{
+    // more than one request's worth, not frame aligned
+    common::round_trip(&*disk(), 100, 300, 0x33);
+}
+
+#[test_case]
+fn checks_the_disk_end()
+{
+    common::check_end(&*disk(), 0x44);
+}